@@ -0,0 +1,124 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use gateway_rs::{
+    gateway::dedup,
+    link_packet::LinkPacket,
+    router::Routing,
+    service::{breaker::Breakers, router::EnvelopeVersion},
+    settings::{
+        BackhaulPreset, BackhaulSettings, CircuitBreakerSettings, DedupKey, MetadataSettings, Nat64,
+    },
+};
+use helium_proto::{routing_information::Data as RoutingData, Eui};
+use semtech_udp::{push_data, MacAddress};
+use slog::{o, Logger};
+
+/// An unconfirmed uplink for devaddr 0x48020304 as reported by a packet
+/// forwarder.
+const RXPK: &str = r#"{
+    "time": "2021-06-30T12:00:00.000000Z",
+    "tmst": 3512348611,
+    "chan": 2,
+    "rfch": 0,
+    "freq": 903.9,
+    "stat": 1,
+    "modu": "LORA",
+    "datr": "SF7BW125",
+    "codr": "4/5",
+    "rssi": -35,
+    "lsnr": 5.1,
+    "size": 16,
+    "data": "QAQDAkgAAQABqrvMESIzRA=="
+}"#;
+
+fn rxpk() -> push_data::RxPk {
+    serde_json::from_str(RXPK).expect("rxpk")
+}
+
+fn mac() -> MacAddress {
+    MacAddress::new(&[0, 0, 0, 0, 4, 3, 2, 1])
+}
+
+fn routing() -> Routing {
+    let logger = Logger::root(slog::Discard, o!());
+    let routing = helium_proto::Routing {
+        oui: 1,
+        subnets: vec![vec![0, 2, 0, 127, 255, 0]],
+        ..Default::default()
+    };
+    let mut breakers = Breakers::new(&CircuitBreakerSettings {
+        failures: 5,
+        open_secs: 30,
+    });
+    let backhaul = BackhaulSettings {
+        preset: BackhaulPreset::Ethernet,
+        timeout: 10,
+        keepalive: 30,
+        reconnect_delay: 5,
+        interface: None,
+        prefer_ipv6: false,
+        nat64: Nat64::Off,
+        nat64_prefix: None,
+        envelope_version: EnvelopeVersion::LATEST,
+        response_deadline: true,
+        response_margin: 200,
+    };
+    Routing::from_proto(&logger, &routing, &mut breakers, &backhaul).expect("routing")
+}
+
+fn metadata() -> MetadataSettings {
+    MetadataSettings {
+        frequency_step_hz: 1,
+        rssi_step_db: 1,
+        snr_step_tenths: 1,
+    }
+}
+
+fn from_push_data(c: &mut Criterion) {
+    let rxpk = rxpk();
+    let mac = mac();
+    let metadata = metadata();
+    c.bench_function("from_push_data", |b| {
+        b.iter(|| LinkPacket::from_push_data(black_box(&rxpk), mac, &metadata).expect("packet"))
+    });
+}
+
+fn routing_decision(c: &mut Criterion) {
+    let routing = routing();
+    let devaddr = RoutingData::Devaddr(0x48020304);
+    let eui = RoutingData::Eui(Eui {
+        deveui: 0x0102030405060708,
+        appeui: 0x0807060504030201,
+    });
+    c.bench_function("routing_decision_devaddr", |b| {
+        b.iter(|| routing.matches_routing_data(black_box(&devaddr)))
+    });
+    c.bench_function("routing_decision_eui", |b| {
+        b.iter(|| routing.matches_routing_data(black_box(&eui)))
+    });
+}
+
+fn dedup_key(c: &mut Criterion) {
+    let payload = rxpk().get_data().to_vec();
+    c.bench_function("dedup_key_payload", |b| {
+        b.iter(|| dedup::key_hash(DedupKey::Payload, black_box(&payload)))
+    });
+    c.bench_function("dedup_key_frame", |b| {
+        b.iter(|| dedup::key_hash(DedupKey::Frame, black_box(&payload)))
+    });
+}
+
+fn to_pull_resp(c: &mut Criterion) {
+    let packet = LinkPacket::from_push_data(&rxpk(), mac(), &metadata()).expect("packet");
+    c.bench_function("to_pull_resp", |b| {
+        b.iter(|| black_box(&packet).to_pull_resp(false).expect("txpk"))
+    });
+}
+
+criterion_group!(
+    benches,
+    from_push_data,
+    routing_decision,
+    dedup_key,
+    to_pull_resp
+);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+//! Sends a steady stream of semtech PUSH_DATA frames to a gateway to exercise
+//! the uplink path under load. The example registers itself as a packet
+//! forwarder with a PULL_DATA and then sends uplinks at the requested rate.
+//!
+//! cargo run --release --example load_test -- --rate 100 --count 10000
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0x00;
+const PULL_DATA: u8 = 0x02;
+
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// The gateway listen address
+    #[structopt(long, default_value = "127.0.0.1:1680")]
+    gateway: SocketAddr,
+    /// Uplinks per second
+    #[structopt(long, default_value = "50")]
+    rate: u32,
+    /// Total number of uplinks to send
+    #[structopt(long, default_value = "1000")]
+    count: u32,
+    /// Number of rxpk entries per PUSH_DATA frame
+    #[structopt(long, default_value = "1")]
+    batch: u32,
+}
+
+fn frame(identifier: u8, mac: &[u8; 8], json: Option<&str>) -> Vec<u8> {
+    let token: u16 = rand::random();
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend_from_slice(&token.to_be_bytes());
+    frame.push(identifier);
+    frame.extend_from_slice(mac);
+    if let Some(json) = json {
+        frame.extend_from_slice(json.as_bytes());
+    }
+    frame
+}
+
+fn rxpk(tmst: u32, devaddr: u32) -> String {
+    // An unconfirmed uplink with an empty frame payload and a zero mic
+    let mut payload = vec![0x40];
+    payload.extend_from_slice(&devaddr.to_le_bytes());
+    payload.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0xAA, 0, 0, 0, 0]);
+    format!(
+        r#"{{"tmst":{},"chan":0,"rfch":0,"freq":903.9,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-50,"lsnr":7.5,"size":{},"data":"{}"}}"#,
+        tmst,
+        payload.len(),
+        base64::encode(&payload)
+    )
+}
+
+fn main() -> std::io::Result<()> {
+    let cli = Cli::from_args();
+    let mac: [u8; 8] = rand::random();
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(cli.gateway)?;
+    socket.send(&frame(PULL_DATA, &mac, None))?;
+
+    let interval = Duration::from_secs_f64(cli.batch as f64 / cli.rate.max(1) as f64);
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < cli.count {
+        let entries: Vec<String> = (0..cli.batch.min(cli.count - sent))
+            .map(|n| {
+                rxpk(
+                    start.elapsed().as_micros() as u32,
+                    rand::random::<u32>().wrapping_add(n),
+                )
+            })
+            .collect();
+        let json = format!(r#"{{"rxpk":[{}]}}"#, entries.join(","));
+        socket.send(&frame(PUSH_DATA, &mac, Some(&json)))?;
+        sent += entries.len() as u32;
+        thread::sleep(interval);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "sent {} uplinks in {:.1}s ({:.1}/s)",
+        sent,
+        elapsed,
+        sent as f64 / elapsed
+    );
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use crate::*;
+use config::{Config, File};
+use http::uri::Uri;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::Path,
+};
+
+/// The name of the address book overlay file edited by the `address-book`
+/// command in the settings folder.
+pub const OVERLAY_FILE: &str = "address_book.toml";
+
+/// The role a trusted endpoint plays for the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// A router packets are delivered to
+    Router,
+    /// A validator queried for chain related state
+    Gateway,
+    /// An operator configuration service
+    Bootstrap,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Router => f.write_str("router"),
+            Self::Gateway => f.write_str("gateway"),
+            Self::Bootstrap => f.write_str("bootstrap"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "router" => Ok(Self::Router),
+            "gateway" => Ok(Self::Gateway),
+            "bootstrap" => Ok(Self::Bootstrap),
+            unsupported => Err(Error::custom(format!("unsupported role: {}", unsupported))),
+        }
+    }
+}
+
+/// A trusted endpoint. The public key is pinned: the endpoint is only trusted
+/// when it identifies itself with this key.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Endpoint {
+    pub role: Role,
+    #[serde(deserialize_with = "settings::deserialize_uri")]
+    pub uri: Uri,
+    #[serde(deserialize_with = "settings::deserialize_pubkey")]
+    pub public_key: PublicKey,
+}
+
+impl Endpoint {
+    fn new(role: Role, keyed_uri: &KeyedUri) -> Self {
+        Self {
+            role,
+            uri: keyed_uri.uri.clone(),
+            public_key: keyed_uri.public_key.clone(),
+        }
+    }
+
+    pub fn keyed_uri(&self) -> KeyedUri {
+        KeyedUri {
+            uri: self.uri.clone(),
+            public_key: self.public_key.clone(),
+        }
+    }
+}
+
+/// The named, trusted endpoints the gateway talks to.
+///
+/// Entries come from the `address_book` section of the settings, including
+/// the address book overlay. The `router`, `gateways` and `bootstrap.server`
+/// settings are added as `router.<channel>`, `gateway.<index>` and
+/// `bootstrap` unless an entry with that name already exists.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct AddressBook {
+    entries: BTreeMap<String, Endpoint>,
+}
+
+impl AddressBook {
+    pub(crate) fn add_settings(
+        &mut self,
+        router: &HashMap<String, KeyedUri>,
+        gateways: &[KeyedUri],
+        bootstrap: Option<&KeyedUri>,
+    ) {
+        for (channel, keyed_uri) in router {
+            self.entries
+                .entry(format!("router.{}", channel))
+                .or_insert_with(|| Endpoint::new(Role::Router, keyed_uri));
+        }
+        for (index, keyed_uri) in gateways.iter().enumerate() {
+            self.entries
+                .entry(format!("gateway.{}", index))
+                .or_insert_with(|| Endpoint::new(Role::Gateway, keyed_uri));
+        }
+        if let Some(keyed_uri) = bootstrap {
+            self.entries
+                .entry("bootstrap".to_string())
+                .or_insert_with(|| Endpoint::new(Role::Bootstrap, keyed_uri));
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Endpoint> {
+        self.entries.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Endpoint)> {
+        self.entries.iter()
+    }
+
+    /// Returns the endpoints with the given role, ordered by name.
+    pub fn with_role(&self, role: Role) -> Vec<KeyedUri> {
+        self.entries
+            .values()
+            .filter(|endpoint| endpoint.role == role)
+            .map(Endpoint::keyed_uri)
+            .collect()
+    }
+}
+
+/// An endpoint as stored in the address book overlay.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Record {
+    pub role: Role,
+    pub uri: String,
+    pub public_key: String,
+}
+
+/// Reads the address book overlay in the given settings folder.
+pub fn load_overlay(settings_path: &Path) -> Result<BTreeMap<String, Record>> {
+    let path = settings_path.join(OVERLAY_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let mut c = Config::new();
+    c.merge(File::from(path))?;
+    match c.get("address_book") {
+        Ok(records) => Ok(records),
+        Err(config::ConfigError::NotFound(_)) => Ok(BTreeMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes the given records as the address book overlay in the given settings
+/// folder. String values are encoded as json strings, which are valid toml
+/// basic strings.
+pub fn save_overlay(settings_path: &Path, records: &BTreeMap<String, Record>) -> Result {
+    let mut out = String::from("## Generated by the address-book command.\n");
+    for (name, record) in records {
+        out.push_str(&format!(
+            "\n[address_book.{}]\nrole = {}\nuri = {}\npublic_key = {}\n",
+            serde_json::to_string(name)?,
+            serde_json::to_string(&record.role)?,
+            serde_json::to_string(&record.uri)?,
+            serde_json::to_string(&record.public_key)?,
+        ));
+    }
+    fs::write(settings_path.join(OVERLAY_FILE), out)?;
+    Ok(())
+}
@@ -0,0 +1,437 @@
+use crate::*;
+use anomaly::Baseline;
+use serde_json::{json, Value};
+use settings::AlertSettings;
+use signer::Signatures;
+use slog::{info, o, warn, Logger};
+use stats::{Aggregate, Stats};
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    fmt,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+use store::Store;
+use tokio::{io::AsyncWriteExt, process, sync::watch, time};
+
+/// Minimum number of downlinks in the window before the ack error rate is
+/// judged, so a single failed downlink on a quiet gateway does not alert.
+pub const MIN_ACK_DOWNLINKS: u64 = 5;
+/// Seconds to wait for the webhook or the alert command.
+pub const ALERT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Alert {
+    NoUplinks,
+    RouterDown,
+    DiskFull,
+    AckErrorRate,
+    UplinkAnomaly,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoUplinks => f.write_str("no_uplinks"),
+            Self::RouterDown => f.write_str("router_down"),
+            Self::DiskFull => f.write_str("disk_full"),
+            Self::AckErrorRate => f.write_str("ack_error_rate"),
+            Self::UplinkAnomaly => f.write_str("uplink_anomaly"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rules {
+    window: Duration,
+    no_uplinks: Option<Duration>,
+    router_down: bool,
+    disk_full: Option<u64>,
+    ack_error_rate: Option<u64>,
+    anomaly_sensitivity: Option<f64>,
+}
+
+impl Rules {
+    fn new(settings: &AlertSettings) -> Self {
+        let enabled = |value: u64| if value > 0 { Some(value) } else { None };
+        Self {
+            window: Duration::from_secs(settings.window * 60),
+            no_uplinks: enabled(settings.no_uplinks)
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            router_down: settings.router_down,
+            disk_full: enabled(settings.disk_full),
+            ack_error_rate: enabled(settings.ack_error_rate),
+            anomaly_sensitivity: Some(settings.anomaly_sensitivity)
+                .filter(|sensitivity| *sensitivity > 0.0),
+        }
+    }
+
+    /// How long the traffic samples need to be kept for.
+    fn retention(&self) -> Duration {
+        self.no_uplinks.unwrap_or_default().max(self.window)
+    }
+
+    /// Returns the active alerts with a description of each.
+    fn check(
+        &self,
+        samples: &Samples,
+        disk_used: Option<u64>,
+        baseline: &Baseline,
+        now: u64,
+    ) -> HashMap<Alert, String> {
+        let mut active = HashMap::new();
+        if let Some(timeout) = self.no_uplinks {
+            // Only judged once the samples span the whole timeout
+            if let Some((span, first, last)) = samples.over(timeout) {
+                if span >= timeout && last.uplinks == first.uplinks {
+                    active.insert(
+                        Alert::NoUplinks,
+                        format!("no uplinks for {} minutes", span.as_secs() / 60),
+                    );
+                }
+            }
+        }
+        if let Some((span, first, last)) = samples.over(self.window) {
+            if let Some(sensitivity) = self.anomaly_sensitivity.filter(|_| span >= self.window) {
+                let uplinks = last.uplinks - first.uplinks;
+                if let Some(anomaly) = baseline.check(now, uplinks, span, sensitivity) {
+                    active.insert(Alert::UplinkAnomaly, anomaly.to_string());
+                }
+            }
+            let requests = last.router_requests - first.router_requests;
+            let failures = last.router_failures - first.router_failures;
+            if self.router_down && requests > 0 && failures == requests {
+                active.insert(
+                    Alert::RouterDown,
+                    format!("all {} router requests failed", requests),
+                );
+            }
+            let downlinks = last.downlinks - first.downlinks;
+            let failures = last.downlink_failures - first.downlink_failures;
+            if let Some(rate) = self.ack_error_rate {
+                if downlinks >= MIN_ACK_DOWNLINKS && failures * 100 >= rate * downlinks {
+                    active.insert(
+                        Alert::AckErrorRate,
+                        format!("{} of {} downlinks failed", failures, downlinks),
+                    );
+                }
+            }
+        }
+        if let (Some(limit), Some(used)) = (self.disk_full, disk_used) {
+            if used >= limit {
+                active.insert(Alert::DiskFull, format!("store filesystem {}% used", used));
+            }
+        }
+        active
+    }
+}
+
+/// Samples of the running traffic totals.
+#[derive(Debug, Default)]
+struct Samples(VecDeque<(Instant, Aggregate)>);
+
+impl Samples {
+    fn push(&mut self, now: Instant, totals: Aggregate, retention: Duration) {
+        self.0.push_back((now, totals));
+        // Keep one sample at least as old as the retention
+        while self
+            .0
+            .get(1)
+            .map_or(false, |(at, _)| now.duration_since(*at) >= retention)
+        {
+            self.0.pop_front();
+        }
+    }
+
+    /// Returns the time spanned and the first and last sample over the given
+    /// window, or over all samples if they don't span the window yet.
+    fn over(&self, window: Duration) -> Option<(Duration, &Aggregate, &Aggregate)> {
+        let (last_at, last) = self.0.back()?;
+        let (first_at, first) = self
+            .0
+            .iter()
+            .rev()
+            .find(|(at, _)| last_at.duration_since(*at) >= window)
+            .or_else(|| self.0.front())?;
+        Some((last_at.duration_since(*first_at), first, last))
+    }
+}
+
+#[derive(Debug)]
+struct Hooks {
+    webhook: Option<String>,
+    command: Option<String>,
+    args: Vec<String>,
+    signatures: Signatures,
+    labels: Value,
+}
+
+impl Hooks {
+    async fn send(&self, alert: Alert, state: &str, message: &str, logger: &Logger) {
+        let payload = json!({
+            "alert": alert.to_string(),
+            "state": state,
+            "message": message,
+            "gateway": self.signatures.public_key().to_string(),
+            "labels": self.labels,
+            "timestamp": stats::unix_secs(),
+        })
+        .to_string();
+        if let Some(webhook) = &self.webhook {
+            if let Err(err) = curl::post(webhook, &self.args, payload.clone(), |_| Ok(())).await {
+                warn!(logger, "failed to post alert {}: {:?}", alert, err);
+            }
+        }
+        if let Some(command) = &self.command {
+            if let Err(err) = run_command(command, &payload).await {
+                warn!(
+                    logger,
+                    "failed to run alert command for {}: {:?}", alert, err
+                );
+            }
+        }
+    }
+}
+
+async fn run_command(command: &str, payload: &str) -> Result {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(payload.as_bytes()).await?;
+    }
+    let status = time::timeout(Duration::from_secs(ALERT_TIMEOUT_SECS), child.wait())
+        .await
+        .map_err(|_| Error::custom("alert command timed out"))??;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::custom(format!(
+            "alert command failed: {:?}",
+            status.code()
+        )))
+    }
+}
+
+/// Creates the handle the gateway reads the active alerts from for the api,
+/// and the service checking the alert rules.
+pub fn alerts(
+    stats: Stats,
+    store: Store,
+    signatures: Signatures,
+    settings: &Settings,
+) -> (Alerts, AlertService) {
+    let alerts = &settings.alerts;
+    let mut args = curl::interface_args(&settings.backhaul.interface);
+    args.extend_from_slice(&[
+        "-H".to_string(),
+        "Content-Type: application/json".to_string(),
+        "--max-time".to_string(),
+        ALERT_TIMEOUT_SECS.to_string(),
+    ]);
+    let hooks = Hooks {
+        webhook: alerts.webhook.clone(),
+        command: alerts.command.clone(),
+        args,
+        signatures,
+        labels: json!(settings.labels),
+    };
+    let (sender, receiver) = watch::channel(json!([]));
+    (
+        Alerts(receiver),
+        AlertService {
+            rules: Rules::new(alerts),
+            hooks,
+            stats,
+            store,
+            check_interval: Duration::from_secs(alerts.check_interval.max(1)),
+            store_path: settings.store.path.clone(),
+            enabled: alerts.enabled,
+            active: sender,
+        },
+    )
+}
+
+/// The alerts firing at the last check.
+#[derive(Debug)]
+pub struct Alerts(watch::Receiver<Value>);
+
+impl Alerts {
+    pub fn active(&self) -> Value {
+        self.0.borrow().clone()
+    }
+}
+
+/// Checks the gateway health periodically and sends an alert to the
+/// configured webhook and command when a rule starts or stops firing.
+#[derive(Debug)]
+pub struct AlertService {
+    rules: Rules,
+    hooks: Hooks,
+    stats: Stats,
+    store: Store,
+    check_interval: Duration,
+    store_path: PathBuf,
+    enabled: bool,
+    active: watch::Sender<Value>,
+}
+
+impl AlertService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "alerts"));
+        if !self.enabled {
+            info!(logger, "disabling");
+            return Ok(());
+        }
+        info!(logger, "starting");
+        let mut check_timer = time::interval(self.check_interval);
+        let mut samples = Samples::default();
+        let mut firing: HashMap<Alert, String> = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = check_timer.tick() => {
+                    let active = self.check(&mut samples, &logger).await;
+                    for (alert, message) in &active {
+                        if !firing.contains_key(alert) {
+                            warn!(logger, "alert {} firing: {}", alert, message);
+                            self.hooks.send(*alert, "firing", message, &logger).await;
+                        }
+                    }
+                    for (alert, message) in &firing {
+                        if !active.contains_key(alert) {
+                            info!(logger, "alert {} resolved", alert);
+                            self.hooks.send(*alert, "resolved", message, &logger).await;
+                        }
+                    }
+                    let _ = self.active.send(
+                        active
+                            .iter()
+                            .map(|(alert, message)| json!({ "alert": alert.to_string(), "message": message }))
+                            .collect(),
+                    );
+                    firing = active;
+                }
+            }
+        }
+    }
+
+    async fn check(&self, samples: &mut Samples, logger: &Logger) -> HashMap<Alert, String> {
+        let rules = &self.rules;
+        samples.push(Instant::now(), self.stats.totals(), rules.retention());
+        let disk_used = match rules.disk_full {
+            Some(_) => disk_usage(&self.store_path),
+            None => None,
+        };
+        let now = stats::unix_secs();
+        let baseline = match rules.anomaly_sensitivity {
+            // Relearned on every check, there are at most a few hundred
+            // hourly aggregates
+            Some(_) => match stats::hourly(&self.store) {
+                Ok(hourly) => Baseline::learn(&hourly, now),
+                Err(err) => {
+                    warn!(logger, "failed to read hourly stats: {:?}", err);
+                    Baseline::default()
+                }
+            },
+            None => Baseline::default(),
+        };
+        rules.check(samples, disk_used, &baseline, now)
+    }
+}
+
+/// Returns the percentage in use of the filesystem holding the given path, or
+/// the closest existing parent of it.
+fn disk_usage(path: &Path) -> Option<u64> {
+    let path = path.ancestors().find(|path| path.exists())?;
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: the path is nul terminated and statvfs fills in the stat on
+    // success
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // The block counts are 32 bits on some targets
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, free, available) = (
+        stat.f_blocks as u64,
+        stat.f_bfree as u64,
+        stat.f_bavail as u64,
+    );
+    usage(blocks, free, available)
+}
+
+/// The percentage of a filesystem in use, rounded up, the way df reports
+/// it: the blocks reserved for root count as neither used nor available.
+fn usage(blocks: u64, free: u64, available: u64) -> Option<u64> {
+    let used = blocks.checked_sub(free)?;
+    let total = used + available;
+    if total == 0 {
+        return None;
+    }
+    Some((used * 100).div_ceil(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let rules = Rules {
+            window: Duration::from_secs(600),
+            no_uplinks: Some(Duration::from_secs(1200)),
+            router_down: true,
+            disk_full: Some(90),
+            ack_error_rate: Some(20),
+            anomaly_sensitivity: None,
+        };
+        let start = Instant::now();
+        let mut samples = Samples::default();
+        let at = |secs| start + Duration::from_secs(secs);
+        samples.push(at(0), Aggregate::default(), rules.retention());
+        let totals = Aggregate {
+            router_requests: 4,
+            router_failures: 4,
+            downlinks: 10,
+            downlink_failures: 1,
+            ..Default::default()
+        };
+        samples.push(at(600), totals.clone(), rules.retention());
+        let active = rules.check(&samples, Some(50), &Baseline::default(), 0);
+        assert!(active.contains_key(&Alert::RouterDown));
+        assert!(!active.contains_key(&Alert::AckErrorRate));
+        assert!(!active.contains_key(&Alert::NoUplinks));
+
+        let totals = Aggregate {
+            downlinks: 20,
+            downlink_failures: 5,
+            ..totals
+        };
+        samples.push(at(1200), totals, rules.retention());
+        let active = rules.check(&samples, Some(95), &Baseline::default(), 0);
+        assert!(!active.contains_key(&Alert::RouterDown));
+        assert!(active.contains_key(&Alert::AckErrorRate));
+        assert!(active.contains_key(&Alert::NoUplinks));
+        assert!(active.contains_key(&Alert::DiskFull));
+
+        assert_eq!(Some(87), usage(1000, 130, 130));
+        // Blocks reserved for root
+        assert_eq!(Some(92), usage(1000, 130, 80));
+        assert_eq!(None, usage(0, 0, 0));
+        assert!(disk_usage(Path::new("/")).is_some());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::*;
+use stats::Aggregate;
+use std::{fmt, time::Duration};
+
+/// Days of hourly stats an hour of the day needs before its baseline is
+/// used.
+pub const MIN_BASELINE_DAYS: usize = 3;
+const HOUR_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    mean: f64,
+    variance: f64,
+}
+
+/// The usual number of uplinks per hour for each hour of the day (UTC),
+/// learned from the hourly stats.
+#[derive(Debug, Default)]
+pub struct Baseline([Option<Rate>; 24]);
+
+/// Uplink traffic far off the baseline of its hour of the day. Far more
+/// uplinks usually mean a jammer or a noisy neighbour, far fewer a failed
+/// antenna or a packet forwarder that stopped forwarding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub hour: usize,
+    pub minutes: u64,
+    pub uplinks: u64,
+    pub expected: f64,
+    /// How many standard deviations the uplinks are off the baseline
+    pub deviation: f64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} uplinks in the last {} minutes, {} than the usual {:.0} at {:02}:00 UTC",
+            self.uplinks,
+            self.minutes,
+            if self.deviation > 0.0 {
+                "more"
+            } else {
+                "fewer"
+            },
+            self.expected,
+            self.hour
+        )
+    }
+}
+
+impl Baseline {
+    /// Learns the baseline from hourly aggregates, leaving out the current
+    /// hour since it is not complete yet.
+    pub fn learn(hourly: &[Aggregate], now: u64) -> Self {
+        let current = now / HOUR_SECS * HOUR_SECS;
+        let mut counts: [Vec<f64>; 24] = Default::default();
+        for aggregate in hourly.iter().filter(|aggregate| aggregate.start < current) {
+            counts[hour_of_day(aggregate.start)].push(aggregate.uplinks as f64);
+        }
+        let mut baseline = Self::default();
+        for (hour, counts) in counts.iter().enumerate() {
+            if counts.len() < MIN_BASELINE_DAYS {
+                continue;
+            }
+            let n = counts.len() as f64;
+            let mean = counts.iter().sum::<f64>() / n;
+            let variance = counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            baseline.0[hour] = Some(Rate { mean, variance });
+        }
+        baseline
+    }
+
+    /// Compares the uplinks counted over the span ending at `now` to the
+    /// baseline of the hour of the day. Returns an anomaly when they are
+    /// `sensitivity` or more standard deviations off.
+    pub fn check(
+        &self,
+        now: u64,
+        uplinks: u64,
+        span: Duration,
+        sensitivity: f64,
+    ) -> Option<Anomaly> {
+        let hour = hour_of_day(now);
+        let rate = self.0[hour]?;
+        let fraction = span.as_secs_f64() / HOUR_SECS as f64;
+        let expected = rate.mean * fraction;
+        // Uplinks arrive at random even at a steady rate, so the spread is at
+        // least that of a poisson process on top of the day to day variance
+        let spread = (expected + rate.variance * fraction * fraction)
+            .sqrt()
+            .max(1.0);
+        let deviation = (uplinks as f64 - expected) / spread;
+        if deviation.abs() < sensitivity {
+            return None;
+        }
+        Some(Anomaly {
+            hour,
+            minutes: span.as_secs() / 60,
+            uplinks,
+            expected,
+            deviation,
+        })
+    }
+}
+
+fn hour_of_day(unix_secs: u64) -> usize {
+    ((unix_secs / HOUR_SECS) % 24) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline() {
+        let day = 24 * HOUR_SECS;
+        let at_five = |day_offset: u64| day_offset * day + 5 * HOUR_SECS;
+        let hourly: Vec<Aggregate> = [90, 100, 110]
+            .iter()
+            .enumerate()
+            .map(|(i, uplinks)| Aggregate {
+                start: at_five(i as u64),
+                uplinks: *uplinks,
+                ..Default::default()
+            })
+            .collect();
+        let now = at_five(3) + 900;
+        let window = Duration::from_secs(900);
+        // Only two days of data, no baseline yet
+        assert!(Baseline::learn(&hourly[..2], now)
+            .check(now, 0, window, 4.0)
+            .is_none());
+
+        let baseline = Baseline::learn(&hourly, now);
+        assert!(baseline.check(now, 25, window, 4.0).is_none());
+        let quiet = baseline.check(now, 0, window, 4.0).expect("anomaly");
+        assert!(quiet.deviation < 0.0);
+        assert_eq!(
+            "0 uplinks in the last 15 minutes, fewer than the usual 25 at 05:00 UTC",
+            quiet.to_string()
+        );
+        assert!(
+            baseline
+                .check(now, 200, window, 4.0)
+                .expect("anomaly")
+                .deviation
+                > 0.0
+        );
+        // No baseline for other hours of the day
+        assert!(baseline.check(now + HOUR_SECS, 0, window, 4.0).is_none());
+    }
+}
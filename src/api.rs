@@ -0,0 +1,831 @@
+use crate::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time,
+};
+
+/// Maximum number of api requests waiting for the gateway.
+pub const API_QUEUE_SIZE: usize = 8;
+/// Maximum size of an http api request, head and body.
+pub const HTTP_MAX_REQUEST: usize = 64 * 1024;
+/// Maximum length of a json api request line.
+pub const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// How long a client has to send a whole request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The methods that change the gateway and need a control token. All other
+/// methods only read gateway state.
+pub const CONTROL_METHODS: &[&str] = &["settings_dry_run", "settings_apply"];
+/// The http verbs a request line is recognized by on the json api.
+const HTTP_VERBS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// A request read from an api client, answered by the gateway.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub params: Value,
+    pub reply: oneshot::Sender<std::result::Result<Value, String>>,
+}
+
+impl Request {
+    pub fn respond(self, result: std::result::Result<Value, String>) {
+        // The client may have disconnected in the meantime
+        let _ = self.reply.send(result);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// What an api token allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Control,
+}
+
+impl Role {
+    /// The role an api method needs.
+    pub fn required(method: &str) -> Self {
+        if CONTROL_METHODS.contains(&method) {
+            Self::Control
+        } else {
+            Self::Read
+        }
+    }
+}
+
+/// Why an api call was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Denied {
+    /// No token or an unknown one
+    Unauthorized,
+    /// The token does not allow the method
+    Forbidden,
+    /// A control method without any control token configured
+    Disabled,
+}
+
+impl Denied {
+    fn status(self) -> &'static str {
+        match self {
+            Self::Unauthorized => "401 Unauthorized",
+            Self::Forbidden | Self::Disabled => "403 Forbidden",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::Disabled => "control methods need a control token in the api settings",
+        }
+    }
+}
+
+/// The api tokens and their roles. Read methods are allowed to every call
+/// when there are no tokens, control methods are refused unless a control
+/// token is configured.
+#[derive(Debug, Clone, Default)]
+struct Auth(Arc<Vec<(String, Role)>>);
+
+impl Auth {
+    fn new(settings: &Settings) -> Self {
+        let read = settings
+            .api
+            .read_tokens
+            .iter()
+            .map(|token| (token.clone(), Role::Read));
+        let control = settings
+            .api
+            .control_tokens
+            .iter()
+            .map(|token| (token.clone(), Role::Control));
+        Self(Arc::new(read.chain(control).collect()))
+    }
+
+    /// The role of a token. Every token is compared in constant time so the
+    /// time taken does not tell how much of a token was right. A token
+    /// listed with both roles has the control role.
+    fn role(&self, token: &str) -> Option<Role> {
+        self.0
+            .iter()
+            .filter(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+            .map(|(_, role)| *role)
+            .fold(None, |best, role| best.max(Some(role)))
+    }
+
+    fn authorize(&self, token: Option<&str>, method: &str) -> std::result::Result<(), Denied> {
+        let required = Role::required(method);
+        if required == Role::Control && !self.0.iter().any(|(_, role)| *role == Role::Control) {
+            return Err(Denied::Disabled);
+        }
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let role = token
+            .and_then(|token| self.role(token))
+            .ok_or(Denied::Unauthorized)?;
+        if role < required {
+            return Err(Denied::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+/// Creates the receiving end of api requests for the gateway and the service
+/// accepting api clients.
+pub fn api(settings: &Settings) -> (Api, ApiService) {
+    if !settings.api.enabled {
+        return (
+            Api { requests: None },
+            ApiService {
+                state: None,
+                socket: None,
+                http_listen_addr: None,
+                auth: Auth::default(),
+            },
+        );
+    }
+    let (sender, receiver) = mpsc::channel(API_QUEUE_SIZE);
+    (
+        Api {
+            requests: Some(receiver),
+        },
+        ApiService {
+            state: Some((settings.api.listen_addr, sender)),
+            socket: settings
+                .api
+                .socket
+                .clone()
+                .map(|path| (path, settings.api.socket_mode)),
+            http_listen_addr: settings.api.http_listen_addr,
+            auth: Auth::new(settings),
+        },
+    )
+}
+
+/// Api requests for the gateway.
+#[derive(Debug)]
+pub struct Api {
+    requests: Option<Receiver<Request>>,
+}
+
+impl Api {
+    pub fn is_enabled(&self) -> bool {
+        self.requests.is_some()
+    }
+
+    pub async fn recv(&mut self) -> Option<Request> {
+        self.requests.as_mut()?.recv().await
+    }
+}
+
+/// Serves the local api, one json request per line answered with one json
+/// line holding either a `result` or an `error`. The api listens on the
+/// loopback address by default since it is not authenticated unless tokens
+/// are configured, in which case each request carries a `token` whose role
+/// has to allow the method.
+///
+/// The same methods can be served over http for dashboards and curl, as
+/// `GET /api/<method>?<param>=<value>` or `POST /api/<method>` with the json
+/// params as the body, with the token as a bearer token. Responses carry no
+/// CORS headers, so pages from other origins can not call the api. The local
+/// api is not a grpc service, so there is no grpc-web mapping.
+///
+/// With a socket path set the json api listens on a unix socket instead of a
+/// tcp port, so access is granted by the file permissions.
+#[derive(Debug)]
+pub struct ApiService {
+    state: Option<(SocketAddr, Sender<Request>)>,
+    /// The unix socket path and file mode
+    socket: Option<(PathBuf, u32)>,
+    http_listen_addr: Option<SocketAddr>,
+    auth: Auth,
+}
+
+impl ApiService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "api"));
+        let (listen_addr, requests) = match self.state.take() {
+            Some(state) => state,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        let (listener, socket_listener) = match &self.socket {
+            Some((path, mode)) => {
+                info!(logger, "starting"; "socket" => path.display().to_string());
+                (None, Some(bind_socket(path, *mode)?))
+            }
+            None => {
+                info!(logger, "starting"; "listen_addr" => listen_addr.to_string());
+                (Some(TcpListener::bind(listen_addr).await?), None)
+            }
+        };
+        let http_listener = match self.http_listen_addr {
+            Some(addr) => {
+                info!(logger, "serving http api"; "listen_addr" => addr.to_string());
+                Some(TcpListener::bind(addr).await?)
+            }
+            None => None,
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    if let Some((path, _)) = &self.socket {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    return Ok(())
+                },
+                accepted = accept(&listener), if listener.is_some() => {
+                    let (stream, addr) = accepted?;
+                    debug!(logger, "api client connected from {}", addr);
+                    tokio::spawn(serve(stream, requests.clone(), self.auth.clone(), logger.clone()));
+                }
+                accepted = accept_socket(&socket_listener), if socket_listener.is_some() => {
+                    let (stream, _) = accepted?;
+                    debug!(logger, "api client connected on socket");
+                    tokio::spawn(serve(stream, requests.clone(), self.auth.clone(), logger.clone()));
+                }
+                accepted = accept(&http_listener), if http_listener.is_some() => {
+                    let (stream, addr) = accepted?;
+                    debug!(logger, "http api client connected from {}", addr);
+                    tokio::spawn(serve_http(stream, requests.clone(), self.auth.clone(), logger.clone()));
+                }
+            }
+        }
+    }
+}
+
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn accept_socket(
+    listener: &Option<UnixListener>,
+) -> std::io::Result<(UnixStream, tokio::net::unix::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Binds the api socket with the given file mode, replacing the socket left
+/// by an earlier run. A socket another instance still accepts connections on
+/// is refused as an address in use rather than taken over. The socket is
+/// bound in a directory only the gateway can enter and given its mode there
+/// before it is moved into place, so it is never reachable with a wider one.
+fn bind_socket(path: &Path, mode: u32) -> Result<UnixListener> {
+    use std::os::unix::fs::DirBuilderExt;
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!(
+                    "api socket {} is in use by another instance",
+                    path.display()
+                ),
+            )
+            .into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::custom(format!("invalid api socket {}", path.display())))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    if private.exists() {
+        std::fs::remove_dir_all(&private)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = bind_staged(&private.join(file_name), path, mode);
+    std::fs::remove_dir_all(&private)?;
+    bound
+}
+
+fn bind_staged(staged: &Path, path: &Path, mode: u32) -> Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    let listener = UnixListener::bind(staged)?;
+    std::fs::set_permissions(staged, std::fs::Permissions::from_mode(mode))?;
+    std::fs::rename(staged, path)?;
+    Ok(listener)
+}
+
+/// Serves json lines to an api client. The first line that is not json ends
+/// the connection, so a client speaking another protocol, like a browser
+/// made to send http to the api port, gets nothing but the first error. An
+/// http request line is not answered at all. So are lines longer than
+/// `MAX_REQUEST_LEN`, and a client that takes longer than
+/// `REQUEST_TIMEOUT` to send a line is dropped.
+async fn serve<S>(stream: S, requests: Sender<Request>, auth: Auth, logger: Logger)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    loop {
+        let line = match time::timeout(REQUEST_TIMEOUT, read_line(&mut reader)).await {
+            Ok(Ok(Some(line))) => line,
+            Ok(Ok(None)) => return,
+            Ok(Err(err)) => {
+                debug!(logger, "closing api connection: {}", err);
+                return;
+            }
+            Err(_) => {
+                debug!(logger, "closing idle api connection");
+                return;
+            }
+        };
+        if is_http(&line) {
+            debug!(logger, "closing api connection of an http client");
+            return;
+        }
+        let (result, close) = match serde_json::from_str::<Value>(&line) {
+            Ok(value) => match serde_json::from_value::<Call>(value) {
+                Ok(call) => match auth.authorize(call.token.as_deref(), &call.method) {
+                    Ok(()) => (dispatch(&requests, call).await, false),
+                    Err(denied) => (Err(denied.message().to_string()), false),
+                },
+                Err(err) => (Err(format!("invalid request: {}", err)), false),
+            },
+            Err(err) => (Err(format!("invalid request: {}", err)), true),
+        };
+        let response = match result {
+            Ok(result) => json!({ "result": result }),
+            Err(error) => json!({ "error": error }),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        if let Err(err) = writer.write_all(response.as_bytes()).await {
+            warn!(logger, "failed to answer api client: {:?}", err);
+            return;
+        }
+        if close {
+            debug!(
+                logger,
+                "closing api connection after a line that is not json"
+            );
+            return;
+        }
+    }
+}
+
+/// Reads a request line of at most `MAX_REQUEST_LEN` bytes, without the line
+/// ending. Returns None at the end of the stream.
+async fn read_line<R>(reader: &mut R) -> std::result::Result<Option<String>, String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    let read = reader
+        .take(MAX_REQUEST_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|err| err.to_string())?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_REQUEST_LEN {
+        return Err("request too long".to_string());
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+/// Whether a line looks like the request line of an http request.
+fn is_http(line: &str) -> bool {
+    let line = line.trim_end();
+    line.ends_with(" HTTP/1.0")
+        || line.ends_with(" HTTP/1.1")
+        || line
+            .split_whitespace()
+            .next()
+            .map_or(false, |verb| HTTP_VERBS.contains(&verb))
+}
+
+/// Compares two byte strings in time that only depends on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// An http api request.
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+    verb: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+}
+
+impl HttpRequest {
+    /// Parses the head of an http request, up to the empty line.
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.lines();
+        let mut request_line = lines.next()?.split_whitespace();
+        let verb = request_line.next()?.to_string();
+        let target = request_line.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Self {
+            verb,
+            path: percent_decode(path),
+            query,
+            headers,
+        })
+    }
+
+    fn content_length(&self) -> usize {
+        self.headers
+            .get("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// The api method of the request path.
+    fn method(&self) -> Option<&str> {
+        self.path
+            .strip_prefix("/api/")
+            .filter(|method| !method.is_empty())
+    }
+
+    fn bearer(&self) -> Option<&str> {
+        self.headers.get("authorization")?.strip_prefix("Bearer ")
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Serves one http api request and closes the connection.
+async fn serve_http(mut stream: TcpStream, requests: Sender<Request>, auth: Auth, logger: Logger) {
+    let (status, body) = match time::timeout(REQUEST_TIMEOUT, read_http(&mut stream)).await {
+        Ok(Ok((request, body))) => answer_http(&requests, &auth, request, body).await,
+        Ok(Err(err)) => ("400 Bad Request", json!({ "error": err })),
+        Err(_) => (
+            "408 Request Timeout",
+            json!({ "error": "request timed out" }),
+        ),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        warn!(logger, "failed to answer http api client: {:?}", err);
+    }
+}
+
+async fn read_http(stream: &mut TcpStream) -> std::result::Result<(HttpRequest, Vec<u8>), String> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > HTTP_MAX_REQUEST {
+            return Err("request too large".to_string());
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err("incomplete request".to_string()),
+            Ok(read) => buf.extend_from_slice(&chunk[..read]),
+        }
+    };
+    let request = HttpRequest::parse(&String::from_utf8_lossy(&buf[..head_end]))
+        .ok_or_else(|| "invalid request".to_string())?;
+    let length = request.content_length();
+    if length > HTTP_MAX_REQUEST {
+        return Err("request too large".to_string());
+    }
+    let mut body = buf.split_off(head_end + 4);
+    while body.len() < length {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err("incomplete request".to_string()),
+            Ok(read) => body.extend_from_slice(&chunk[..read]),
+        }
+    }
+    body.truncate(length);
+    Ok((request, body))
+}
+
+async fn answer_http(
+    requests: &Sender<Request>,
+    auth: &Auth,
+    request: HttpRequest,
+    body: Vec<u8>,
+) -> (&'static str, Value) {
+    let method = match request.method() {
+        Some(method) => method.to_string(),
+        None => return ("404 Not Found", json!({ "error": "not found" })),
+    };
+    if let Err(denied) = auth.authorize(request.bearer(), &method) {
+        return (denied.status(), json!({ "error": denied.message() }));
+    }
+    let params = match request.verb.as_str() {
+        "GET" => json!(request.query),
+        "POST" if body.is_empty() => Value::Null,
+        "POST" => match serde_json::from_slice(&body) {
+            Ok(params) => params,
+            Err(err) => {
+                return (
+                    "400 Bad Request",
+                    json!({ "error": format!("invalid params: {}", err) }),
+                )
+            }
+        },
+        _ => {
+            return (
+                "405 Method Not Allowed",
+                json!({ "error": "method not allowed" }),
+            )
+        }
+    };
+    match dispatch(
+        requests,
+        Call {
+            method,
+            params,
+            token: None,
+        },
+    )
+    .await
+    {
+        Ok(result) => ("200 OK", json!({ "result": result })),
+        Err(error) => ("400 Bad Request", json!({ "error": error })),
+    }
+}
+
+async fn dispatch(requests: &Sender<Request>, call: Call) -> std::result::Result<Value, String> {
+    let (reply, response) = oneshot::channel();
+    let request = Request {
+        method: call.method,
+        params: call.params,
+        reply,
+    };
+    if requests.send(request).await.is_err() {
+        return Err("gateway not running".to_string());
+    }
+    response
+        .await
+        .unwrap_or_else(|_| Err("gateway not running".to_string()))
+}
+
+/// Calls a method of the api of the running gateway, on the api socket when
+/// one is set and exists and on the api address otherwise.
+pub async fn call(settings: &Settings, method: &str, params: Value) -> Result<Value> {
+    if !settings.api.enabled {
+        return Err(Error::custom(
+            "gateway api not enabled, set enabled in the [api] settings",
+        ));
+    }
+    let not_reachable =
+        |err: std::io::Error| Error::custom(format!("gateway api not reachable: {}", err));
+    match settings.api.socket.as_deref().filter(|path| path.exists()) {
+        Some(path) => {
+            let stream = UnixStream::connect(path).await.map_err(not_reachable)?;
+            call_stream(stream, settings, method, params).await
+        }
+        None => {
+            let stream = TcpStream::connect(settings.api.listen_addr)
+                .await
+                .map_err(not_reachable)?;
+            call_stream(stream, settings, method, params).await
+        }
+    }
+}
+
+async fn call_stream<S>(
+    stream: S,
+    settings: &Settings,
+    method: &str,
+    params: Value,
+) -> Result<Value>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut request = json!({
+        "method": method,
+        "params": params,
+        "token": settings.api.client_token(),
+    })
+    .to_string();
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| Error::custom("gateway api closed the connection"))?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    match response.get("error").and_then(|e| e.as_str()) {
+        Some(error) => Err(Error::custom(error)),
+        None => Ok(response["result"].take()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_request() {
+        let request = HttpRequest::parse(
+            "GET /api/dutycycle?since=7d&name=a%20b+c HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Authorization: Bearer secret",
+        )
+        .expect("request");
+        assert_eq!(Some("dutycycle"), request.method());
+        assert_eq!(Some(&"7d".to_string()), request.query.get("since"));
+        assert_eq!(Some(&"a b c".to_string()), request.query.get("name"));
+        assert_eq!(Some("secret"), request.bearer());
+        assert_eq!(0, request.content_length());
+
+        let request =
+            HttpRequest::parse("POST /api/ HTTP/1.1\r\nContent-Length: 12").expect("request");
+        assert_eq!(None, request.method());
+        assert_eq!(12, request.content_length());
+        assert_eq!(None, request.bearer());
+    }
+
+    #[test]
+    fn auth() {
+        let open = Auth::default();
+        assert_eq!(Ok(()), open.authorize(None, "stats"));
+        assert_eq!(
+            Err(Denied::Disabled),
+            open.authorize(None, "settings_apply")
+        );
+        let read_only = Auth(Arc::new(vec![("reader".to_string(), Role::Read)]));
+        assert_eq!(
+            Err(Denied::Disabled),
+            read_only.authorize(Some("reader"), "settings_dry_run")
+        );
+
+        let auth = Auth(Arc::new(
+            vec![
+                ("reader".to_string(), Role::Read),
+                ("operator".to_string(), Role::Control),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        assert_eq!(Err(Denied::Unauthorized), auth.authorize(None, "stats"));
+        assert_eq!(
+            Err(Denied::Unauthorized),
+            auth.authorize(Some("other"), "stats")
+        );
+        assert_eq!(Ok(()), auth.authorize(Some("reader"), "stats"));
+        assert_eq!(Ok(()), auth.authorize(Some("operator"), "stats"));
+        assert_eq!(Role::Read, Role::required("stats"));
+        assert_eq!(
+            Err(Denied::Forbidden),
+            auth.authorize(Some("reader"), "settings_apply")
+        );
+        assert_eq!(Ok(()), auth.authorize(Some("operator"), "settings_apply"));
+        assert_eq!(
+            Err(Denied::Unauthorized),
+            auth.authorize(Some("operato"), "stats")
+        );
+    }
+
+    #[test]
+    fn lines() {
+        assert!(is_http("GET / HTTP/1.1"));
+        assert!(is_http("POST /api/stats HTTP/1.0\r"));
+        assert!(is_http("OPTIONS *"));
+        assert!(!is_http(r#"{"method":"stats"}"#));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[tokio::test]
+    async fn request_lines() {
+        let mut input = &b"{\"method\":\"stats\"}\r\nlast"[..];
+        assert_eq!(
+            Ok(Some(r#"{"method":"stats"}"#.to_string())),
+            read_line(&mut input).await
+        );
+        assert_eq!(Ok(Some("last".to_string())), read_line(&mut input).await);
+        assert_eq!(Ok(None), read_line(&mut input).await);
+
+        let long = vec![b'x'; MAX_REQUEST_LEN + 1];
+        assert!(read_line(&mut &long[..]).await.is_err());
+        let mut fits = vec![b'x'; MAX_REQUEST_LEN];
+        fits.push(b'\n');
+        assert!(read_line(&mut &fits[..]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn socket() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir =
+            std::env::temp_dir().join(format!("helium_gateway-api-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("api.sock");
+        let listener = bind_socket(&path, 0o660).expect("bind");
+        let mode = std::fs::metadata(&path)
+            .expect("metadata")
+            .permissions()
+            .mode();
+        assert_eq!(0o660, mode & 0o777);
+
+        // A socket in use is not taken over
+        match bind_socket(&path, 0o660) {
+            Err(Error::IO(err)) => assert_eq!(std::io::ErrorKind::AddrInUse, err.kind()),
+            other => panic!("unexpected bind: {:?}", other.map(|_| ())),
+        }
+        // One left behind is replaced
+        drop(listener);
+        assert!(path.exists());
+        bind_socket(&path, 0o600).expect("bind");
+        let mode = std::fs::metadata(&path)
+            .expect("metadata")
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+        std::fs::remove_dir_all(&dir).expect("remove");
+    }
+}
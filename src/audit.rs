@@ -0,0 +1,318 @@
+use crate::*;
+use helium_crypto::Verify;
+use serde::{Deserialize, Serialize};
+use signer::{Purpose, Signatures};
+use std::{
+    fs::{self, OpenOptions},
+    hash::Hasher,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use xxhash_c::XXH64;
+
+/// The name of the audit log of configuration changes in the settings
+/// folder, one json entry per line.
+pub const AUDIT_FILE: &str = "audit.log";
+
+/// A configuration change applied to a settings file. Each entry is signed
+/// by the gateway key over the entry itself, which includes the signature of
+/// the entry before it, so entries can not be changed, reordered or dropped
+/// from the middle of the log without breaking the chain. Dropping entries
+/// from the end is caught by the head of the chain, anchored outside the
+/// settings folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    pub timestamp: u64,
+    /// What applied the change, bootstrap, address_book or push
+    pub source: String,
+    /// The public key of who asked for the change: the bootstrap server that
+    /// signed the configuration or the gateway itself for local commands
+    pub requester: String,
+    /// The changed settings file
+    pub file: String,
+    /// Checksums (xxh64, hex) of the file before and after the change, None
+    /// when there was no file
+    pub before: Option<String>,
+    pub after: Option<String>,
+    /// The gateway key that signed the entry
+    pub signer: String,
+    /// The signature of the previous entry, empty for the first entry
+    pub prev: String,
+    /// The signature (base64) over the entry with an empty signature
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Entry {
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&Self {
+            signature: String::new(),
+            ..self.clone()
+        })?)
+    }
+}
+
+/// The last entry of the chain, kept apart from the log.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Head {
+    seq: u64,
+    signature: String,
+}
+
+impl From<&Entry> for Head {
+    fn from(entry: &Entry) -> Self {
+        Self {
+            seq: entry.seq,
+            signature: entry.signature.clone(),
+        }
+    }
+}
+
+/// The audit log in the settings folder and the anchor of its head.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    head: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(settings: &Settings) -> Self {
+        Self::at(&settings.path, &settings.audit.head)
+    }
+
+    pub fn at(settings_path: &Path, head: &Path) -> Self {
+        Self {
+            path: settings_path.join(AUDIT_FILE),
+            head: head.to_path_buf(),
+        }
+    }
+
+    /// Appends a change of the given settings file, signed by the gateway
+    /// key, and anchors it as the head. The contents are those of the file
+    /// before and after the change. A log that does not end at its anchored
+    /// head is refused, so a cut off log is not extended. A log without a
+    /// head, from before heads were anchored, gets one.
+    pub fn record(
+        &self,
+        signatures: &Signatures,
+        source: &str,
+        requester: &str,
+        file: &str,
+        before: Option<&str>,
+        after: Option<&str>,
+    ) -> Result {
+        let entries = self.load()?;
+        if let Some(head) = self.load_head()? {
+            check_head(&entries, &head)?;
+        }
+        let last = entries.last();
+        let mut entry = Entry {
+            seq: last.map_or(0, |last| last.seq + 1),
+            timestamp: stats::unix_secs(),
+            source: source.to_string(),
+            requester: requester.to_string(),
+            file: file.to_string(),
+            before: before.map(checksum),
+            after: after.map(checksum),
+            signer: signatures.public_key().to_string(),
+            prev: last.map(|last| last.signature.clone()).unwrap_or_default(),
+            signature: String::new(),
+        };
+        entry.signature = base64::encode(signatures.sign(Purpose::Audit, &entry.signed_bytes()?)?);
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        self.write_head(&entry)
+    }
+
+    /// Loads the audit log, oldest entry first.
+    pub fn load(&self) -> Result<Vec<Entry>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    /// Checks the chain of the given entries, and that it ends at the
+    /// anchored head.
+    pub fn verify(&self, entries: &[Entry]) -> Result {
+        verify(entries)?;
+        match self.load_head()? {
+            Some(head) => check_head(entries, &head),
+            None if entries.is_empty() => Ok(()),
+            None => Err(Error::custom("audit log has no anchored head")),
+        }
+    }
+
+    /// Anchors the head to the end of the log after checking its chain, for
+    /// a log restored from a bundle.
+    pub fn anchor(&self) -> Result {
+        let entries = self.load()?;
+        verify(&entries)?;
+        match entries.last() {
+            Some(last) => self.write_head(last),
+            None => Ok(()),
+        }
+    }
+
+    fn load_head(&self) -> Result<Option<Head>> {
+        match fs::read(&self.head) {
+            Ok(head) => Ok(Some(serde_json::from_slice(&head)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replaces the head file in one rename, so a crash leaves the old head
+    /// or the new one.
+    fn write_head(&self, entry: &Entry) -> Result {
+        if let Some(parent) = self.head.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let staged = self.head.with_extension("tmp");
+        fs::write(&staged, serde_json::to_vec(&Head::from(entry))?)?;
+        fs::rename(&staged, &self.head)?;
+        Ok(())
+    }
+}
+
+/// Checks that a log ends at its head. A log one entry past its head is a
+/// crash between appending the entry and anchoring it, and is accepted.
+fn check_head(entries: &[Entry], head: &Head) -> Result {
+    if entries
+        .iter()
+        .rev()
+        .take(2)
+        .any(|entry| &Head::from(entry) == head)
+    {
+        Ok(())
+    } else {
+        Err(Error::custom(format!(
+            "audit log does not end at its anchored head, entry {}",
+            head.seq
+        )))
+    }
+}
+
+/// Checks the signature of every entry and that each links to the one
+/// before it, failing at the first entry that does not.
+fn verify(entries: &[Entry]) -> Result {
+    let mut previous: Option<&Entry> = None;
+    for entry in entries {
+        let invalid = |reason: &str| {
+            Err(Error::custom(format!(
+                "audit entry {} {}",
+                entry.seq, reason
+            )))
+        };
+        let (seq, prev) = previous.map_or((0, ""), |previous| {
+            (previous.seq + 1, previous.signature.as_str())
+        });
+        if entry.seq != seq || entry.prev != prev {
+            return invalid("does not follow the entry before it");
+        }
+        let signer = PublicKey::from_str(&entry.signer)?;
+        let signature = base64::decode(&entry.signature)?;
+        if signer.verify(&entry.signed_bytes()?, &signature).is_err() {
+            return invalid("has an invalid signature");
+        }
+        previous = Some(entry);
+    }
+    Ok(())
+}
+
+fn checksum(contents: &str) -> String {
+    let mut hasher = XXH64::new(0);
+    hasher.write(contents.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network};
+    use rand::rngs::OsRng;
+    use settings::SigningSettings;
+    use std::sync::Arc;
+
+    #[test]
+    fn chain() {
+        let path =
+            std::env::temp_dir().join(format!("helium_gateway-audit-{}", rand::random::<u32>()));
+        fs::create_dir_all(&path).expect("dir");
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let requester = keypair.public_key().to_string();
+        let signatures = Signatures::with_limits(
+            Arc::new(keypair),
+            &SigningSettings {
+                uplink_per_minute: 0,
+                peer_per_minute: 0,
+            },
+        );
+        let log = AuditLog::at(&path, &path.join("anchor").join("audit.head"));
+        let changes = [
+            (None, Some("a")),
+            (Some("a"), Some("b")),
+            (Some("b"), Some("c")),
+        ];
+        for (before, after) in changes.iter() {
+            log.record(
+                &signatures,
+                "bootstrap",
+                &requester,
+                "bootstrap.toml",
+                *before,
+                *after,
+            )
+            .expect("record");
+        }
+        let mut entries = log.load().expect("load");
+        assert_eq!(3, entries.len());
+        assert_eq!(entries[0].after, entries[1].before);
+        assert!(log.verify(&entries).is_ok());
+        assert_eq!(3, signatures.to_json()["purposes"]["audit"]["signed"]);
+
+        // Cutting off the last entry leaves a valid chain that does not end
+        // at the head, and the cut off log is not extended
+        let contents = fs::read_to_string(path.join(AUDIT_FILE)).expect("read");
+        let cut: Vec<&str> = contents.lines().take(2).collect();
+        fs::write(path.join(AUDIT_FILE), cut.join("\n")).expect("write");
+        let cut = log.load().expect("load");
+        assert!(verify(&cut).is_ok());
+        assert!(log.verify(&cut).is_err());
+        assert!(log
+            .record(
+                &signatures,
+                "push",
+                &requester,
+                "push.toml",
+                None,
+                Some("d")
+            )
+            .is_err());
+        let _ = fs::remove_dir_all(&path);
+
+        entries[0].requester = "someone else".to_string();
+        assert!(verify(&entries).is_err());
+        entries.remove(0);
+        assert!(verify(&entries).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::*;
+use address_book::Role;
+use audit::AuditLog;
+use serde::Deserialize;
+use service::signed::{Freshness, SignedRequest};
+use signer::Signatures;
+use slog::{info, o, warn, Logger};
+use std::{collections::HashMap, fs, path::PathBuf};
+use tokio::time;
+
+/// The name of the settings overlay file written by the bootstrap client in
+/// the settings folder.
+pub const OVERLAY_FILE: &str = "bootstrap.toml";
+
+/// A signed configuration as returned by the bootstrap server. The signature
+/// is the operator signature over the bytes of the `config` string, which is
+/// itself a JSON encoded `Config`, preceded by the nonce and timestamp when
+/// the server returns them.
+#[derive(Debug, Deserialize)]
+struct SignedConfig {
+    config: String,
+    signature: String,
+    #[serde(flatten)]
+    freshness: Freshness,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    uri: String,
+    public_key: String,
+}
+
+/// The runtime configuration an operator can provide through bootstrapping.
+/// Any entries that are not provided are left at their local values.
+#[derive(Debug, Deserialize)]
+struct Config {
+    region: Option<String>,
+    channel: Option<String>,
+    #[serde(default)]
+    router: HashMap<String, Endpoint>,
+    #[serde(default)]
+    gateways: Vec<Endpoint>,
+    #[serde(default)]
+    features: HashMap<String, bool>,
+}
+
+impl Config {
+    /// Renders the configuration as a settings overlay. String values are
+    /// encoded as json strings, which are valid toml basic strings.
+    fn to_toml(&self) -> Result<String> {
+        let mut out = String::from("## Generated by the bootstrap client. Do not edit.\n\n");
+        if let Some(region) = &self.region {
+            out.push_str(&format!("region = {}\n", serde_json::to_string(region)?));
+        }
+        if let Some(channel) = &self.channel {
+            out.push_str(&format!(
+                "\n[update]\nchannel = {}\n",
+                serde_json::to_string(channel)?
+            ));
+        }
+        let mut channels: Vec<&String> = self.router.keys().collect();
+        channels.sort();
+        for channel in channels {
+            out.push_str(&format!("\n[router.{}]\n", serde_json::to_string(channel)?));
+            self.router[channel].write_toml(&mut out)?;
+        }
+        for gateway in &self.gateways {
+            out.push_str("\n[[gateways]]\n");
+            gateway.write_toml(&mut out)?;
+        }
+        if !self.features.is_empty() {
+            out.push_str("\n[features]\n");
+            let mut names: Vec<&String> = self.features.keys().collect();
+            names.sort();
+            for name in names {
+                out.push_str(&format!(
+                    "{} = {}\n",
+                    serde_json::to_string(name)?,
+                    self.features[name]
+                ));
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Endpoint {
+    fn write_toml(&self, out: &mut String) -> Result {
+        out.push_str(&format!(
+            "public_key = {}\nuri = {}\n",
+            serde_json::to_string(&self.public_key)?,
+            serde_json::to_string(&self.uri)?
+        ));
+        Ok(())
+    }
+}
+
+/// Periodically fetches the operator signed runtime configuration for this
+/// gateway and stores it in the settings overlay. Changes to the overlay take
+/// effect when the gateway is restarted.
+#[derive(Debug)]
+pub struct Bootstrap {
+    server: Option<KeyedUri>,
+    interval: time::Duration,
+    signatures: Signatures,
+    settings_path: PathBuf,
+    audit: AuditLog,
+    interface: Option<String>,
+    require_fresh: bool,
+}
+
+impl Bootstrap {
+    pub fn new(settings: &Settings, signatures: Signatures) -> Self {
+        Self {
+            server: settings
+                .address_book
+                .with_role(Role::Bootstrap)
+                .into_iter()
+                .next(),
+            interval: time::Duration::from_secs(settings.bootstrap.interval.max(1) as u64 * 60),
+            signatures,
+            settings_path: settings.path.clone(),
+            audit: AuditLog::new(settings),
+            interface: settings.backhaul.interface.clone(),
+            require_fresh: settings.bootstrap.require_fresh,
+        }
+    }
+
+    pub async fn run(&self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "bootstrap"));
+        let server = match &self.server {
+            Some(server) => server,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "uri" => server.uri.to_string());
+        let mut interval = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = interval.tick() => match self.fetch(server).await {
+                    Ok(overlay) => match self.apply(server, &overlay) {
+                        Ok(true) => info!(logger, "bootstrap settings updated, restart to apply"),
+                        Ok(false) => info!(logger, "bootstrap settings unchanged"),
+                        Err(err) => warn!(logger, "failed to apply bootstrap settings: {:?}", err),
+                    },
+                    Err(err) => warn!(logger, "failed to fetch bootstrap settings: {:?}", err),
+                }
+            }
+        }
+    }
+
+    /// Fetches and verifies the configuration for this gateway, returning it
+    /// as a settings overlay. The request is signed by the gateway key.
+    async fn fetch(&self, server: &KeyedUri) -> Result<String> {
+        let url = format!(
+            "{}/{}",
+            server.uri.to_string().trim_end_matches('/'),
+            self.signatures.public_key()
+        );
+        let request = SignedRequest::new(&self.signatures, server.uri.path())?;
+        let mut args = curl::interface_args(&self.interface);
+        args.extend_from_slice(&[
+            "-s".to_string(),
+            "-H".to_string(),
+            "Accept: application/json".to_string(),
+        ]);
+        args.extend(request.curl_args());
+        let signed: SignedConfig =
+            curl::get(url, &args, |output| Ok(serde_json::from_slice(output)?)).await?;
+        request.verify_response(
+            &server.public_key,
+            signed.config.as_bytes(),
+            &signed.signature,
+            &signed.freshness,
+            self.require_fresh,
+        )?;
+        let config: Config = serde_json::from_str(&signed.config)?;
+        config.to_toml()
+    }
+
+    /// Writes the given overlay if it differs from the current one and
+    /// records the change in the audit log. An overlay that results in
+    /// invalid settings or can not be recorded is rolled back. Returns
+    /// whether the overlay was changed.
+    fn apply(&self, server: &KeyedUri, overlay: &str) -> Result<bool> {
+        let path = self.settings_path.join(OVERLAY_FILE);
+        let previous = fs::read_to_string(&path).ok();
+        if previous.as_deref() == Some(overlay) {
+            return Ok(false);
+        }
+        fs::write(&path, overlay)?;
+        let applied = Settings::new(&self.settings_path).and_then(|_| {
+            self.audit.record(
+                &self.signatures,
+                "bootstrap",
+                &server.public_key.to_string(),
+                OVERLAY_FILE,
+                previous.as_deref(),
+                Some(overlay),
+            )
+        });
+        if let Err(err) = applied {
+            match previous {
+                Some(previous) => fs::write(&path, previous)?,
+                None => fs::remove_file(&path)?,
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,377 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use settings::StoreBackend;
+use std::{
+    collections::BTreeMap,
+    fs,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+use tokio::process;
+use xxhash_c::XXH64;
+
+/// The layout version of state bundles.
+pub const BUNDLE_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+const SETTINGS_DIR: &str = "settings";
+const STORE_DIR: &str = "store";
+/// The settings files carried in a bundle. default.toml comes with the
+/// installed release and is left out.
+const SETTINGS_FILES: &[&str] = &[
+    settings::SETTINGS_FILE,
+    address_book::OVERLAY_FILE,
+    bootstrap::OVERLAY_FILE,
+    push::OVERLAY_FILE,
+    audit::AUDIT_FILE,
+];
+
+/// Describes a state bundle. The keypair itself is never bundled, only its
+/// public key and where it is kept, so the key has to be moved to the new
+/// hardware separately (or stays in its secure element).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: u64,
+    pub gateway: String,
+    pub key_type: Option<String>,
+    pub keypair: KeyReference,
+    pub store_backend: String,
+    /// Stable ids of the packet forwarders of the gateway
+    pub gateway_ids: BTreeMap<String, Vec<String>>,
+    /// Checksums (xxh64, hex) of the bundled files by path in the bundle
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyReference {
+    /// Keys are kept in files, there is no other key backend yet
+    pub backend: String,
+    pub path: String,
+    /// The public key of a key rotation in progress, if any
+    pub rotating_to: Option<String>,
+}
+
+/// Writes a bundle of the settings files and the store of the gateway to
+/// the given tar file. The server should be stopped so the store is not
+/// written to while it is copied.
+pub async fn export(settings: &Settings, output: &Path) -> Result<Manifest> {
+    let staging = Staging::new("export")?;
+    let settings_dir = staging.path.join(SETTINGS_DIR);
+    fs::create_dir_all(&settings_dir)?;
+    for file in SETTINGS_FILES {
+        let source = settings.path.join(file);
+        if source.exists() {
+            fs::copy(&source, settings_dir.join(file))?;
+        }
+    }
+    if settings.store.backend != StoreBackend::Memory && settings.store.path.exists() {
+        let name = settings
+            .store
+            .path
+            .file_name()
+            .ok_or_else(|| Error::custom("store path has no file name"))?;
+        copy_all(
+            &settings.store.path,
+            &staging.path.join(STORE_DIR).join(name),
+        )?;
+    }
+    let manifest = Manifest {
+        version: BUNDLE_VERSION,
+        created: stats::unix_secs(),
+        gateway: settings.keypair.public_key().to_string(),
+        key_type: settings::key_type_name(settings.keypair.public_key()).map(str::to_string),
+        keypair: KeyReference {
+            backend: "file".to_string(),
+            path: settings.keypair_path.clone(),
+            rotating_to: keypair::load_rotation(&settings.keypair_path)?
+                .map(|rotation| rotation.public_key),
+        },
+        store_backend: backend_name(settings.store.backend),
+        gateway_ids: settings
+            .gateway_ids
+            .iter()
+            .map(|(id, macs)| (id.clone(), macs.clone()))
+            .collect(),
+        files: checksums(&staging.path)?,
+    };
+    fs::write(
+        staging.path.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    tar(&[
+        "-cf".as_ref(),
+        output.as_os_str(),
+        "-C".as_ref(),
+        staging.path.as_os_str(),
+        ".".as_ref(),
+    ])
+    .await?;
+    Ok(manifest)
+}
+
+/// Restores the settings files and the store from a bundle after checking
+/// its integrity, keeping the replaced settings files and store as `.bak`.
+/// Bundles with files the manifest does not list are refused. Unless forced,
+/// the bundle has to be for the gateway key found on this hardware.
+pub async fn import(settings: &Settings, input: &Path, force: bool) -> Result<Manifest> {
+    let staging = Staging::new("import")?;
+    tar(&[
+        "-xf".as_ref(),
+        input.as_os_str(),
+        "-C".as_ref(),
+        staging.path.as_os_str(),
+    ])
+    .await?;
+    let manifest: Manifest = serde_json::from_slice(&fs::read(staging.path.join(MANIFEST_FILE))?)?;
+    if manifest.version > BUNDLE_VERSION {
+        return Err(Error::custom(format!(
+            "bundle version {} is newer than supported",
+            manifest.version
+        )));
+    }
+    let found = checksums(&staging.path)?;
+    if let Some(file) = found
+        .keys()
+        .find(|file| !manifest.files.contains_key(*file))
+    {
+        return Err(Error::custom(format!(
+            "bundle file {} is not in the manifest",
+            file
+        )));
+    }
+    for (file, checksum) in &manifest.files {
+        if found.get(file) != Some(checksum) {
+            return Err(Error::custom(format!(
+                "bundle file {} is corrupt or missing",
+                file
+            )));
+        }
+    }
+    let public_key = settings.keypair.public_key().to_string();
+    if public_key != manifest.gateway && !force {
+        return Err(Error::custom(format!(
+            "bundle is for gateway {} but the key at {} is {}, move the key file first",
+            manifest.gateway, settings.keypair_path, public_key
+        )));
+    }
+    let bundled_store = staging.path.join(STORE_DIR);
+    if bundled_store.exists() {
+        if manifest.store_backend != backend_name(settings.store.backend) {
+            return Err(Error::custom(format!(
+                "bundle holds a {} store but the {} store is configured",
+                manifest.store_backend,
+                backend_name(settings.store.backend)
+            )));
+        }
+        if settings.store.path.exists() {
+            let backup = backup_path(&settings.store.path);
+            if backup.is_dir() {
+                fs::remove_dir_all(&backup)?;
+            } else if backup.exists() {
+                fs::remove_file(&backup)?;
+            }
+            fs::rename(&settings.store.path, &backup)?;
+        }
+        for entry in fs::read_dir(&bundled_store)? {
+            copy_all(&entry?.path(), &settings.store.path)?;
+        }
+    }
+    for file in SETTINGS_FILES {
+        let source = staging.path.join(SETTINGS_DIR).join(file);
+        if !source.exists() {
+            continue;
+        }
+        let target = settings.path.join(file);
+        if target.exists() {
+            fs::copy(&target, backup_path(&target))?;
+        }
+        fs::copy(&source, &target)?;
+    }
+    // The head of the restored audit log is anchored outside the bundle
+    audit::AuditLog::new(settings).anchor()?;
+    Ok(manifest)
+}
+
+/// The path a replaced file or directory is kept at, with `.bak` appended.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_os_string();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+fn backend_name(backend: StoreBackend) -> String {
+    format!("{:?}", backend).to_lowercase()
+}
+
+async fn tar(args: &[&std::ffi::OsStr]) -> Result {
+    let output = process::Command::new("tar").args(args).output().await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::custom(format!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Copies a file or a directory with everything in it.
+fn copy_all(source: &Path, target: &Path) -> Result {
+    if source.is_dir() {
+        fs::create_dir_all(target)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &target.join(entry.file_name()))?;
+        }
+    } else {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+/// Returns the checksums of all files below the given directory except the
+/// manifest, by their path relative to it.
+fn checksums(root: &Path) -> Result<BTreeMap<String, String>> {
+    fn walk(root: &Path, dir: &Path, checksums: &mut BTreeMap<String, String>) -> Result {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(root, &path, checksums)?;
+                continue;
+            }
+            let name = path
+                .strip_prefix(root)
+                .map_err(|_| Error::custom("bundle path outside of bundle"))?
+                .to_string_lossy()
+                .to_string();
+            if name == MANIFEST_FILE {
+                continue;
+            }
+            let mut hasher = XXH64::new(0);
+            hasher.write(&fs::read(&path)?);
+            checksums.insert(name, format!("{:016x}", hasher.finish()));
+        }
+        Ok(())
+    }
+    let mut checksums = BTreeMap::new();
+    walk(root, root, &mut checksums)?;
+    Ok(checksums)
+}
+
+/// A scratch directory, removed when dropped.
+struct Staging {
+    path: PathBuf,
+}
+
+impl Staging {
+    fn new(purpose: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "helium_gateway-{}-{}",
+            purpose,
+            rand::random::<u32>()
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_and_copy() {
+        let staging = Staging::new("test").expect("staging");
+        let source = staging.path.join("source");
+        fs::create_dir_all(source.join("db")).expect("dir");
+        fs::write(source.join("db").join("conf"), b"a").expect("write");
+        fs::write(staging.path.join(MANIFEST_FILE), b"{}").expect("write");
+        copy_all(&source, &staging.path.join("target")).expect("copy");
+
+        let sums = checksums(&staging.path).expect("checksums");
+        assert_eq!(2, sums.len());
+        assert_eq!(sums.get("source/db/conf"), sums.get("target/db/conf"));
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let staging = Staging::new("test").expect("staging");
+        let root = &staging.path;
+        let settings_path = root.join("settings");
+        let store_path = root.join("state");
+        fs::create_dir_all(&settings_path).expect("dir");
+        fs::create_dir_all(&store_path).expect("dir");
+        fs::write(
+            settings_path.join("default.toml"),
+            include_str!("../config/default.toml"),
+        )
+        .expect("write");
+        let settings_toml = format!(
+            "keypair = \"{}\"\n[store]\nbackend = \"sled\"\npath = \"{}\"\n[audit]\nhead = \"{}\"\n",
+            root.join("gateway_key.bin").display(),
+            store_path.display(),
+            root.join("audit.head").display(),
+        );
+        fs::write(settings_path.join(settings::SETTINGS_FILE), &settings_toml).expect("write");
+        fs::write(store_path.join("db"), b"exported").expect("write");
+        let settings = Settings::new(&settings_path).expect("settings");
+
+        let bundle = root.join("bundle.tar");
+        let exported = export(&settings, &bundle).await.expect("export");
+        assert!(exported
+            .files
+            .contains_key(&format!("{}/settings.toml", SETTINGS_DIR)));
+        assert!(exported
+            .files
+            .contains_key(&format!("{}/state/db", STORE_DIR)));
+
+        // The store and settings changed after the export are restored and
+        // kept as backups
+        fs::write(store_path.join("db"), b"changed").expect("write");
+        let changed = format!("labels = {{ site = \"roof\" }}\n{}", settings_toml);
+        fs::write(settings_path.join(settings::SETTINGS_FILE), &changed).expect("write");
+        let imported = import(&settings, &bundle, false).await.expect("import");
+        assert_eq!(exported.files, imported.files);
+        assert_eq!(
+            b"exported".to_vec(),
+            fs::read(store_path.join("db")).expect("db")
+        );
+        assert_eq!(
+            b"changed".to_vec(),
+            fs::read(root.join("state.bak").join("db")).expect("backup")
+        );
+        assert_eq!(
+            settings_toml,
+            fs::read_to_string(settings_path.join(settings::SETTINGS_FILE)).expect("settings")
+        );
+        assert_eq!(
+            changed,
+            fs::read_to_string(settings_path.join("settings.toml.bak")).expect("backup")
+        );
+
+        // A file the manifest does not list
+        let extra = root.join("extra");
+        fs::create_dir_all(extra.join(STORE_DIR)).expect("dir");
+        fs::write(extra.join(STORE_DIR).join("other"), b"unchecked").expect("write");
+        tar(&[
+            "-rf".as_ref(),
+            bundle.as_os_str(),
+            "-C".as_ref(),
+            extra.as_os_str(),
+            format!("./{}/other", STORE_DIR).as_ref(),
+        ])
+        .await
+        .expect("append");
+        assert!(import(&settings, &bundle, false).await.is_err());
+        assert!(!store_path.join("other").exists());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::*;
+use gateway::antennas::Antennas;
+use semtech_udp::{
+    pull_resp,
+    server_runtime::{Event, UdpRuntime},
+    CodingRate, MacAddress, Modulation, StringOrNum,
+};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Seconds to wait for the packet forwarder to connect.
+const CONNECT_SECS: u64 = 30;
+/// The pause between the end of one test frame and the start of the next.
+const FRAME_GAP: Duration = Duration::from_millis(10);
+/// The largest LoRa frame the concentrator sends.
+const MAX_FRAME_SIZE: usize = 255;
+
+/// A test transmission for regulatory certification, refused unless the
+/// safety interlocks of the certification settings allow it.
+#[derive(Debug, Clone)]
+pub struct TestTransmission {
+    /// The frequency in MHz
+    pub frequency: f32,
+    /// The transmit power in dBm
+    pub power: u64,
+    pub duration: Duration,
+}
+
+impl TestTransmission {
+    /// Checks the transmission against the interlocks: test transmissions
+    /// have to be enabled, and the power and duration within their limits.
+    /// The frequency, datarate and power also have to fit the downlink plan
+    /// of the configured region.
+    pub fn check(&self, settings: &Settings, datarate: &str) -> Result {
+        let certification = &settings.certification;
+        if !certification.enabled {
+            return Err(Error::custom(
+                "test transmissions are disabled, enable them in the [certification] settings",
+            ));
+        }
+        if self.power > certification.max_power {
+            return Err(Error::custom(format!(
+                "power {} dBm over the maximum of {} dBm",
+                self.power, certification.max_power
+            )));
+        }
+        let max_duration = Duration::from_secs(certification.max_duration);
+        if self.duration.as_secs() == 0 || self.duration > max_duration {
+            return Err(Error::custom(format!(
+                "duration has to be between 1 and {} seconds",
+                certification.max_duration
+            )));
+        }
+        if let Some(region) = settings.region {
+            region::validate_downlink(region, self.frequency, datarate)?;
+            region::check_power(region, self.power)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends modulated test frames back to back, or at the given interval,
+/// through the first packet forwarder that connects until the duration is
+/// over or the command is interrupted. No frame is started that would still
+/// be on the air at the end of the duration. Returns a summary of what was
+/// sent.
+pub async fn modtest(
+    settings: &Settings,
+    transmission: &TestTransmission,
+    datarate: &str,
+    size: usize,
+    interval: Duration,
+) -> Result<Value> {
+    transmit(settings, transmission, datarate, size, interval, true).await
+}
+
+/// Keys the transmitter for the duration on the given frequency. The
+/// semtech udp protocol can not command an unmodulated carrier, so the
+/// carrier is held with back to back frames of the largest size the
+/// datarate allows and a constant payload, leaving only the turnaround of
+/// the forwarder between them. The interlocks are those of modulated test
+/// frames.
+pub async fn cw(
+    settings: &Settings,
+    transmission: &TestTransmission,
+    datarate: &str,
+) -> Result<Value> {
+    let size = settings
+        .region
+        .and_then(|region| region::max_payload(region, datarate))
+        .unwrap_or(MAX_FRAME_SIZE)
+        .min(MAX_FRAME_SIZE);
+    transmit(
+        settings,
+        transmission,
+        datarate,
+        size,
+        Duration::from_secs(0),
+        false,
+    )
+    .await
+}
+
+/// Sends the test frames of modtest and cw, with a frame counter at the
+/// start of each when counted.
+async fn transmit(
+    settings: &Settings,
+    transmission: &TestTransmission,
+    datarate: &str,
+    size: usize,
+    interval: Duration,
+    counted: bool,
+) -> Result<Value> {
+    transmission.check(settings, datarate)?;
+    let airtime = region::airtime(datarate, size)
+        .ok_or_else(|| Error::custom(format!("unsupported datarate: {}", datarate)))?;
+    let (mut udp_runtime, mac) = connect(settings).await?;
+    check_forwarder_power(settings, &mac, transmission.power)?;
+    let rfch = Antennas::new(&settings.antennas)?.tx_rf_chain(&mac, &mac.to_string());
+    let interval = interval.max(airtime + FRAME_GAP);
+    let started = Instant::now();
+    let last_start = started + transmission.duration.saturating_sub(airtime);
+    let (mut sent, mut refused) = (0u64, 0u64);
+    let mut ticker = time::interval(interval);
+    let interrupted = signals::shutdown_signal();
+    tokio::pin!(interrupted);
+    loop {
+        tokio::select! {
+            _ = &mut interrupted => break,
+            _ = ticker.tick() => {
+                if Instant::now() > last_start {
+                    break;
+                }
+                let counter = if counted { Some(sent + refused) } else { None };
+                let txpk = test_frame(transmission, rfch, datarate, size, counter)?;
+                let mut downlink = udp_runtime.prepare_empty_downlink(mac);
+                downlink.set_packet(txpk);
+                let dispatch = downlink.dispatch(Some(Duration::from_secs(gateway::DOWNLINK_TIMEOUT_SECS)));
+                tokio::select! {
+                    _ = &mut interrupted => break,
+                    dispatched = dispatch => match dispatched {
+                        Ok(()) => sent += 1,
+                        Err(_) => refused += 1,
+                    },
+                }
+            }
+        }
+    }
+    Ok(json!({
+        "forwarder": mac.to_string(),
+        "frequency": transmission.frequency,
+        "datarate": datarate,
+        "power": transmission.power,
+        "size": size,
+        "interval_ms": interval.as_millis() as u64,
+        "sent": sent,
+        "refused": refused,
+        "airtime_ms": (airtime * sent as u32).as_millis() as u64,
+        "elapsed_ms": started.elapsed().as_millis() as u64,
+    }))
+}
+
+/// Binds the listen address and waits for a packet forwarder to connect.
+async fn connect(settings: &Settings) -> Result<(UdpRuntime, MacAddress)> {
+    let mut udp_runtime = UdpRuntime::new(settings.listen_addr).await?;
+    let mac = time::timeout(Duration::from_secs(CONNECT_SECS), async {
+        loop {
+            match udp_runtime.recv().await {
+                Event::NewClient((mac, _)) | Event::UpdateClient((mac, _)) => return mac,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::custom("no packet forwarder connected"))?;
+    Ok((udp_runtime, mac))
+}
+
+/// Refuses power over the antenna limit of the forwarder.
+fn check_forwarder_power(settings: &Settings, mac: &MacAddress, power: u64) -> Result {
+    let antennas = Antennas::new(&settings.antennas)?;
+    match antennas.max_power(mac, &mac.to_string()) {
+        Some(max_power) if power > max_power => Err(Error::custom(format!(
+            "power {} dBm over the antenna limit of {} dBm for {}",
+            power, max_power, mac
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A test frame sent immediately, with the counter if given and a fixed
+/// pattern so that the frames can be told apart on a spectrum analyzer
+/// decoder.
+fn test_frame(
+    transmission: &TestTransmission,
+    rfch: u64,
+    datarate: &str,
+    size: usize,
+    counter: Option<u64>,
+) -> Result<pull_resp::TxPk> {
+    let mut payload = counter
+        .map(|counter| counter.to_be_bytes().to_vec())
+        .unwrap_or_default();
+    payload.resize(size, 0x55);
+    Ok(pull_resp::TxPk {
+        imme: true,
+        ipol: true,
+        modu: Modulation::LORA,
+        codr: CodingRate::_4_5,
+        datr: datarate.parse()?,
+        freq: transmission.frequency as f64,
+        size: payload.len() as u64,
+        data: payload,
+        powe: transmission.power,
+        rfch,
+        tmst: StringOrNum::S("immediate".to_string()),
+        tmms: None,
+        fdev: None,
+        prea: None,
+        ncrc: None,
+    })
+}
@@ -0,0 +1,422 @@
+use crate::*;
+use peers::Message;
+use serde_json::{json, Value};
+use signer::{Purpose, Signatures};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time};
+
+/// Heartbeats are sent this many times per lease, so a lost heartbeat does
+/// not end the lease of the leader.
+pub const HEARTBEATS_PER_LEASE: u32 = 3;
+
+/// Another host of the cluster.
+#[derive(Debug, Clone)]
+struct Member {
+    addr: SocketAddr,
+    /// Unix milliseconds of the last heartbeat, which have to increase to
+    /// tell heartbeats from replays
+    timestamp: u64,
+    leader: bool,
+    last_seen: Instant,
+}
+
+/// Lease based leader election between the hosts of a cluster. The leader
+/// holds a lease that every heartbeat it sends renews. When no lease is held
+/// the live host with the lowest key takes the lead, and a host that finds
+/// another leader with a lower key steps down. A new leader is kept when the
+/// old one comes back, so leadership only moves when the leader fails.
+#[derive(Debug)]
+pub struct Election {
+    own_key: String,
+    lease: Duration,
+    started: Instant,
+    leading: bool,
+    members: HashMap<String, Member>,
+}
+
+impl Election {
+    pub fn new(own_key: String, lease: Duration, now: Instant) -> Self {
+        Self {
+            own_key,
+            lease,
+            started: now,
+            leading: false,
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn is_leading(&self) -> bool {
+        self.leading
+    }
+
+    /// Takes part in the election with the rotated key of this host.
+    pub fn rekey(&mut self, own_key: String) {
+        self.own_key = own_key;
+    }
+
+    /// Records a heartbeat of another host. Returns false for heartbeats
+    /// that are not newer than the last one of the host.
+    pub fn heard(
+        &mut self,
+        key: &str,
+        addr: SocketAddr,
+        timestamp: u64,
+        leader: bool,
+        now: Instant,
+    ) -> bool {
+        if let Some(member) = self.members.get(key) {
+            if timestamp <= member.timestamp {
+                return false;
+            }
+        }
+        self.members.insert(
+            key.to_string(),
+            Member {
+                addr,
+                timestamp,
+                leader,
+                last_seen: now,
+            },
+        );
+        true
+    }
+
+    fn is_live(&self, member: &Member, now: Instant) -> bool {
+        now.saturating_duration_since(member.last_seen) < self.lease
+    }
+
+    /// The other host holding the lease, the one with the lowest key when
+    /// several claim it.
+    pub fn lease_holder(&self, now: Instant) -> Option<&str> {
+        self.members
+            .iter()
+            .filter(|(_, member)| member.leader && self.is_live(member, now))
+            .map(|(key, _)| key.as_str())
+            .min()
+    }
+
+    /// Updates whether this host leads, returning whether that changed. A
+    /// host waits one lease after starting before taking the lead, to hear
+    /// the heartbeats of a leader that is already there.
+    pub fn update(&mut self, now: Instant) -> bool {
+        let leading = match self.lease_holder(now) {
+            Some(holder) if !self.leading || holder < self.own_key.as_str() => false,
+            _ if self.leading => true,
+            _ => {
+                let lowest = self
+                    .members
+                    .iter()
+                    .filter(|(_, member)| self.is_live(member, now))
+                    .map(|(key, _)| key.as_str())
+                    .chain(std::iter::once(self.own_key.as_str()))
+                    .min();
+                now.saturating_duration_since(self.started) >= self.lease
+                    && lowest == Some(self.own_key.as_str())
+            }
+        };
+        let changed = leading != self.leading;
+        self.leading = leading;
+        changed
+    }
+
+    /// Gives up the lead when this host can not send its heartbeats, and
+    /// waits a lease again before taking it, since the other hosts take over
+    /// once they stop hearing from it. Returns whether this host was leading.
+    pub fn step_down(&mut self, now: Instant) -> bool {
+        self.started = now;
+        std::mem::replace(&mut self.leading, false)
+    }
+
+    fn to_json(&self, now: Instant) -> Value {
+        let members: Vec<Value> = self
+            .members
+            .iter()
+            .map(|(key, member)| {
+                json!({
+                    "key": key,
+                    "addr": member.addr.to_string(),
+                    "leader": member.leader && self.is_live(member, now),
+                    "live": self.is_live(member, now),
+                    "last_seen_ms": now.saturating_duration_since(member.last_seen).as_millis() as u64,
+                })
+            })
+            .collect();
+        json!({
+            "leading": self.leading,
+            "leader": if self.leading { Some(self.own_key.as_str()) } else { self.lease_holder(now) },
+            "members": members,
+        })
+    }
+}
+
+/// A cheaply cloneable handle to the leader election of the cluster. Without
+/// clustering this host always leads.
+#[derive(Debug, Clone, Default)]
+pub struct Cluster(Option<Arc<Mutex<Election>>>);
+
+impl Cluster {
+    /// Whether this host dispatches downlinks.
+    pub fn is_leader(&self) -> bool {
+        match &self.0 {
+            Some(election) => election.lock().expect("cluster").is_leading(),
+            None => true,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match &self.0 {
+            Some(election) => {
+                let mut state = election.lock().expect("cluster").to_json(Instant::now());
+                state["enabled"] = json!(true);
+                state
+            }
+            None => json!({ "enabled": false, "leading": true }),
+        }
+    }
+}
+
+/// Creates the handle to the leader election and the service exchanging
+/// heartbeats with the other hosts of the cluster.
+pub fn cluster(settings: &Settings, signatures: Signatures) -> Result<(Cluster, ClusterService)> {
+    let keys = settings
+        .cluster
+        .keys
+        .iter()
+        .map(|key| PublicKey::from_str(key).map(|key| key.to_string()))
+        .collect::<std::result::Result<Vec<String>, _>>()?;
+    if settings.cluster.enabled && keys.is_empty() {
+        return Err(Error::custom("cluster keys are required for clustering"));
+    }
+    let lease = Duration::from_millis(settings.cluster.lease_ms.max(HEARTBEATS_PER_LEASE as u64));
+    let election = if settings.cluster.enabled {
+        Some(Arc::new(Mutex::new(Election::new(
+            signatures.public_key().to_string(),
+            lease,
+            Instant::now(),
+        ))))
+    } else {
+        None
+    };
+    let service = ClusterService {
+        listen_addr: settings.cluster.listen_addr,
+        peers: settings.cluster.peers.clone(),
+        keys,
+        lease,
+        signatures,
+        election: election.clone(),
+    };
+    Ok((Cluster(election), service))
+}
+
+/// Exchanges signed heartbeats with the static list of the other hosts of
+/// the cluster and runs the leader election. Every host forwards its
+/// uplinks, but only the leader transmits downlinks, so a downlink goes out
+/// once.
+///
+/// With two hosts there is no majority to grant the lease, so a network
+/// split between the hosts makes both lead until it heals.
+#[derive(Debug)]
+pub struct ClusterService {
+    listen_addr: SocketAddr,
+    peers: Vec<SocketAddr>,
+    /// The keys of the hosts to accept
+    keys: Vec<String>,
+    lease: Duration,
+    signatures: Signatures,
+    election: Option<Arc<Mutex<Election>>>,
+}
+
+impl ClusterService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "cluster"));
+        let election = match self.election.clone() {
+            Some(election) => election,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        let socket = UdpSocket::bind(self.listen_addr).await?;
+        info!(logger, "starting"; "listen_addr" => self.listen_addr.to_string());
+        let mut own_key = self.signatures.public_key().to_string();
+        let mut rotations = self.signatures.rotations();
+        let mut heartbeat_timer = time::interval(self.lease / HEARTBEATS_PER_LEASE);
+        let mut buf = vec![0u8; peers::PEER_MESSAGE_SIZE];
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = heartbeat_timer.tick() => {
+                    self.update(&logger, &election);
+                    if let Err(err) = self.heartbeat(&socket, &own_key, &election).await {
+                        warn!(logger, "failed to send heartbeat: {:?}", err);
+                        if election.lock().expect("cluster").step_down(Instant::now()) {
+                            warn!(logger, "stepping down, the other hosts can not hear this one");
+                        }
+                    }
+                },
+                Ok(()) = rotations.changed() => {
+                    own_key = self.signatures.public_key().to_string();
+                    info!(logger, "sending heartbeats with rotated key {}", own_key);
+                    election.lock().expect("cluster").rekey(own_key.clone());
+                },
+                received = socket.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            warn!(logger, "failed to receive cluster message: {:?}", err);
+                            continue;
+                        }
+                    };
+                    match Message::decode(&buf[..len]) {
+                        Ok(Message::Heartbeat { key, timestamp, leader }) if key != own_key => {
+                            if !self.keys.contains(&key) {
+                                debug!(logger, "ignoring unknown host {} at {}", key, addr);
+                                continue;
+                            }
+                            if !is_current(timestamp, stats::unix_millis(), self.lease) {
+                                debug!(logger, "ignoring stale heartbeat of {} at {}", key, addr);
+                                continue;
+                            }
+                            election
+                                .lock()
+                                .expect("cluster")
+                                .heard(&key, addr, timestamp, leader, Instant::now());
+                            self.update(&logger, &election);
+                        }
+                        Ok(_) => (),
+                        Err(err) => debug!(logger, "ignoring cluster message from {}: {:?}", addr, err),
+                    }
+                }
+            }
+        }
+    }
+
+    fn update(&self, logger: &Logger, election: &Mutex<Election>) {
+        let now = Instant::now();
+        let mut election = election.lock().expect("cluster");
+        if !election.update(now) {
+            return;
+        }
+        if election.is_leading() {
+            info!(logger, "leading the cluster, dispatching downlinks");
+        } else {
+            info!(
+                logger,
+                "following {}",
+                election.lease_holder(now).unwrap_or("none")
+            );
+        }
+    }
+
+    async fn heartbeat(
+        &self,
+        socket: &UdpSocket,
+        own_key: &str,
+        election: &Mutex<Election>,
+    ) -> Result {
+        let message = Message::Heartbeat {
+            key: own_key.to_string(),
+            timestamp: stats::unix_millis(),
+            leader: election.lock().expect("cluster").is_leading(),
+        };
+        // One signature covers the heartbeat to every peer
+        let encoded = message.encode(&self.signatures, Purpose::Heartbeat)?;
+        for peer in &self.peers {
+            socket.send_to(&encoded, peer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a heartbeat sent at the given unix milliseconds is within a lease
+/// of the local clock, in either direction. Older heartbeats are replays,
+/// which the last heartbeat of a host no longer catches after a restart.
+fn is_current(timestamp: u64, now: u64, lease: Duration) -> bool {
+    let lease = lease.as_millis() as u64;
+    timestamp.saturating_add(lease) >= now && timestamp <= now.saturating_add(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn election() {
+        let lease = Duration::from_secs(3);
+        let start = Instant::now();
+        let addr: SocketAddr = "10.0.0.2:1692".parse().expect("addr");
+        let mut a = Election::new("a".to_string(), lease, start);
+
+        // Waits a lease before taking the lead
+        assert!(!a.update(start));
+        let later = start + lease;
+        assert!(a.update(later));
+        assert!(a.is_leading());
+
+        // Keeps the lead while a host with a higher key is around
+        assert!(a.heard("b", addr, 1, false, later));
+        assert!(!a.heard("b", addr, 1, false, later));
+        a.update(later);
+        assert!(a.is_leading());
+
+        // Follows a leader that holds the lease
+        let mut b = Election::new("b".to_string(), lease, start);
+        b.heard("a", addr, 1, true, later);
+        b.update(later);
+        assert!(!b.is_leading());
+        assert_eq!(Some("a"), b.lease_holder(later));
+
+        // Takes over once the lease of the leader runs out, and keeps the
+        // lead when the old leader comes back
+        let failover = later + lease;
+        assert!(b.update(failover));
+        assert!(b.is_leading());
+        b.heard("a", addr, 2, false, failover);
+        b.update(failover);
+        assert!(b.is_leading());
+
+        // Of two leaders the one with the higher key steps down
+        b.heard("a", addr, 3, true, failover);
+        assert!(b.update(failover));
+        assert!(!b.is_leading());
+    }
+
+    #[test]
+    fn step_down() {
+        let lease = Duration::from_secs(3);
+        let start = Instant::now();
+        let mut a = Election::new("a".to_string(), lease, start);
+        a.update(start + lease);
+        assert!(a.is_leading());
+
+        // Waits a lease before taking the lead again
+        let failed = start + lease * 2;
+        assert!(a.step_down(failed));
+        assert!(!a.step_down(failed));
+        assert!(!a.update(failed));
+        assert!(!a.is_leading());
+        assert!(a.update(failed + lease));
+        assert!(a.is_leading());
+    }
+
+    #[test]
+    fn current() {
+        let lease = Duration::from_secs(3);
+        let now = 1_600_000_000_000;
+        assert!(is_current(now, now, lease));
+        assert!(is_current(now - 3_000, now, lease));
+        assert!(is_current(now + 3_000, now, lease));
+        assert!(!is_current(now - 3_001, now, lease));
+        assert!(!is_current(now + 3_001, now, lease));
+    }
+}
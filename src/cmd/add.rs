@@ -1,5 +1,9 @@
-use crate::{cmd::*, keypair::Keypair, service::api, Error, PublicKey, Result, Settings};
-use helium_crypto::Sign;
+use crate::{
+    cmd::*,
+    service::api,
+    signer::{Purpose, Signatures},
+    Error, PublicKey, Result, Settings,
+};
 use helium_proto::{BlockchainTxn, BlockchainTxnAddGatewayV1, Message, Txn};
 use serde_derive::Deserialize;
 use serde_json::json;
@@ -40,7 +44,7 @@ impl Cmd {
         };
 
         txn.fee = txn_fee(&config, &txn)?;
-        txn.gateway_signature = txn_sign(&settings.keypair, &txn)?;
+        txn.gateway_signature = txn_sign(&Signatures::new(&settings), &txn)?;
 
         print_txn(&self.mode, &txn)
     }
@@ -54,12 +58,12 @@ fn txn_fee(config: &TxnFeeConfig, txn: &BlockchainTxnAddGatewayV1) -> Result<u64
     Ok(config.get_txn_fee(to_envelope_vec(&txn)?.len()))
 }
 
-fn txn_sign(keypair: &Keypair, txn: &BlockchainTxnAddGatewayV1) -> Result<Vec<u8>> {
+fn txn_sign(signatures: &Signatures, txn: &BlockchainTxnAddGatewayV1) -> Result<Vec<u8>> {
     let mut txn = txn.clone();
     txn.owner_signature = vec![];
     txn.payer_signature = vec![];
     txn.gateway_signature = vec![];
-    Ok(keypair.sign(&to_vec(&txn)?)?)
+    signatures.sign(Purpose::Command, &to_vec(&txn)?)
 }
 
 fn to_envelope_vec(txn: &BlockchainTxnAddGatewayV1) -> Result<Vec<u8>> {
@@ -0,0 +1,116 @@
+use crate::{
+    address_book::{self, Record, Role},
+    audit,
+    cmd::*,
+    signer::Signatures,
+    Error, PublicKey, Result, Settings,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, fs};
+use structopt::StructOpt;
+
+/// Commands on the address book of trusted endpoints
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// List all endpoints in the address book
+    List,
+    /// Add or replace an endpoint in the address book
+    Add {
+        /// The name of the endpoint, for example "router.release"
+        name: String,
+        /// The role of the endpoint (router, gateway or bootstrap)
+        #[structopt(long)]
+        role: Role,
+        /// The uri of the endpoint
+        #[structopt(long)]
+        uri: http::Uri,
+        /// The public key the endpoint is pinned to
+        #[structopt(long)]
+        public_key: PublicKey,
+    },
+    /// Remove an endpoint that was added with the add command
+    Remove {
+        /// The name of the endpoint
+        name: String,
+    },
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        match self {
+            Cmd::List => {
+                let entries: serde_json::Map<String, serde_json::Value> = settings
+                    .address_book
+                    .iter()
+                    .map(|(name, endpoint)| {
+                        (
+                            name.clone(),
+                            json!({
+                                "role": endpoint.role,
+                                "uri": endpoint.uri.to_string(),
+                                "public_key": endpoint.public_key.to_string(),
+                            }),
+                        )
+                    })
+                    .collect();
+                print_json(&entries)
+            }
+            Cmd::Add {
+                name,
+                role,
+                uri,
+                public_key,
+            } => {
+                let mut records = address_book::load_overlay(&settings.path)?;
+                records.insert(
+                    name.clone(),
+                    Record {
+                        role: *role,
+                        uri: uri.to_string(),
+                        public_key: public_key.to_string(),
+                    },
+                );
+                update_overlay(&settings, &records)?;
+                print_json(&json!({ "added": name }))
+            }
+            Cmd::Remove { name } => {
+                let mut records = address_book::load_overlay(&settings.path)?;
+                if records.remove(name).is_none() {
+                    return Err(Error::custom(format!(
+                        "{} was not added with the address-book command",
+                        name
+                    )));
+                }
+                update_overlay(&settings, &records)?;
+                print_json(&json!({ "removed": name }))
+            }
+        }
+    }
+}
+
+/// Saves the overlay and checks that the resulting settings are still valid,
+/// restoring the previous overlay if not or if the change can not be
+/// recorded.
+fn update_overlay(settings: &Settings, records: &BTreeMap<String, Record>) -> Result {
+    let path = settings.path.join(address_book::OVERLAY_FILE);
+    let previous = fs::read_to_string(&path).ok();
+    address_book::save_overlay(&settings.path, records)?;
+    let applied = Settings::new(&settings.path).and_then(|_| {
+        audit::AuditLog::new(settings).record(
+            &Signatures::new(settings),
+            "address_book",
+            &settings.keypair.public_key().to_string(),
+            address_book::OVERLAY_FILE,
+            previous.as_deref(),
+            fs::read_to_string(&path).ok().as_deref(),
+        )
+    });
+    if let Err(err) = applied {
+        match previous {
+            Some(previous) => fs::write(&path, previous)?,
+            None => fs::remove_file(&path)?,
+        }
+        return Err(err);
+    }
+    Ok(())
+}
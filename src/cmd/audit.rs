@@ -0,0 +1,29 @@
+use crate::{cmd::*, *};
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Show the signed log of configuration changes and check its chain
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// The maximum number of entries to show, newest first
+    #[structopt(long, short = "n")]
+    limit: Option<usize>,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let log = audit::AuditLog::new(&settings);
+        let entries = log.load()?;
+        let verified = log.verify(&entries);
+        let shown: Vec<&audit::Entry> = entries
+            .iter()
+            .rev()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        print_json(&json!({
+            "verified": verified.is_ok(),
+            "error": verified.err().map(|err| format!("{:?}", err)),
+            "entries": shown,
+        }))
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{cmd::*, *};
+use error::ServiceError;
+use helium_proto::BlockchainStateChannelMessageV1;
+use serde_json::json;
+use service::{
+    breaker::CircuitBreaker,
+    router::{RouteOptions, Service as RouterService},
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use tokio::time;
+
+/// The LoRaWAN RX1 delay. A downlink has to be back at the packet forwarder
+/// before the RX1 window opens this long after the end of the uplink.
+pub const RX1_DELAY_MS: u64 = 1_000;
+/// Time of the RX1 budget taken by everything but the router round trip:
+/// the uplink reaching the gateway, signing, and the forwarder scheduling the
+/// transmit.
+pub const RX1_MARGIN_MS: u64 = 200;
+
+/// Benchmarks for deciding on gateway settings
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    Backhaul(Backhaul),
+}
+
+/// Measure round-trip latency and jitter to the given routers and report
+/// whether downlinks can make the RX1 window.
+///
+/// Each round trip is an empty route request, sent over the same grpc
+/// transport used for uplinks. Only routers given with --router are
+/// benchmarked, so the configured production routers do not get the
+/// requests; point it at a staging or local router, ideally at the same
+/// network distance.
+#[derive(Debug, StructOpt)]
+pub struct Backhaul {
+    /// Router uri to benchmark, repeat for more than one
+    #[structopt(long = "router", required = true, number_of_values = 1)]
+    routers: Vec<http::Uri>,
+    /// Number of round trips per router
+    #[structopt(short = "n", default_value = "10")]
+    count: usize,
+    /// Milliseconds between round trips
+    #[structopt(long, default_value = "200")]
+    interval: u64,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        match self {
+            Cmd::Backhaul(cmd) => cmd.run(settings).await,
+        }
+    }
+}
+
+impl Backhaul {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let mut reports = vec![];
+        for uri in &self.routers {
+            let breaker = Arc::new(CircuitBreaker::new(&settings.circuit_breaker));
+            let mut client = RouterService::new(uri.clone(), None, breaker, &settings.backhaul)?;
+            // The first request also sets up the connection, which uplinks
+            // normally find already established
+            let connect = round_trip(&mut client).await;
+            let mut samples = vec![];
+            let mut failures = 0;
+            if connect.is_ok() {
+                for _ in 0..self.count {
+                    time::sleep(Duration::from_millis(self.interval)).await;
+                    match round_trip(&mut client).await {
+                        Ok(rtt) => samples.push(rtt),
+                        Err(_) => failures += 1,
+                    }
+                }
+            }
+            reports.push(report(uri, connect, &samples, failures));
+        }
+        print_json(&reports)
+    }
+}
+
+/// Times a single grpc round trip. Requests rejected by the router still
+/// made the round trip; only transport failures count as failed.
+async fn round_trip(client: &mut RouterService) -> Result<Duration> {
+    let start = Instant::now();
+    match client
+        .route(
+            BlockchainStateChannelMessageV1 { msg: None },
+            RouteOptions::default(),
+        )
+        .await
+    {
+        Ok(_) => Ok(start.elapsed()),
+        Err(Error::Service(ServiceError::Rpc(status)))
+            if !matches!(
+                status.code(),
+                tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+            ) =>
+        {
+            Ok(start.elapsed())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn report(
+    uri: &http::Uri,
+    connect: Result<Duration>,
+    samples: &[Duration],
+    failures: usize,
+) -> serde_json::Value {
+    let connect = match connect {
+        Ok(connect) => connect,
+        Err(err) => {
+            return json!({
+                "uri": uri.to_string(),
+                "error": format!("{:?}", err),
+                "rx1_feasible": false,
+            })
+        }
+    };
+    let mut millis: Vec<u64> = samples.iter().map(|d| d.as_millis() as u64).collect();
+    // Jitter as the mean difference between consecutive round trips
+    let jitter = if millis.len() > 1 {
+        let diffs: u64 = millis
+            .windows(2)
+            .map(|w| {
+                if w[0] > w[1] {
+                    w[0] - w[1]
+                } else {
+                    w[1] - w[0]
+                }
+            })
+            .sum();
+        Some(diffs / (millis.len() as u64 - 1))
+    } else {
+        None
+    };
+    millis.sort_unstable();
+    let percentile = |p: usize| {
+        if millis.is_empty() {
+            None
+        } else {
+            Some(millis[(millis.len() - 1) * p / 100])
+        }
+    };
+    let p95 = percentile(95);
+    json!({
+        "uri": uri.to_string(),
+        "connect_ms": connect.as_millis() as u64,
+        "samples": millis.len(),
+        "failures": failures,
+        "min_ms": millis.first(),
+        "median_ms": percentile(50),
+        "p95_ms": p95,
+        "max_ms": millis.last(),
+        "jitter_ms": jitter,
+        "rx1_budget_ms": RX1_DELAY_MS - RX1_MARGIN_MS,
+        "rx1_feasible": failures == 0 && p95.map_or(false, |p95| p95 + RX1_MARGIN_MS < RX1_DELAY_MS),
+    })
+}
@@ -0,0 +1,37 @@
+use crate::{cmd::*, *};
+use certification::TestTransmission;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Key the transmitter on a frequency for regulatory certification, through
+/// a packet forwarder connected to the listen address. The carrier is held
+/// with back to back frames since the packet forwarder can not send an
+/// unmodulated one. Run this with the server stopped, and only with test
+/// transmissions enabled in the certification settings.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// The frequency in MHz
+    #[structopt(long)]
+    frequency: f32,
+    /// The datarate of the frames holding the carrier
+    #[structopt(long, default_value = "SF12BW125")]
+    datarate: String,
+    /// The transmit power in dBm
+    #[structopt(long, default_value = "14")]
+    power: u64,
+    /// How long to transmit for, in seconds
+    #[structopt(long, default_value = "10")]
+    duration: u64,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let transmission = TestTransmission {
+            frequency: self.frequency,
+            power: self.power,
+            duration: Duration::from_secs(self.duration),
+        };
+        let summary = certification::cw(&settings, &transmission, &self.datarate).await?;
+        print_json(&summary)
+    }
+}
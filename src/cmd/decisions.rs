@@ -0,0 +1,29 @@
+use crate::{cmd::*, *};
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Show why recent uplinks of the running gateway were routed where they were
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Only show uplinks of this DevAddr (hex)
+    #[structopt(long)]
+    devaddr: Option<String>,
+    /// Only show join requests of this DevEUI (hex)
+    #[structopt(long)]
+    deveui: Option<String>,
+    /// The maximum number of decisions to show, newest first
+    #[structopt(long, short = "n")]
+    limit: Option<u64>,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let params = json!({
+            "devaddr": self.devaddr,
+            "deveui": self.deveui,
+            "limit": self.limit,
+        });
+        let decisions = api::call(&settings, "decisions", params).await?;
+        print_json(&decisions)
+    }
+}
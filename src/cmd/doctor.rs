@@ -0,0 +1,327 @@
+use crate::{cmd::*, *};
+use address_book::Role;
+use gateway::antennas::Antennas;
+use helium_crypto::Verify;
+use semtech_udp::{
+    pull_resp,
+    server_runtime::{Event, UdpRuntime},
+    CodingRate, MacAddress, Modulation, StringOrNum,
+};
+use serde_json::json;
+use service::gateway::Service as GatewayService;
+use settings::{BackhaulSettings, Nat64};
+use signer::{Purpose, Signatures};
+use std::{
+    net::UdpSocket,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+use tokio::time;
+
+/// Seconds to wait for the packet forwarder to connect for the loopback test.
+const LOOPBACK_CONNECT_SECS: u64 = 30;
+/// Prefix of the loopback test frame, followed by a random nonce.
+const LOOPBACK_MAGIC: &[u8] = b"helium-gateway-selftest";
+
+/// Check the gateway setup and print a pass/fail report. Run this with the
+/// server stopped, since the server holds the listen address.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Transmit a test frame on the self test frequency and check that the
+    /// concentrator hears it. Needs a packet forwarder connected to the
+    /// listen address and a concentrator that can receive while it
+    /// transmits, or a second radio.
+    #[structopt(long)]
+    loopback: bool,
+}
+
+#[derive(Debug)]
+struct Check {
+    name: String,
+    result: Result<String>,
+}
+
+impl Check {
+    fn new<S: ToString>(name: S, result: Result<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            result,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match &self.result {
+            Ok(detail) => json!({ "check": self.name, "result": "pass", "detail": detail }),
+            Err(err) => json!({ "check": self.name, "result": "fail", "detail": err_detail(err) }),
+        }
+    }
+}
+
+fn err_detail(err: &Error) -> String {
+    match err {
+        Error::Custom(msg) => msg.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let mut settings = settings;
+        settings.backhaul.nat64_prefix = resolve::nat64_prefix(&settings.backhaul.nat64).await;
+        // Settings that fail to parse never get here, the error is reported
+        // when loading them
+        let mut checks = vec![
+            Check::new(
+                "settings",
+                Ok(match &settings.profile {
+                    Some(profile) => format!(
+                        "loaded from {} with profile {}",
+                        settings.path.display(),
+                        profile
+                    ),
+                    None => format!("loaded from {}", settings.path.display()),
+                }),
+            ),
+            Check::new("keypair", check_keypair(&settings)),
+            Check::new("listen_addr", check_listen_addr(&settings)),
+            Check::new("time", check_time()),
+            Check::new("region", check_region(&settings)),
+        ];
+        if settings.backhaul.nat64 != Nat64::Off {
+            checks.push(Check::new("nat64", check_nat64(&settings)));
+        }
+        if self.loopback {
+            let (tx, rx) = match check_loopback(&settings).await {
+                Ok((tx, rx)) => (Ok(tx), rx),
+                Err(err) => (Err(err), Err(Error::custom("no test frame sent"))),
+            };
+            checks.push(Check::new("loopback tx", tx));
+            checks.push(Check::new("loopback rx", rx));
+        }
+        for router in settings.address_book.with_role(Role::Router) {
+            let result = check_reachable(&router.uri, &settings.backhaul).await;
+            checks.push(Check::new(format!("router {}", router.uri), result));
+        }
+        for gateway in settings.address_book.with_role(Role::Gateway) {
+            let name = format!("gateway {}", gateway.uri);
+            checks.push(Check::new(name, check_gateway(gateway, &settings).await));
+        }
+        let passed = checks.iter().all(|check| check.result.is_ok());
+        print_json(&json!({
+            "result": if passed { "pass" } else { "fail" },
+            "checks": checks.iter().map(Check::to_json).collect::<Vec<_>>(),
+        }))?;
+        if !passed {
+            return Err(Error::custom("gateway checks failed"));
+        }
+        Ok(())
+    }
+}
+
+fn check_keypair(settings: &Settings) -> Result<String> {
+    let msg = b"gateway doctor";
+    let signature = Signatures::new(settings).sign(Purpose::Command, msg)?;
+    let public_key = settings.keypair.public_key();
+    public_key.verify(msg, &signature)?;
+    Ok(format!("{} signs", public_key))
+}
+
+fn check_listen_addr(settings: &Settings) -> Result<String> {
+    match UdpSocket::bind(settings.listen_addr) {
+        Ok(_) => Ok(format!("{} is available", settings.listen_addr)),
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => Err(Error::custom(format!(
+            "{} is in use, is the server already running?",
+            settings.listen_addr
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// There is no trusted time source to compare against, so this only catches
+/// clocks that were never set, as on gateways without an RTC before NTP has
+/// synced.
+fn check_time() -> Result<String> {
+    let now = sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if now < sntp::MIN_SANE_TIME {
+        return Err(Error::custom(format!("system clock at {} is not set", now)));
+    }
+    Ok(format!("system clock at {}", now))
+}
+
+fn check_region(settings: &Settings) -> Result<String> {
+    match settings.region {
+        Some(region) if region::has_plan(region) => {
+            Ok(format!("channel plan for {:?} available", region))
+        }
+        Some(region) => Err(Error::custom(format!("no channel plan for {:?}", region))),
+        None => Ok("inferred from uplinks".to_string()),
+    }
+}
+
+fn check_nat64(settings: &Settings) -> Result<String> {
+    match settings.backhaul.nat64_prefix {
+        Some(prefix) => Ok(format!("IPv4 addresses reached through {}/96", prefix)),
+        None => Err(Error::custom(
+            "no NAT64 prefix discovered, is the resolver a DNS64 resolver?",
+        )),
+    }
+}
+
+/// Routers do not sign their responses, so only their reachability can be
+/// checked. Their keys are verified on the state channel. Both address
+/// families are tried so the report shows which of them get through.
+async fn check_reachable(uri: &http::Uri, backhaul: &BackhaulSettings) -> Result<String> {
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::custom(format!("no host in {}", uri)))?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = resolve::resolve(host, port, backhaul).await?;
+    let attempts = resolve::attempts(&addrs, Duration::from_secs(backhaul.timeout)).await;
+    let detail = attempts
+        .iter()
+        .map(|attempt| match &attempt.result {
+            Ok(elapsed) => format!(
+                "{} {} connected in {}ms",
+                attempt.family(),
+                attempt.addr,
+                elapsed.as_millis()
+            ),
+            Err(err) => format!(
+                "{} {} failed: {}",
+                attempt.family(),
+                attempt.addr,
+                err_detail(err)
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    if attempts.iter().any(|attempt| attempt.result.is_ok()) {
+        Ok(detail)
+    } else if attempts.is_empty() {
+        Err(Error::custom(format!("{} has no addresses", host)))
+    } else {
+        Err(Error::custom(detail))
+    }
+}
+
+/// Sends a test frame through the first packet forwarder that connects and
+/// waits for the concentrator to report it as an uplink. Returns the
+/// transmit result and, if the frame was sent, the receive result.
+///
+/// The frame is sent with uplink polarity so the concentrator can demodulate
+/// it. Many concentrators are deaf while transmitting, in which case only
+/// the transmit result says something about the gateway.
+async fn check_loopback(settings: &Settings) -> Result<(String, Result<String>)> {
+    let self_test = &settings.self_test;
+    let frequency = self_test
+        .frequency
+        .ok_or_else(|| Error::custom("no self_test frequency configured"))?;
+    // Transmitting outside the region plan is refused before a packet
+    // forwarder connects
+    if let Some(region) = settings.region {
+        region::validate_downlink(region, frequency, &self_test.datarate)?;
+        region::check_power(region, self_test.power)?;
+    }
+    let mut udp_runtime = UdpRuntime::new(settings.listen_addr).await?;
+    let mac = time::timeout(Duration::from_secs(LOOPBACK_CONNECT_SECS), async {
+        loop {
+            match udp_runtime.recv().await {
+                Event::NewClient((mac, _)) | Event::UpdateClient((mac, _)) => return mac,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::custom("no packet forwarder connected"))?;
+
+    let mut payload = LOOPBACK_MAGIC.to_vec();
+    payload.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+    let txpk = pull_resp::TxPk {
+        imme: true,
+        ipol: false,
+        modu: Modulation::LORA,
+        codr: CodingRate::_4_5,
+        datr: self_test.datarate.parse()?,
+        freq: frequency as f64,
+        size: payload.len() as u64,
+        data: payload.clone(),
+        powe: self_test.power,
+        rfch: Antennas::new(&settings.antennas)?.tx_rf_chain(&mac, &mac.to_string()),
+        tmst: StringOrNum::S("immediate".to_string()),
+        tmms: None,
+        fdev: None,
+        prea: None,
+        ncrc: None,
+    };
+    let mut downlink = udp_runtime.prepare_empty_downlink(mac);
+    downlink.set_packet(txpk);
+    downlink
+        .dispatch(Some(Duration::from_secs(gateway::DOWNLINK_TIMEOUT_SECS)))
+        .await
+        .map_err(|err| Error::custom(format!("{} refused the test frame: {:?}", mac, err)))?;
+    let tx = format!("{} sent a test frame at {} MHz", mac, frequency);
+    let rx = heard(&mut udp_runtime, mac, &payload, self_test.timeout).await;
+    Ok((tx, rx))
+}
+
+async fn heard(
+    udp_runtime: &mut UdpRuntime,
+    mac: MacAddress,
+    payload: &[u8],
+    timeout: u64,
+) -> Result<String> {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match time::timeout(remaining, udp_runtime.recv()).await {
+            Ok(Event::PacketReceived(rxpk, from)) if rxpk.get_data() == payload => {
+                let rssi = rxpk
+                    .get_signal_rssi()
+                    .unwrap_or_else(|| rxpk.get_channel_rssi());
+                return Ok(format!(
+                    "{} heard the test frame from {} at {} dBm, {} dB snr",
+                    from,
+                    mac,
+                    rssi,
+                    rxpk.get_snr()
+                ));
+            }
+            Ok(_) => continue,
+            Err(_) => break,
+        }
+    }
+    Err(Error::custom(format!(
+        "test frame not heard within {}s, the concentrator may not receive while transmitting",
+        timeout
+    )))
+}
+
+/// Gateways sign their routing updates, so reading the first update checks
+/// both that the gateway is reachable and that its key matches.
+async fn check_gateway(gateway: KeyedUri, settings: &Settings) -> Result<String> {
+    let timeout = Duration::from_secs(settings.backhaul.timeout);
+    let mut service = GatewayService::new(gateway, &settings.backhaul)?;
+    let update = time::timeout(timeout, async {
+        let mut stream = service.routing(0).await?;
+        stream.message().await
+    })
+    .await;
+    match update {
+        Ok(Ok(Some(response))) => Ok(format!(
+            "key verified at routing height {}",
+            response.height()
+        )),
+        Ok(Ok(None)) => Err(Error::custom("routing stream closed")),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(Error::custom("routing request timed out")),
+    }
+}
@@ -0,0 +1,14 @@
+use crate::{cmd::*, *};
+use serde_json::Value;
+use structopt::StructOpt;
+
+/// Show the downlinks the running gateway holds for each packet forwarder
+#[derive(Debug, StructOpt)]
+pub struct Cmd {}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let queue = api::call(&settings, "downlinks", Value::Null).await?;
+        print_json(&queue)
+    }
+}
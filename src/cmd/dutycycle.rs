@@ -0,0 +1,48 @@
+use crate::{cmd::*, *};
+use serde_json::{json, Value};
+use structopt::StructOpt;
+
+/// Commands on the transmit time counters kept for duty cycle compliance
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// Show the transmit time per band and per day of the running gateway
+    Report {
+        /// How far back to show, like 7d or 30d
+        #[structopt(long, default_value = "30d")]
+        since: String,
+        /// Print comma separated values instead of json
+        #[structopt(long)]
+        csv: bool,
+    },
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        match self {
+            Cmd::Report { since, csv } => {
+                stats::parse_since(since)?;
+                let report = api::call(&settings, "dutycycle", json!({ "since": since })).await?;
+                if *csv {
+                    print_csv(&report);
+                    Ok(())
+                } else {
+                    print_json(&report)
+                }
+            }
+        }
+    }
+}
+
+fn print_csv(report: &Value) {
+    println!("day,band,transmissions,airtime_ms,duty_cycle_percent");
+    for day in report["days"].as_array().into_iter().flatten() {
+        println!(
+            "{},{},{},{},{:.4}",
+            day["day"],
+            day["band"].as_str().unwrap_or_default(),
+            day["transmissions"],
+            day["airtime_ms"],
+            day["duty_cycle_percent"].as_f64().unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use crate::{cmd::*, *};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Export the gateway settings, state store and identity metadata to a
+/// bundle for moving the gateway to new hardware. The keypair is not
+/// included. Stop the server first.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// The bundle file to write
+    #[structopt(long, short = "o", default_value = "gateway-bundle.tar")]
+    output: PathBuf,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let manifest = bundle::export(&settings, &self.output).await?;
+        print_json(&manifest)
+    }
+}
@@ -0,0 +1,21 @@
+use crate::{cmd::*, *};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Import a bundle written by the export command. Move the keypair file (or
+/// secure element) of the gateway over first, and stop the server.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// The bundle file to read
+    input: PathBuf,
+    /// Import even if the bundle is for a different gateway key
+    #[structopt(long)]
+    force: bool,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let manifest = bundle::import(&settings, &self.input, self.force).await?;
+        print_json(&manifest)
+    }
+}
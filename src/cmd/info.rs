@@ -0,0 +1,24 @@
+use crate::{cmd::*, *};
+use fingerprint::Fingerprint;
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Show the gateway version, key and the environment it runs in, and the
+/// gateways found on the LAN when the server is running
+#[derive(Debug, StructOpt)]
+pub struct Cmd {}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let peers = api::call(&settings, "peers", serde_json::Value::Null)
+            .await
+            .ok()
+            .map(|mut result| result["peers"].take());
+        print_json(&json!({
+            "address": settings.keypair.public_key().to_string(),
+            "environment": Fingerprint::collect(&settings),
+            "features": settings.features.list(),
+            "peers": peers,
+        }))
+    }
+}
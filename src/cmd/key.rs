@@ -1,22 +1,48 @@
 use crate::{cmd::*, *};
 use angry_purple_tiger::AnimalName;
 use serde_json::json;
+use signer::Signatures;
+use std::time::Duration;
 use structopt::StructOpt;
 
 /// Commands on gateway keys
 #[derive(Debug, StructOpt)]
 pub enum Cmd {
     Info(Info),
+    Rotate(Rotate),
 }
 
 /// Commands on gateway keys
 #[derive(Debug, StructOpt)]
 pub struct Info {}
 
+/// Rotate the gateway keypair. A rotation generates a new keypair that
+/// replaces the current keypair once a grace period has passed. Until then
+/// the current keypair remains in use.
+#[derive(Debug, StructOpt)]
+pub enum Rotate {
+    /// Start a key rotation. This prints the new key along with signatures
+    /// of both keys which can be submitted to register the new key.
+    Start {
+        /// Grace period in hours before the new keypair is used
+        #[structopt(long, default_value = "24")]
+        grace: u64,
+    },
+    /// Show the status of an in-progress key rotation
+    Status,
+    /// Complete an in-progress key rotation without waiting for the grace
+    /// period to end or for the new key to be registered. The new key is
+    /// used after the next restart.
+    Complete,
+    /// Abort an in-progress key rotation
+    Abort,
+}
+
 impl Cmd {
     pub async fn run(&self, settings: Settings) -> Result {
         match self {
             Cmd::Info(cmd) => cmd.run(settings).await,
+            Cmd::Rotate(cmd) => cmd.run(settings).await,
         }
     }
 }
@@ -31,3 +57,51 @@ impl Info {
         print_json(&table)
     }
 }
+
+impl Rotate {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let path = &settings.keypair_path;
+        match self {
+            Rotate::Start { grace } => {
+                let (next, rotation) =
+                    keypair::start_rotation(path, Duration::from_secs(grace * 3600))?;
+                let signatures = Signatures::new(&settings);
+                let mut table = keypair::registration(&signatures, &next, &rotation)?;
+                table["new_name"] = json!(next
+                    .public_key()
+                    .to_string()
+                    .parse::<AnimalName>()
+                    .unwrap()
+                    .to_string());
+                print_json(&table)
+            }
+            Rotate::Status => match keypair::load_rotation(path)? {
+                Some(rotation) => print_json(&json!({
+                    "address": settings.keypair.public_key().to_string(),
+                    "new_address": rotation.public_key,
+                    "swap_at": rotation.swap_at(),
+                    "due": rotation.is_due(),
+                })),
+                None => print_json(&json!({
+                    "address": settings.keypair.public_key().to_string(),
+                })),
+            },
+            Rotate::Complete => {
+                if !keypair::complete_rotation(path, true)? {
+                    return Err(Error::custom("no key rotation in progress"));
+                }
+                print_json(&json!({
+                    "address": keypair::load_from_file(path)?.public_key().to_string(),
+                }))
+            }
+            Rotate::Abort => {
+                if !keypair::abort_rotation(path)? {
+                    return Err(Error::custom("no key rotation in progress"));
+                }
+                print_json(&json!({
+                    "address": settings.keypair.public_key().to_string(),
+                }))
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{cmd::*, *};
+use migration::Migration;
+use serde_json::json;
+use std::fs;
+use structopt::StructOpt;
+
+/// Update settings.toml to the current settings layout. The original file is
+/// kept as settings.toml.bak. Comments in the file are not preserved.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// Print the migrated settings instead of writing them
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let path = settings.path.join(settings::SETTINGS_FILE);
+        if !path.exists() {
+            return print_json(&json!({ "changes": [] }));
+        }
+        let migration = Migration::load(&path)?;
+        if self.dry_run {
+            println!("{}", migration.to_toml()?);
+            return Ok(());
+        }
+        if !migration.is_empty() {
+            let backup = path.with_extension("toml.bak");
+            fs::copy(&path, &backup)?;
+            fs::write(&path, migration.to_toml()?)?;
+        }
+        print_json(&json!({ "changes": migration.changes }))
+    }
+}
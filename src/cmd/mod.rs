@@ -1,6 +1,21 @@
 pub mod add;
+pub mod address_book;
+pub mod audit;
+pub mod bench;
+pub mod cw;
+pub mod decisions;
+pub mod doctor;
+pub mod downlinks;
+pub mod dutycycle;
+pub mod export;
+pub mod import;
+pub mod info;
 pub mod key;
+pub mod migrate;
+pub mod modtest;
 pub mod server;
+pub mod stats;
+pub mod tunnel;
 pub mod update;
 
 use crate::Result;
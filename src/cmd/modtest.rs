@@ -0,0 +1,50 @@
+use crate::{cmd::*, *};
+use certification::TestTransmission;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Transmit modulated LoRa test frames for regulatory certification, through
+/// a packet forwarder connected to the listen address. Run this with the
+/// server stopped, and only with test transmissions enabled in the
+/// certification settings.
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// The frequency in MHz
+    #[structopt(long)]
+    frequency: f32,
+    /// The datarate of the test frames
+    #[structopt(long, default_value = "SF7BW125")]
+    datarate: String,
+    /// The transmit power in dBm
+    #[structopt(long, default_value = "14")]
+    power: u64,
+    /// The test frame size in bytes
+    #[structopt(long, default_value = "20")]
+    size: usize,
+    /// How long to transmit for, in seconds
+    #[structopt(long, default_value = "10")]
+    duration: u64,
+    /// Milliseconds from the start of one frame to the start of the next,
+    /// 0 to send back to back
+    #[structopt(long, default_value = "0")]
+    interval: u64,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let transmission = TestTransmission {
+            frequency: self.frequency,
+            power: self.power,
+            duration: Duration::from_secs(self.duration),
+        };
+        let summary = certification::modtest(
+            &settings,
+            &transmission,
+            &self.datarate,
+            self.size,
+            Duration::from_millis(self.interval),
+        )
+        .await?;
+        print_json(&summary)
+    }
+}
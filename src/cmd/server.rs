@@ -1,4 +1,5 @@
 use crate::*;
+use signals::LogSwitch;
 use slog::Logger;
 use structopt::StructOpt;
 
@@ -11,8 +12,14 @@ impl Cmd {
         &self,
         shutdown: &triggered::Listener,
         settings: Settings,
+        log_switch: LogSwitch,
         logger: &Logger,
     ) -> Result {
-        server::run(shutdown, &settings, logger).await
+        let mut settings = settings;
+        if !startup::wait(&settings, shutdown, logger).await? {
+            return Ok(());
+        }
+        settings.backhaul.nat64_prefix = resolve::nat64_prefix(&settings.backhaul.nat64).await;
+        server::run(shutdown, &settings, log_switch, logger).await
     }
 }
@@ -0,0 +1,28 @@
+use crate::{cmd::*, *};
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Show traffic and router latency aggregates of the running gateway
+#[derive(Debug, StructOpt)]
+pub struct Cmd {
+    /// How far back to show, like 30m, 24h or 7d
+    #[structopt(long, default_value = "24h")]
+    since: String,
+    /// The maximum number of devices to show downlink outcomes for, those
+    /// with the most failures (default: 20)
+    #[structopt(long, short = "n")]
+    limit: Option<u64>,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        stats::parse_since(&self.since)?;
+        let stats = api::call(
+            &settings,
+            "stats",
+            json!({ "since": self.since, "limit": self.limit }),
+        )
+        .await?;
+        print_json(&stats)
+    }
+}
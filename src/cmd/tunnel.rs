@@ -0,0 +1,58 @@
+use crate::*;
+use gateway::allowlist::Allowlist;
+use slog::Logger;
+use structopt::StructOpt;
+use tunnel::{Cipher, Direction, Relay};
+
+/// Commands for the encrypted packet forwarder tunnel
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// Relay a local packet forwarder to a remote gateway through the
+    /// encrypted tunnel, using the tunnel key from the settings
+    Connect {
+        /// The tunnel address of the remote gateway
+        remote: String,
+        /// The address to listen on for the local packet forwarder
+        #[structopt(long, default_value = "127.0.0.1:1680")]
+        listen: std::net::SocketAddr,
+    },
+    /// Generate a new tunnel key
+    GenerateKey,
+}
+
+impl Cmd {
+    pub async fn run(
+        &self,
+        shutdown: &triggered::Listener,
+        settings: Settings,
+        logger: &Logger,
+    ) -> Result {
+        match self {
+            Self::Connect { remote, listen } => {
+                let key = settings
+                    .tunnel
+                    .key
+                    .as_ref()
+                    .ok_or_else(|| Error::custom("no tunnel key in settings"))?;
+                let remote = tokio::net::lookup_host(remote)
+                    .await?
+                    .next()
+                    .ok_or_else(|| {
+                        Error::custom(format!("unresolved tunnel address: {}", remote))
+                    })?;
+                let mut relay = Relay::new(
+                    key,
+                    *listen,
+                    remote,
+                    Direction::Seal,
+                    Allowlist::new(&settings.allowlist)?,
+                )?;
+                relay.run(shutdown.clone(), logger).await
+            }
+            Self::GenerateKey => {
+                println!("{}", Cipher::generate_key());
+                Ok(())
+            }
+        }
+    }
+}
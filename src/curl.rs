@@ -1,7 +1,16 @@
 use crate::*;
 use futures::FutureExt;
-use std::ffi::OsStr;
-use tokio::process;
+use std::{ffi::OsStr, process::Stdio};
+use tokio::{io::AsyncWriteExt, process};
+
+/// Returns the curl arguments to bind a request to the given network
+/// interface or source address, if any.
+pub fn interface_args(interface: &Option<String>) -> Vec<String> {
+    match interface {
+        Some(interface) => vec!["--interface".to_string(), interface.clone()],
+        None => vec![],
+    }
+}
 
 pub fn get<U, I, S, R, F>(url: U, args: I, f: F) -> Future<R>
 where
@@ -22,3 +31,45 @@ where
         })
         .boxed()
 }
+
+/// Posts the given body to a url. Unlike `get` a failed request (including
+/// http error responses) is reported as an error without calling the response
+/// handler. The body is piped through stdin, so it doesn't show up in the
+/// process list and isn't taken for a file name.
+pub fn post<U, I, S, R, F>(url: U, args: I, body: String, f: F) -> Future<R>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    U: AsRef<OsStr>,
+    F: FnOnce(&[u8]) -> Result<R> + std::marker::Send + 'static,
+{
+    let child = process::Command::new("curl")
+        .kill_on_drop(true)
+        .args(args)
+        .arg("-s")
+        .arg("-f")
+        .arg("--data-binary")
+        .arg("@-")
+        .arg(&url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    async move {
+        let mut child = child?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Dropping stdin closes it, which ends the body
+            stdin.write_all(body.as_bytes()).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if output.status.success() {
+            f(&output.stdout)
+        } else {
+            Err(Error::custom(format!(
+                "curl post failed: {:?}",
+                output.status.code()
+            )))
+        }
+    }
+    .boxed()
+}
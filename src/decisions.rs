@@ -0,0 +1,187 @@
+use crate::*;
+use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+use link_packet::LinkPacket;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Why an uplink went where it went, or nowhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// Routed to the routers of the OUIs whose EUI filter matched the join
+    /// request
+    EuiFilter,
+    /// Routed to the routers of the OUIs whose DevAddr prefix matched
+    DevaddrPrefix,
+    /// Routed to the default router since no OUI matched
+    Default,
+    /// Dropped since the routers it matched all have an open circuit
+    CircuitOpen,
+    /// Dropped since it carries no DevAddr or EUIs to route by
+    NoRouting,
+    /// Dropped since its region could not be inferred from the frequency
+    NoRegion,
+    /// Dropped as a duplicate of an uplink seen within the dedup window
+    Duplicate,
+    /// Dropped from a full uplink queue to make room for a newer uplink of
+    /// the same packet forwarder
+    QueueFull,
+    /// Forwarded to the private network server only
+    Lns,
+    /// Sent to the join server instead of routed
+    JoinServer,
+    /// Left to a gateway of the site that heard it with a better signal
+    Peer,
+}
+
+/// The routing decision for one uplink.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub timestamp: u64,
+    pub trace_id: String,
+    pub gateway_mac: String,
+    /// The DevAddr of a data uplink, in hex
+    pub devaddr: Option<String>,
+    /// The DevEUI and AppEUI of a join request, in hex
+    pub deveui: Option<String>,
+    pub appeui: Option<String>,
+    pub reason: Reason,
+    /// The OUIs that matched
+    pub ouis: Vec<u32>,
+    /// The routers the uplink was sent to
+    pub routers: Vec<String>,
+    /// Matching routers skipped because their circuit is open
+    pub skipped: Vec<String>,
+}
+
+impl Decision {
+    pub fn new(packet: &LinkPacket, reason: Reason) -> Self {
+        let routing_data = match &packet.packet.routing {
+            Some(RoutingInformation { data: Some(data) }) => Some(data),
+            _ => None,
+        };
+        let (devaddr, eui) = match routing_data {
+            Some(RoutingData::Devaddr(devaddr)) => (Some(format!("{:08x}", devaddr)), None),
+            Some(RoutingData::Eui(eui)) => (None, Some(eui)),
+            None => (None, None),
+        };
+        Self {
+            timestamp: stats::unix_secs(),
+            trace_id: packet.trace_id.to_string(),
+            gateway_mac: packet.gateway_mac.to_string(),
+            devaddr,
+            deveui: eui.map(|eui| format!("{:016x}", eui.deveui)),
+            appeui: eui.map(|eui| format!("{:016x}", eui.appeui)),
+            reason,
+            ouis: vec![],
+            routers: vec![],
+            skipped: vec![],
+        }
+    }
+}
+
+/// A cheaply cloneable handle to record the routing decisions of recent
+/// uplinks in a bounded ring buffer, oldest dropped first.
+#[derive(Debug, Clone)]
+pub struct Decisions {
+    capacity: usize,
+    recent: Arc<Mutex<VecDeque<Decision>>>,
+}
+
+impl Decisions {
+    /// Creates a ring buffer keeping the given number of decisions. Nothing
+    /// is kept with a capacity of 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub fn record(&self, decision: Decision) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut recent) = self.recent.lock() {
+            if recent.len() >= self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(decision);
+        }
+    }
+
+    /// Returns the recorded decisions, newest first, for the api. The
+    /// optional `devaddr` and `deveui` params (hex) select the uplinks of one
+    /// device and `limit` caps the number returned.
+    pub fn query(&self, params: &Value) -> Value {
+        let devaddr = params["devaddr"].as_str().map(str::to_lowercase);
+        let deveui = params["deveui"].as_str().map(str::to_lowercase);
+        let limit = params["limit"].as_u64().unwrap_or(u64::MAX) as usize;
+        let recent = match self.recent.lock() {
+            Ok(recent) => recent,
+            Err(_) => return json!({ "capacity": self.capacity, "decisions": [] }),
+        };
+        let decisions: Vec<&Decision> = recent
+            .iter()
+            .rev()
+            .filter(|decision| devaddr.is_none() || decision.devaddr == devaddr)
+            .filter(|decision| deveui.is_none() || decision.deveui == deveui)
+            .take(limit)
+            .collect();
+        json!({
+            "capacity": self.capacity,
+            "decisions": decisions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::Packet;
+    use link_packet::{Antenna, TraceId};
+    use semtech_udp::MacAddress;
+
+    fn uplink(devaddr: u32, trace_id: u64) -> LinkPacket {
+        LinkPacket {
+            gateway_mac: MacAddress::new(&1u64.to_be_bytes()),
+            trace_id: TraceId::from(trace_id),
+            packet: Packet {
+                routing: Some(RoutingInformation {
+                    data: Some(RoutingData::Devaddr(devaddr)),
+                }),
+                ..Default::default()
+            },
+            payload: Default::default(),
+            radio: None,
+            antenna: Antenna::default(),
+            metadata: Default::default(),
+            seq: 0,
+            router: None,
+            received: None,
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let decisions = Decisions::new(2);
+        for (trace_id, devaddr) in [(1, 0x48000001), (2, 0x48000002), (3, 0x48000001)].iter() {
+            decisions.record(Decision::new(&uplink(*devaddr, *trace_id), Reason::Default));
+        }
+        let result = decisions.query(&Value::Null);
+        let recent = result["decisions"].as_array().expect("decisions");
+        // The oldest decision was dropped, the newest comes first
+        assert_eq!(2, recent.len());
+        assert_eq!("48000001", recent[0]["devaddr"]);
+        assert_eq!("default", recent[0]["reason"]);
+
+        let result = decisions.query(&json!({ "devaddr": "48000002" }));
+        assert_eq!(1, result["decisions"].as_array().expect("decisions").len());
+        let result = decisions.query(&json!({ "limit": 1 }));
+        assert_eq!(1, result["decisions"].as_array().expect("decisions").len());
+    }
+}
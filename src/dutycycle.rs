@@ -0,0 +1,201 @@
+use crate::*;
+use semtech_udp::pull_resp;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use store::Store;
+
+const DAILY_TREE: &str = "dutycycle_daily";
+const DAY_SECS: u64 = 86_400;
+
+/// The ETSI EN 300 220 sub-bands of the 863-870 MHz band that LoRaWAN uses,
+/// as (name, min MHz, max MHz, duty cycle limit in percent).
+const ETSI_BANDS: &[(&str, f64, f64, f64)] = &[
+    ("h1.3", 863.0, 865.0, 0.1),
+    ("h1.4", 865.0, 868.0, 1.0),
+    ("h1.5", 868.0, 868.6, 1.0),
+    ("h1.6", 868.7, 869.2, 0.1),
+    ("h1.7", 869.4, 869.65, 10.0),
+    ("h1.9", 869.7, 870.0, 1.0),
+];
+
+/// The transmit time in one band on one day (starting at `day`, unix
+/// seconds).
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DayBand {
+    pub day: u64,
+    pub band: String,
+    pub transmissions: u64,
+    pub airtime_us: u64,
+}
+
+impl DayBand {
+    /// The share of the day spent transmitting in the band, in percent.
+    pub fn duty_cycle(&self) -> f64 {
+        self.airtime_us as f64 / (DAY_SECS as f64 * 10_000.0)
+    }
+
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.day.to_be_bytes().to_vec();
+        key.extend_from_slice(self.band.as_bytes());
+        key
+    }
+}
+
+/// A downlink sent by a packet forwarder.
+#[derive(Debug, Clone)]
+pub struct Transmit {
+    pub band: String,
+    pub airtime_us: u64,
+}
+
+impl Transmit {
+    /// The band and airtime of a downlink about to be sent. None for
+    /// datarates other than LoRa ones.
+    pub fn from_txpk(txpk: &pull_resp::TxPk) -> Option<Self> {
+        let airtime = region::airtime(&txpk.datr.to_string(), txpk.data.len())?;
+        Some(Self {
+            band: band(txpk.freq),
+            airtime_us: airtime.as_micros() as u64,
+        })
+    }
+}
+
+/// The band a frequency (in MHz) counts towards: its ETSI sub-band in the
+/// 863-870 MHz band and the whole MHz elsewhere, where duty cycle rules are
+/// per band rather than per channel.
+pub fn band(frequency: f64) -> String {
+    ETSI_BANDS
+        .iter()
+        .find(|(_, min, max, _)| frequency >= *min && frequency < *max)
+        .map(|(name, _, _, _)| name.to_string())
+        .unwrap_or_else(|| format!("{} MHz", frequency.floor()))
+}
+
+/// The duty cycle limit of a band in percent, None for bands without one.
+pub fn limit(band: &str) -> Option<f64> {
+    ETSI_BANDS
+        .iter()
+        .find(|(name, _, _, _)| *name == band)
+        .map(|(_, _, _, limit)| *limit)
+}
+
+/// Durable counters of the transmit time per band and per day, kept in the
+/// store so they survive restarts, for operators that have to show duty
+/// cycle compliance.
+#[derive(Debug, Clone)]
+pub struct DutyCycle {
+    store: Store,
+    retention_days: u64,
+}
+
+impl DutyCycle {
+    pub fn new(store: Store, settings: &Settings) -> Self {
+        Self {
+            store,
+            retention_days: settings.stats.dutycycle_retention_days,
+        }
+    }
+
+    /// Counts a sent downlink towards the current day.
+    pub fn record(&self, transmit: &Transmit) -> Result {
+        let mut day_band = DayBand {
+            day: stats::unix_secs() / DAY_SECS * DAY_SECS,
+            band: transmit.band.clone(),
+            ..Default::default()
+        };
+        let key = day_band.key();
+        match self.store.get::<DayBand>(DAILY_TREE, &key)? {
+            Some(stored) => day_band = stored,
+            // The first transmission of a day is a good time to drop the
+            // days past their retention
+            None => self.expire(day_band.day)?,
+        }
+        day_band.transmissions += 1;
+        day_band.airtime_us += transmit.airtime_us;
+        self.store.put(DAILY_TREE, &key, &day_band)
+    }
+
+    /// The counters of a band for the current day.
+    pub fn today(&self, band: &str) -> Result<DayBand> {
+        let day_band = DayBand {
+            day: stats::unix_secs() / DAY_SECS * DAY_SECS,
+            band: band.to_string(),
+            ..Default::default()
+        };
+        Ok(self
+            .store
+            .get::<DayBand>(DAILY_TREE, &day_band.key())?
+            .unwrap_or(day_band))
+    }
+
+    /// The counters of the days since the given time ago, oldest first.
+    pub fn days(&self, since: std::time::Duration) -> Result<Vec<DayBand>> {
+        let from = stats::unix_secs().saturating_sub(since.as_secs()) / DAY_SECS * DAY_SECS;
+        Ok(self
+            .store
+            .values::<DayBand>(DAILY_TREE)?
+            .into_iter()
+            .filter(|day_band| day_band.day >= from)
+            .collect())
+    }
+
+    pub fn report(&self, since: std::time::Duration) -> Result<Value> {
+        let days: Vec<Value> = self
+            .days(since)?
+            .iter()
+            .map(|day_band| {
+                json!({
+                    "day": day_band.day,
+                    "band": day_band.band,
+                    "transmissions": day_band.transmissions,
+                    "airtime_ms": day_band.airtime_us / 1000,
+                    "duty_cycle_percent": day_band.duty_cycle(),
+                })
+            })
+            .collect();
+        Ok(json!({ "days": days }))
+    }
+
+    fn expire(&self, today: u64) -> Result {
+        let oldest = today.saturating_sub(self.retention_days * DAY_SECS);
+        for day_band in self.store.values::<DayBand>(DAILY_TREE)? {
+            if day_band.day < oldest {
+                self.store.remove(DAILY_TREE, &day_band.key())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bands() {
+        assert_eq!("h1.4", band(867.1));
+        assert_eq!("h1.5", band(868.1));
+        assert_eq!("h1.7", band(869.525));
+        assert_eq!("923 MHz", band(923.3));
+        assert_eq!(Some(10.0), limit("h1.7"));
+        assert_eq!(None, limit("923 MHz"));
+
+        let dutycycle = DutyCycle {
+            store: Store::memory(),
+            retention_days: 1,
+        };
+        let transmit = Transmit {
+            band: "h1.5".to_string(),
+            airtime_us: 864_000,
+        };
+        dutycycle.record(&transmit).expect("record");
+        dutycycle.record(&transmit).expect("record");
+        let days = dutycycle
+            .days(std::time::Duration::from_secs(DAY_SECS))
+            .expect("days");
+        assert_eq!(1, days.len());
+        assert_eq!(2, days[0].transmissions);
+        // 1.728 s of a day
+        assert!((days[0].duty_cycle() - 0.002).abs() < 1e-9);
+    }
+}
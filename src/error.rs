@@ -21,6 +21,72 @@ pub enum Error {
     Service(#[from] ServiceError),
     #[error("semtech udp error")]
     Semtech(#[from] semtech_udp::server_runtime::Error),
+    #[error("downlink error")]
+    Downlink(#[from] DownlinkError),
+    #[error("store error")]
+    Store(#[from] StoreError),
+    #[error("verify error")]
+    Verify(#[from] VerifyError),
+    #[error("restart requested: {0}")]
+    Restart(String),
+    #[error("key file error: {0}")]
+    Key(String),
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("store backend {0} not supported by this build")]
+    Unsupported(String),
+    #[error("store schema version {0} is newer than supported")]
+    Version(u32),
+    #[error("corrupt saved state in {0}")]
+    Corrupt(String),
+    #[cfg(feature = "sled")]
+    #[error("sled error")]
+    Sled(#[from] sled::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite error")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Reasons a downlink is rejected before it is sent to a packet forwarder.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DownlinkError {
+    #[error("frequency {0} MHz not in region downlink plan")]
+    Frequency(f32),
+    #[error("datarate {0} not allowed in region")]
+    Datarate(String),
+    #[error("no downlink plan for region {0:?}")]
+    Region(helium_proto::Region),
+    #[error("payload of {0} bytes over the maximum of {1} bytes for {2}")]
+    Payload(usize, usize, String),
+    #[error("airtime of {0} ms over the maximum of {1} ms for {2}")]
+    Airtime(u64, u64, String),
+    #[error("power of {0} dBm over the region maximum of {1} dBm")]
+    Power(u64, u64),
+    #[error("frequency {0} MHz out of the transmit range of the concentrator")]
+    TxFrequency(f32),
+    #[error("datarate {0} not supported by the concentrator")]
+    TxDatarate(String),
+}
+
+/// Reasons a signed uplink envelope fails verification.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    #[error("message is not an uplink packet")]
+    NotAPacket,
+    #[error("invalid hotspot key")]
+    Key,
+    #[error("invalid signature")]
+    Signature,
+    #[error("envelope carries no region")]
+    NoRegion,
+    #[error("unknown region {0}")]
+    UnknownRegion(i32),
+    #[error("frequency {0} MHz not in region {1:?} uplink plan")]
+    Region(f32, helium_proto::Region),
+    #[error("packet is {0} ms old")]
+    Stale(u64),
 }
 
 #[derive(Error, Debug)]
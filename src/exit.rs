@@ -0,0 +1,108 @@
+use crate::*;
+use std::io;
+
+/// Why the gateway process exits. Each reason has its own exit code so a
+/// supervisor can apply a different restart policy to each, like restarting
+/// after an update but not on a broken configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The command completed
+    Completed,
+    /// Shut down by the named signal
+    Signal(&'static str),
+    /// The settings could not be loaded
+    Config,
+    /// The listen address could not be bound
+    Bind,
+    /// The gateway key could not be loaded, saved or used
+    Key,
+    /// An installed update asked for a restart
+    Restart,
+    /// Any other failure
+    Failure,
+}
+
+impl ExitReason {
+    /// The reason for the result of a command, given the signal that shut the
+    /// gateway down if any.
+    pub fn from_result(res: &Result, signal: Option<&'static str>) -> Self {
+        match res {
+            Ok(()) => signal.map_or(Self::Completed, Self::Signal),
+            Err(err) => Self::from_error(err),
+        }
+    }
+
+    pub fn from_error(err: &Error) -> Self {
+        match err {
+            Error::Restart(_) => Self::Restart,
+            Error::Config(_) => Self::Config,
+            Error::Key(_) | Error::CryptoError(_) => Self::Key,
+            err if is_bind_error(err) => Self::Bind,
+            _ => Self::Failure,
+        }
+    }
+
+    /// The process exit code, following the BSD sysexits codes where one
+    /// fits.
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Completed | Self::Signal(_) => 0,
+            Self::Failure => 1,
+            Self::Key => 65,
+            Self::Bind => 69,
+            Self::Restart => 75,
+            Self::Config => 78,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Signal(_) => "signal",
+            Self::Config => "config",
+            Self::Bind => "bind",
+            Self::Key => "key",
+            Self::Restart => "restart",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+fn is_bind_error(err: &Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return matches!(
+                err.kind(),
+                io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons() {
+        let reason = |err: Error| ExitReason::from_error(&err).code();
+        assert_eq!(0, ExitReason::from_result(&Ok(()), Some("SIGTERM")).code());
+        assert_eq!(
+            69,
+            reason(io::Error::new(io::ErrorKind::AddrInUse, "test").into())
+        );
+        assert_eq!(
+            1,
+            reason(io::Error::new(io::ErrorKind::NotFound, "test").into())
+        );
+        assert_eq!(
+            78,
+            reason(config::ConfigError::Message("invalid type".to_string()).into())
+        );
+        assert_eq!(65, reason(Error::Key("test".to_string())));
+        assert_eq!(75, reason(Error::Restart("test".to_string())));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::*;
+use link_packet::LinkPacket;
+use lorawan::{Direction, PHYPayload, PHYPayloadFrame};
+use serde_json::{json, Value};
+use settings::PayloadPrivacy;
+use slog::{debug, info, o, warn, Logger};
+use std::{fs, io::Cursor, path::PathBuf};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::broadcast::{self, error::RecvError},
+};
+
+/// Number of uplinks a feed consumer can fall behind before it skips uplinks.
+pub const FEED_QUEUE_SIZE: usize = 128;
+
+/// Creates the handle the gateway publishes uplinks to and the service that
+/// serves them to local consumers.
+pub fn feed(settings: &Settings) -> (Feed, FeedService) {
+    let feed = &settings.feed;
+    if !feed.enabled {
+        return (
+            Feed {
+                sender: None,
+                privacy: PayloadPrivacy::Keep,
+                labels: Value::Null,
+            },
+            FeedService { socket: None },
+        );
+    }
+    let (sender, _) = broadcast::channel(FEED_QUEUE_SIZE);
+    (
+        Feed {
+            sender: Some(sender.clone()),
+            privacy: settings.privacy.payload,
+            labels: json!(settings.labels),
+        },
+        FeedService {
+            socket: Some((feed.path.clone(), sender)),
+        },
+    )
+}
+
+/// A cheaply cloneable handle to publish decoded uplinks to the feed.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    sender: Option<broadcast::Sender<String>>,
+    privacy: PayloadPrivacy,
+    /// The labels of the gateway, added to each record
+    labels: Value,
+}
+
+impl Feed {
+    pub fn uplink(&self, packet: &LinkPacket, gateway_id: &str) {
+        let sender = match &self.sender {
+            Some(sender) if sender.receiver_count() > 0 => sender,
+            _ => return,
+        };
+        let mut record = json!({
+            "trace_id": packet.trace_id.to_string(),
+            "gateway_mac": packet.gateway_mac.to_string(),
+            "gateway_id": gateway_id,
+            "frequency": packet.packet.frequency,
+            "datarate": packet.packet.datarate,
+            "rssi": packet.packet.signal_strength,
+            "snr": packet.packet.snr,
+            "timestamp": packet.packet.timestamp,
+            "time_ns": packet.metadata.time_ns,
+            "antenna": packet.antenna.antenna,
+            "rf_chain": packet.antenna.rf_chain,
+            "if_chain": packet.antenna.if_chain,
+            "payload": base64::encode(privacy::payload(&packet.payload, self.privacy)),
+            "labels": self.labels,
+        });
+        record["lorawan"] = decode(&packet.payload);
+        // Sending only fails when all consumers went away
+        let _ = sender.send(format!("{}\n", record));
+    }
+}
+
+/// Decodes the LoRaWAN header fields of an uplink. The frame payload stays
+/// encrypted and is only included in raw form in the feed record.
+fn decode(payload: &[u8]) -> Value {
+    let packet = match PHYPayload::read(Direction::Uplink, &mut Cursor::new(payload)) {
+        Ok(packet) => packet,
+        Err(_) => return Value::Null,
+    };
+    let mtype = format!("{:?}", packet.mtype());
+    match &packet.payload {
+        PHYPayloadFrame::JoinRequest(request) => json!({
+            "mtype": mtype,
+            "dev_eui": format!("{:016x}", request.dev_eui),
+            "app_eui": format!("{:016x}", request.app_eui),
+        }),
+        PHYPayloadFrame::MACPayload(mac_payload) => json!({
+            "mtype": mtype,
+            "dev_addr": format!("{:08x}", mac_payload.dev_addr()),
+            "fcnt": mac_payload.fhdr.fcnt,
+            "fport": mac_payload.fport,
+        }),
+        PHYPayloadFrame::JoinAccept(_) => json!({ "mtype": mtype }),
+    }
+}
+
+/// Streams decoded uplinks as line delimited json to every consumer connected
+/// to a unix domain socket. Consumers that do not keep up skip uplinks rather
+/// than hold up the packet path or other consumers.
+#[derive(Debug)]
+pub struct FeedService {
+    socket: Option<(PathBuf, broadcast::Sender<String>)>,
+}
+
+impl FeedService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "feed"));
+        let (path, sender) = match self.socket.take() {
+            Some(socket) => socket,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        // Remove a socket left behind by an earlier run
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        info!(logger, "starting"; "path" => path.display().to_string());
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    let _ = fs::remove_file(&path);
+                    return Ok(())
+                },
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted?;
+                    debug!(logger, "feed consumer connected");
+                    tokio::spawn(serve(stream, sender.subscribe(), logger.clone()));
+                }
+            }
+        }
+    }
+}
+
+async fn serve(mut stream: UnixStream, mut uplinks: broadcast::Receiver<String>, logger: Logger) {
+    loop {
+        match uplinks.recv().await {
+            Ok(line) => {
+                if stream.write_all(line.as_bytes()).await.is_err() {
+                    debug!(logger, "feed consumer disconnected");
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(logger, "slow feed consumer skipped {} uplinks", skipped)
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
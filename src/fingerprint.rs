@@ -0,0 +1,76 @@
+use crate::*;
+use serde::Serialize;
+use std::fs;
+
+/// Where the hardware model is read from, in order. Device tree models cover
+/// most embedded gateways, DMI the x86 ones.
+const MODEL_PATHS: &[&str] = &[
+    "/proc/device-tree/model",
+    "/sys/firmware/devicetree/base/model",
+    "/sys/class/dmi/id/product_name",
+];
+const OS_RELEASE_PATHS: &[&str] = &["/etc/os-release", "/usr/lib/os-release"];
+const KERNEL_PATH: &str = "/proc/sys/kernel/osrelease";
+
+/// A description of the environment the gateway runs in, for support
+/// triage across different hardware. The concentrator is only known once a
+/// packet forwarder reports it, and is logged with the forwarder instead.
+#[derive(Debug, Clone, Serialize)]
+pub struct Fingerprint {
+    pub version: String,
+    pub platform: String,
+    pub arch: &'static str,
+    pub hardware: Option<String>,
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    pub key_type: Option<&'static str>,
+}
+
+impl Fingerprint {
+    pub fn collect(settings: &Settings) -> Self {
+        Self {
+            version: settings::version().to_string(),
+            platform: settings.update.platform.clone(),
+            arch: std::env::consts::ARCH,
+            hardware: MODEL_PATHS.iter().find_map(|path| read_trimmed(path)),
+            os: OS_RELEASE_PATHS.iter().find_map(|path| {
+                fs::read_to_string(path)
+                    .ok()
+                    .and_then(|release| os_name(&release))
+            }),
+            kernel: read_trimmed(KERNEL_PATH),
+            key_type: settings::key_type_name(settings.keypair.public_key()),
+        }
+    }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    // Device tree strings are nul terminated
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Returns the PRETTY_NAME of an os-release file.
+fn os_name(release: &str) -> Option<String> {
+    release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release() {
+        let release = "NAME=\"OpenWrt\"\nPRETTY_NAME=\"OpenWrt 21.02.0\"\nID=openwrt\n";
+        assert_eq!(Some("OpenWrt 21.02.0".to_string()), os_name(release));
+        assert_eq!(None, os_name("ID=openwrt\n"));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::*;
+use helium_proto::Region;
+use settings::{Concentrator, ForwarderConfigSettings};
+use slog::{debug, info, warn, Logger};
+use std::{fs, path::PathBuf};
+use tokio::process;
+
+/// Keeps the channel configuration of a managed packet forwarder in line with
+/// the gateway region.
+///
+/// The semtech udp protocol has no way to push configuration to a forwarder,
+/// so the `SX1301_conf` section for the region, or the `SX130x_conf` section
+/// for an SX1302 concentrator, is written to a file the forwarder reads, and
+/// an optional command is run to restart the forwarder when the file
+/// changed. A failed write is tried again the next time the region is
+/// applied.
+#[derive(Debug)]
+pub struct ForwarderConfig {
+    path: Option<PathBuf>,
+    concentrator: Concentrator,
+    restart: Option<String>,
+    applied: Option<Region>,
+}
+
+impl ForwarderConfig {
+    pub fn new(settings: &ForwarderConfigSettings) -> Self {
+        Self {
+            path: settings.path.clone(),
+            concentrator: settings.concentrator,
+            restart: settings.restart.clone(),
+            applied: None,
+        }
+    }
+
+    /// Writes the channel configuration for the given region unless it was
+    /// already applied.
+    pub fn apply(&mut self, region: Region, logger: &Logger) {
+        let path = match &self.path {
+            Some(path) if self.applied != Some(region) => path,
+            _ => return,
+        };
+        let conf = match region::concentrator_conf(region, self.concentrator) {
+            Some(conf) => conf,
+            None => {
+                warn!(
+                    logger,
+                    "no forwarder channel configuration for {:?}", region
+                );
+                self.applied = Some(region);
+                return;
+            }
+        };
+        let contents = match serde_json::to_string_pretty(&conf) {
+            Ok(contents) => contents,
+            Err(err) => {
+                warn!(
+                    logger,
+                    "failed to encode forwarder configuration: {:?}", err
+                );
+                return;
+            }
+        };
+        if fs::read_to_string(path).map_or(false, |current| current == contents) {
+            debug!(
+                logger,
+                "forwarder configuration up to date for {:?}", region
+            );
+            self.applied = Some(region);
+            return;
+        }
+        if let Err(err) = fs::write(path, contents) {
+            warn!(
+                logger,
+                "failed to write forwarder configuration {}: {:?}",
+                path.display(),
+                err
+            );
+            return;
+        }
+        info!(logger, "wrote forwarder configuration for {:?}", region;
+            "path" => path.display().to_string());
+        self.applied = Some(region);
+        if let Some(restart) = self.restart.clone() {
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                match process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&restart)
+                    .status()
+                    .await
+                {
+                    Ok(status) if status.success() => info!(logger, "restarted packet forwarder"),
+                    Ok(status) => warn!(logger, "forwarder restart failed: {}", status),
+                    Err(err) => warn!(logger, "forwarder restart failed: {:?}", err),
+                }
+            });
+        }
+    }
+}
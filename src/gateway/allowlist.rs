@@ -0,0 +1,175 @@
+use crate::*;
+use config::{Config, File, FileFormat};
+use file_watch::FileWatch;
+use identity::parse_mac;
+use semtech_udp::MacAddress;
+use settings::AllowlistSettings;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// An IP network in CIDR notation, like "192.168.1.0/24". A plain address is
+/// a network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::custom(format!("invalid network: {}", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(*ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(*ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix as u32;
+        shift >= bits || (network >> shift) == (ip >> shift)
+    }
+}
+
+/// Restricts which packet forwarders are served. The semtech udp protocol has
+/// no authentication, so anyone who can reach the listen port can push
+/// packets or claim downlinks for any MAC. When configured, only forwarders
+/// with an allowed MAC and connecting from an allowed network are served.
+///
+/// Entries can also come from a separate allow-list file, which is reloaded
+/// when it changes so forwarders can be added without a restart.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    settings: AllowlistSettings,
+    file: Option<FileWatch>,
+    macs: HashSet<MacAddress>,
+    networks: Vec<Network>,
+    /// The last address seen for each forwarder MAC
+    addrs: HashMap<MacAddress, IpAddr>,
+}
+
+impl Allowlist {
+    pub fn new(settings: &AllowlistSettings) -> Result<Self> {
+        let mut allowlist = Self {
+            settings: settings.clone(),
+            file: settings.path.as_deref().map(FileWatch::new),
+            ..Default::default()
+        };
+        allowlist.load()?;
+        Ok(allowlist)
+    }
+
+    /// Reloads the allow-list file if it changed, returning whether it was
+    /// reloaded. The current entries stay in place if the file is invalid.
+    pub fn refresh(&mut self) -> Result<bool> {
+        if !self.file.as_mut().map_or(false, |file| file.changed()) {
+            return Ok(false);
+        }
+        self.load()?;
+        Ok(true)
+    }
+
+    fn load(&mut self) -> Result {
+        let mut entries = AllowlistSettings {
+            macs: self.settings.macs.clone(),
+            networks: self.settings.networks.clone(),
+            path: None,
+        };
+        if let Some(file) = &self.file {
+            let mut c = Config::new();
+            c.merge(File::from(file.path()).format(FileFormat::Toml))?;
+            let file_entries: AllowlistSettings = c.try_into()?;
+            entries.macs.extend(file_entries.macs);
+            entries.networks.extend(file_entries.networks);
+        }
+        let macs = entries
+            .macs
+            .iter()
+            .map(|mac| parse_mac(mac))
+            .collect::<Result<_>>()?;
+        let networks = entries
+            .networks
+            .iter()
+            .map(|network| network.parse())
+            .collect::<Result<_>>()?;
+        self.macs = macs;
+        self.networks = networks;
+        Ok(())
+    }
+
+    /// Records the address a forwarder connects from.
+    pub fn update_addr(&mut self, mac: MacAddress, addr: &SocketAddr) {
+        if !self.networks.is_empty() {
+            self.addrs.insert(mac, addr.ip());
+        }
+    }
+
+    /// Whether the given forwarder is allowed. With a network restriction in
+    /// place forwarders are rejected until their address is known.
+    pub fn allows(&self, mac: &MacAddress) -> bool {
+        let mac_allowed = self.macs.is_empty() || self.macs.contains(mac);
+        let network_allowed = self.networks.is_empty()
+            || self.addrs.get(mac).map_or(false, |ip| {
+                self.networks.iter().any(|network| network.contains(ip))
+            });
+        mac_allowed && network_allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks() {
+        let network: Network = "192.168.1.0/24".parse().expect("network");
+        assert!(network.contains(&"192.168.1.20".parse().unwrap()));
+        assert!(!network.contains(&"192.168.2.20".parse().unwrap()));
+        assert!(!network.contains(&"::1".parse().unwrap()));
+        let any: Network = "::/0".parse().expect("network");
+        assert!(any.contains(&"fe80::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn allows() {
+        let mut allowlist = Allowlist::new(&AllowlistSettings {
+            macs: vec!["aa555a0000000001".to_string()],
+            networks: vec!["10.0.0.0/8".to_string()],
+            path: None,
+        })
+        .expect("allowlist");
+        let mac = MacAddress::new(&[0xaa, 0x55, 0x5a, 0, 0, 0, 0, 1]);
+        let other = MacAddress::new(&[0xaa, 0x55, 0x5a, 0, 0, 0, 0, 2]);
+        assert!(!allowlist.allows(&mac));
+        allowlist.update_addr(mac, &"10.1.2.3:1700".parse().unwrap());
+        allowlist.update_addr(other, &"10.1.2.4:1700".parse().unwrap());
+        assert!(allowlist.allows(&mac));
+        assert!(!allowlist.allows(&other));
+        allowlist.update_addr(mac, &"192.168.1.2:1700".parse().unwrap());
+        assert!(!allowlist.allows(&mac));
+    }
+}
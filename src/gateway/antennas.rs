@@ -0,0 +1,181 @@
+use crate::*;
+use gateway::identity;
+use link_packet::Antenna;
+use semtech_udp::{pull_resp, MacAddress};
+use settings::AntennaSettings;
+use std::collections::HashMap;
+
+/// Names for the antennas of multi-antenna concentrators, used in logs,
+/// traces and counters in place of the antenna index, and the transmit power
+/// limits of forwarders with high gain antennas.
+#[derive(Debug, Default)]
+pub struct Antennas {
+    names: HashMap<u64, String>,
+    /// The radio chain downlinks are transmitted on, unless overridden for
+    /// the forwarder
+    tx_rf_chain: u64,
+    tx_rf_chains: PerForwarder,
+    /// Maximum transmit power in dBm
+    max_power: PerForwarder,
+}
+
+/// A value set by forwarder MAC or by gateway id.
+#[derive(Debug, Default)]
+struct PerForwarder {
+    macs: HashMap<MacAddress, u64>,
+    ids: HashMap<String, u64>,
+}
+
+impl PerForwarder {
+    fn new(values: &HashMap<String, u64>) -> Self {
+        let mut per_forwarder = Self::default();
+        for (forwarder, value) in values {
+            match identity::parse_mac(forwarder) {
+                Ok(mac) => per_forwarder.macs.insert(mac, *value),
+                Err(_) => per_forwarder.ids.insert(forwarder.clone(), *value),
+            };
+        }
+        per_forwarder
+    }
+
+    /// The value of a forwarder, by MAC or else by gateway id.
+    fn get(&self, mac: &MacAddress, gateway_id: &str) -> Option<u64> {
+        self.macs
+            .get(mac)
+            .or_else(|| self.ids.get(gateway_id))
+            .copied()
+    }
+}
+
+impl Antennas {
+    pub fn new(settings: &AntennaSettings) -> Result<Self> {
+        let names = settings
+            .names
+            .iter()
+            .map(|(index, name)| {
+                index
+                    .parse()
+                    .map(|index| (index, name.clone()))
+                    .map_err(|_| Error::custom(format!("invalid antenna index: {}", index)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            names,
+            tx_rf_chain: settings.tx_rf_chain,
+            tx_rf_chains: PerForwarder::new(&settings.tx_rf_chains),
+            max_power: PerForwarder::new(&settings.max_power),
+        })
+    }
+
+    /// The radio chain downlinks to a forwarder are transmitted on, by MAC
+    /// or else by gateway id.
+    pub fn tx_rf_chain(&self, mac: &MacAddress, gateway_id: &str) -> u64 {
+        self.tx_rf_chains
+            .get(mac, gateway_id)
+            .unwrap_or(self.tx_rf_chain)
+    }
+
+    /// Caps the transmit power of a downlink to the limit of its forwarder,
+    /// by MAC or else by gateway id. Returns the requested power if it had to
+    /// be reduced.
+    pub fn cap_power(
+        &self,
+        mac: &MacAddress,
+        gateway_id: &str,
+        txpk: &mut pull_resp::TxPk,
+    ) -> Option<u64> {
+        let max_power = self.max_power(mac, gateway_id)?;
+        if txpk.powe <= max_power {
+            return None;
+        }
+        let requested = txpk.powe;
+        txpk.powe = max_power;
+        Some(requested)
+    }
+
+    /// The transmit power limit of a forwarder, by MAC or else by gateway id.
+    pub fn max_power(&self, mac: &MacAddress, gateway_id: &str) -> Option<u64> {
+        self.max_power.get(mac, gateway_id)
+    }
+
+    /// Returns the name of the antenna a packet was received on, which is the
+    /// antenna index if it is not named.
+    pub fn name(&self, antenna: &Antenna) -> Option<String> {
+        let index = antenna.antenna?;
+        Some(
+            self.names
+                .get(&index)
+                .cloned()
+                .unwrap_or_else(|| index.to_string()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::Packet;
+    use link_packet::{LinkPacket, TraceId};
+
+    #[test]
+    fn cap_power() {
+        let antennas = Antennas::new(&AntennaSettings {
+            names: HashMap::new(),
+            tx_rf_chain: 0,
+            tx_rf_chains: HashMap::new(),
+            max_power: vec![
+                ("00:00:00:00:00:00:00:01".to_string(), 20),
+                ("rooftop".to_string(), 14),
+            ]
+            .into_iter()
+            .collect(),
+        })
+        .expect("antennas");
+        let downlink = LinkPacket {
+            gateway_mac: MacAddress::new(&1u64.to_be_bytes()),
+            trace_id: TraceId::from(1),
+            packet: Packet {
+                datarate: "SF12BW125".to_string(),
+                ..Default::default()
+            },
+            payload: Default::default(),
+            radio: None,
+            antenna: Antenna::default(),
+            metadata: Default::default(),
+            seq: 0,
+            router: None,
+            received: None,
+        };
+        let mut txpk = downlink.to_pull_resp(false).expect("txpk").expect("rx1");
+        let mac = MacAddress::new(&1u64.to_be_bytes());
+        assert_eq!(Some(27), antennas.cap_power(&mac, "other", &mut txpk));
+        assert_eq!(20, txpk.powe);
+        assert_eq!(None, antennas.cap_power(&mac, "other", &mut txpk));
+
+        let other = MacAddress::new(&2u64.to_be_bytes());
+        assert_eq!(Some(20), antennas.cap_power(&other, "rooftop", &mut txpk));
+        assert_eq!(14, txpk.powe);
+        assert_eq!(None, antennas.cap_power(&other, "basement", &mut txpk));
+    }
+
+    #[test]
+    fn tx_rf_chain() {
+        let antennas = Antennas::new(&AntennaSettings {
+            names: HashMap::new(),
+            tx_rf_chain: 0,
+            tx_rf_chains: vec![
+                ("00:00:00:00:00:00:00:01".to_string(), 1),
+                ("rooftop".to_string(), 1),
+            ]
+            .into_iter()
+            .collect(),
+            max_power: HashMap::new(),
+        })
+        .expect("antennas");
+        let mac = MacAddress::new(&1u64.to_be_bytes());
+        let other = MacAddress::new(&2u64.to_be_bytes());
+        assert_eq!(1, antennas.tx_rf_chain(&mac, "other"));
+        assert_eq!(1, antennas.tx_rf_chain(&other, "rooftop"));
+        assert_eq!(0, antennas.tx_rf_chain(&other, "basement"));
+    }
+}
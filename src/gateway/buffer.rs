@@ -0,0 +1,155 @@
+use crate::*;
+use helium_proto::{Message, Packet as LoraPacket};
+use link_packet::{Antenna, LinkPacket, Metadata, Radio, TraceId};
+use semtech_udp::MacAddress;
+use serde::{Deserialize, Serialize};
+use settings::DownlinkBufferSettings;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// A buffered downlink as it is saved to the store.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedDownlink {
+    gateway_mac: String,
+    trace_id: u64,
+    /// The base64 encoded downlink packet protobuf
+    packet: String,
+    /// Milliseconds the downlink had been buffered when it was saved
+    age_ms: u64,
+}
+
+/// Holds downlinks for packet forwarders that are temporarily unreachable so
+/// they can be dispatched when the forwarder opens its PULL_DATA path again.
+/// Downlinks are only held for a short time since they are useless once
+/// their transmit window has passed.
+#[derive(Debug)]
+pub struct DownlinkBuffer {
+    size: usize,
+    max_age: Duration,
+    downlinks: VecDeque<(Instant, LinkPacket)>,
+}
+
+impl DownlinkBuffer {
+    pub fn new(settings: &DownlinkBufferSettings) -> Self {
+        Self {
+            size: settings.size,
+            max_age: Duration::from_millis(settings.max_age_ms),
+            downlinks: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.downlinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.downlinks.is_empty()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// Buffers a downlink. Returns the oldest buffered downlink if it had to
+    /// be dropped to make room.
+    pub fn push(&mut self, downlink: LinkPacket) -> Option<LinkPacket> {
+        let dropped = if self.downlinks.len() >= self.size {
+            self.downlinks.pop_front().map(|(_, downlink)| downlink)
+        } else {
+            None
+        };
+        self.downlinks.push_back((Instant::now(), downlink));
+        dropped
+    }
+
+    /// Removes and returns the buffered downlinks for the given forwarder that
+    /// have not expired.
+    pub fn take(&mut self, mac: &MacAddress) -> Vec<LinkPacket> {
+        let max_age = self.max_age;
+        let (taken, kept): (VecDeque<_>, VecDeque<_>) = self
+            .downlinks
+            .drain(..)
+            .partition(|(_, downlink)| downlink.gateway_mac == *mac);
+        self.downlinks = kept;
+        taken
+            .into_iter()
+            .filter(|(buffered, _)| buffered.elapsed() <= max_age)
+            .map(|(_, downlink)| downlink)
+            .collect()
+    }
+
+    /// The buffered downlinks, oldest first, with the time they have been
+    /// buffered for.
+    pub fn iter(&self) -> impl Iterator<Item = (Duration, &LinkPacket)> {
+        self.downlinks
+            .iter()
+            .map(|(buffered, downlink)| (buffered.elapsed(), downlink))
+    }
+
+    /// The buffered downlinks, for saving.
+    pub fn saved(&self) -> Result<Vec<SavedDownlink>> {
+        self.downlinks
+            .iter()
+            .map(|(buffered, downlink)| {
+                let mut lora = downlink.packet.clone();
+                lora.payload = downlink.payload.to_vec();
+                let mut packet = Vec::with_capacity(lora.encoded_len());
+                lora.encode(&mut packet)?;
+                Ok(SavedDownlink {
+                    gateway_mac: downlink.gateway_mac.to_string(),
+                    trace_id: downlink.trace_id.as_u64(),
+                    packet: base64::encode(&packet),
+                    age_ms: buffered.elapsed().as_millis() as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// Restores saved downlinks that have not expired in the time they spent
+    /// saved. Returns the number of restored downlinks.
+    pub fn restore(&mut self, saved: Vec<SavedDownlink>, saved_for: Duration) -> Result<usize> {
+        let now = Instant::now();
+        let mut restored = 0;
+        for downlink in saved {
+            let age = Duration::from_millis(downlink.age_ms) + saved_for;
+            let buffered = match now.checked_sub(age) {
+                Some(buffered) if age <= self.max_age => buffered,
+                _ => continue,
+            };
+            let mut packet = LoraPacket::decode(base64::decode(&downlink.packet)?.as_slice())?;
+            if self.downlinks.len() >= self.size {
+                break;
+            }
+            self.downlinks.push_back((
+                buffered,
+                LinkPacket {
+                    gateway_mac: gateway::identity::parse_mac(&downlink.gateway_mac)?,
+                    trace_id: TraceId::from(downlink.trace_id),
+                    payload: std::mem::take(&mut packet.payload).into(),
+                    radio: Radio::from_datarate(&packet.datarate),
+                    antenna: Antenna::default(),
+                    metadata: Metadata::from_packet(&packet),
+                    seq: 0,
+                    router: None,
+                    received: None,
+                    packet,
+                },
+            ));
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Drops expired downlinks, returning the dropped ones.
+    pub fn expire(&mut self) -> Vec<LinkPacket> {
+        let max_age = self.max_age;
+        let (expired, kept): (VecDeque<_>, VecDeque<_>) = self
+            .downlinks
+            .drain(..)
+            .partition(|(buffered, _)| buffered.elapsed() > max_age);
+        self.downlinks = kept;
+        expired.into_iter().map(|(_, downlink)| downlink).collect()
+    }
+}
@@ -0,0 +1,400 @@
+use crate::*;
+use error::DownlinkError;
+use semtech_udp::MacAddress;
+use serde::Serialize;
+use serde_json::Value;
+use settings::{Concentrator, HealthSettings};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Health information reported by a packet forwarder in its periodic `stat`
+/// frames. Only the vendor extensions that are commonly seen in the wild are
+/// recognized:
+///
+/// * `temp` - concentrator temperature in degrees Celsius
+/// * `pps` - whether the GPS PPS signal is locked (boolean or 0/1)
+/// * `fwv` - forwarder firmware version
+/// * `hal` - concentrator HAL version, which identifies the concentrator
+///   family (for example sx1301 or sx1302 HALs)
+///
+/// The location is the standard `lati`, `long` and `alti` fields, which
+/// forwarders with a GPS report once they have a fix.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    pub temperature: Option<f64>,
+    pub pps_lock: Option<bool>,
+    pub firmware: Option<String>,
+    pub concentrator: Option<String>,
+    pub rx_received: Option<u64>,
+    pub rx_ok: Option<u64>,
+    pub tx_emitted: Option<u64>,
+    pub location: Option<Location>,
+}
+
+/// A location in degrees, with the altitude in meters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6},{:.6}", self.latitude, self.longitude)
+    }
+}
+
+impl Health {
+    pub fn from_stat(stat: &Value) -> Self {
+        let pps_lock = match stat.get("pps") {
+            Some(Value::Bool(v)) => Some(*v),
+            Some(Value::Number(v)) => v.as_u64().map(|v| v != 0),
+            _ => None,
+        };
+        Self {
+            temperature: stat.get("temp").and_then(Value::as_f64),
+            pps_lock,
+            firmware: stat.get("fwv").and_then(Value::as_str).map(String::from),
+            concentrator: stat.get("hal").and_then(Value::as_str).map(String::from),
+            rx_received: stat.get("rxnb").and_then(Value::as_u64),
+            rx_ok: stat.get("rxok").and_then(Value::as_u64),
+            tx_emitted: stat.get("txnb").and_then(Value::as_u64),
+            location: Self::location(stat),
+        }
+    }
+
+    fn location(stat: &Value) -> Option<Location> {
+        let latitude = stat.get("lati").and_then(Value::as_f64)?;
+        let longitude = stat.get("long").and_then(Value::as_f64)?;
+        // Forwarders without a fix report 0,0
+        if latitude == 0.0 && longitude == 0.0 {
+            return None;
+        }
+        Some(Location {
+            latitude,
+            longitude,
+            altitude: stat.get("alti").and_then(Value::as_f64),
+        })
+    }
+
+    /// The transmit capabilities of the concentrator, when the reported HAL
+    /// names a known concentrator family.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        let hal = self.concentrator.as_deref()?.to_lowercase();
+        let concentrator = if hal.contains("1302") || hal.contains("1303") {
+            Concentrator::Sx1302
+        } else if hal.contains("1301") {
+            Concentrator::Sx1301
+        } else {
+            return None;
+        };
+        Some(Capabilities { concentrator })
+    }
+
+    /// Returns the alarms raised by this health report for the given
+    /// thresholds.
+    pub fn alarms(&self, settings: &HealthSettings) -> Vec<Alarm> {
+        let mut alarms = vec![];
+        if let Some(temperature) = self.temperature {
+            if temperature > settings.max_temperature {
+                alarms.push(Alarm::Temperature(temperature));
+            }
+        }
+        if settings.pps_alarm && self.pps_lock == Some(false) {
+            alarms.push(Alarm::PpsUnlocked);
+        }
+        alarms
+    }
+}
+
+/// What the concentrator of a packet forwarder can transmit, by the
+/// concentrator family of its HAL. Downlinks it can't transmit are rejected
+/// before they are sent instead of failing in the forwarder's tx_ack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub concentrator: Concentrator,
+}
+
+impl Capabilities {
+    /// The highest power in dBm of the tx_lut of the reference designs of
+    /// both concentrator families. Forwarders send a downlink above their
+    /// highest power at that power, so downlink power is capped to it rather
+    /// than rejected.
+    pub const MAX_POWER: u64 = 27;
+
+    /// Checks a downlink frequency (in MHz) against the transmit range of
+    /// the concentrator radios. The SX1301 drives an SX1255 for the sub 500
+    /// MHz bands or an SX1257 for the others, the SX1250 of the SX1302
+    /// covers all of them.
+    pub fn check_frequency(&self, frequency: f32) -> Result<(), DownlinkError> {
+        let khz = (frequency * 1000.0).round() as u32;
+        let ranges: &[(u32, u32)] = match self.concentrator {
+            Concentrator::Sx1301 => &[(400_000, 510_000), (862_000, 1_020_000)],
+            Concentrator::Sx1302 => &[(150_000, 960_000)],
+        };
+        if !ranges.iter().any(|(min, max)| (*min..=*max).contains(&khz)) {
+            return Err(DownlinkError::TxFrequency(frequency));
+        }
+        Ok(())
+    }
+
+    /// Checks a downlink datarate against the spreading factors of the
+    /// concentrator. Only the SX1302 transmits at SF5 and SF6.
+    pub fn check_datarate(&self, datarate: &str) -> Result<(), DownlinkError> {
+        let min_spreading_factor = match self.concentrator {
+            Concentrator::Sx1301 => 7,
+            Concentrator::Sx1302 => 5,
+        };
+        match region::parse_lora_datarate(datarate) {
+            Some((spreading_factor, _)) if spreading_factor < min_spreading_factor => {
+                Err(DownlinkError::TxDatarate(datarate.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alarm {
+    Temperature(f64),
+    PpsUnlocked,
+}
+
+impl fmt::Display for Alarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Temperature(v) => write!(f, "concentrator temperature {:.1}C", v),
+            Self::PpsUnlocked => f.write_str("pps unlocked"),
+        }
+    }
+}
+
+/// A packet forwarder known to the gateway.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    /// The last time a PULL_DATA keepalive was received. Downlinks can only
+    /// reach a forwarder that keeps its PULL_DATA path open.
+    pub last_pull: Instant,
+    pub health: Option<Health>,
+    pub alarms: Vec<Alarm>,
+}
+
+impl Client {
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.health.as_ref().and_then(Health::capabilities)
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    pub fn is_stale(&self, timeout: Duration) -> bool {
+        self.last_pull.elapsed() > timeout
+    }
+}
+
+/// Keeps track of the packet forwarders that have connected to the gateway.
+#[derive(Debug)]
+pub struct ClientRegistry {
+    clients: HashMap<MacAddress, Client>,
+    timeout: Duration,
+}
+
+impl ClientRegistry {
+    /// Creates a registry that considers clients stale when no PULL_DATA was
+    /// received from them within the given timeout.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            clients: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Inserts or updates a client. New and updated clients are reported by
+    /// the udp runtime as a result of a PULL_DATA which also refreshes the
+    /// liveness of the client.
+    pub fn upsert(&mut self, mac: MacAddress, addr: SocketAddr) {
+        let now = Instant::now();
+        let client = self.clients.entry(mac).or_insert_with(|| Client {
+            addr,
+            last_seen: now,
+            last_pull: now,
+            health: None,
+            alarms: vec![],
+        });
+        client.addr = addr;
+        client.last_seen = now;
+        client.last_pull = now;
+    }
+
+    /// Records a PULL_DATA keepalive for a known client.
+    pub fn pull_data(&mut self, mac: &MacAddress) {
+        if let Some(client) = self.clients.get_mut(mac) {
+            let now = Instant::now();
+            client.last_seen = now;
+            client.last_pull = now;
+        }
+    }
+
+    /// Whether the given client is known but has not sent a PULL_DATA within
+    /// the liveness timeout. Unknown clients are not considered stale.
+    pub fn is_stale(&self, mac: &MacAddress) -> bool {
+        self.clients
+            .get(mac)
+            .map_or(false, |client| client.is_stale(self.timeout))
+    }
+
+    /// Removes all stale clients from the registry, returning the ones that
+    /// were removed.
+    pub fn evict_stale(&mut self) -> Vec<(MacAddress, Client)> {
+        let timeout = self.timeout;
+        let stale: Vec<MacAddress> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.is_stale(timeout))
+            .map(|(mac, _)| *mac)
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|mac| self.clients.remove(&mac).map(|client| (mac, client)))
+            .collect()
+    }
+
+    /// Returns the most recently seen client that is not stale.
+    pub fn most_recent(&self) -> Option<(&MacAddress, &Client)> {
+        self.clients
+            .iter()
+            .filter(|(_, client)| !client.is_stale(self.timeout))
+            .max_by_key(|(_, client)| client.last_seen)
+    }
+
+    pub fn remove(&mut self, mac: &MacAddress) -> Option<Client> {
+        self.clients.remove(mac)
+    }
+
+    pub fn get(&self, mac: &MacAddress) -> Option<&Client> {
+        self.clients.get(mac)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MacAddress, &Client)> {
+        self.clients.iter()
+    }
+
+    /// Records a health report for a client. Returns the alarms that were
+    /// newly raised and those that have cleared since the last report.
+    pub fn update_health(
+        &mut self,
+        mac: &MacAddress,
+        health: Health,
+        settings: &HealthSettings,
+    ) -> (Vec<Alarm>, Vec<Alarm>) {
+        let client = match self.clients.get_mut(mac) {
+            Some(client) => client,
+            None => return (vec![], vec![]),
+        };
+        let alarms = health.alarms(settings);
+        let raised = alarms
+            .iter()
+            .filter(|alarm| !has_alarm(&client.alarms, alarm))
+            .cloned()
+            .collect();
+        let cleared = client
+            .alarms
+            .iter()
+            .filter(|alarm| !has_alarm(&alarms, alarm))
+            .cloned()
+            .collect();
+        client.last_seen = Instant::now();
+        client.health = Some(health);
+        client.alarms = alarms;
+        (raised, cleared)
+    }
+}
+
+/// Alarms are compared by kind so a changing temperature reading does not
+/// re-raise an already active alarm.
+fn has_alarm(alarms: &[Alarm], alarm: &Alarm) -> bool {
+    alarms
+        .iter()
+        .any(|a| std::mem::discriminant(a) == std::mem::discriminant(alarm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_stat() {
+        let health = Health::from_stat(&json!({
+            "time": "2021-06-30 12:00:00 GMT",
+            "rxnb": 10,
+            "rxok": 8,
+            "txnb": 2,
+            "temp": 61.5,
+            "pps": 0,
+            "fwv": "2.0.1",
+            "lati": 46.24,
+            "long": 3.2523,
+            "alti": 145,
+        }));
+        assert_eq!(Some(61.5), health.temperature);
+        assert_eq!(Some(false), health.pps_lock);
+        assert_eq!(Some("2.0.1".to_string()), health.firmware);
+        assert_eq!(Some(8), health.rx_ok);
+        assert_eq!(
+            Some(Location {
+                latitude: 46.24,
+                longitude: 3.2523,
+                altitude: Some(145.0),
+            }),
+            health.location
+        );
+    }
+
+    #[test]
+    fn alarms() {
+        let settings = HealthSettings {
+            max_temperature: 60.0,
+            pps_alarm: true,
+        };
+        let health = Health::from_stat(&json!({"temp": 61.5, "pps": true}));
+        assert_eq!(vec![Alarm::Temperature(61.5)], health.alarms(&settings));
+        assert!(Health::from_stat(&json!({})).alarms(&settings).is_empty());
+    }
+
+    #[test]
+    fn capabilities() {
+        let capabilities = |hal| Health::from_stat(&json!({ "hal": hal })).capabilities();
+        assert_eq!(None, capabilities("5.0.1"));
+        let sx1301 = capabilities("SX1301 v5.0.1").unwrap();
+        let sx1302 = capabilities("sx1302_hal 2.1.0").unwrap();
+        assert_eq!(Concentrator::Sx1302, sx1302.concentrator);
+
+        assert!(sx1301.check_frequency(869.525).is_ok());
+        assert!(sx1301.check_frequency(505.3).is_ok());
+        assert_eq!(
+            Err(DownlinkError::TxFrequency(600.0)),
+            sx1301.check_frequency(600.0)
+        );
+        assert!(sx1302.check_frequency(600.0).is_ok());
+        assert_eq!(
+            Err(DownlinkError::TxFrequency(1000.0)),
+            sx1302.check_frequency(1000.0)
+        );
+
+        assert!(sx1301.check_datarate("SF12BW125").is_ok());
+        assert_eq!(
+            Err(DownlinkError::TxDatarate("SF6BW125".to_string())),
+            sx1301.check_datarate("SF6BW125")
+        );
+        assert!(sx1302.check_datarate("SF6BW125").is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::*;
+use semtech_udp::{pull_resp, push_data, MacAddress, StringOrNum};
+use serde_json::Value;
+use settings::ClockSource;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Offset of the json object in a PULL_RESP frame, after the protocol
+/// version, token and identifier.
+const PULL_RESP_HEADER_LEN: usize = 4;
+
+/// A GPS time reference older than this is not used to schedule downlinks,
+/// since the concentrator counter drifts against GPS time.
+pub const MAX_REFERENCE_AGE_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy)]
+struct Reference {
+    tmst: u64,
+    tmms: u64,
+    received: Instant,
+}
+
+/// Relates the concentrator counter of each forwarder to GPS time, using
+/// the last uplink that carried both.
+#[derive(Debug, Default)]
+pub struct GpsClocks {
+    references: HashMap<MacAddress, Reference>,
+}
+
+impl GpsClocks {
+    /// Records the timestamps of an uplink. Forwarders only report the GPS
+    /// time (`tmms`) of uplinks while they have a GPS lock.
+    pub fn observe(&mut self, mac: MacAddress, rxpk: &push_data::RxPk) {
+        let value = match serde_json::to_value(rxpk) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        match (
+            value.get("tmst").and_then(|v| v.as_u64()),
+            value.get("tmms").and_then(|v| v.as_u64()),
+        ) {
+            (Some(tmst), Some(tmms)) => {
+                self.references.insert(
+                    mac,
+                    Reference {
+                        tmst,
+                        tmms,
+                        received: Instant::now(),
+                    },
+                );
+            }
+            _ => {
+                self.references.remove(&mac);
+            }
+        }
+    }
+
+    /// Converts a concentrator timestamp of the given forwarder to GPS time in
+    /// milliseconds, if there is a recent reference.
+    pub fn tmms(&self, mac: &MacAddress, tmst: u64) -> Option<u64> {
+        let reference = self.references.get(mac)?;
+        if reference.received.elapsed() > Duration::from_secs(MAX_REFERENCE_AGE_SECS) {
+            return None;
+        }
+        // The counter is 32 bits and may have wrapped since the reference
+        let offset = tmst.wrapping_sub(reference.tmst) & 0xffff_ffff;
+        let offset = if offset >= 0x8000_0000 {
+            offset as i64 - 0x1_0000_0000
+        } else {
+            offset as i64
+        };
+        Some((reference.tmms as i64 + offset / 1000) as u64)
+    }
+}
+
+/// Applies the scheduling reference to a downlink, falling back to the
+/// concentrator counter when GPS time is preferred but not available.
+/// Returns the reference used.
+///
+/// The udp runtime always sends the concentrator counter as well, which
+/// forwarders schedule by when it is present. The transport takes it out of
+/// downlinks with a GPS time with `gps_timed` before they are sent.
+pub fn schedule(
+    preferred: ClockSource,
+    txpk: pull_resp::TxPk,
+    tmms: Option<u64>,
+) -> (pull_resp::TxPk, ClockSource) {
+    match (preferred, tmms) {
+        (ClockSource::Immediate, _) => (
+            pull_resp::TxPk {
+                imme: true,
+                tmst: StringOrNum::S("immediate".to_string()),
+                tmms: None,
+                ..txpk
+            },
+            ClockSource::Immediate,
+        ),
+        (ClockSource::Tmms, Some(tmms)) if !txpk.imme => (
+            pull_resp::TxPk {
+                tmms: Some(StringOrNum::N(tmms)),
+                ..txpk
+            },
+            ClockSource::Tmms,
+        ),
+        (_, _) if txpk.imme => (txpk, ClockSource::Immediate),
+        (_, _) => (txpk, ClockSource::Tmst),
+    }
+}
+
+/// Drops the concentrator counter from a PULL_RESP frame whose downlink is
+/// scheduled by GPS time, so the forwarder schedules it by the GPS time.
+/// Returns None for any other frame, which is sent as it is.
+pub fn gps_timed(frame: &[u8]) -> Option<Vec<u8>> {
+    match frame {
+        [1..=2, _, _, 0x03, ..] if frame.len() > PULL_RESP_HEADER_LEN => (),
+        _ => return None,
+    }
+    let mut body: Value = serde_json::from_slice(&frame[PULL_RESP_HEADER_LEN..]).ok()?;
+    let txpk = body.get_mut("txpk")?.as_object_mut()?;
+    if txpk.get("imme") == Some(&Value::Bool(true)) || !txpk.contains_key("tmms") {
+        return None;
+    }
+    txpk.remove("tmst")?;
+    let mut timed = frame[..PULL_RESP_HEADER_LEN].to_vec();
+    timed.extend_from_slice(&serde_json::to_vec(&body).ok()?);
+    Some(timed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tmms() {
+        let mac = MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut clocks = GpsClocks::default();
+        clocks.references.insert(
+            mac,
+            Reference {
+                tmst: 0xffff_0000,
+                tmms: 1_000_000,
+                received: Instant::now(),
+            },
+        );
+        // One second after the reference, across the counter wrap
+        let tmst = (0xffff_0000u64 + 1_000_000) & 0xffff_ffff;
+        assert_eq!(Some(1_001_000), clocks.tmms(&mac, tmst));
+        assert_eq!(Some(999_000), clocks.tmms(&mac, 0xffff_0000 - 1_000_000));
+        assert_eq!(None, clocks.tmms(&MacAddress::new(&[0; 8]), tmst));
+    }
+
+    #[test]
+    fn gps_timed_frame() {
+        let mut frame = vec![2, 0x12, 0x34, 0x03];
+        frame.extend_from_slice(
+            br#"{"txpk":{"imme":false,"tmst":1000,"tmms":1001000,"freq":923.3}}"#,
+        );
+        let timed = gps_timed(&frame).expect("timed");
+        assert_eq!(&frame[..4], &timed[..4]);
+        let body: Value = serde_json::from_slice(&timed[4..]).expect("json");
+        assert_eq!(None, body["txpk"].get("tmst"));
+        assert_eq!(Some(&json!(1_001_000)), body["txpk"].get("tmms"));
+        assert_eq!(Some(&json!(923.3)), body["txpk"].get("freq"));
+
+        // Downlinks scheduled by the concentrator counter are left alone
+        let mut frame = vec![2, 0x12, 0x34, 0x03];
+        frame.extend_from_slice(br#"{"txpk":{"imme":false,"tmst":1000,"freq":923.3}}"#);
+        assert!(gps_timed(&frame).is_none());
+        // A PULL_ACK
+        assert!(gps_timed(&[2, 0x12, 0x34, 0x04]).is_none());
+    }
+}
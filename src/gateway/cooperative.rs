@@ -0,0 +1,109 @@
+use crate::*;
+use dedup::key_hash;
+use link_packet::LinkPacket;
+use peers::{Peers, Sighting};
+use settings::DedupKey;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Maximum number of uplinks held at once. Uplinks beyond this are
+/// forwarded without holding them.
+pub const MAX_HELD_UPLINKS: usize = 1024;
+
+/// What to do with an uplink once its hold is over.
+#[derive(Debug)]
+pub enum Release {
+    /// No peer heard it better, so it is forwarded
+    Forward(LinkPacket),
+    /// The peer with the given key heard it better and forwards it
+    Peer(LinkPacket, String),
+}
+
+#[derive(Debug)]
+struct Held {
+    until: Instant,
+    sighting: Sighting,
+    packet: LinkPacket,
+}
+
+/// Shares the uplinks heard by this gateway with the other gateways of the
+/// site, so that an uplink heard by several of them is forwarded upstream
+/// only by the one with the best signal.
+///
+/// Each uplink is reported to the peers right away and held for a short
+/// while to hear their reports. An uplink the peers report late is
+/// forwarded by more than one gateway, which the routers deduplicate.
+#[derive(Debug)]
+pub struct Cooperative {
+    enabled: bool,
+    key: DedupKey,
+    hold: Duration,
+    own_key: String,
+    peers: Peers,
+    held: VecDeque<Held>,
+}
+
+impl Cooperative {
+    pub fn new(settings: &Settings, peers: Peers) -> Self {
+        Self {
+            enabled: settings.peers.enabled && settings.peers.dedup,
+            key: settings.dedup.key,
+            hold: Duration::from_millis(settings.peers.dedup_hold_ms),
+            own_key: settings.keypair.public_key().to_string(),
+            peers,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Compares the signal of held uplinks under the rotated key of this
+    /// gateway, which the peers know it by from its next announcement.
+    pub fn rekey(&mut self, own_key: String) {
+        self.own_key = own_key;
+    }
+
+    /// Reports an uplink to the peers and holds it. Returns the uplink right
+    /// away when cooperative dedup is disabled or too many uplinks are held.
+    pub fn hold(&mut self, packet: LinkPacket) -> Option<LinkPacket> {
+        if !self.enabled || self.held.len() >= MAX_HELD_UPLINKS {
+            return Some(packet);
+        }
+        let sighting = Sighting {
+            hash: key_hash(self.key, &packet.payload),
+            snr_tenths: packet.metadata.snr_tenths,
+            rssi_dbm: packet.metadata.rssi_dbm,
+        };
+        self.peers.seen(sighting);
+        self.held.push_back(Held {
+            until: Instant::now() + self.hold,
+            sighting,
+            packet,
+        });
+        None
+    }
+
+    /// When the oldest held uplink is to be released.
+    pub fn next_release(&self) -> Option<Instant> {
+        self.held.front().map(|held| held.until)
+    }
+
+    /// Releases the uplinks whose hold is over, oldest first.
+    pub fn release(&mut self) -> Vec<Release> {
+        let now = Instant::now();
+        let mut released = vec![];
+        while let Some(held) = self.held.front() {
+            if held.until > now {
+                break;
+            }
+            let held = self.held.pop_front().expect("held uplink");
+            released.push(
+                match self.peers.better_peer(&held.sighting, &self.own_key) {
+                    Some(peer) => Release::Peer(held.packet, peer),
+                    None => Release::Forward(held.packet),
+                },
+            );
+        }
+        released
+    }
+}
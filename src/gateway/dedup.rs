@@ -0,0 +1,243 @@
+use crate::*;
+use settings::{DedupKey, DedupSettings};
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    time::{Duration, Instant},
+};
+use xxhash_c::XXH64;
+
+/// Remembers the payload hashes of recently forwarded uplinks so the same
+/// uplink, heard by more than one packet forwarder of a gateway or resent
+/// after a restart, is routed only once. Hashes are kept per logical gateway
+/// id: a copy heard by another gateway is its own witness and is routed.
+///
+/// Hashes are kept for the restart ttl as well, to be saved across a
+/// restart. Uplinks a forwarder buffered while the gateway restarted arrive
+/// well after the window, so hashes restored from before the restart match
+/// for the restart ttl instead.
+#[derive(Debug)]
+pub struct Dedup {
+    key: DedupKey,
+    window: Duration,
+    restart_ttl: Duration,
+    recent: HashMap<u64, Instant>,
+    restored: HashMap<u64, Instant>,
+    /// Whether hashes were added since the last save
+    changed: bool,
+}
+
+impl Dedup {
+    pub fn new(settings: &DedupSettings) -> Self {
+        Self {
+            key: settings.key,
+            window: Duration::from_millis(settings.window_ms),
+            restart_ttl: Duration::from_secs(settings.restart_ttl_secs),
+            recent: HashMap::new(),
+            restored: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.recent.len() + self.restored.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recent.is_empty() && self.restored.is_empty()
+    }
+
+    /// Records the payload heard by a gateway and returns whether the gateway
+    /// already heard it within the window.
+    pub fn is_duplicate(&mut self, gateway_id: &str, payload: &[u8]) -> bool {
+        if self.window.as_millis() == 0 {
+            return false;
+        }
+        let hash = gateway_hash(gateway_id, key_hash(self.key, payload));
+        let now = Instant::now();
+        self.changed = true;
+        if let Some(seen) = self.restored.remove(&hash) {
+            if now.duration_since(seen) <= self.restart_ttl {
+                self.recent.insert(hash, now);
+                return true;
+            }
+        }
+        match self.recent.insert(hash, now) {
+            Some(seen) => now.duration_since(seen) <= self.window,
+            None => false,
+        }
+    }
+
+    pub fn expire(&mut self) {
+        let (window, restart_ttl) = (self.window, self.restart_ttl);
+        let keep = window.max(restart_ttl);
+        self.recent.retain(|_, seen| seen.elapsed() <= keep);
+        self.restored
+            .retain(|_, seen| seen.elapsed() <= restart_ttl);
+    }
+
+    /// Whether hashes were added since the last save, so saving can be
+    /// skipped while no uplinks arrive.
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Marks the hashes as saved.
+    pub fn mark_saved(&mut self) {
+        self.changed = false;
+    }
+
+    /// The hashes seen within the restart ttl with their age in
+    /// milliseconds, for saving.
+    pub fn saved(&self) -> Vec<(u64, u64)> {
+        self.recent
+            .iter()
+            .chain(self.restored.iter())
+            .filter(|(_, seen)| seen.elapsed() <= self.restart_ttl)
+            .map(|(hash, seen)| (*hash, seen.elapsed().as_millis() as u64))
+            .collect()
+    }
+
+    /// Restores saved hashes, aged by the time they spent saved. Hashes older
+    /// than the restart ttl are dropped.
+    pub fn restore(&mut self, saved: Vec<(u64, u64)>, saved_for: Duration) {
+        let now = Instant::now();
+        for (hash, age_ms) in saved {
+            let age = Duration::from_millis(age_ms) + saved_for;
+            if age > self.restart_ttl {
+                continue;
+            }
+            if let Some(seen) = now.checked_sub(age) {
+                self.restored.insert(hash, seen);
+            }
+        }
+    }
+}
+
+/// Hashes the part of the payload that identifies an uplink.
+pub fn key_hash(key: DedupKey, payload: &[u8]) -> u64 {
+    let mut hasher = XXH64::new(0);
+    match (key, frame_key(payload)) {
+        (DedupKey::Frame, Some((mhdr, devaddr, fcnt, fport))) => {
+            hasher.write(mhdr);
+            hasher.write(devaddr);
+            hasher.write(fcnt);
+            hasher.write(fport);
+        }
+        _ => hasher.write(payload),
+    }
+    hasher.finish()
+}
+
+/// Hashes the uplink hash with the gateway that heard it.
+fn gateway_hash(gateway_id: &str, hash: u64) -> u64 {
+    let mut hasher = XXH64::new(0);
+    hasher.write(gateway_id.as_bytes());
+    hasher.write(&hash.to_be_bytes());
+    hasher.finish()
+}
+
+/// The MHDR, DevAddr, frame counter and FPort of a data uplink. The FPort is
+/// empty for frames without one. Unlike a MIC+DevAddr+FCnt key, the MIC is
+/// left out on purpose, since a retransmission of the uplink with the same
+/// frame counter can carry another one. The MHDR is kept so a confirmed and
+/// an unconfirmed uplink with the same counter are told apart.
+fn frame_key(payload: &[u8]) -> Option<(&[u8], &[u8], &[u8], &[u8])> {
+    use lorawan::MType;
+    // MHDR, DevAddr, FCtrl, FCnt and MIC
+    if payload.len() < 12 {
+        return None;
+    }
+    match MType::from(payload[0] >> 5) {
+        MType::UnconfirmedUp | MType::ConfirmedUp => {
+            // The FPort follows the FOpts, if there is anything before the MIC
+            let mic = payload.len() - 4;
+            let fport = (8 + (payload[5] & 0x0f) as usize).min(mic);
+            Some((
+                &payload[0..1],
+                &payload[1..5],
+                &payload[6..8],
+                &payload[fport..(fport + 1).min(mic)],
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates() {
+        let settings = DedupSettings {
+            window_ms: 2_000,
+            restart_ttl_secs: 60,
+            key: DedupKey::Payload,
+        };
+        let mut dedup = Dedup::new(&settings);
+        assert!(!dedup.changed());
+        assert!(!dedup.is_duplicate("gw1", b"uplink"));
+        assert!(dedup.changed());
+        assert!(dedup.is_duplicate("gw1", b"uplink"));
+        assert!(!dedup.is_duplicate("gw1", b"other"));
+        // The same uplink heard by another gateway
+        assert!(!dedup.is_duplicate("gw2", b"uplink"));
+        dedup.mark_saved();
+        assert!(!dedup.changed());
+
+        // Restored hashes match past the window, up to the restart ttl
+        let mut restored = Dedup::new(&settings);
+        restored.restore(dedup.saved(), Duration::from_secs(30));
+        assert!(restored.is_duplicate("gw1", b"uplink"));
+        let mut expired = Dedup::new(&settings);
+        expired.restore(dedup.saved(), Duration::from_secs(120));
+        assert!(expired.is_empty());
+    }
+
+    #[test]
+    fn keys() {
+        // DevAddr 0x01020304, FCnt 7, FPort 1 and a MIC
+        let uplink = [0x40, 4, 3, 2, 1, 0, 7, 0, 1, 0xaa, 9, 9, 9, 9];
+        // A retransmission with another MIC
+        let mut changed = uplink;
+        changed[13] = 8;
+        assert_eq!(
+            key_hash(DedupKey::Frame, &uplink),
+            key_hash(DedupKey::Frame, &changed)
+        );
+        assert_ne!(
+            key_hash(DedupKey::Payload, &uplink),
+            key_hash(DedupKey::Payload, &changed)
+        );
+        // Another FPort
+        let mut changed = uplink;
+        changed[8] = 2;
+        assert_ne!(
+            key_hash(DedupKey::Frame, &uplink),
+            key_hash(DedupKey::Frame, &changed)
+        );
+        // Another frame counter
+        let mut changed = uplink;
+        changed[6] = 8;
+        assert_ne!(
+            key_hash(DedupKey::Frame, &uplink),
+            key_hash(DedupKey::Frame, &changed)
+        );
+        // A confirmed uplink with the same frame counter
+        let mut changed = uplink;
+        changed[0] = 0x80;
+        assert_ne!(
+            key_hash(DedupKey::Frame, &uplink),
+            key_hash(DedupKey::Frame, &changed)
+        );
+        // The FPort follows the FOpts
+        let with_fopts = [0x40, 4, 3, 2, 1, 1, 7, 0, 3, 1, 0xaa, 9, 9, 9, 9];
+        let mut changed = with_fopts;
+        changed[9] = 2;
+        assert_ne!(
+            key_hash(DedupKey::Frame, &with_fopts),
+            key_hash(DedupKey::Frame, &changed)
+        );
+    }
+}
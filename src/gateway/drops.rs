@@ -0,0 +1,43 @@
+use std::{fs, net::SocketAddr};
+
+/// Reads the kernel receive drop counter of the udp socket bound to the given
+/// address from `/proc/net/udp` (or `udp6`). Drops happen when packets arrive
+/// faster than they are read and the socket receive buffer fills up.
+///
+/// Sockets are matched by port. Returns None when the counter is not
+/// available, for example on systems without procfs.
+pub fn read_drops(addr: &SocketAddr) -> Option<u64> {
+    let table = match addr {
+        SocketAddr::V4(_) => "/proc/net/udp",
+        SocketAddr::V6(_) => "/proc/net/udp6",
+    };
+    let contents = fs::read_to_string(table).ok()?;
+    parse_drops(&contents, addr.port())
+}
+
+fn parse_drops(contents: &str, port: u16) -> Option<u64> {
+    contents.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_port = fields.get(1)?.rsplit(':').next()?;
+        if u16::from_str_radix(local_port, 16).ok()? != port {
+            return None;
+        }
+        fields.last()?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let contents = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  826: 00000000:14E9 00000000:0000 07 00000000:00000000 00:00000000 00000000   105        0 18729 2 0000000000000000 0
+  941: 0100007F:0690 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 20646 2 0000000000000000 42
+";
+        assert_eq!(Some(42), parse_drops(contents, 1680));
+        assert_eq!(Some(0), parse_drops(contents, 5353));
+        assert_eq!(None, parse_drops(contents, 1700));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::*;
+use dutycycle::DayBand;
+use gateway::sessions::Uplink;
+use helium_proto::Region;
+use serde::{Deserialize, Serialize};
+use settings::DownlinkGuardSettings;
+
+/// A downlink to check without sending it, as given to the
+/// `validate_downlink` api method.
+#[derive(Debug, Deserialize)]
+pub struct Candidate {
+    /// The packet forwarder by MAC, the most recently seen one when not set
+    #[serde(default)]
+    pub gateway_mac: Option<String>,
+    /// The frequency in MHz
+    pub frequency: f32,
+    /// The datarate, like SF9BW125
+    pub datarate: String,
+    /// The transmit power in dBm
+    #[serde(default)]
+    pub power: Option<u64>,
+    /// The PHYPayload (base64)
+    #[serde(default)]
+    pub payload: Option<String>,
+    /// The PHYPayload length in bytes, when the payload is not given
+    #[serde(default)]
+    pub size: Option<usize>,
+    /// The concentrator timestamp to send at
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// The DevAddr (hex) of the device, to check the window timing against
+    /// its last uplink
+    #[serde(default)]
+    pub dev_addr: Option<String>,
+}
+
+impl Candidate {
+    /// The PHYPayload length of the candidate.
+    pub fn len(&self) -> std::result::Result<usize, String> {
+        match (&self.payload, self.size) {
+            (Some(payload), _) => base64::decode(payload)
+                .map(|payload| payload.len())
+                .map_err(|err| format!("invalid payload: {}", err)),
+            (None, Some(size)) => Ok(size),
+            (None, None) => Err("either payload or size is required".to_string()),
+        }
+    }
+
+    pub fn dev_addr(&self) -> std::result::Result<Option<u32>, String> {
+        self.dev_addr
+            .as_deref()
+            .map(|dev_addr| {
+                u32::from_str_radix(dev_addr, 16)
+                    .map_err(|_| format!("invalid dev_addr: {}", dev_addr))
+            })
+            .transpose()
+    }
+}
+
+/// The outcome of one check of a candidate downlink.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Whether a candidate downlink would be accepted, with the outcome of each
+/// check.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub accepted: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn check(&mut self, name: &'static str, outcome: std::result::Result<String, String>) {
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.checks.push(Check {
+            name,
+            passed,
+            detail,
+        });
+    }
+
+    pub fn finish(mut self) -> Self {
+        self.accepted = self.checks.iter().all(|check| check.passed);
+        self
+    }
+}
+
+/// Checks the frequency, datarate and size of a candidate against the
+/// downlink plan of the region and the downlink guard.
+pub fn check_region(
+    region: Option<Region>,
+    candidate: &Candidate,
+    len: usize,
+    guard: &DownlinkGuardSettings,
+) -> std::result::Result<String, String> {
+    let region = match region {
+        Some(region) => region,
+        None => return Ok("region not set, not checked".to_string()),
+    };
+    region::validate_downlink(region, candidate.frequency, &candidate.datarate)
+        .and_then(|_| region::check_downlink_size(region, &candidate.datarate, len, guard))
+        .map(|_| format!("{:?}", region))
+        .map_err(|err| err.to_string())
+}
+
+/// Checks that the airtime of a candidate keeps its band within the duty
+/// cycle limit, averaged over the current day.
+pub fn check_duty_cycle(today: &DayBand, airtime_us: u64) -> std::result::Result<String, String> {
+    let limit = match dutycycle::limit(&today.band) {
+        Some(limit) => limit,
+        None => return Ok(format!("no duty cycle limit in {}", today.band)),
+    };
+    let after = DayBand {
+        airtime_us: today.airtime_us + airtime_us,
+        ..today.clone()
+    };
+    let detail = format!(
+        "{:.4}% of {}% in {} today",
+        after.duty_cycle(),
+        limit,
+        today.band
+    );
+    if after.duty_cycle() > limit {
+        return Err(detail);
+    }
+    Ok(detail)
+}
+
+/// Checks that the window of a candidate is still ahead, from the
+/// concentrator timestamp and arrival of the last uplink of the device.
+pub fn check_timing(
+    timestamp: u64,
+    uplink: Option<&Uplink>,
+) -> std::result::Result<String, String> {
+    let uplink = match uplink {
+        Some(uplink) => uplink,
+        None => return Ok("no recent uplink of the device, not checked".to_string()),
+    };
+    // Concentrator timestamps are a 32 bit microsecond counter
+    let deadline_ms = (timestamp.wrapping_sub(uplink.timestamp) & 0xffff_ffff) / 1000;
+    let elapsed_ms = uplink.received.elapsed().as_millis() as u64;
+    if elapsed_ms >= deadline_ms {
+        return Err(format!(
+            "window {} ms after the uplink already passed, {} ms ago",
+            deadline_ms, elapsed_ms
+        ));
+    }
+    Ok(format!("{} ms left", deadline_ms - elapsed_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use link_packet::TraceId;
+    use std::time::Instant;
+
+    #[test]
+    fn checks() {
+        let today = DayBand {
+            band: "h1.5".to_string(),
+            airtime_us: 860_000_000,
+            ..Default::default()
+        };
+        // 1% of a day is 864 s
+        assert!(check_duty_cycle(&today, 1_000_000).is_ok());
+        assert!(check_duty_cycle(&today, 5_000_000).is_err());
+
+        let uplink = Uplink {
+            trace_id: TraceId::random(),
+            fcnt: 1,
+            confirmed: false,
+            timestamp: 0xffff_ff00,
+            received: Instant::now(),
+        };
+        // The window one second after the uplink, across the timestamp wrap
+        assert!(check_timing(1_000_000, Some(&uplink)).is_ok());
+        assert!(check_timing(0xffff_ff00, Some(&uplink)).is_err());
+
+        let mut report = Report::default();
+        report.check("region", Ok("EU868".to_string()));
+        assert!(report.finish().accepted);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::*;
+use semtech_udp::MacAddress;
+use std::collections::HashMap;
+
+/// Maps the MACs of packet forwarders to stable logical gateway ids, so logs,
+/// traces and alarms for a gateway stay together when its concentrator or
+/// network card is replaced and the forwarder MAC changes.
+#[derive(Debug, Default)]
+pub struct Identities {
+    ids: HashMap<MacAddress, String>,
+}
+
+impl Identities {
+    /// Creates the mapping from logical ids to the MACs they cover. MACs are
+    /// hex encoded, optionally separated by ':' or '-'.
+    pub fn new(gateway_ids: &HashMap<String, Vec<String>>) -> Result<Self> {
+        let mut ids = HashMap::new();
+        for (id, macs) in gateway_ids {
+            for mac in macs {
+                if let Some(other) = ids.insert(parse_mac(mac)?, id.clone()) {
+                    return Err(Error::custom(format!(
+                        "mac {} mapped to both {} and {}",
+                        mac, other, id
+                    )));
+                }
+            }
+        }
+        Ok(Self { ids })
+    }
+
+    /// Returns the logical id for the given MAC, which is the MAC itself if
+    /// it is not mapped.
+    pub fn id(&self, mac: &MacAddress) -> String {
+        self.ids
+            .get(mac)
+            .cloned()
+            .unwrap_or_else(|| mac.to_string())
+    }
+}
+
+pub fn parse_mac(s: &str) -> Result<MacAddress> {
+    let hex: String = s.chars().filter(|c| *c != ':' && *c != '-').collect();
+    if hex.len() != 16 {
+        return Err(Error::custom(format!("invalid mac: {}", s)));
+    }
+    let value =
+        u64::from_str_radix(&hex, 16).map_err(|_| Error::custom(format!("invalid mac: {}", s)))?;
+    Ok(MacAddress::new(&value.to_be_bytes()))
+}
@@ -0,0 +1,1445 @@
+use crate::*;
+use alerts::Alerts;
+use allowlist::Allowlist;
+use antennas::Antennas;
+use api::{Api, Request};
+use buffer::DownlinkBuffer;
+use clients::{Capabilities, Client, ClientRegistry, Health, Location};
+use clock::GpsClocks;
+use cluster::Cluster;
+use cooperative::{Cooperative, Release};
+use decisions::{Decision, Decisions, Reason};
+use dedup::Dedup;
+use dry_run::{Candidate, Report};
+use dutycycle::{DutyCycle, Transmit};
+use error::DownlinkError;
+use feed::Feed;
+use helium_proto::Region;
+use identity::Identities;
+use join_server::JoinServer;
+use link_packet::LinkPacket;
+use lns::{Arbiter, Lns, LnsDownlink, Source};
+use log_limit::LogLimiter;
+use mirror::Mirror;
+use noise::NoiseFloors;
+use peers::Peers;
+use push::Push;
+use quarantine::Quarantine;
+use semtech_udp::{
+    pull_resp, push_data,
+    server_runtime::{Error as SemtechError, Event},
+    tx_ack, MacAddress, StringOrNum,
+};
+use serde_json::{json, Value};
+use sessions::{Delivery, Retry, Sessions, Timing, Uplink};
+use settings::{
+    ClockSource, DownlinkGuardSettings, HealthSettings, LocationPrivacy, MetadataSettings,
+    PayloadPrivacy, TooLatePolicy,
+};
+use signer::Signatures;
+use slog::{debug, error, info, o, warn, Logger};
+use stats::Stats;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use store::Store;
+use supervisor::Liveness;
+use telemetry::Tracer;
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch,
+    },
+    time,
+};
+use transport::PacketTransport;
+use udp::Failure;
+use uplink_queue::UplinkQueue;
+
+pub mod allowlist;
+pub mod antennas;
+pub mod buffer;
+pub mod clients;
+pub mod clock;
+pub mod cooperative;
+pub mod dedup;
+pub mod drops;
+pub mod dry_run;
+pub mod identity;
+pub mod noise;
+pub mod quarantine;
+pub mod salvage;
+pub mod sessions;
+pub mod transport;
+pub mod udp;
+pub mod uplink_queue;
+
+pub const DOWNLINK_TIMEOUT_SECS: u64 = 5;
+pub const UPLINK_TIMEOUT_SECS: u64 = 6;
+/// How often the client registry is checked for stale packet forwarders.
+pub const CLIENT_EVICTION_INTERVAL_SECS: u64 = 10;
+/// How often the kernel receive drop counter of the udp socket is checked.
+pub const DROP_CHECK_INTERVAL_SECS: u64 = 60;
+/// Seconds between applying a settings push and restarting, which leaves
+/// time to answer the api request.
+pub const PUSH_RESTART_DELAY_SECS: u64 = 1;
+
+const STATE_TREE: &str = "gateway";
+const DOWNLINKS_KEY: &[u8] = b"downlinks";
+const DEDUP_KEY: &[u8] = b"dedup";
+
+#[derive(Debug)]
+pub struct Gateway {
+    uplinks: Sender<LinkPacket>,
+    uplink_queue: UplinkQueue,
+    cooperative: Cooperative,
+    downlinks: Receiver<LinkPacket>,
+    transport: Box<dyn PacketTransport>,
+    /// The last read receive drop counter of the transport
+    udp_drops: Option<u64>,
+    tracer: Tracer,
+    mirror: Mirror,
+    feed: Feed,
+    lns: Lns,
+    join_server: JoinServer,
+    api: Api,
+    alerts: Alerts,
+    peers: Peers,
+    cluster: Cluster,
+    signatures: Signatures,
+    /// Swaps of the gateway keypair
+    rotations: watch::Receiver<Arc<Keypair>>,
+    arbiter: Arbiter,
+    liveness: Liveness,
+    clients: ClientRegistry,
+    health: HealthSettings,
+    downlink_buffer: DownlinkBuffer,
+    client_fallback: bool,
+    identities: Identities,
+    log_limiter: LogLimiter,
+    quarantine: Quarantine,
+    allowlist: Allowlist,
+    sessions: Sessions,
+    antennas: Antennas,
+    noise_floors: NoiseFloors,
+    dedup: Dedup,
+    metadata: MetadataSettings,
+    store: Store,
+    stats: Stats,
+    dutycycle: DutyCycle,
+    /// The labels of the gateway, shown with the stats
+    labels: Value,
+    decisions: Decisions,
+    save_interval: Duration,
+    /// Number of uplinks received by antenna name
+    antenna_uplinks: HashMap<String, u64>,
+    snapshots: watch::Receiver<()>,
+    /// Number of downlinks sent to a fallback forwarder
+    fallback_downlinks: u64,
+    too_late_policy: TooLatePolicy,
+    clock: ClockSource,
+    privacy: PayloadPrivacy,
+    location_privacy: (LocationPrivacy, u8),
+    gps_clocks: GpsClocks,
+    /// Number of downlinks that were too late, by receive window
+    too_late_downlinks: HashMap<&'static str, u64>,
+    /// Number of downlinks sent for the private network server
+    lns_downlinks: u64,
+    /// Number of downlinks refused because the other source claimed the
+    /// transmitter first
+    arbitrated_downlinks: u64,
+    /// Number of downlinks sent with less than the requested power
+    capped_downlinks: u64,
+    /// The configured region and downlink guard, for downlink dry runs
+    region: Option<Region>,
+    downlink_guard: Arc<DownlinkGuardSettings>,
+    push: Push,
+    /// When to restart for applied settings
+    restart_at: Option<Instant>,
+}
+
+impl Gateway {
+    pub async fn new(
+        transport: Box<dyn PacketTransport>,
+        uplinks: Sender<LinkPacket>,
+        downlinks: Receiver<LinkPacket>,
+        tracer: Tracer,
+        mirror: Mirror,
+        feed: Feed,
+        lns: Lns,
+        join_server: JoinServer,
+        api: Api,
+        alerts: Alerts,
+        peers: Peers,
+        cluster: Cluster,
+        signatures: Signatures,
+        liveness: Liveness,
+        snapshots: watch::Receiver<()>,
+        store: Store,
+        stats: Stats,
+        decisions: Decisions,
+        settings: &Settings,
+    ) -> Result<Self> {
+        let push = Push::new(settings, signatures.clone())?;
+        let gateway = Gateway {
+            uplinks,
+            uplink_queue: UplinkQueue::new(&settings.uplink_queue),
+            cooperative: Cooperative::new(settings, peers.clone()),
+            downlinks,
+            udp_drops: transport.drops(),
+            transport,
+            tracer,
+            mirror,
+            feed,
+            lns,
+            join_server,
+            api,
+            alerts,
+            peers,
+            cluster,
+            rotations: signatures.rotations(),
+            signatures,
+            arbiter: Arbiter::default(),
+            liveness,
+            clients: ClientRegistry::new(Duration::from_secs(settings.client_timeout)),
+            health: settings.health.clone(),
+            downlink_buffer: DownlinkBuffer::new(&settings.downlink_buffer),
+            client_fallback: settings.client_fallback,
+            identities: Identities::new(&settings.gateway_ids)?,
+            log_limiter: LogLimiter::new(Duration::from_secs(settings.log.summary_interval)),
+            quarantine: Quarantine::new(&settings.quarantine),
+            allowlist: Allowlist::new(&settings.allowlist)?,
+            sessions: Sessions::new(&settings.sessions),
+            antennas: Antennas::new(&settings.antennas)?,
+            noise_floors: NoiseFloors::default(),
+            dedup: Dedup::new(&settings.dedup),
+            metadata: settings.metadata.clone(),
+            dutycycle: DutyCycle::new(store.clone(), settings),
+            labels: json!(settings.labels),
+            store,
+            stats,
+            decisions,
+            save_interval: Duration::from_secs(settings.store.save_interval.max(1)),
+            antenna_uplinks: HashMap::new(),
+            snapshots,
+            fallback_downlinks: 0,
+            too_late_policy: settings.downlink.too_late,
+            clock: settings.downlink.clock,
+            privacy: settings.privacy.payload,
+            location_privacy: (
+                settings.privacy.location,
+                settings.privacy.location_resolution,
+            ),
+            gps_clocks: GpsClocks::default(),
+            too_late_downlinks: HashMap::new(),
+            lns_downlinks: 0,
+            arbitrated_downlinks: 0,
+            capped_downlinks: 0,
+            region: settings.region,
+            downlink_guard: Arc::new(settings.downlink_guard.clone()),
+            push,
+            restart_at: None,
+        };
+        Ok(gateway)
+    }
+
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "gateway"));
+        info!(logger, "starting");
+        self.restore_state(&logger);
+        let mut eviction_timer = time::interval(Duration::from_secs(CLIENT_EVICTION_INTERVAL_SECS));
+        let mut drop_timer = time::interval(Duration::from_secs(DROP_CHECK_INTERVAL_SECS));
+        let mut save_timer = time::interval(self.save_interval);
+        loop {
+            let release_at = self.cooperative.next_release();
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    self.save_state(&logger);
+                    return Ok(())
+                },
+                event = self.transport.recv() => match event {
+                    Ok(event) => {
+                        if let Err(err) = self.handle_udp_event(&logger, event).await {
+                            if self.log_limiter.allow("udp_event_error") {
+                                warn!(logger, "ignoring packet forwarder event error: {:?}", err);
+                            }
+                        }
+                    }
+                    Err(err) => self.handle_udp_failure(&logger, err, &shutdown).await?,
+                },
+                Ok(permit) = self.uplinks.reserve(), if !self.uplink_queue.is_empty() => {
+                    if let Some(uplink) = self.uplink_queue.pop() {
+                        permit.send(uplink);
+                    }
+                },
+                _ = time::sleep_until(release_at.unwrap_or_else(std::time::Instant::now).into()),
+                    if release_at.is_some() => self.release_held(&logger),
+                downlink = self.downlinks.recv() => match downlink {
+                    Some(packet) => self.handle_downlink(&logger, packet).await?,
+                    None => {
+                        warn!(logger, "ignoring closed downlinks channel");
+                        continue;
+                    }
+                },
+                Some(downlink) = self.lns.recv(), if self.lns.is_enabled() =>
+                    self.handle_lns_downlink(&logger, downlink).await,
+                Some(accept) = self.join_server.recv(), if self.join_server.is_enabled() =>
+                    self.handle_downlink(&logger, accept).await?,
+                Some(request) = self.api.recv(), if self.api.is_enabled() =>
+                    self.handle_api(request),
+                _ = time::sleep_until(self.restart_at.unwrap_or_else(Instant::now).into()),
+                    if self.restart_at.is_some() => {
+                    self.save_state(&logger);
+                    return Err(Error::Restart("applied settings push".to_string()))
+                },
+                Ok(()) = self.snapshots.changed() => self.log_snapshot(&logger),
+                Ok(()) = self.rotations.changed() => {
+                    let own_key = self.signatures.public_key().to_string();
+                    info!(logger, "using rotated key {}", own_key);
+                    self.cooperative.rekey(own_key);
+                },
+                _ = drop_timer.tick() => self.check_drops(&logger),
+                _ = save_timer.tick() => self.save_state(&logger),
+                _ = eviction_timer.tick() => {
+                    self.evict_stale_clients(&logger);
+                    self.expire_downlinks(&logger);
+                    self.expire_quarantine(&logger);
+                    self.refresh_allowlist(&logger);
+                    self.sessions.expire();
+                    self.dedup.expire();
+                    self.arbiter.expire();
+                    self.uplink_queue.prune();
+                    self.log_summaries(&logger);
+                    self.check_push(&logger)?;
+                },
+            }
+        }
+    }
+
+    async fn handle_udp_event(&mut self, logger: &Logger, event: Event) -> Result {
+        let source = match &event {
+            Event::PacketReceived(_, mac) => Some(mac),
+            Event::RawPacket(semtech_udp::Up::PushData(packet)) => Some(&packet.gateway_mac),
+            Event::RawPacket(semtech_udp::Up::PullData(packet)) => Some(&packet.gateway_mac),
+            _ => None,
+        };
+        if let Some(mac) = source {
+            if !self.accepts(logger, mac) {
+                return Ok(());
+            }
+        }
+        match event {
+            Event::UnableToParseUdpFrame(buf) => {
+                // The transport passes the readable entries of a repaired
+                // PUSH_DATA on as uplinks of their own
+                if let Some(salvaged) = salvage::push_data(&buf) {
+                    self.stats.invalid_rxpks(salvaged.invalid);
+                    if self.log_limiter.allow("invalid_rxpk") {
+                        warn!(logger, "ignoring {} invalid rxpk entries from {}", salvaged.invalid, salvaged.mac;
+                            "valid" => salvaged.valid);
+                    }
+                    return Ok(());
+                }
+                if let Some(mac) = self.quarantine.record_failure(&buf) {
+                    warn!(logger, "quarantining {} for repeated unparseable frames", mac;
+                        "gateway_id" => self.identities.id(&mac));
+                }
+                if self.log_limiter.allow("udp_parse_error") {
+                    warn!(logger, "ignoring semtech udp parsing error for {:?}", privacy::udp_frame(&buf, self.privacy);
+                        "bytes" => buf.len())
+                }
+            }
+            Event::NewClient((mac, addr)) if !self.authorize(&mac, &addr) => {
+                warn!(
+                    logger,
+                    "rejecting unauthorized packet forwarder: {}, {}", mac, addr
+                );
+            }
+            Event::UpdateClient((mac, addr)) if !self.authorize(&mac, &addr) => {
+                warn!(
+                    logger,
+                    "rejecting unauthorized packet forwarder: {}, {}", mac, addr
+                );
+                self.clients.remove(&mac);
+            }
+            Event::NewClient((mac, addr)) => {
+                info!(logger, "new packet forwarder client: {}, {}", mac, addr;
+                    "gateway_id" => self.identities.id(&mac));
+                self.clients.upsert(mac, addr);
+                self.dispatch_buffered(logger, mac).await?;
+            }
+            Event::UpdateClient((mac, addr)) => {
+                info!(logger, "mac existed, but IP updated: {}, {}", mac, addr;
+                    "gateway_id" => self.identities.id(&mac));
+                self.clients.upsert(mac, addr);
+                self.dispatch_buffered(logger, mac).await?;
+            }
+            Event::PacketReceived(rxpk, gateway_mac) => self.handle_rxpk(logger, rxpk, gateway_mac),
+            Event::NoClientWithMac(_packet, mac) => {
+                info!(
+                    logger,
+                    "ignoring send to client with unknown MAC: {:?}", mac
+                )
+            }
+            Event::RawPacket(raw) => match raw {
+                semtech_udp::Up::PushData(packet) => {
+                    let mac = packet.gateway_mac;
+                    if let Some(stat) = &packet.data.stat {
+                        self.handle_stat(logger, mac, stat);
+                    }
+                    if let Some(rxpks) = packet.data.rxpk {
+                        for rxpk in rxpks {
+                            info!(logger, "uplink {}, from {}", rxpk, mac)
+                        }
+                    }
+                }
+                semtech_udp::Up::PullData(packet) => {
+                    self.clients.pull_data(&packet.gateway_mac);
+                    self.liveness.pull_data();
+                    self.lns.pull_data(packet.gateway_mac);
+                    debug!(logger, "GWMP frame received {:?}", packet);
+                    self.dispatch_buffered(logger, packet.gateway_mac).await?;
+                }
+                _ => debug!(logger, "GWMP frame received {:?}", raw),
+            },
+        };
+        Ok(())
+    }
+
+    /// Whether events from the given forwarder are handled, which they are
+    /// not while it is quarantined or when it is not allowed.
+    fn accepts(&mut self, logger: &Logger, mac: &MacAddress) -> bool {
+        if self.quarantine.is_blocked(mac) {
+            return false;
+        }
+        if !self.allowlist.allows(mac) {
+            if self.log_limiter.allow("unauthorized_forwarder") {
+                warn!(
+                    logger,
+                    "ignoring packet from unauthorized forwarder {}", mac
+                );
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Handles an uplink of a forwarder. Each rxpk of a PUSH_DATA frame is
+    /// handled on its own, so an entry that fails to convert only loses
+    /// itself.
+    fn handle_rxpk(&mut self, logger: &Logger, rxpk: push_data::RxPk, gateway_mac: MacAddress) {
+        self.gps_clocks.observe(gateway_mac, &rxpk);
+        let gateway_id = self.identities.id(&gateway_mac);
+        match LinkPacket::from_push_data(&rxpk, gateway_mac, &self.metadata) {
+            Ok(packet) if packet.is_longfi() => {
+                info!(logger, "ignoring longfi packet";
+                    "trace_id" => packet.trace_id.to_string());
+            }
+            Ok(packet) if self.dedup.is_duplicate(&gateway_id, &packet.payload) => {
+                debug!(logger, "ignoring duplicate uplink from {}", gateway_mac;
+                    "trace_id" => packet.trace_id.to_string());
+                self.decisions
+                    .record(Decision::new(&packet, Reason::Duplicate));
+            }
+            Ok(packet) => {
+                self.stats.uplink();
+                let antenna = self.antennas.name(&packet.antenna);
+                debug!(logger, "received uplink";
+                    "trace_id" => packet.trace_id.to_string(),
+                    "gateway_id" => &gateway_id,
+                    "antenna" => &antenna);
+                let mut span = self.tracer.span("udp receive", packet.trace_id);
+                if let Some(antenna) = &antenna {
+                    *self.antenna_uplinks.entry(antenna.clone()).or_insert(0) += 1;
+                    span.attribute("antenna", antenna);
+                }
+                span.attribute("gateway_mac", gateway_mac);
+                self.link_quality(logger, &packet, &mut span);
+                self.feed.uplink(&packet, &gateway_id);
+                self.track_session(logger, &packet);
+                span.attribute("gateway_id", gateway_id);
+                span.attribute("frequency", packet.packet.frequency);
+                span.attribute("datarate", &packet.packet.datarate);
+                self.mirror.uplink(&packet);
+                if self.lns.uplink(&rxpk, &packet) {
+                    debug!(logger, "uplink forwarded to the network server only";
+                        "trace_id" => packet.trace_id.to_string());
+                    self.decisions.record(Decision::new(&packet, Reason::Lns));
+                } else if self.join_server.join(&packet) {
+                    debug!(logger, "join request sent to the join server";
+                        "trace_id" => packet.trace_id.to_string());
+                    self.decisions
+                        .record(Decision::new(&packet, Reason::JoinServer));
+                } else if let Some(packet) = self.cooperative.hold(packet) {
+                    self.queue_uplink(logger, packet);
+                }
+            }
+            Err(err) => {
+                self.stats.invalid_rxpks(1);
+                if self.log_limiter.allow("push_data_error") {
+                    warn!(logger, "ignoring rxpk from {}: {:?}", gateway_mac, err);
+                }
+            }
+        }
+    }
+
+    fn queue_uplink(&mut self, logger: &Logger, packet: LinkPacket) {
+        let gateway_mac = packet.gateway_mac;
+        if let Some(dropped) = self.uplink_queue.push(packet) {
+            if self.log_limiter.allow("uplink_queue_full") {
+                warn!(logger, "dropping queued uplink, uplinks from {} arrive faster than they are routed", gateway_mac;
+                    "trace_id" => dropped.trace_id.to_string());
+            }
+            self.decisions
+                .record(Decision::new(&dropped, Reason::QueueFull));
+        }
+    }
+
+    /// Queues the uplinks held for cooperative dedup that no peer heard
+    /// better.
+    fn release_held(&mut self, logger: &Logger) {
+        for release in self.cooperative.release() {
+            match release {
+                Release::Forward(packet) => self.queue_uplink(logger, packet),
+                Release::Peer(packet, peer) => {
+                    debug!(logger, "leaving uplink to peer {}", peer;
+                        "trace_id" => packet.trace_id.to_string());
+                    self.decisions.record(Decision::new(&packet, Reason::Peer));
+                }
+            }
+        }
+    }
+
+    /// Handles a failure of the packet transport. Socket failures rebind the
+    /// listen address with a backoff and fatal ones stop the gateway.
+    async fn handle_udp_failure(
+        &mut self,
+        logger: &Logger,
+        err: Error,
+        shutdown: &triggered::Listener,
+    ) -> Result {
+        match udp::classify(&err) {
+            Failure::Socket => {
+                self.transport.recover(&err, shutdown, logger).await?;
+                // Packet forwarders reconnect with their next PULL_DATA
+                self.udp_drops = self.transport.drops();
+                Ok(())
+            }
+            Failure::Fatal => {
+                error!(logger, "packet forwarder socket failed: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    fn evict_stale_clients(&mut self, logger: &Logger) {
+        for (mac, client) in self.clients.evict_stale() {
+            warn!(
+                logger,
+                "evicting stale packet forwarder client: {}, {}", mac, client.addr;
+                "last_pull_secs" => client.last_pull.elapsed().as_secs()
+            );
+        }
+    }
+
+    /// Updates the noise floor of the packet's channel and qualifies the link
+    /// margin of the packet against it.
+    fn link_quality(&mut self, logger: &Logger, packet: &LinkPacket, span: &mut telemetry::Span) {
+        let lora = &packet.packet;
+        let noise_floor = self
+            .noise_floors
+            .observe(lora.frequency, lora.signal_strength, lora.snr);
+        span.attribute("noise_floor", format!("{:.1}", noise_floor));
+        let floor = match packet
+            .radio
+            .and_then(|radio| noise::demod_floor(radio.spreading_factor))
+        {
+            Some(floor) => floor,
+            None => return,
+        };
+        // The margin above the demodulation floor, and the part of it that
+        // is lost to noise above the channel's usual floor
+        let link_margin = lora.snr - floor;
+        let interference = noise::packet_noise(lora.signal_strength, lora.snr) - noise_floor;
+        span.attribute("link_margin", format!("{:.1}", link_margin));
+        debug!(logger, "uplink link quality";
+            "trace_id" => packet.trace_id.to_string(),
+            "link_margin" => link_margin,
+            "noise_floor" => noise_floor,
+            "interference" => interference);
+    }
+
+    fn track_session(&mut self, logger: &Logger, packet: &LinkPacket) {
+        match self.sessions.uplink(packet) {
+            Some((dev_addr, Some(Retry::AfterDelivery(delivery)))) => {
+                warn!(logger, "device {:08x} repeated a confirmed uplink after a {} downlink, the downlink likely never arrived", dev_addr, delivery;
+                    "trace_id" => packet.trace_id.to_string());
+            }
+            Some((dev_addr, Some(Retry::Unanswered))) => {
+                debug!(logger, "device {:08x} repeated an unanswered confirmed uplink", dev_addr;
+                    "trace_id" => packet.trace_id.to_string());
+            }
+            _ => (),
+        }
+    }
+
+    fn refresh_allowlist(&mut self, logger: &Logger) {
+        match self.allowlist.refresh() {
+            Ok(true) => info!(logger, "reloaded forwarder allowlist"),
+            Ok(false) => (),
+            Err(err) => warn!(logger, "keeping current forwarder allowlist: {:?}", err),
+        }
+    }
+
+    /// Records the address of a forwarder and returns whether it is allowed.
+    fn authorize(&mut self, mac: &MacAddress, addr: &SocketAddr) -> bool {
+        self.allowlist.update_addr(*mac, addr);
+        self.allowlist.allows(mac)
+    }
+
+    fn expire_quarantine(&mut self, logger: &Logger) {
+        for mac in self.quarantine.expire() {
+            info!(logger, "releasing {} from quarantine", mac;
+                "gateway_id" => self.identities.id(&mac));
+        }
+    }
+
+    fn log_summaries(&mut self, logger: &Logger) {
+        for (kind, suppressed) in self.log_limiter.summaries() {
+            warn!(
+                logger,
+                "suppressed {} repeated {} warnings", suppressed, kind
+            );
+        }
+    }
+
+    fn check_drops(&mut self, logger: &Logger) {
+        let drops = match self.transport.drops() {
+            Some(drops) => drops,
+            None => return,
+        };
+        if let Some(previous) = self.udp_drops {
+            if drops > previous {
+                warn!(logger, "udp socket dropped {} packets, consider raising net.core.rmem_default", drops - previous;
+                    "total_drops" => drops);
+            }
+        }
+        self.udp_drops = Some(drops);
+    }
+
+    fn log_snapshot(&self, logger: &Logger) {
+        info!(logger, "snapshot";
+            "udp_drops" => self.udp_drops,
+            "clients" => self.clients.iter().count(),
+            "buffered_downlinks" => self.downlink_buffer.len(),
+            "fallback_downlinks" => self.fallback_downlinks,
+            "lns_downlinks" => self.lns_downlinks,
+            "arbitrated_downlinks" => self.arbitrated_downlinks,
+            "capped_downlinks" => self.capped_downlinks,
+            "too_late_rx1" => self.too_late_downlinks.get("rx1").copied().unwrap_or(0),
+            "too_late_rx2" => self.too_late_downlinks.get("rx2").copied().unwrap_or(0),
+            "too_late_lns" => self.too_late_downlinks.get("lns").copied().unwrap_or(0),
+            "quarantined" => self.quarantine.blocked(),
+            "recent_uplinks" => self.dedup.len(),
+            "sessions" => self.sessions.len());
+        for (frequency, channel) in self.noise_floors.iter() {
+            info!(logger, "channel {}", frequency;
+                "noise_floor" => channel.noise_floor,
+                "samples" => channel.samples);
+        }
+        for (mac, queued, dropped) in self.uplink_queue.metrics() {
+            info!(logger, "uplink queue {}", mac;
+                "gateway_id" => self.identities.id(mac),
+                "queued" => queued,
+                "dropped" => dropped);
+        }
+        for (antenna, uplinks) in &self.antenna_uplinks {
+            info!(logger, "antenna {}", antenna; "uplinks" => uplinks);
+        }
+        for (mac, client) in self.clients.iter() {
+            let alarms: Vec<String> = client.alarms.iter().map(|a| a.to_string()).collect();
+            let location = self.forwarder_location(client).map(|l| l.to_string());
+            info!(logger, "client {}", mac;
+                "gateway_id" => self.identities.id(mac),
+                "addr" => client.addr.to_string(),
+                "idle_secs" => client.idle().as_secs(),
+                "last_pull_secs" => client.last_pull.elapsed().as_secs(),
+                "temperature" => client.health.as_ref().and_then(|h| h.temperature),
+                "firmware" => client.health.as_ref().and_then(|h| h.firmware.clone()),
+                "concentrator" => client.health.as_ref().and_then(|h| h.concentrator.clone()),
+                "location" => location,
+                "alarms" => alarms.join(", "));
+        }
+        for (dev_addr, session) in self.sessions.iter() {
+            let uplinks: Vec<String> = session.uplinks.iter().map(|u| u.fcnt.to_string()).collect();
+            let downlinks: Vec<String> = session
+                .downlinks
+                .iter()
+                .map(|d| format!("{} {}", d.fcnt, d.delivery))
+                .collect();
+            info!(logger, "device {:08x}", dev_addr;
+                "uplink_fcnts" => uplinks.join(", "),
+                "downlinks" => downlinks.join(", "));
+        }
+    }
+
+    fn handle_api(&mut self, request: Request) {
+        let result = match request.method.as_str() {
+            "downlinks" => Ok(self.downlink_queue()),
+            "forwarders" => Ok(self.forwarders()),
+            "peers" => Ok(self.peers.to_json()),
+            "cluster" => Ok(self.cluster.to_json()),
+            "alerts" => Ok(self.alerts.active()),
+            "decisions" => Ok(self.decisions.query(&request.params)),
+            "stats" => {
+                let since = request.params["since"].as_str().unwrap_or("24h");
+                let device_limit = request.params["limit"]
+                    .as_u64()
+                    .map_or(stats::DEFAULT_DEVICE_LIMIT, |limit| limit as usize);
+                stats::parse_since(since)
+                    .and_then(|since| self.stats.query(&self.store, since, device_limit))
+                    .map(|mut stats| {
+                        stats["signatures"] = self.signatures.to_json();
+                        stats["labels"] = self.labels.clone();
+                        stats
+                    })
+                    .map_err(|err| format!("{:?}", err))
+            }
+            "dutycycle" => {
+                let since = request.params["since"].as_str().unwrap_or("30d");
+                stats::parse_since(since)
+                    .and_then(|since| self.dutycycle.report(since))
+                    .map_err(|err| format!("{:?}", err))
+            }
+            "validate_downlink" => serde_json::from_value(request.params.clone())
+                .map_err(|err| format!("invalid downlink: {}", err))
+                .and_then(|candidate| self.dry_run(&candidate))
+                .map(|report| json!(report)),
+            "settings_push" => Ok(self.push.to_json()),
+            "settings_dry_run" => request.params["settings"]
+                .as_str()
+                .ok_or_else(|| "settings toml is required".to_string())
+                .map(|overlay| json!(self.push.preview(overlay))),
+            "settings_apply" => self.apply_push(&request.params),
+            method => Err(format!("unknown method: {}", method)),
+        };
+        request.respond(result);
+    }
+
+    /// Applies settings pushed by a fleet manager, restarting shortly after
+    /// when they change any setting.
+    fn apply_push(&mut self, params: &Value) -> std::result::Result<Value, String> {
+        let overlay = params["settings"]
+            .as_str()
+            .ok_or("settings toml is required")?;
+        let forwarder = self.has_forwarder();
+        let (preview, restart) = self
+            .push
+            .apply(overlay, forwarder, params["probation"].as_u64())
+            .map_err(|err| push::describe(&err))?;
+        if restart {
+            self.restart_at = Some(Instant::now() + Duration::from_secs(PUSH_RESTART_DELAY_SECS));
+        }
+        Ok(json!({ "restart": restart, "preview": preview }))
+    }
+
+    /// Ends the probation of pushed settings once it is over, restarting
+    /// with the previous settings when no packet forwarder is connected.
+    fn check_push(&mut self, logger: &Logger) -> Result {
+        match self.push.check(self.has_forwarder()) {
+            Ok(true) => info!(logger, "keeping pushed settings after probation"),
+            Ok(false) => (),
+            Err(err) => {
+                warn!(
+                    logger,
+                    "rolling back pushed settings, no packet forwarder connected"
+                );
+                self.save_state(logger);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn has_forwarder(&self) -> bool {
+        self.clients
+            .iter()
+            .any(|(mac, _)| !self.clients.is_stale(mac))
+    }
+
+    /// Checks whether a downlink would be accepted for sending, without
+    /// sending it or claiming its transmit time.
+    fn dry_run(&self, candidate: &Candidate) -> std::result::Result<Report, String> {
+        let len = candidate.len()?;
+        let dev_addr = candidate.dev_addr()?;
+        let mac = match &candidate.gateway_mac {
+            Some(mac) => identity::parse_mac(mac).map_err(|err| format!("{:?}", err))?,
+            None => {
+                *self
+                    .clients
+                    .most_recent()
+                    .ok_or("no packet forwarder connected")?
+                    .0
+            }
+        };
+        let mut report = Report::default();
+        report.check(
+            "region",
+            dry_run::check_region(self.region, candidate, len, &self.downlink_guard),
+        );
+        let airtime = region::airtime(&candidate.datarate, len);
+        let band = dutycycle::band(candidate.frequency as f64);
+        report.check(
+            "duty_cycle",
+            match airtime {
+                Some(airtime) => self
+                    .dutycycle
+                    .today(&band)
+                    .map_err(|err| format!("{:?}", err))
+                    .and_then(|today| {
+                        dry_run::check_duty_cycle(&today, airtime.as_micros() as u64)
+                    }),
+                None => Err(format!("no airtime for datarate {}", candidate.datarate)),
+            },
+        );
+        report.check(
+            "concentrator",
+            match self.clients.get(&mac).and_then(Client::capabilities) {
+                Some(capabilities) => capabilities
+                    .check_frequency(candidate.frequency)
+                    .and_then(|_| capabilities.check_datarate(&candidate.datarate))
+                    .map(|_| format!("supported by the {:?}", capabilities.concentrator))
+                    .map_err(|err| err.to_string()),
+                None => Ok("capabilities not reported".to_string()),
+            },
+        );
+        let gateway_id = self.identities.id(&mac);
+        report.check(
+            "power",
+            Ok(match (candidate.power, self.max_power(&mac, &gateway_id)) {
+                (Some(power), Some(max_power)) if power > max_power => {
+                    format!("capped from {} to {} dBm", power, max_power)
+                }
+                _ => "not capped".to_string(),
+            }),
+        );
+        if let Some(timestamp) = candidate.timestamp {
+            let uplink = dev_addr
+                .and_then(|dev_addr| self.sessions.get(dev_addr))
+                .and_then(|session| session.uplinks.back());
+            report.check("timing", dry_run::check_timing(timestamp, uplink));
+            report.check(
+                "transmitter",
+                match self.arbiter.holder(&mac, Source::Helium, timestamp) {
+                    Some(holder) => Err(format!("claimed by {}", holder)),
+                    None => Ok("free".to_string()),
+                },
+            );
+        }
+        report.check(
+            "client",
+            if !self.allowlist.allows(&mac) {
+                Err(format!("{} is not allowed", mac))
+            } else if self.clients.get(&mac).is_none() || self.clients.is_stale(&mac) {
+                if self.downlink_buffer.is_enabled() {
+                    Ok(format!(
+                        "{} unreachable, the downlink would be buffered",
+                        mac
+                    ))
+                } else {
+                    Err(format!("{} is unreachable", mac))
+                }
+            } else {
+                Ok(format!("{} connected", mac))
+            },
+        );
+        Ok(report.finish())
+    }
+
+    /// The downlinks waiting for each packet forwarder, with the state of the
+    /// forwarder they are waiting for. Join accepts and confirmed downlinks
+    /// are critical, like their uplinks are when signing.
+    fn downlink_queue(&self) -> Value {
+        let mut queues: HashMap<MacAddress, Vec<Value>> =
+            self.clients.iter().map(|(mac, _)| (*mac, vec![])).collect();
+        for (buffered, downlink) in self.downlink_buffer.iter() {
+            let critical = matches!(
+                downlink
+                    .payload
+                    .first()
+                    .map(|mhdr| lorawan::MType::from(mhdr >> 5)),
+                Some(lorawan::MType::JoinAccept) | Some(lorawan::MType::ConfirmedDown)
+            );
+            queues.entry(downlink.gateway_mac).or_default().push(json!({
+                "trace_id": downlink.trace_id.to_string(),
+                "tmst": downlink.packet.timestamp,
+                "age_ms": buffered.as_millis() as u64,
+                "frequency": downlink.packet.frequency,
+                "datarate": downlink.packet.datarate,
+                "rx2": downlink.packet.rx2_window.is_some(),
+                "priority": if critical { "critical" } else { "normal" },
+            }));
+        }
+        let clients: Vec<Value> = queues
+            .into_iter()
+            .map(|(mac, downlinks)| {
+                let state = match self.clients.get(&mac) {
+                    None => "unknown",
+                    Some(_) if self.clients.is_stale(&mac) => "stale",
+                    Some(_) => "connected",
+                };
+                json!({
+                    "mac": mac.to_string(),
+                    "gateway_id": self.identities.id(&mac),
+                    "state": state,
+                    "count": downlinks.len(),
+                    "next_tmst": downlinks.iter().filter_map(|d| d["tmst"].as_u64()).min(),
+                    "downlinks": downlinks,
+                })
+            })
+            .collect();
+        json!({
+            "buffering": self.downlink_buffer.is_enabled(),
+            "buffered": self.downlink_buffer.len(),
+            "clients": clients,
+        })
+    }
+
+    /// The connected packet forwarders and their last reported health, for
+    /// the local api.
+    fn forwarders(&self) -> Value {
+        let forwarders: Vec<Value> = self
+            .clients
+            .iter()
+            .map(|(mac, client)| {
+                let health = client.health.as_ref();
+                json!({
+                    "mac": mac.to_string(),
+                    "gateway_id": self.identities.id(mac),
+                    "addr": client.addr.to_string(),
+                    "stale": self.clients.is_stale(mac),
+                    "last_seen_secs": client.idle().as_secs(),
+                    "temperature": health.and_then(|h| h.temperature),
+                    "firmware": health.and_then(|h| h.firmware.clone()),
+                    "concentrator": health.and_then(|h| h.concentrator.clone()),
+                    "location": self.forwarder_location(client),
+                    "alarms": client.alarms.iter().map(|a| a.to_string()).collect::<Vec<String>>(),
+                })
+            })
+            .collect();
+        json!({ "forwarders": forwarders })
+    }
+
+    /// The location of a forwarder as it may be reported.
+    fn forwarder_location(&self, client: &Client) -> Option<Location> {
+        let (privacy, resolution) = self.location_privacy;
+        let location = client.health.as_ref()?.location?;
+        privacy::location(location, privacy, resolution)
+    }
+
+    /// Restores the downlink buffer and recent uplinks saved before the last
+    /// shutdown or crash. State that fails its integrity check is discarded.
+    fn restore_state(&mut self, logger: &Logger) {
+        match self.store.load(STATE_TREE, DOWNLINKS_KEY) {
+            Ok(Some(saved)) => match self.downlink_buffer.restore(saved.value, saved.age) {
+                Ok(restored) => info!(logger, "restored {} buffered downlinks", restored),
+                Err(err) => warn!(logger, "discarding saved downlinks: {:?}", err),
+            },
+            Ok(None) => (),
+            Err(err) => warn!(logger, "discarding saved downlinks: {:?}", err),
+        }
+        match self.store.load(STATE_TREE, DEDUP_KEY) {
+            Ok(Some(saved)) => self.dedup.restore(saved.value, saved.age),
+            Ok(None) => (),
+            Err(err) => warn!(logger, "discarding saved recent uplinks: {:?}", err),
+        }
+    }
+
+    /// Saves the downlink buffer, and the recent uplinks when uplinks arrived
+    /// since the last save.
+    fn save_state(&mut self, logger: &Logger) {
+        let saved = self.downlink_buffer.saved().and_then(|downlinks| {
+            self.store.save(STATE_TREE, DOWNLINKS_KEY, &downlinks)?;
+            if self.dedup.changed() {
+                self.store
+                    .save(STATE_TREE, DEDUP_KEY, &self.dedup.saved())?;
+                self.dedup.mark_saved();
+            }
+            Ok(())
+        });
+        if let Err(err) = saved {
+            warn!(logger, "failed to save gateway state: {:?}", err);
+        }
+    }
+
+    /// Dispatches downlinks that were buffered while the given forwarder was
+    /// unreachable.
+    async fn dispatch_buffered(&mut self, logger: &Logger, mac: MacAddress) -> Result {
+        for downlink in self.downlink_buffer.take(&mac) {
+            info!(logger, "dispatching buffered downlink to {}", mac;
+                "trace_id" => downlink.trace_id.to_string());
+            self.handle_downlink(logger, downlink).await?;
+        }
+        Ok(())
+    }
+
+    fn expire_downlinks(&mut self, logger: &Logger) {
+        for downlink in self.downlink_buffer.expire() {
+            warn!(logger, "dropping expired buffered downlink to {}", downlink.gateway_mac;
+                "trace_id" => downlink.trace_id.to_string());
+        }
+    }
+
+    fn handle_stat(&mut self, logger: &Logger, mac: MacAddress, stat: &push_data::Stat) {
+        let health = match serde_json::to_value(stat) {
+            Ok(value) => Health::from_stat(&value),
+            Err(err) => {
+                warn!(logger, "ignoring unreadable stat from {}: {:?}", mac, err);
+                return;
+            }
+        };
+        let logger = logger.new(o!("gateway_id" => self.identities.id(&mac)));
+        debug!(logger, "stat from {}", mac;
+            "temperature" => health.temperature,
+            "pps_lock" => health.pps_lock,
+            "firmware" => health.firmware.clone());
+        let previous = self.clients.get(&mac).and_then(|c| c.health.as_ref());
+        if previous.map(|h| (&h.firmware, &h.concentrator))
+            != Some((&health.firmware, &health.concentrator))
+        {
+            info!(logger, "packet forwarder {}", mac;
+                "firmware" => health.firmware.clone(),
+                "concentrator" => health.concentrator.clone());
+        }
+        let (raised, cleared) = self.clients.update_health(&mac, health, &self.health);
+        for alarm in raised {
+            warn!(logger, "alarm raised for {}: {}", mac, alarm);
+        }
+        for alarm in cleared {
+            info!(logger, "alarm cleared for {}: {}", mac, alarm);
+        }
+    }
+
+    async fn handle_downlink(&mut self, logger: &Logger, mut downlink: LinkPacket) -> Result {
+        let logger = logger.new(o!("trace_id" => downlink.trace_id.to_string()));
+        let mut span = self.tracer.span("downlink dispatch", downlink.trace_id);
+        span.attribute("gateway_mac", downlink.gateway_mac);
+        span.attribute("gateway_id", self.identities.id(&downlink.gateway_mac));
+        if !self.cluster.is_leader() {
+            debug!(logger, "leaving downlink to the cluster leader");
+            span.attribute("result", "follower");
+            return Ok(());
+        }
+        if self.client_fallback && self.clients.get(&downlink.gateway_mac).is_none() {
+            if let Some((mac, _)) = self.clients.most_recent() {
+                self.fallback_downlinks += 1;
+                warn!(logger, "sending downlink for unknown client {} to {}", downlink.gateway_mac, mac;
+                    "fallback_downlinks" => self.fallback_downlinks);
+                span.attribute("fallback_mac", mac);
+                downlink.gateway_mac = *mac;
+            }
+        }
+        let mac = downlink.gateway_mac;
+        if !self.allowlist.allows(&mac) {
+            warn!(logger, "refusing downlink to unauthorized client: {}", mac);
+            span.attribute("result", "unauthorized_client");
+            return Ok(());
+        }
+        let unreachable = self.clients.is_stale(&mac) || self.clients.get(&mac).is_none();
+        if unreachable && self.downlink_buffer.is_enabled() {
+            info!(logger, "buffering downlink for unreachable client: {}", mac);
+            span.attribute("result", "buffered");
+            if let Some(dropped) = self.downlink_buffer.push(downlink) {
+                warn!(logger, "dropping oldest buffered downlink to {}", dropped.gateway_mac;
+                    "dropped_trace_id" => dropped.trace_id.to_string());
+            }
+            return Ok(());
+        }
+        if self.clients.is_stale(&mac) {
+            warn!(logger, "refusing downlink to stale client: {}", mac);
+            span.attribute("result", "stale_client");
+            return Ok(());
+        }
+        if let Err(err) = self.check_capabilities(&logger, &mut downlink) {
+            warn!(logger, "rejecting downlink {} can't transmit: {}", mac, err);
+            span.attribute("result", "unsupported_downlink");
+            let delivery = Delivery::Failed(err.to_string());
+            span.attribute("delivery", delivery.to_string());
+            self.stats.routed_downlink(
+                sessions::downlink_dev_addr(&downlink.payload),
+                downlink.router.as_deref(),
+                false,
+            );
+            self.sessions.downlink(&downlink, delivery);
+            return Ok(());
+        }
+        if let Some(holder) = self
+            .arbiter
+            .claim(mac, Source::Helium, downlink.packet.timestamp)
+        {
+            self.arbitrated_downlinks += 1;
+            warn!(
+                logger,
+                "refusing downlink to {}, the transmitter is claimed by {}", mac, holder
+            );
+            span.attribute("result", "arbitrated");
+            return Ok(());
+        }
+        self.mirror.downlink(&downlink);
+
+        let txpk = match downlink.to_pull_resp(false)? {
+            Some(txpk) => txpk,
+            None => return Ok(()),
+        };
+        let (mut txpk, clock) = self.schedule(
+            &mac,
+            pull_resp::TxPk {
+                rfch: self.tx_rf_chain(&mac),
+                ..txpk
+            },
+        );
+        if let Some(requested) = self.cap_power(&logger, &mac, &mut txpk) {
+            span.attribute("power_requested", requested);
+        }
+        span.attribute("power", txpk.powe);
+        info!(
+            logger,
+            "rx1 downlink {} via {}",
+            txpk,
+            mac;
+            "clock" => clock.to_string()
+        );
+        span.attribute("clock", clock.to_string());
+        // The windows the forwarder reported the downlink too late for, with
+        // the concentrator timestamp of the window
+        let mut too_late = vec![];
+        let transmit = Transmit::from_txpk(&txpk);
+        let rx1 = self
+            .transport
+            .dispatch(mac, txpk, Some(Duration::from_secs(DOWNLINK_TIMEOUT_SECS)))
+            .await;
+        if rx1.is_ok() {
+            self.record_transmit(&logger, transmit);
+        }
+        if let Err(SemtechError::Ack(tx_ack::Error::TooLate)) = &rx1 {
+            too_late.push(("rx1", downlink.packet.timestamp));
+        }
+        let delivery = match rx1 {
+            // On a too early or too late error retry on the rx2 slot if available.
+            Err(SemtechError::Ack(tx_ack::Error::TooEarly))
+            | Err(SemtechError::Ack(tx_ack::Error::TooLate)) => {
+                let rx2_holder =
+                    downlink.packet.rx2_window.as_ref().and_then(|window| {
+                        self.arbiter.claim(mac, Source::Helium, window.timestamp)
+                    });
+                if let Some(holder) = rx2_holder {
+                    self.arbitrated_downlinks += 1;
+                    Delivery::Failed(format!("rx2 claimed by {}", holder))
+                } else if let Some(txpk) = downlink.to_pull_resp(true)? {
+                    let (mut txpk, clock) = self.schedule(
+                        &mac,
+                        pull_resp::TxPk {
+                            rfch: self.tx_rf_chain(&mac),
+                            ..txpk
+                        },
+                    );
+                    self.cap_power(&logger, &mac, &mut txpk);
+                    info!(
+                        logger,
+                        "rx2 downlink {} via {}",
+                        txpk,
+                        mac;
+                        "clock" => clock.to_string()
+                    );
+                    span.attribute("rx2_clock", clock.to_string());
+                    let transmit = Transmit::from_txpk(&txpk);
+                    let rx2 = self
+                        .transport
+                        .dispatch(mac, txpk, Some(Duration::from_secs(DOWNLINK_TIMEOUT_SECS)))
+                        .await;
+                    if rx2.is_ok() {
+                        self.record_transmit(&logger, transmit);
+                    }
+                    if let (Err(SemtechError::Ack(tx_ack::Error::TooLate)), Some(window)) =
+                        (&rx2, &downlink.packet.rx2_window)
+                    {
+                        too_late.push(("rx2", window.timestamp));
+                    }
+                    match rx2 {
+                        Err(err) => {
+                            warn!(logger, "ignoring rx2 downlink error: {:?}", err);
+                            Delivery::Failed(format!("rx2 {:?}", err))
+                        }
+                        Ok(()) => Delivery::Rx2,
+                    }
+                } else {
+                    Delivery::Failed("rx1 missed without rx2 window".to_string())
+                }
+            }
+            Err(err) => {
+                warn!(logger, "ignoring rx1 downlink error: {:?}", err);
+                Delivery::Failed(format!("rx1 {:?}", err))
+            }
+            Ok(()) => Delivery::Rx1,
+        };
+        span.attribute("delivery", delivery.to_string());
+        self.stats.routed_downlink(
+            sessions::downlink_dev_addr(&downlink.payload),
+            downlink.router.as_deref(),
+            matches!(delivery, Delivery::Rx1 | Delivery::Rx2),
+        );
+        let uplink = self.sessions.downlink(&downlink, delivery.clone());
+        if let Some(uplink) = &uplink {
+            debug!(logger, "downlink {} for uplink fcnt {}", delivery, uplink.fcnt;
+                "uplink_age_ms" => uplink.received.elapsed().as_millis() as u64);
+        }
+        for (window, timestamp) in too_late {
+            self.too_late(logger, Some(&mut span), window, timestamp, uplink.as_ref());
+        }
+        Ok(())
+    }
+
+    /// Checks a downlink against the capabilities the forwarder's
+    /// concentrator reported. Like the region checks of the router, an
+    /// unsupported rx2 window is removed while an unsupported rx1 window
+    /// rejects the downlink.
+    fn check_capabilities(
+        &self,
+        logger: &Logger,
+        downlink: &mut LinkPacket,
+    ) -> std::result::Result<(), DownlinkError> {
+        let capabilities = match self
+            .clients
+            .get(&downlink.gateway_mac)
+            .and_then(Client::capabilities)
+        {
+            Some(capabilities) => capabilities,
+            None => return Ok(()),
+        };
+        let packet = &mut downlink.packet;
+        capabilities.check_frequency(packet.frequency)?;
+        capabilities.check_datarate(&packet.datarate)?;
+        if let Some(rx2) = &packet.rx2_window {
+            if let Err(err) = capabilities
+                .check_frequency(rx2.frequency)
+                .and_then(|_| capabilities.check_datarate(&rx2.datarate))
+            {
+                warn!(logger, "dropping unsupported rx2 window: {}", err);
+                packet.rx2_window = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the airtime of a sent downlink for the duty cycle report.
+    fn record_transmit(&self, logger: &Logger, transmit: Option<Transmit>) {
+        if let Some(transmit) = transmit {
+            if let Err(err) = self.dutycycle.record(&transmit) {
+                warn!(logger, "failed to store transmit time: {:?}", err);
+            }
+        }
+    }
+
+    /// The transmit power limit of a forwarder, the lowest of its antenna
+    /// limit, the maximum EIRP of the region and the maximum power of its
+    /// concentrator.
+    fn max_power(&self, mac: &MacAddress, gateway_id: &str) -> Option<u64> {
+        let antenna = self.antennas.max_power(mac, gateway_id);
+        let plan = self.region.and_then(region::max_power);
+        let concentrator = self.concentrator_max_power(mac);
+        [antenna, plan, concentrator]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    fn concentrator_max_power(&self, mac: &MacAddress) -> Option<u64> {
+        self.clients
+            .get(mac)
+            .and_then(Client::capabilities)
+            .map(|_| Capabilities::MAX_POWER)
+    }
+
+    fn tx_rf_chain(&self, mac: &MacAddress) -> u64 {
+        self.antennas.tx_rf_chain(mac, &self.identities.id(mac))
+    }
+
+    /// Caps the transmit power of a downlink to the limit of its forwarder,
+    /// the maximum EIRP of the region and the maximum power of the
+    /// concentrator, logging the requested power when it had to be reduced.
+    fn cap_power(
+        &mut self,
+        logger: &Logger,
+        mac: &MacAddress,
+        txpk: &mut pull_resp::TxPk,
+    ) -> Option<u64> {
+        let gateway_id = self.identities.id(mac);
+        let requested = txpk.powe;
+        self.antennas.cap_power(mac, &gateway_id, txpk);
+        if let Some(max_power) = self.region.and_then(region::max_power) {
+            txpk.powe = txpk.powe.min(max_power);
+        }
+        if let Some(max_power) = self.concentrator_max_power(mac) {
+            txpk.powe = txpk.powe.min(max_power);
+        }
+        if txpk.powe == requested {
+            return None;
+        }
+        self.capped_downlinks += 1;
+        info!(logger, "capping downlink power for {} to {} dBm", mac, txpk.powe;
+            "gateway_id" => gateway_id,
+            "power_requested" => requested);
+        Some(requested)
+    }
+
+    /// Schedules a downlink by the configured clock source. GPS time is
+    /// only used while the forwarder reports a GPS lock and recently sent an
+    /// uplink with a GPS time.
+    fn schedule(&self, mac: &MacAddress, txpk: pull_resp::TxPk) -> (pull_resp::TxPk, ClockSource) {
+        let gps_locked = self
+            .clients
+            .get(mac)
+            .and_then(|client| client.health.as_ref())
+            .and_then(|health| health.pps_lock)
+            != Some(false);
+        let tmms = match &txpk.tmst {
+            StringOrNum::N(tmst) if self.clock == ClockSource::Tmms && gps_locked => {
+                self.gps_clocks.tmms(mac, *tmst)
+            }
+            _ => None,
+        };
+        clock::schedule(self.clock, txpk, tmms)
+    }
+
+    /// Sends a downlink requested by the private network server and reports
+    /// the forwarder's tx_ack back to it. The network server picks the
+    /// window and radio parameters itself, so there is no rx2 fallback here.
+    async fn handle_lns_downlink(&mut self, logger: &Logger, downlink: LnsDownlink) {
+        let LnsDownlink {
+            gateway_mac: mac,
+            token,
+            mut txpk,
+        } = downlink;
+        if !self.allowlist.allows(&mac) || self.clients.get(&mac).is_none() {
+            warn!(
+                logger,
+                "refusing network server downlink to unknown client: {}", mac
+            );
+            return;
+        }
+        if !self.cluster.is_leader() {
+            debug!(
+                logger,
+                "leaving network server downlink to the cluster leader"
+            );
+            return;
+        }
+        if let Some(capabilities) = self.clients.get(&mac).and_then(Client::capabilities) {
+            // Answered like the forwarder would, without sending it there
+            if let Err(err) = capabilities.check_frequency(txpk.freq as f32) {
+                warn!(
+                    logger,
+                    "refusing network server downlink to {}: {}", mac, err
+                );
+                self.lns.tx_ack(mac, token, Some("TX_FREQ".to_string()));
+                return;
+            }
+        }
+        let tmst = lns::txpk_timestamp(&txpk);
+        if let Some(tmst) = tmst {
+            if let Some(holder) = self.arbiter.claim(mac, Source::Lns, tmst) {
+                self.arbitrated_downlinks += 1;
+                warn!(
+                    logger,
+                    "refusing network server downlink to {}, the transmitter is claimed by {}",
+                    mac,
+                    holder
+                );
+                self.lns
+                    .tx_ack(mac, token, Some("COLLISION_PACKET".to_string()));
+                return;
+            }
+        }
+        let capped = self.cap_power(logger, &mac, &mut txpk).map(|_| txpk.powe);
+        let uplink = self.sessions.last_uplink(&txpk.data);
+        info!(logger, "network server downlink {} via {}", txpk, mac);
+        let transmit = Transmit::from_txpk(&txpk);
+        match self
+            .transport
+            .dispatch(mac, txpk, Some(Duration::from_secs(DOWNLINK_TIMEOUT_SECS)))
+            .await
+        {
+            Ok(()) => {
+                self.lns_downlinks += 1;
+                self.stats.downlink(true);
+                self.record_transmit(logger, transmit);
+                match capped {
+                    Some(power) => self.lns.tx_ack_power(mac, token, power),
+                    None => self.lns.tx_ack(mac, token, None),
+                }
+            }
+            Err(SemtechError::Ack(tx_ack::Error::TooLate)) => {
+                self.stats.downlink(false);
+                // Without a timestamp the downlink was sent immediately
+                let timing = tmst.and_then(|tmst| {
+                    let mut span = uplink
+                        .as_ref()
+                        .map(|uplink| self.tracer.span("lns downlink", uplink.trace_id));
+                    self.too_late(logger, span.as_mut(), "lns", tmst, uplink.as_ref())
+                });
+                self.lns.tx_ack_too_late(mac, token, timing);
+            }
+            Err(SemtechError::Ack(err)) => {
+                self.stats.downlink(false);
+                warn!(
+                    logger,
+                    "network server downlink to {} failed: {:?}", mac, err
+                );
+                self.lns.tx_ack(mac, token, Some(lns::ack_error_name(&err)));
+            }
+            Err(err) => warn!(logger, "ignoring network server downlink error: {:?}", err),
+        }
+    }
+
+    /// Counts a downlink the forwarder received too late for a receive
+    /// window and, unless configured to drop these silently, reports it with
+    /// the timing that made it miss. The timing is only known when the
+    /// uplink of the exchange is still tracked in the sessions, and is
+    /// returned for the network server's tx_ack.
+    ///
+    /// The router protocol has no way to report failed downlinks, so for
+    /// Helium downlinks the report goes to the log and the exported trace.
+    fn too_late(
+        &mut self,
+        logger: &Logger,
+        span: Option<&mut telemetry::Span>,
+        window: &'static str,
+        timestamp: u64,
+        uplink: Option<&Uplink>,
+    ) -> Option<Timing> {
+        *self.too_late_downlinks.entry(window).or_insert(0) += 1;
+        if self.too_late_policy == TooLatePolicy::Drop {
+            debug!(logger, "{} downlink too late", window);
+            return None;
+        }
+        let timing = uplink.map(|uplink| uplink.timing(timestamp));
+        if let Some(span) = span {
+            span.attribute("too_late", window);
+            if let Some(timing) = timing {
+                span.attribute("deadline_ms", timing.deadline_ms);
+                span.attribute("elapsed_ms", timing.elapsed_ms);
+                span.attribute("budget_ms", timing.budget_ms);
+            }
+        }
+        warn!(logger, "{} downlink too late", window;
+            "deadline_ms" => timing.map(|t| t.deadline_ms),
+            "elapsed_ms" => timing.map(|t| t.elapsed_ms),
+            "budget_ms" => timing.map(|t| t.budget_ms));
+        timing
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+/// Weight of a new sample in the noise floor moving average.
+pub const NOISE_FLOOR_WEIGHT: f32 = 0.05;
+
+/// The lowest SNR (in dB) at which a LoRa packet can still be demodulated,
+/// by spreading factor.
+pub fn demod_floor(spreading_factor: u32) -> Option<f32> {
+    match spreading_factor {
+        7 => Some(-7.5),
+        8 => Some(-10.0),
+        9 => Some(-12.5),
+        10 => Some(-15.0),
+        11 => Some(-17.5),
+        12 => Some(-20.0),
+        _ => None,
+    }
+}
+
+/// The noise power (in dBm) in the channel of a packet. The RSSI is the power
+/// of signal and noise together, so the noise is what remains after taking
+/// out the signal share given by the SNR.
+pub fn packet_noise(rssi: f32, snr: f32) -> f32 {
+    rssi - 10.0 * (1.0 + 10f32.powf(snr / 10.0)).log10()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel {
+    /// Estimated noise floor in dBm
+    pub noise_floor: f32,
+    pub samples: u64,
+}
+
+/// Estimates the noise floor of each channel from the noise measured with
+/// every received packet. Packet forwarders do not report idle channel
+/// measurements, so channels without traffic have no estimate.
+#[derive(Debug, Default)]
+pub struct NoiseFloors {
+    /// Channels by frequency in kHz
+    channels: HashMap<u32, Channel>,
+}
+
+impl NoiseFloors {
+    /// Records the noise of a packet received on the given frequency (in MHz)
+    /// and returns the updated noise floor estimate of the channel.
+    pub fn observe(&mut self, frequency: f32, rssi: f32, snr: f32) -> f32 {
+        let noise = packet_noise(rssi, snr);
+        let khz = (frequency * 1000.0).round() as u32;
+        let channel = self.channels.entry(khz).or_insert(Channel {
+            noise_floor: noise,
+            samples: 0,
+        });
+        channel.noise_floor += NOISE_FLOOR_WEIGHT * (noise - channel.noise_floor);
+        channel.samples += 1;
+        channel.noise_floor
+    }
+
+    /// Returns the channels by frequency in MHz.
+    pub fn iter(&self) -> impl Iterator<Item = (f32, &Channel)> {
+        self.channels
+            .iter()
+            .map(|(khz, channel)| (*khz as f32 / 1000.0, channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise() {
+        // A strong packet with all power in the signal
+        assert!((packet_noise(-60.0, 10.0) - -70.4).abs() < 0.1);
+        // A packet below the noise is mostly noise
+        assert!((packet_noise(-110.0, -10.0) - -110.4).abs() < 0.1);
+
+        let mut floors = NoiseFloors::default();
+        let first = floors.observe(904.1, -60.0, 10.0);
+        let second = floors.observe(904.1, -50.0, 10.0);
+        assert!(second > first && second < first + 10.0);
+        assert_eq!(2, floors.iter().next().map(|(_, c)| c.samples).unwrap());
+    }
+}
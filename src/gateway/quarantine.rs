@@ -0,0 +1,136 @@
+use crate::*;
+use semtech_udp::MacAddress;
+use settings::QuarantineSettings;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Source {
+    failures: u32,
+    since: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Tracks packet forwarders that keep sending frames that can not be parsed
+/// and temporarily ignores their traffic.
+///
+/// The udp runtime does not report the address a frame came from, so sources
+/// are identified by the gateway MAC in the frame header. Frames too short or
+/// malformed to carry a header can not be attributed and are not tracked.
+#[derive(Debug)]
+pub struct Quarantine {
+    threshold: u32,
+    window: Duration,
+    duration: Duration,
+    sources: HashMap<MacAddress, Source>,
+}
+
+impl Quarantine {
+    pub fn new(settings: &QuarantineSettings) -> Self {
+        Self {
+            threshold: settings.threshold,
+            window: Duration::from_secs(settings.window_secs),
+            duration: Duration::from_secs(settings.duration_secs),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Records an unparseable frame. Returns the source MAC if this frame
+    /// pushed the source over the threshold and it is now blocked.
+    pub fn record_failure(&mut self, frame: &[u8]) -> Option<MacAddress> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let mac = frame_mac(frame)?;
+        let now = Instant::now();
+        let source = self.sources.entry(mac).or_insert(Source {
+            failures: 0,
+            since: now,
+            blocked_until: None,
+        });
+        if source.blocked_until.is_some() {
+            return None;
+        }
+        if now.duration_since(source.since) > self.window {
+            source.failures = 0;
+            source.since = now;
+        }
+        source.failures += 1;
+        if source.failures < self.threshold {
+            return None;
+        }
+        source.blocked_until = Some(now + self.duration);
+        Some(mac)
+    }
+
+    pub fn is_blocked(&self, mac: &MacAddress) -> bool {
+        self.sources
+            .get(mac)
+            .and_then(|source| source.blocked_until)
+            .map_or(false, |until| Instant::now() < until)
+    }
+
+    /// Forgets sources whose failure window or block has passed, returning the
+    /// sources that were unblocked.
+    pub fn expire(&mut self) -> Vec<MacAddress> {
+        let now = Instant::now();
+        let window = self.window;
+        let mut unblocked = Vec::new();
+        self.sources
+            .retain(|mac, source| match source.blocked_until {
+                Some(until) if now >= until => {
+                    unblocked.push(*mac);
+                    false
+                }
+                Some(_) => true,
+                None => now.duration_since(source.since) <= window,
+            });
+        unblocked
+    }
+
+    pub fn blocked(&self) -> usize {
+        self.sources
+            .values()
+            .filter(|source| source.blocked_until.is_some())
+            .count()
+    }
+}
+
+/// Returns the gateway MAC from the header of a PUSH_DATA, PULL_DATA or
+/// TX_ACK frame.
+pub fn frame_mac(frame: &[u8]) -> Option<MacAddress> {
+    match frame {
+        [1..=2, _, _, 0x00 | 0x02 | 0x05, ..] if frame.len() >= 12 => {
+            let mac: &[u8; 8] = frame[4..12].try_into().ok()?;
+            Some(MacAddress::new(mac))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block() {
+        let mut quarantine = Quarantine::new(&QuarantineSettings {
+            threshold: 2,
+            window_secs: 60,
+            duration_secs: 0,
+        });
+        let frame = [2, 0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, b'{'];
+        let mac = MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(None, quarantine.record_failure(b"junk"));
+        assert_eq!(None, quarantine.record_failure(&frame));
+        assert_eq!(Some(mac), quarantine.record_failure(&frame));
+        assert_eq!(1, quarantine.blocked());
+        // A zero duration block is over right away
+        assert!(!quarantine.is_blocked(&mac));
+        assert_eq!(vec![mac], quarantine.expire());
+        assert_eq!(0, quarantine.blocked());
+    }
+}
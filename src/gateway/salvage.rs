@@ -0,0 +1,100 @@
+use semtech_udp::{push_data, MacAddress};
+use serde_json::{json, Value};
+use std::convert::TryInto;
+
+/// Offset of the json object in a PUSH_DATA frame, after the protocol
+/// version, token, identifier and gateway MAC.
+const PUSH_DATA_HEADER_LEN: usize = 12;
+
+/// A PUSH_DATA frame repaired to the entries that could be read.
+#[derive(Debug)]
+pub struct Salvaged {
+    pub mac: MacAddress,
+    /// The frame with only the readable rxpk entries, and the stat entry if
+    /// it is readable
+    pub frame: Vec<u8>,
+    /// The number of rxpk entries that could be read
+    pub valid: usize,
+    /// The number of rxpk entries that could not be read
+    pub invalid: usize,
+}
+
+/// Repairs a PUSH_DATA frame the udp runtime would fail to parse by reading
+/// its rxpk entries one at a time. The runtime parses a frame as a whole, so
+/// a single malformed entry would otherwise lose every uplink of the frame
+/// and leave it unacknowledged. Returns None when the frame is not a
+/// PUSH_DATA, needs no repair or has no readable rxpk.
+pub fn push_data(frame: &[u8]) -> Option<Salvaged> {
+    match frame {
+        [1..=2, _, _, 0x00, ..] if frame.len() > PUSH_DATA_HEADER_LEN => (),
+        _ => return None,
+    }
+    let mac: &[u8; 8] = frame[4..PUSH_DATA_HEADER_LEN].try_into().ok()?;
+    let json = &frame[PUSH_DATA_HEADER_LEN..];
+    if serde_json::from_slice::<push_data::Data>(json).is_ok() {
+        return None;
+    }
+    let body: Value = serde_json::from_slice(json).ok()?;
+    let entries = body.get("rxpk")?.as_array()?;
+    let rxpks: Vec<&Value> = entries
+        .iter()
+        .filter(|entry| readable::<push_data::RxPk>(entry))
+        .collect();
+    if rxpks.is_empty() {
+        return None;
+    }
+    let mut repaired = json!({ "rxpk": rxpks });
+    if let Some(stat) = body
+        .get("stat")
+        .filter(|stat| readable::<push_data::Stat>(stat))
+    {
+        repaired["stat"] = stat.clone();
+    }
+    let mut repaired_frame = frame[..PUSH_DATA_HEADER_LEN].to_vec();
+    repaired_frame.extend_from_slice(&serde_json::to_vec(&repaired).ok()?);
+    Some(Salvaged {
+        mac: MacAddress::new(mac),
+        frame: repaired_frame,
+        valid: rxpks.len(),
+        invalid: entries.len() - rxpks.len(),
+    })
+}
+
+/// Whether a json value reads as the given type. The untagged rxpk enum
+/// only reads from json text, not from a parsed value.
+fn readable<T: serde::de::DeserializeOwned>(value: &Value) -> bool {
+    serde_json::from_str::<T>(&value.to_string()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semtech_udp::parser::Parser;
+
+    #[test]
+    fn mixed() {
+        let mut frame = vec![2, 0x12, 0x34, 0x00, 1, 2, 3, 4, 5, 6, 7, 8];
+        frame.extend_from_slice(
+            br#"{"rxpk":[
+                {"tmst":1,"chan":0,"rfch":0,"freq":868.1,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-35,"lsnr":5.1,"size":4,"data":"AQIDBA=="},
+                {"tmst":"bad","freq":868.1}
+            ]}"#,
+        );
+        let salvaged = push_data(&frame).expect("salvaged");
+        assert_eq!(MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]), salvaged.mac);
+        assert_eq!(1, salvaged.valid);
+        assert_eq!(1, salvaged.invalid);
+        match semtech_udp::Packet::parse(&salvaged.frame) {
+            Ok(semtech_udp::Packet::Up(semtech_udp::Up::PushData(packet))) => {
+                assert_eq!(0x1234, packet.random_token);
+                assert_eq!(1, packet.data.rxpk.map_or(0, |rxpks| rxpks.len()));
+            }
+            other => panic!("unexpected repair: {:?}", other),
+        }
+        // A frame that needs no repair
+        assert!(push_data(&salvaged.frame).is_none());
+        // A PULL_DATA frame
+        frame[3] = 0x02;
+        assert!(push_data(&frame).is_none());
+    }
+}
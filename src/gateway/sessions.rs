@@ -0,0 +1,329 @@
+use crate::*;
+use link_packet::{LinkPacket, TraceId};
+use lorawan::{Direction, MType, PHYPayload, PHYPayloadFrame};
+use settings::SessionSettings;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+/// Number of uplinks and downlinks remembered per device.
+pub const SESSION_HISTORY: usize = 4;
+
+/// A recent uplink of a device.
+#[derive(Debug, Clone)]
+pub struct Uplink {
+    pub trace_id: TraceId,
+    pub fcnt: u16,
+    pub confirmed: bool,
+    /// The concentrator timestamp of the uplink in microseconds
+    pub timestamp: u64,
+    pub received: Instant,
+}
+
+/// The timing of a downlink that missed its receive window, in milliseconds.
+/// The deadline is when the window opened after the uplink and elapsed the
+/// time from receiving the uplink to the forwarder refusing the downlink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub deadline_ms: u64,
+    pub elapsed_ms: u64,
+    /// The deadline less the time elapsed, negative when it was missed
+    pub budget_ms: i64,
+}
+
+impl Uplink {
+    /// The timing of a downlink for the window at the given concentrator
+    /// timestamp, as of now.
+    pub fn timing(&self, timestamp: u64) -> Timing {
+        // Concentrator timestamps are a 32 bit microsecond counter
+        let deadline_ms = (timestamp.wrapping_sub(self.timestamp) & 0xffff_ffff) / 1000;
+        let elapsed_ms = self.received.elapsed().as_millis() as u64;
+        Timing {
+            deadline_ms,
+            elapsed_ms,
+            budget_ms: deadline_ms as i64 - elapsed_ms as i64,
+        }
+    }
+}
+
+/// How the delivery of a downlink to the packet forwarder went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// Sent in the first receive window
+    Rx1,
+    /// Sent in the second receive window
+    Rx2,
+    /// Not sent, with the reason
+    Failed(String),
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rx1 => f.write_str("rx1"),
+            Self::Rx2 => f.write_str("rx2"),
+            Self::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// A recent downlink to a device and how its delivery went.
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    pub trace_id: TraceId,
+    pub fcnt: u16,
+    pub delivery: Delivery,
+    pub sent: Instant,
+}
+
+/// The recent transactions of a device.
+#[derive(Debug, Default)]
+pub struct Session {
+    pub uplinks: VecDeque<Uplink>,
+    pub downlinks: VecDeque<Downlink>,
+}
+
+impl Session {
+    fn last_seen(&self) -> Option<Instant> {
+        let uplink = self.uplinks.back().map(|uplink| uplink.received);
+        let downlink = self.downlinks.back().map(|downlink| downlink.sent);
+        uplink.max(downlink)
+    }
+}
+
+/// What an uplink says about earlier downlinks to the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Retry {
+    /// A confirmed uplink was repeated even though a downlink answering it
+    /// was delivered to the forwarder, so the device likely never received it
+    AfterDelivery(Delivery),
+    /// A confirmed uplink was repeated without a delivered answer
+    Unanswered,
+}
+
+/// Recent uplinks and downlinks by DevAddr, to correlate downlink delivery
+/// with the device transaction it belongs to. A repeated confirmed uplink
+/// after a delivered downlink is the typical sign of a downlink that never
+/// arrived.
+#[derive(Debug)]
+pub struct Sessions {
+    size: usize,
+    max_age: Duration,
+    sessions: HashMap<u32, Session>,
+}
+
+impl Sessions {
+    pub fn new(settings: &SessionSettings) -> Self {
+        Self {
+            size: settings.size,
+            max_age: Duration::from_secs(settings.max_age_secs),
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u32, &Session)> {
+        self.sessions.iter()
+    }
+
+    pub fn get(&self, dev_addr: u32) -> Option<&Session> {
+        self.sessions.get(&dev_addr)
+    }
+
+    /// The last uplink of the device a downlink is for, the one a network
+    /// server downlink answers.
+    pub fn last_uplink(&self, payload: &[u8]) -> Option<Uplink> {
+        let dev_addr = downlink_dev_addr(payload)?;
+        self.sessions.get(&dev_addr)?.uplinks.back().cloned()
+    }
+
+    /// Records an uplink, returning the DevAddr of the device and whether the
+    /// uplink is a retry of an earlier confirmed uplink.
+    pub fn uplink(&mut self, packet: &LinkPacket) -> Option<(u32, Option<Retry>)> {
+        if self.size == 0 {
+            return None;
+        }
+        let (dev_addr, fcnt, confirmed) = frame_header(Direction::Uplink, &packet.payload)?;
+        if !self.sessions.contains_key(&dev_addr) && self.sessions.len() >= self.size {
+            self.evict_oldest();
+        }
+        let session = self.sessions.entry(dev_addr).or_default();
+        let retry = match session.uplinks.back() {
+            Some(last) if confirmed && last.confirmed && last.fcnt == fcnt => Some(
+                match session
+                    .downlinks
+                    .iter()
+                    .rev()
+                    .find(|downlink| downlink.trace_id == last.trace_id)
+                {
+                    Some(Downlink {
+                        delivery: delivery @ (Delivery::Rx1 | Delivery::Rx2),
+                        ..
+                    }) => Retry::AfterDelivery(delivery.clone()),
+                    _ => Retry::Unanswered,
+                },
+            ),
+            _ => None,
+        };
+        push_bounded(
+            &mut session.uplinks,
+            Uplink {
+                trace_id: packet.trace_id,
+                fcnt,
+                confirmed,
+                timestamp: packet.packet.timestamp,
+                received: Instant::now(),
+            },
+        );
+        Some((dev_addr, retry))
+    }
+
+    /// Records the delivery of a downlink, returning the uplink of the same
+    /// device exchange if it is known.
+    pub fn downlink(&mut self, packet: &LinkPacket, delivery: Delivery) -> Option<Uplink> {
+        let (dev_addr, fcnt, _) = frame_header(Direction::Downlink, &packet.payload)?;
+        let session = self.sessions.get_mut(&dev_addr)?;
+        push_bounded(
+            &mut session.downlinks,
+            Downlink {
+                trace_id: packet.trace_id,
+                fcnt,
+                delivery,
+                sent: Instant::now(),
+            },
+        );
+        session
+            .uplinks
+            .iter()
+            .rev()
+            .find(|uplink| uplink.trace_id == packet.trace_id)
+            .cloned()
+    }
+
+    /// Forgets devices that have not been heard from within the maximum age.
+    pub fn expire(&mut self) {
+        let max_age = self.max_age;
+        self.sessions.retain(|_, session| {
+            session
+                .last_seen()
+                .map_or(false, |last_seen| last_seen.elapsed() <= max_age)
+        });
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(dev_addr) = self
+            .sessions
+            .iter()
+            .min_by_key(|(_, session)| session.last_seen())
+            .map(|(dev_addr, _)| *dev_addr)
+        {
+            self.sessions.remove(&dev_addr);
+        }
+    }
+}
+
+fn push_bounded<T>(history: &mut VecDeque<T>, item: T) {
+    if history.len() >= SESSION_HISTORY {
+        history.pop_front();
+    }
+    history.push_back(item);
+}
+
+/// Returns the DevAddr of a data frame downlink.
+pub fn downlink_dev_addr(payload: &[u8]) -> Option<u32> {
+    frame_header(Direction::Downlink, payload).map(|(dev_addr, _, _)| dev_addr)
+}
+
+/// Returns the DevAddr, frame counter and whether the frame is confirmed for
+/// data frames.
+fn frame_header(direction: Direction, payload: &[u8]) -> Option<(u32, u16, bool)> {
+    // The payload reader expects at least a header and MIC
+    if payload.len() < 5 {
+        return None;
+    }
+    let packet = PHYPayload::read(direction, &mut Cursor::new(payload)).ok()?;
+    let confirmed = matches!(packet.mtype(), MType::ConfirmedUp | MType::ConfirmedDown);
+    match packet.payload {
+        PHYPayloadFrame::MACPayload(mac_payload) => {
+            Some((mac_payload.dev_addr(), mac_payload.fhdr.fcnt, confirmed))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::Packet as LoraPacket;
+    use semtech_udp::MacAddress;
+
+    fn packet(mhdr: u8, trace_id: TraceId) -> LinkPacket {
+        // DevAddr 0x01020304, no FOpts, FCnt 7, no FPort and a MIC
+        let payload = vec![mhdr, 0x04, 0x03, 0x02, 0x01, 0x00, 0x07, 0x00, 1, 2, 3, 4];
+        LinkPacket {
+            gateway_mac: MacAddress::new(&[0; 8]),
+            trace_id,
+            radio: None,
+            antenna: Default::default(),
+            metadata: Default::default(),
+            seq: 0,
+            router: None,
+            received: None,
+            packet: LoraPacket::default(),
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn retry_after_delivery() {
+        let mut sessions = Sessions::new(&SessionSettings {
+            size: 1,
+            max_age_secs: 60,
+        });
+        let first = TraceId::random();
+        assert_eq!(
+            Some((0x01020304, None)),
+            sessions.uplink(&packet(0x80, first))
+        );
+        let uplink = sessions
+            .downlink(&packet(0x60, first), Delivery::Rx1)
+            .expect("uplink");
+        assert_eq!(7, uplink.fcnt);
+        assert_eq!(
+            Some((0x01020304, Some(Retry::AfterDelivery(Delivery::Rx1)))),
+            sessions.uplink(&packet(0x80, TraceId::random()))
+        );
+        assert_eq!(1, sessions.len());
+    }
+
+    #[test]
+    fn timing() {
+        let mut sessions = Sessions::new(&SessionSettings {
+            size: 1,
+            max_age_secs: 60,
+        });
+        let mut uplink = packet(0x80, TraceId::random());
+        // A second before the concentrator counter wraps
+        uplink.packet.timestamp = 0xffff_ffff - 999_999;
+        sessions.uplink(&uplink);
+        let uplink = sessions
+            .last_uplink(&packet(0x60, TraceId::random()).payload)
+            .expect("uplink");
+        let timing = uplink.timing(999_999);
+        assert_eq!(1999, timing.deadline_ms);
+        assert_eq!(
+            timing.deadline_ms as i64 - timing.elapsed_ms as i64,
+            timing.budget_ms
+        );
+    }
+}
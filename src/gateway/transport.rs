@@ -0,0 +1,57 @@
+use crate::*;
+use async_trait::async_trait;
+use gateway::udp::UdpTransport;
+use semtech_udp::{
+    pull_resp::TxPk,
+    server_runtime::{Error as SemtechError, Event},
+    MacAddress,
+};
+use slog::Logger;
+use std::time::Duration;
+
+/// How the gateway exchanges packets with the concentrators it serves.
+///
+/// Transports speak the semtech udp vocabulary: they report forwarders and
+/// their uplinks as server runtime events and take downlinks as a `TxPk`,
+/// answering with the tx_ack error of a refused downlink. A transport for
+/// another protocol translates to and from it, so the gateway handles the
+/// packets of every transport the same way.
+#[async_trait]
+pub trait PacketTransport: std::fmt::Debug + Send {
+    /// Waits for the next event of the packet forwarders. An error is a
+    /// failure of the transport itself, like its socket failing, to
+    /// `recover` from.
+    async fn recv(&mut self) -> Result<Event>;
+
+    /// Sends a downlink to the given forwarder and waits for its transmit
+    /// acknowledgment, up to the timeout if given.
+    async fn dispatch(
+        &mut self,
+        mac: MacAddress,
+        txpk: TxPk,
+        timeout: Option<Duration>,
+    ) -> std::result::Result<(), SemtechError>;
+
+    /// Recovers from a transient failure of the transport, like a socket
+    /// error, returning an error when it can not. Returns early without
+    /// recovering when shut down.
+    async fn recover(
+        &mut self,
+        err: &Error,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result;
+
+    /// The total number of packets lost because they arrived faster than
+    /// they were read, if the transport can tell.
+    fn drops(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Opens the packet transport selected in the settings.
+pub async fn open(settings: &Settings, logger: &Logger) -> Result<Box<dyn PacketTransport>> {
+    Ok(Box::new(
+        UdpTransport::bind(settings.listen_addr, logger).await?,
+    ))
+}
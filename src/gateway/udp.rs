@@ -0,0 +1,360 @@
+use crate::*;
+use async_trait::async_trait;
+use gateway::{clock, drops, salvage, transport::PacketTransport};
+use relay::{Upstreams, MAX_DATAGRAM};
+use semtech_udp::{
+    pull_resp::TxPk,
+    server_runtime::{Error as SemtechError, Event, UdpRuntime},
+    MacAddress,
+};
+use slog::{debug, warn, Logger};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::mpsc::Receiver, time};
+
+/// Bounds of the backoff between attempts to rebind the listen address.
+pub const REBIND_BACKOFF_MIN_SECS: u64 = 1;
+pub const REBIND_BACKOFF_MAX_SECS: u64 = 60;
+/// Seconds a packet forwarder can be silent before its relay to the server
+/// runtime is closed. Forwarders send a PULL_DATA every few seconds.
+pub const FORWARDER_TIMEOUT_SECS: u64 = 120;
+
+/// The semtech udp transport, serving packet forwarders on the listen
+/// address.
+///
+/// The transport owns the listen socket and relays each forwarder to a
+/// server runtime on a loopback port, so failures of the listen socket are
+/// seen and frames the runtime can not parse can be repaired first: a
+/// PUSH_DATA with malformed rxpk entries is passed on with only the readable
+/// ones, which the runtime acknowledges and reports like any other uplink.
+/// Replies of the runtime go out from the listen socket, the address
+/// forwarders expect them from, with the concentrator counter taken out of
+/// downlinks scheduled by GPS time.
+#[derive(Debug)]
+pub struct UdpTransport {
+    runtime: UdpRuntime,
+    listen_addr: SocketAddr,
+    socket: UdpSocket,
+    forwarders: Upstreams<SocketAddr>,
+    replies: Receiver<(SocketAddr, Vec<u8>)>,
+    /// The forwarder of each relay address the runtime sees
+    relayed: HashMap<SocketAddr, SocketAddr>,
+    eviction_timer: time::Interval,
+    buf: Vec<u8>,
+    logger: Logger,
+}
+
+impl UdpTransport {
+    pub async fn bind(listen_addr: SocketAddr, logger: &Logger) -> Result<Self> {
+        let runtime_addr = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        let (forwarders, replies) = Upstreams::new(runtime_addr);
+        Ok(Self {
+            runtime: UdpRuntime::new(runtime_addr).await?,
+            listen_addr,
+            socket: UdpSocket::bind(listen_addr).await?,
+            forwarders,
+            replies,
+            relayed: HashMap::new(),
+            eviction_timer: time::interval(Duration::from_secs(FORWARDER_TIMEOUT_SECS)),
+            buf: vec![0u8; MAX_DATAGRAM],
+            logger: logger.clone(),
+        })
+    }
+
+    /// Relays a frame from a forwarder to the runtime, repaired when it is a
+    /// PUSH_DATA with entries that can not be read. Returns the original of
+    /// a repaired frame for the gateway to count the lost entries.
+    async fn relay(&mut self, addr: SocketAddr, len: usize) -> Result<Option<Event>> {
+        if self.forwarders.connect(&addr, &self.logger).await? {
+            if let Some(relay_addr) = self.forwarders.local_addr(&addr) {
+                self.relayed.insert(relay_addr, addr);
+            }
+        }
+        let frame = &self.buf[..len];
+        match salvage::push_data(frame) {
+            Some(salvaged) => {
+                let original = frame.to_vec();
+                self.forwarders.send(&addr, &salvaged.frame).await?;
+                Ok(Some(Event::UnableToParseUdpFrame(original)))
+            }
+            None => {
+                self.forwarders.send(&addr, frame).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Translates the relay address in a client event of the runtime to the
+    /// address of the forwarder.
+    fn forwarder_event(&self, event: Event) -> Event {
+        let forwarder = |addr: SocketAddr| self.relayed.get(&addr).copied().unwrap_or(addr);
+        match event {
+            Event::NewClient((mac, addr)) => Event::NewClient((mac, forwarder(addr))),
+            Event::UpdateClient((mac, addr)) => Event::UpdateClient((mac, forwarder(addr))),
+            event => event,
+        }
+    }
+}
+
+/// An ephemeral address of the family of the listen address.
+fn any_addr(listen_addr: SocketAddr) -> SocketAddr {
+    match listen_addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+#[async_trait]
+impl PacketTransport for UdpTransport {
+    async fn recv(&mut self) -> Result<Event> {
+        loop {
+            tokio::select! {
+                event = self.runtime.recv() => return Ok(self.forwarder_event(event)),
+                received = self.socket.recv_from(&mut self.buf) => {
+                    let (len, addr) = received?;
+                    // A failed relay only loses the frame
+                    match self.relay(addr, len).await {
+                        Ok(Some(event)) => return Ok(event),
+                        Ok(None) => (),
+                        Err(err) => warn!(self.logger, "failed to relay frame from {}: {:?}", addr, err),
+                    }
+                }
+                Some((addr, reply)) = self.replies.recv() => {
+                    let reply = clock::gps_timed(&reply).unwrap_or(reply);
+                    if let Err(err) = self.socket.send_to(&reply, addr).await {
+                        warn!(self.logger, "failed to send frame to {}: {:?}", addr, err);
+                    }
+                }
+                _ = self.eviction_timer.tick() => {
+                    let evicted = self.forwarders.evict(Duration::from_secs(FORWARDER_TIMEOUT_SECS));
+                    if !evicted.is_empty() {
+                        debug!(self.logger, "closing relays of {} silent forwarders", evicted.len());
+                        self.relayed.retain(|_, forwarder| !evicted.contains(forwarder));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn dispatch(
+        &mut self,
+        mac: MacAddress,
+        txpk: TxPk,
+        timeout: Option<Duration>,
+    ) -> std::result::Result<(), SemtechError> {
+        let mut downlink = self.runtime.prepare_empty_downlink(mac);
+        downlink.set_packet(txpk);
+        downlink.dispatch(timeout).await
+    }
+
+    async fn recover(
+        &mut self,
+        err: &Error,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
+        warn!(
+            logger,
+            "rebinding {} after socket error: {:?}", self.listen_addr, err
+        );
+        rebind(&mut self.socket, self.listen_addr, shutdown, logger).await
+    }
+
+    fn drops(&self) -> Option<u64> {
+        drops::read_drops(&self.listen_addr)
+    }
+}
+
+/// How a failure of the transport socket is dealt with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// A transient failure of the socket, which a new socket may not have
+    Socket,
+    /// A failure a new socket can not fix, like the listen port taken by
+    /// another process or no permission to bind it
+    Fatal,
+}
+
+/// Classifies a failure of the transport socket by the io error that caused
+/// it, if any. Failures without an io error are taken as transient.
+pub fn classify(err: &Error) -> Failure {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return classify_io(err.kind());
+        }
+        source = err.source();
+    }
+    Failure::Socket
+}
+
+fn classify_io(kind: io::ErrorKind) -> Failure {
+    match kind {
+        io::ErrorKind::AddrInUse
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::PermissionDenied => Failure::Fatal,
+        _ => Failure::Socket,
+    }
+}
+
+/// Replaces the listen socket with a fresh one, retrying socket failures
+/// with an exponential backoff. The listen address can only be bound again
+/// once the old socket is closed, so it is first swapped for one on an
+/// ephemeral port. Fatal failures, like another process having taken the
+/// listen address in the meantime, are returned. Gives up when shut down
+/// while waiting for a retry.
+pub async fn rebind(
+    socket: &mut UdpSocket,
+    listen_addr: SocketAddr,
+    shutdown: &triggered::Listener,
+    logger: &Logger,
+) -> Result {
+    *socket = UdpSocket::bind(any_addr(listen_addr)).await?;
+    let mut backoff = Duration::from_secs(REBIND_BACKOFF_MIN_SECS);
+    loop {
+        match UdpSocket::bind(listen_addr).await {
+            Ok(bound) => {
+                *socket = bound;
+                return Ok(());
+            }
+            Err(err) if classify_io(err.kind()) == Failure::Fatal => return Err(err.into()),
+            Err(err) => warn!(
+                logger,
+                "failed to bind {}, retrying in {}s: {:?}",
+                listen_addr,
+                backoff.as_secs(),
+                err
+            ),
+        }
+        tokio::select! {
+            _ = shutdown.clone() => return Ok(()),
+            _ = time::sleep(backoff) => (),
+        }
+        backoff = (backoff * 2).min(Duration::from_secs(REBIND_BACKOFF_MAX_SECS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let err = |kind| Error::from(io::Error::new(kind, "test"));
+        assert_eq!(
+            Failure::Fatal,
+            super::classify(&err(io::ErrorKind::AddrInUse))
+        );
+        assert_eq!(
+            Failure::Fatal,
+            super::classify(&err(io::ErrorKind::PermissionDenied))
+        );
+        assert_eq!(
+            Failure::Socket,
+            super::classify(&err(io::ErrorKind::ConnectionRefused))
+        );
+        assert_eq!(Failure::Socket, super::classify(&Error::custom("test")));
+    }
+
+    /// Receives the next frame of the transport on the forwarder socket,
+    /// handling the events of the transport meanwhile.
+    async fn reply(
+        transport: &mut UdpTransport,
+        forwarder: &UdpSocket,
+        events: &mut Vec<Event>,
+    ) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let deadline = time::sleep(Duration::from_secs(1));
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                event = transport.recv() => events.push(event.expect("event")),
+                received = forwarder.recv_from(&mut buf) => {
+                    let (len, from) = received.expect("reply");
+                    assert_eq!(transport.listen_addr, from);
+                    return buf[..len].to_vec();
+                }
+                _ = &mut deadline => panic!("no reply, events: {:?}", events),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relay() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let listen_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .and_then(|socket| socket.local_addr())
+            .expect("listen addr");
+        let mut transport = UdpTransport::bind(listen_addr, &logger)
+            .await
+            .expect("transport");
+        let forwarder = UdpSocket::bind("127.0.0.1:0").await.expect("forwarder");
+        let forwarder_addr = forwarder.local_addr().expect("forwarder addr");
+        let pull_data = [2, 0x12, 0x34, 0x02, 1, 2, 3, 4, 5, 6, 7, 8];
+        let mac = MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // The forwarder is reported by its own address and acknowledged from
+        // the listen address
+        let mut events = Vec::new();
+        forwarder
+            .send_to(&pull_data, listen_addr)
+            .await
+            .expect("send");
+        assert_eq!(
+            vec![2, 0x12, 0x34, 0x04],
+            reply(&mut transport, &forwarder, &mut events).await
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::NewClient((m, addr)) if *m == mac && *addr == forwarder_addr)));
+
+        // A PUSH_DATA with a malformed entry is acknowledged and its readable
+        // entry reported as an uplink
+        let mut push_data = vec![2, 0x56, 0x78, 0x00, 1, 2, 3, 4, 5, 6, 7, 8];
+        push_data.extend_from_slice(
+            br#"{"rxpk":[
+                {"tmst":1,"chan":0,"rfch":0,"freq":868.1,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-35,"lsnr":5.1,"size":4,"data":"AQIDBA=="},
+                {"tmst":"bad","freq":868.1}
+            ]}"#,
+        );
+        let mut events = Vec::new();
+        forwarder
+            .send_to(&push_data, listen_addr)
+            .await
+            .expect("send");
+        assert_eq!(
+            vec![2, 0x56, 0x78, 0x01],
+            reply(&mut transport, &forwarder, &mut events).await
+        );
+        // The uplink may only be reported after the acknowledgment is sent
+        while !events
+            .iter()
+            .any(|event| matches!(event, Event::PacketReceived(_, m) if *m == mac))
+        {
+            let event = time::timeout(Duration::from_secs(1), transport.recv())
+                .await
+                .expect("uplink");
+            events.push(event.expect("event"));
+        }
+        assert!(matches!(&events[0], Event::UnableToParseUdpFrame(frame) if *frame == push_data));
+
+        // The rebound listen socket keeps relaying
+        let (_trigger, shutdown) = triggered::trigger();
+        transport
+            .recover(&Error::custom("test"), &shutdown, &logger)
+            .await
+            .expect("recover");
+        forwarder
+            .send_to(&pull_data, listen_addr)
+            .await
+            .expect("send");
+        assert_eq!(
+            vec![2, 0x12, 0x34, 0x04],
+            reply(&mut transport, &forwarder, &mut events).await
+        );
+    }
+}
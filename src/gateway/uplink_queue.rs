@@ -0,0 +1,165 @@
+use crate::*;
+use link_packet::LinkPacket;
+use semtech_udp::MacAddress;
+use settings::UplinkQueueSettings;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Default)]
+struct ClientQueue {
+    uplinks: VecDeque<LinkPacket>,
+    dropped: u64,
+}
+
+/// Queues uplinks per packet forwarder and hands them out round robin, so a
+/// forwarder with a lot of traffic can not crowd out the others when uplinks
+/// arrive faster than they are routed.
+///
+/// The uplinks of a forwarder are handed out in the order they were
+/// received, which keeps the rxpk order of a PUSH_DATA, and each is stamped
+/// with the next sequence number on the way out so network servers can tell
+/// reordering in transit from frame counter gaps. The router keeps that
+/// order by starting the requests to each router from a single task.
+#[derive(Debug)]
+pub struct UplinkQueue {
+    depth: usize,
+    clients: HashMap<MacAddress, ClientQueue>,
+    /// Forwarders with queued uplinks, in the order they are served
+    ready: VecDeque<MacAddress>,
+    /// The sequence number of the last uplink handed out
+    seq: u64,
+}
+
+impl UplinkQueue {
+    pub fn new(settings: &UplinkQueueSettings) -> Self {
+        Self {
+            depth: settings.depth.max(1),
+            clients: HashMap::new(),
+            ready: VecDeque::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    /// Queues an uplink. Returns the oldest queued uplink of the same
+    /// forwarder if it had to be dropped to make room.
+    pub fn push(&mut self, uplink: LinkPacket) -> Option<LinkPacket> {
+        let mac = uplink.gateway_mac;
+        let client = self.clients.entry(mac).or_default();
+        let dropped = if client.uplinks.len() >= self.depth {
+            client.dropped += 1;
+            client.uplinks.pop_front()
+        } else {
+            None
+        };
+        if client.uplinks.is_empty() {
+            self.ready.push_back(mac);
+        }
+        client.uplinks.push_back(uplink);
+        dropped
+    }
+
+    /// Returns the next uplink of the next forwarder in turn.
+    pub fn pop(&mut self) -> Option<LinkPacket> {
+        let mac = self.ready.pop_front()?;
+        let client = self.clients.get_mut(&mac)?;
+        let mut uplink = client.uplinks.pop_front()?;
+        if !client.uplinks.is_empty() {
+            self.ready.push_back(mac);
+        }
+        self.seq += 1;
+        uplink.seq = self.seq;
+        Some(uplink)
+    }
+
+    /// Returns the queue depth and dropped uplink count per forwarder.
+    pub fn metrics(&self) -> impl Iterator<Item = (&MacAddress, usize, u64)> {
+        self.clients
+            .iter()
+            .map(|(mac, client)| (mac, client.uplinks.len(), client.dropped))
+    }
+
+    /// Forgets forwarders that have nothing queued and never had uplinks
+    /// dropped, keeping the drop counts of the others for the metrics.
+    pub fn prune(&mut self) {
+        self.clients
+            .retain(|_, client| !client.uplinks.is_empty() || client.dropped > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::Packet;
+    use link_packet::{Antenna, Metadata, TraceId};
+
+    fn uplink(mac: u64, trace_id: u64) -> LinkPacket {
+        LinkPacket {
+            gateway_mac: MacAddress::new(&mac.to_be_bytes()),
+            trace_id: TraceId::from(trace_id),
+            packet: Packet::default(),
+            payload: Default::default(),
+            radio: None,
+            antenna: Antenna::default(),
+            metadata: Metadata::default(),
+            seq: 0,
+            router: None,
+            received: None,
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut queue = UplinkQueue::new(&UplinkQueueSettings { depth: 2 });
+        for trace_id in 0..3 {
+            queue.push(uplink(1, trace_id));
+        }
+        queue.push(uplink(2, 10));
+        let order: Vec<u64> = std::iter::from_fn(|| queue.pop())
+            .map(|uplink| uplink.trace_id.as_u64())
+            .collect();
+        // The oldest uplink of the chatty forwarder was dropped
+        assert_eq!(vec![1, 10, 2], order);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn order() {
+        // Forwarders send their uplinks concurrently, numbered by trace id
+        let (tx, rx) = std::sync::mpsc::channel();
+        let forwarders: Vec<_> = (1..=4u64)
+            .map(|mac| {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    for trace_id in 0..100 {
+                        tx.send(uplink(mac, trace_id)).expect("send");
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        let mut queue = UplinkQueue::new(&UplinkQueueSettings { depth: 400 });
+        let mut popped = vec![];
+        for uplink in rx {
+            queue.push(uplink);
+            if rand::random::<bool>() {
+                popped.extend(queue.pop());
+            }
+        }
+        for forwarder in forwarders {
+            forwarder.join().expect("forwarder");
+        }
+        popped.extend(std::iter::from_fn(|| queue.pop()));
+
+        assert_eq!(400, popped.len());
+        assert!(popped.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+        let mut last: HashMap<MacAddress, u64> = HashMap::new();
+        for uplink in popped {
+            if let Some(previous) = last.insert(uplink.gateway_mac, uplink.trace_id.as_u64()) {
+                assert!(previous < uplink.trace_id.as_u64());
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::*;
+use helium_proto::{routing_information::Data as RoutingData, WindowV1};
+use link_packet::LinkPacket;
+use lns::DevAddrRange;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{info, o, warn, Logger};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Maximum number of join requests waiting for the join server before join
+/// requests are dropped.
+pub const JOIN_QUEUE_SIZE: usize = 16;
+/// Microseconds from a join request to the first and second join accept
+/// window (JOIN_ACCEPT_DELAY1 and JOIN_ACCEPT_DELAY2).
+pub const JOIN_ACCEPT_DELAY1_US: u64 = 5_000_000;
+pub const JOIN_ACCEPT_DELAY2_US: u64 = 6_000_000;
+/// The session parameters join requests ask for: LoRaWAN 1.0.3, RX1DROffset
+/// 0 with the RX2 datarate of the windows, and a one second RX1 delay.
+const MAC_VERSION: &str = "1.0.3";
+const DL_SETTINGS: &str = "00";
+const RX_DELAY: u8 = 1;
+
+/// The parameters of the join accept windows.
+#[derive(Debug, Clone)]
+struct Windows {
+    rx1: bool,
+    rx2_frequency: f32,
+    rx2_datarate: String,
+}
+
+#[derive(Debug, Clone)]
+struct Client {
+    uri: String,
+    network_server: String,
+    net_id: String,
+    dev_addrs: DevAddrRange,
+    args: Vec<String>,
+    windows: Windows,
+}
+
+/// Creates the handle the gateway hands join requests for local join euis to,
+/// and the service exchanging them with the join server.
+pub fn join_server(settings: &Settings) -> Result<(JoinServer, JoinService)> {
+    let join_server = &settings.join_server;
+    if !join_server.enabled {
+        return Ok((JoinServer::disabled(), JoinService { state: None }));
+    }
+    let uri = join_server
+        .uri
+        .clone()
+        .ok_or_else(|| Error::custom("join server enabled without a uri"))?;
+    let network_server = join_server.network_server.clone().ok_or_else(|| {
+        Error::custom("join server enabled without a network server for the sessions")
+    })?;
+    let (rx2_frequency, rx2_datarate) = match (
+        join_server.rx2_frequency,
+        &join_server.rx2_datarate,
+        settings.region.and_then(region::rx2_window),
+    ) {
+        (Some(frequency), Some(datarate), _) => (frequency, datarate.clone()),
+        (None, None, Some((frequency, datarate))) => (frequency, datarate.to_string()),
+        _ => return Err(Error::custom(
+            "join server needs both rx2_frequency and rx2_datarate, or a region to take them from",
+        )),
+    };
+    let join_euis = join_server
+        .join_euis
+        .iter()
+        .map(|eui| {
+            u64::from_str_radix(eui, 16)
+                .map_err(|_| Error::custom(format!("invalid join eui: {}", eui)))
+        })
+        .collect::<Result<_>>()?;
+    let mut args = curl::interface_args(&settings.backhaul.interface);
+    args.extend_from_slice(&[
+        "-H".to_string(),
+        "Content-Type: application/json".to_string(),
+        "--max-time".to_string(),
+        join_server.timeout.to_string(),
+    ]);
+    if let Some(cert) = &join_server.cert {
+        args.extend_from_slice(&["--cert".to_string(), cert.clone()]);
+    }
+    if let Some(key) = &join_server.key {
+        args.extend_from_slice(&["--key".to_string(), key.clone()]);
+    }
+    let client = Client {
+        uri,
+        network_server,
+        net_id: join_server.net_id.clone(),
+        dev_addrs: DevAddrRange::parse(&join_server.dev_addrs)?,
+        args,
+        windows: Windows {
+            rx1: join_server.rx1,
+            rx2_frequency,
+            rx2_datarate,
+        },
+    };
+    let (request_sender, request_receiver) = mpsc::channel(JOIN_QUEUE_SIZE);
+    let (accept_sender, accept_receiver) = mpsc::channel(JOIN_QUEUE_SIZE);
+    Ok((
+        JoinServer {
+            sender: Some(request_sender),
+            accepts: Some(accept_receiver),
+            join_euis,
+        },
+        JoinService {
+            state: Some((client, request_receiver, accept_sender)),
+        },
+    ))
+}
+
+/// The gateway side of the join server. Join requests for the configured
+/// join euis are resolved with the join server instead of being routed, and
+/// the join accepts come back as downlinks.
+#[derive(Debug)]
+pub struct JoinServer {
+    sender: Option<Sender<LinkPacket>>,
+    accepts: Option<Receiver<LinkPacket>>,
+    join_euis: Vec<u64>,
+}
+
+impl JoinServer {
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            accepts: None,
+            join_euis: vec![],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Hands a join request for a local join eui to the join server. Returns
+    /// whether the packet was taken and is not to be routed.
+    pub fn join(&self, packet: &LinkPacket) -> bool {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return false,
+        };
+        match packet.packet.routing.as_ref().and_then(|r| r.data.as_ref()) {
+            Some(RoutingData::Eui(eui)) if self.join_euis.contains(&eui.appeui) => {
+                // A join request dropped here is retried by the device
+                let _ = sender.try_send(packet.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<LinkPacket> {
+        self.accepts.as_mut()?.recv().await
+    }
+}
+
+/// Sends join requests to the join server (LoRaWAN Backend Interfaces 1.0
+/// JoinReq/JoinAns), hands the sessions of accepted joins to the network
+/// server and turns the join accepts into downlinks.
+#[derive(Debug)]
+pub struct JoinService {
+    state: Option<(Client, Receiver<LinkPacket>, Sender<LinkPacket>)>,
+}
+
+impl JoinService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "join_server"));
+        let (client, mut requests, accepts) = match self.state.take() {
+            Some(state) => state,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "uri" => &client.uri);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                Some(request) = requests.recv() => {
+                    // Requests are exchanged concurrently so a slow join server
+                    // does not make later join requests miss their windows
+                    let (client, accepts) = (client.clone(), accepts.clone());
+                    let logger = logger.new(o!("trace_id" => request.trace_id.to_string()));
+                    tokio::spawn(async move {
+                        match client.join(&request).await {
+                            Ok(accept) => {
+                                info!(logger, "join accepted by join server");
+                                let _ = accepts.send(accept).await;
+                            }
+                            Err(err) => warn!(logger, "join request failed: {:?}", err),
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JoinAns {
+    result: JoinResult,
+    #[serde(rename = "PHYPayload")]
+    phy_payload: Option<String>,
+    /// The session keys as key envelopes, passed on to the network server
+    /// as given. LoRaWAN 1.0 sessions have a NwkSKey, 1.1 sessions the
+    /// three network session keys instead.
+    #[serde(rename = "NwkSKey")]
+    nwk_s_key: Option<Value>,
+    #[serde(rename = "FNwkSIntKey")]
+    f_nwk_s_int_key: Option<Value>,
+    #[serde(rename = "SNwkSIntKey")]
+    s_nwk_s_int_key: Option<Value>,
+    #[serde(rename = "NwkSEncKey")]
+    nwk_s_enc_key: Option<Value>,
+    #[serde(rename = "AppSKey")]
+    app_s_key: Option<Value>,
+    #[serde(rename = "SessionKeyID")]
+    session_key_id: Option<String>,
+    lifetime: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JoinResult {
+    result_code: String,
+    description: Option<String>,
+}
+
+impl Client {
+    async fn join(&self, request: &LinkPacket) -> Result<LinkPacket> {
+        let eui = match request
+            .packet
+            .routing
+            .as_ref()
+            .and_then(|r| r.data.as_ref())
+        {
+            Some(RoutingData::Eui(eui)) => eui.clone(),
+            _ => return Err(Error::custom("not a join request")),
+        };
+        let transaction_id = rand::random::<u32>();
+        let dev_addr = format!("{:08X}", self.dev_addrs.random());
+        let body = json!({
+            "ProtocolVersion": "1.0",
+            "SenderID": self.net_id,
+            "ReceiverID": format!("{:016X}", eui.appeui),
+            "TransactionID": transaction_id,
+            "MessageType": "JoinReq",
+            "MACVersion": MAC_VERSION,
+            "PHYPayload": to_hex(&request.payload),
+            "DevEUI": format!("{:016X}", eui.deveui),
+            "DevAddr": dev_addr,
+            "DLSettings": DL_SETTINGS,
+            "RxDelay": RX_DELAY,
+        });
+        let answer: JoinAns = curl::post(&self.uri, &self.args, body.to_string(), |output| {
+            Ok(serde_json::from_slice(output)?)
+        })
+        .await?;
+        if answer.result.result_code != "Success" {
+            return Err(Error::custom(format!(
+                "join server answered {}: {}",
+                answer.result.result_code,
+                answer.result.description.unwrap_or_default()
+            )));
+        }
+        let payload = match &answer.phy_payload {
+            Some(payload) => from_hex(payload)?,
+            None => return Err(Error::custom("join answer without a join accept")),
+        };
+        if answer.nwk_s_key.is_none() && answer.f_nwk_s_int_key.is_none() {
+            return Err(Error::custom("join answer without network session keys"));
+        }
+        // The network server has to know the session before the device gets
+        // the join accept, or the device joins a session nobody serves
+        let session = json!({
+            "TransactionID": transaction_id,
+            "NetID": self.net_id,
+            "JoinEUI": format!("{:016X}", eui.appeui),
+            "DevEUI": format!("{:016X}", eui.deveui),
+            "DevAddr": dev_addr,
+            "MACVersion": MAC_VERSION,
+            "DLSettings": DL_SETTINGS,
+            "RxDelay": RX_DELAY,
+            "NwkSKey": answer.nwk_s_key,
+            "FNwkSIntKey": answer.f_nwk_s_int_key,
+            "SNwkSIntKey": answer.s_nwk_s_int_key,
+            "NwkSEncKey": answer.nwk_s_enc_key,
+            "AppSKey": answer.app_s_key,
+            "SessionKeyID": answer.session_key_id,
+            "Lifetime": answer.lifetime,
+        });
+        curl::post(
+            &self.network_server,
+            &self.args,
+            session.to_string(),
+            |_| Ok(()),
+        )
+        .await
+        .map_err(|err| Error::custom(format!("network server refused the session: {:?}", err)))?;
+        Ok(self.windows.accept(request, payload))
+    }
+}
+
+impl Windows {
+    /// Builds the join accept downlink for a join request. The first join
+    /// accept window uses the frequency and datarate of the join request,
+    /// which only fits regions without separate downlink channels;
+    /// otherwise only the second window is used.
+    fn accept(&self, request: &LinkPacket, payload: Vec<u8>) -> LinkPacket {
+        let uplink = &request.packet;
+        let rx2 = WindowV1 {
+            timestamp: uplink.timestamp.wrapping_add(JOIN_ACCEPT_DELAY2_US) & 0xffff_ffff,
+            frequency: self.rx2_frequency,
+            datarate: self.rx2_datarate.clone(),
+        };
+        let mut downlink = uplink.clone();
+        downlink.routing = None;
+        if self.rx1 {
+            downlink.timestamp = uplink.timestamp.wrapping_add(JOIN_ACCEPT_DELAY1_US) & 0xffff_ffff;
+            downlink.rx2_window = Some(rx2);
+        } else {
+            downlink.timestamp = rx2.timestamp;
+            downlink.frequency = rx2.frequency;
+            downlink.datarate = rx2.datarate;
+            downlink.rx2_window = None;
+        }
+        LinkPacket {
+            gateway_mac: request.gateway_mac,
+            trace_id: request.trace_id,
+            payload: payload.into(),
+            radio: link_packet::Radio::from_datarate(&downlink.datarate),
+            antenna: Default::default(),
+            metadata: link_packet::Metadata::from_packet(&downlink),
+            seq: 0,
+            router: None,
+            received: None,
+            packet: downlink,
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::custom(format!("invalid hex: {}", s));
+    if s.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!("00FF1A", to_hex(&[0x00, 0xff, 0x1a]));
+        assert_eq!(vec![0x00, 0xff, 0x1a], from_hex("00ff1A").expect("hex"));
+        assert!(from_hex("0").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+}
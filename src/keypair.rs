@@ -1,5 +1,18 @@
 use crate::*;
-use std::{convert::TryFrom, fs, path};
+use helium_crypto::Sign;
+use helium_crypto::{KeyTag, KeyType, Network};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use signer::{Purpose, Signatures};
+use slog::{info, o, warn, Logger};
+use std::{
+    convert::TryFrom,
+    fs, path,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::time;
 
 pub type Keypair = helium_crypto::Keypair;
 pub type PublicKey = helium_crypto::PublicKey;
@@ -9,6 +22,31 @@ pub fn load_from_file(path: &str) -> error::Result<Keypair> {
     Ok(Keypair::try_from(&data[..])?)
 }
 
+/// Loads the gateway keypair, generating and saving a new one when there is
+/// no file at the path yet. Failures are key errors.
+pub fn load_or_generate(path: &str) -> Result<Keypair> {
+    match load_from_file(path) {
+        Ok(keypair) => Ok(keypair),
+        Err(Error::IO(io_error)) if io_error.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate(
+                KeyTag {
+                    network: Network::MainNet,
+                    key_type: KeyType::Ed25519,
+                },
+                &mut OsRng,
+            );
+            save_to_file(&keypair, path).map_err(|err| {
+                Error::Key(format!("unable to save key file \"{}\": {:?}", path, err))
+            })?;
+            Ok(keypair)
+        }
+        Err(err) => Err(Error::Key(format!(
+            "unable to load key file \"{}\": {:?}",
+            path, err
+        ))),
+    }
+}
+
 pub fn save_to_file(keypair: &Keypair, path: &str) -> Result {
     if let Some(parent) = path::PathBuf::from(path).parent() {
         fs::create_dir_all(parent)?;
@@ -16,3 +54,210 @@ pub fn save_to_file(keypair: &Keypair, path: &str) -> Result {
     fs::write(path, &keypair.to_bytes())?;
     Ok(())
 }
+
+/// The state of an in-progress key rotation. The state is persisted next to
+/// the keypair file so a rotation survives restarts. Until the grace period
+/// ends the gateway keeps signing with the current key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rotation {
+    /// The public key of the keypair that will replace the current one
+    pub public_key: String,
+    /// Unix time (in seconds) the rotation was started
+    pub started: u64,
+    /// The grace period (in seconds) before the new key is used
+    pub grace: u64,
+    /// Whether the new key was registered at the registration url
+    #[serde(default)]
+    pub registered: bool,
+}
+
+impl Rotation {
+    pub fn swap_at(&self) -> u64 {
+        self.started + self.grace
+    }
+
+    pub fn is_due(&self) -> bool {
+        unix_secs() >= self.swap_at()
+    }
+}
+
+pub fn next_path(path: &str) -> String {
+    format!("{}.next", path)
+}
+
+pub fn rotation_path(path: &str) -> String {
+    format!("{}.rotation", path)
+}
+
+pub fn load_rotation(path: &str) -> Result<Option<Rotation>> {
+    match fs::read(rotation_path(path)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Starts a key rotation for the keypair at the given path by generating the
+/// next keypair and persisting the rotation state. Fails if a rotation is
+/// already in progress.
+pub fn start_rotation(path: &str, grace: Duration) -> Result<(Keypair, Rotation)> {
+    if load_rotation(path)?.is_some() {
+        return Err(Error::custom("key rotation already in progress"));
+    }
+    let current = load_from_file(path)?;
+    let next = Keypair::generate(
+        KeyTag {
+            network: current.public_key().network,
+            key_type: KeyType::Ed25519,
+        },
+        &mut OsRng,
+    );
+    save_to_file(&next, &next_path(path))?;
+    let rotation = Rotation {
+        public_key: next.public_key().to_string(),
+        started: unix_secs(),
+        grace: grace.as_secs(),
+        registered: false,
+    };
+    fs::write(rotation_path(path), serde_json::to_vec(&rotation)?)?;
+    Ok((next, rotation))
+}
+
+/// Completes a rotation for the keypair at the given path if its grace
+/// period has ended, or unconditionally when forced. The previous keypair is
+/// kept with an ".old" suffix and the next keypair is moved into place with a
+/// single rename. Returns whether the keypair was swapped.
+pub fn complete_rotation(path: &str, force: bool) -> Result<bool> {
+    let rotation = match load_rotation(path)? {
+        Some(rotation) if force || rotation.is_due() => rotation,
+        _ => return Ok(false),
+    };
+    let next = next_path(path);
+    if path::Path::new(&next).exists() {
+        fs::copy(path, format!("{}.old", path))?;
+        fs::rename(&next, path)?;
+    } else if load_from_file(path)?.public_key().to_string() != rotation.public_key {
+        return Err(Error::custom(format!(
+            "next keypair for rotation to {} is missing",
+            rotation.public_key
+        )));
+    }
+    // The rename happened, possibly in an earlier interrupted attempt, so
+    // all that is left is to clear the rotation state
+    fs::remove_file(rotation_path(path))?;
+    Ok(true)
+}
+
+/// The registration of the next keypair of a rotation. The current key
+/// vouches for the new key and the new key proves possession by signing the
+/// current key.
+pub fn registration(current: &Signatures, next: &Keypair, rotation: &Rotation) -> Result<Value> {
+    let (current_key, next_key) = (current.public_key(), next.public_key());
+    Ok(json!({
+        "address": current_key.to_string(),
+        "new_address": next_key.to_string(),
+        "swap_at": rotation.swap_at(),
+        "signature": base64::encode(current.sign(Purpose::Rotation, &next_key.to_vec())?),
+        "new_signature": base64::encode(next.sign(&current_key.to_vec())?),
+    }))
+}
+
+/// How often the server checks on an in-progress rotation.
+pub const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Continues an in-progress rotation while the server runs. The new key is
+/// registered at the registration url of the settings, if any, and swapped
+/// in once the grace period has ended. With a registration url the swap
+/// waits for the registration to go through. The swap replaces the keypair
+/// of the signatures, which the router, gateway and peer services follow.
+#[derive(Debug)]
+pub struct RotationService {
+    path: String,
+    register: Option<String>,
+    args: Vec<String>,
+    signatures: Signatures,
+}
+
+impl RotationService {
+    pub fn new(settings: &Settings, signatures: Signatures) -> Self {
+        Self {
+            path: settings.keypair_path.clone(),
+            register: settings.key_rotation.register.clone(),
+            args: curl::interface_args(&settings.backhaul.interface),
+            signatures,
+        }
+    }
+
+    pub async fn run(&self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "rotation"));
+        info!(logger, "starting");
+        let mut check_timer = time::interval(ROTATION_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = check_timer.tick() => {
+                    if let Err(err) = self.check(&logger).await {
+                        warn!(logger, "failed to continue key rotation: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn check(&self, logger: &Logger) -> Result {
+        let path = &self.path;
+        let mut rotation = match load_rotation(path)? {
+            Some(rotation) => rotation,
+            None => return Ok(()),
+        };
+        // Without the next keypair the swap already happened in an
+        // interrupted attempt, and only the rotation state is left
+        let next = next_path(path);
+        if let (Some(url), true) = (&self.register, path::Path::new(&next).exists()) {
+            if !rotation.registered {
+                let body = registration(&self.signatures, &load_from_file(&next)?, &rotation)?;
+                match curl::post(url, &self.args, body.to_string(), |_| Ok(())).await {
+                    Ok(()) => {
+                        rotation.registered = true;
+                        fs::write(rotation_path(path), serde_json::to_vec(&rotation)?)?;
+                        info!(logger, "registered new key"; "new_address" => &rotation.public_key);
+                    }
+                    Err(err) => {
+                        // Retried on the next check, the current key stays
+                        // in use
+                        warn!(logger, "failed to register new key: {:?}", err);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        if complete_rotation(path, false)? {
+            self.signatures.rotate(Arc::new(load_from_file(path)?));
+            info!(logger, "rotated keypair"; "address" => &rotation.public_key);
+        }
+        Ok(())
+    }
+}
+
+/// Aborts an in-progress rotation, discarding the next keypair.
+pub fn abort_rotation(path: &str) -> Result<bool> {
+    if load_rotation(path)?.is_none() {
+        return Ok(false);
+    }
+    let next = next_path(path);
+    if path::Path::new(&next).exists() {
+        fs::remove_file(next)?;
+    }
+    fs::remove_file(rotation_path(path))?;
+    Ok(true)
+}
+
+fn unix_secs() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -1,15 +1,54 @@
+pub mod address_book;
+pub mod alerts;
+pub mod anomaly;
+pub mod api;
+pub mod audit;
+pub mod bootstrap;
+pub mod bundle;
+pub mod certification;
+pub mod cluster;
 pub mod cmd;
 pub mod curl;
+pub mod decisions;
+pub mod dutycycle;
 pub mod error;
+pub mod exit;
+pub mod feed;
+pub mod file_watch;
+pub mod fingerprint;
+pub mod forwarder_config;
 pub mod gateway;
+pub mod join_server;
 pub mod keypair;
 pub mod link_packet;
+pub mod lns;
+pub mod log_limit;
+pub mod memory;
+pub mod migration;
+pub mod mirror;
+pub mod passthrough;
+pub mod peers;
+pub mod privacy;
+pub mod push;
+pub mod region;
+pub mod relay;
 pub mod releases;
+pub mod resolve;
 pub mod router;
 pub mod server;
 pub mod service;
 pub mod settings;
+pub mod signals;
+pub mod signer;
+pub mod sntp;
+pub mod startup;
+pub mod stats;
+pub mod store;
+pub mod supervisor;
+pub mod telemetry;
+pub mod tunnel;
 pub mod updater;
+pub mod verify;
 
 pub use error::{Error, Result};
 pub use keypair::{Keypair, PublicKey};
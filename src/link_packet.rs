@@ -1,5 +1,6 @@
 use crate::*;
-use helium_crypto::Sign;
+use bytes::Bytes;
+use error::DownlinkError;
 use helium_proto::{
     blockchain_state_channel_message_v1::Msg, packet::PacketType,
     routing_information::Data as RoutingData, BlockchainStateChannelMessageV1,
@@ -7,39 +8,327 @@ use helium_proto::{
     Packet as LoraPacket, Region, RoutingInformation,
 };
 use semtech_udp::{pull_resp, push_data, CodingRate, MacAddress, Modulation, StringOrNum};
+use service::router::EnvelopeVersion;
+use settings::{DownlinkGuardSettings, MetadataSettings};
+use signer::{Priority, Signer};
+use std::{fmt, time::Instant};
+
+/// Seconds from the unix epoch to the GPS epoch (1980-01-06).
+const GPS_UNIX_OFFSET_SECS: u64 = 315_964_800;
+/// Leap seconds GPS time is ahead of UTC, unchanged since 2017.
+const GPS_LEAP_SECS: u64 = 18;
+
+/// An identifier assigned to an uplink when it is received from the packet
+/// forwarder. Downlinks produced in response to an uplink carry the same
+/// identifier so a single device exchange can be followed across modules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(u64);
+
+impl TraceId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for TraceId {
+    fn from(v: u64) -> Self {
+        Self(v)
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// The radio parameters of a packet, decomposed from its datarate and
+/// frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Radio {
+    pub spreading_factor: u32,
+    /// Bandwidth in kHz
+    pub bandwidth: u32,
+    /// The channel index in the region channel plan. Only known once the
+    /// region of the packet is known, and never for regions without a fixed
+    /// channel grid.
+    pub channel: Option<u32>,
+}
+
+impl Radio {
+    /// Decomposes a LoRa datarate. Returns None for non LoRa datarates.
+    pub fn from_datarate(datarate: &str) -> Option<Self> {
+        region::parse_lora_datarate(datarate).map(|(spreading_factor, bandwidth)| Self {
+            spreading_factor,
+            bandwidth,
+            channel: None,
+        })
+    }
+}
+
+/// The metadata of a packet in fixed units, whatever format the packet
+/// forwarder reported it in. The radio fields of the packet itself are
+/// derived from these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Metadata {
+    pub frequency_hz: u64,
+    pub rssi_dbm: i32,
+    /// Signal to noise ratio in tenths of a dB
+    pub snr_tenths: i32,
+    /// Receive time in UTC nanoseconds since the unix epoch. Only known when
+    /// the forwarder reports a GPS time.
+    pub time_ns: Option<u64>,
+}
+
+impl Metadata {
+    /// Reads and normalizes the metadata of an rxpk, rounded to the
+    /// precision of the settings.
+    pub fn from_push_data(push_data: &push_data::RxPk, settings: &MetadataSettings) -> Self {
+        let rssi = push_data
+            .get_signal_rssi()
+            .unwrap_or_else(|| push_data.get_channel_rssi());
+        // The server runtime only keeps the GPS time of v2 rxpks
+        let time_ns = match push_data {
+            push_data::RxPk::V2(rxpk) => rxpk
+                .time
+                .as_deref()
+                .and_then(parse_utc_nanos)
+                .or_else(|| rxpk.tmms.map(gps_ms_to_utc_nanos)),
+            push_data::RxPk::V1(_) => None,
+        };
+        Self {
+            frequency_hz: round_to_step(
+                mhz_to_hz(*push_data.get_frequency()) as i64,
+                settings.frequency_step_hz,
+            ) as u64,
+            // A step of 0 keeps the reported value as is
+            rssi_dbm: match settings.rssi_step_db {
+                0 => rssi,
+                step => round_to_step(db_to_tenths(rssi as f64) as i64, step * 10) as i32 / 10,
+            },
+            snr_tenths: round_to_step(
+                db_to_tenths(push_data.get_snr() as f64) as i64,
+                settings.snr_step_tenths,
+            ) as i32,
+            time_ns,
+        }
+    }
+
+    /// The metadata of a packet built elsewhere, like a downlink from a
+    /// router.
+    pub fn from_packet(packet: &LoraPacket) -> Self {
+        Self {
+            frequency_hz: mhz_to_hz(packet.frequency as f64),
+            rssi_dbm: packet.signal_strength.round() as i32,
+            snr_tenths: db_to_tenths(packet.snr as f64),
+            time_ns: None,
+        }
+    }
+
+    pub fn frequency_mhz(&self) -> f32 {
+        hz_to_mhz(self.frequency_hz)
+    }
+
+    pub fn snr(&self) -> f32 {
+        self.snr_tenths as f32 / 10.0
+    }
+}
+
+/// Converts a frequency in MHz, as forwarders report it, to Hz.
+pub fn mhz_to_hz(mhz: f64) -> u64 {
+    (mhz * 1_000_000.0).round().max(0.0) as u64
+}
+
+pub fn hz_to_mhz(hz: u64) -> f32 {
+    (hz as f64 / 1_000_000.0) as f32
+}
+
+/// Converts a level in dB to whole tenths of a dB.
+pub fn db_to_tenths(db: f64) -> i32 {
+    (db * 10.0).round() as i32
+}
+
+/// Rounds a value to the nearest multiple of the step, halves away from
+/// zero. Steps of 0 and 1 leave the value as is.
+pub fn round_to_step<S: Into<u64>>(value: i64, step: S) -> i64 {
+    let step = step.into() as i64;
+    if step <= 1 {
+        return value;
+    }
+    let rounded = (value.abs() + step / 2) / step * step;
+    rounded * value.signum()
+}
+
+/// Parses a UTC time as forwarders report it, like
+/// "2013-03-31T16:21:17.528002Z", to nanoseconds since the unix epoch.
+pub fn parse_utc_nanos(time: &str) -> Option<u64> {
+    let time = time
+        .strip_suffix('Z')
+        .or_else(|| time.strip_suffix("+00:00"))?;
+    let (date, clock) = time.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|v| v.parse::<u32>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (clock, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+    let mut clock = clock.splitn(3, ':').map(|v| v.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+        || fraction.len() > 9
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u64>().ok()? * 10u64.pow(9 - fraction.len() as u32)
+    };
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(secs * 1_000_000_000 + nanos)
+}
+
+/// Converts a GPS time in milliseconds (`tmms`) to UTC nanoseconds since the
+/// unix epoch.
+pub fn gps_ms_to_utc_nanos(tmms: u64) -> u64 {
+    (tmms + (GPS_UNIX_OFFSET_SECS - GPS_LEAP_SECS) * 1_000) * 1_000_000
+}
+
+/// Days since the unix epoch of a date in the proleptic Gregorian calendar,
+/// for years from 1970.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = u64::from(if month <= 2 { year - 1 } else { year });
+    let (era, year_of_era) = (year / 400, year % 400);
+    // Days since March 1st, which puts the leap day at the end of the year
+    let day_of_year = (153 * u64::from((month + 9) % 12) + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
 
 #[derive(Debug, Clone)]
 pub struct LinkPacket {
     pub gateway_mac: MacAddress,
+    pub trace_id: TraceId,
+    /// The packet without its payload, which is only filled in when the
+    /// packet is handed to a router
     pub packet: LoraPacket,
+    /// The PHYPayload, shared rather than copied between the stages the
+    /// packet passes through
+    pub payload: Bytes,
+    pub radio: Option<Radio>,
+    pub antenna: Antenna,
+    pub metadata: Metadata,
+    /// The position of the uplink in the order uplinks are handed to the
+    /// routers, from 1 when the gateway starts. 0 for downlinks and uplinks
+    /// that were not handed on.
+    pub seq: u64,
+    /// The uri of the router that answered with a downlink. None for
+    /// uplinks and downlinks that did not come from a router.
+    pub router: Option<String>,
+    /// When the uplink was received from the packet forwarder. None for
+    /// downlinks.
+    pub received: Option<Instant>,
+}
+
+/// Where on the concentrator an uplink was received. Each index is only known
+/// when the packet forwarder reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Antenna {
+    /// The antenna index. Reported by v2 forwarders; for v1 forwarders, which
+    /// do not report the antenna, this is the radio chain
+    pub antenna: Option<u64>,
+    /// The radio (rf chain) the packet was received on
+    pub rf_chain: Option<u64>,
+    /// The IF channel of the concentrator the packet was received on
+    pub if_chain: Option<u64>,
+}
+
+impl Antenna {
+    /// Reads the antenna fields of a v1 (`rfch`, `chan`) or v2 (`rsig`) rxpk.
+    /// A v2 rxpk does not report the radio chain.
+    pub fn from_push_data(push_data: &push_data::RxPk) -> Self {
+        match push_data {
+            push_data::RxPk::V1(rxpk) => Self {
+                antenna: Some(rxpk.rfch),
+                rf_chain: Some(rxpk.rfch),
+                if_chain: Some(rxpk.chan),
+            },
+            push_data::RxPk::V2(rxpk) => match rxpk.rsig.first() {
+                Some(rsig) => Self {
+                    antenna: Some(rsig.ant as u64),
+                    rf_chain: None,
+                    if_chain: Some(rsig.chan),
+                },
+                None => Self::default(),
+            },
+        }
+    }
 }
 
 impl LinkPacket {
-    pub fn from_push_data(push_data: &push_data::RxPk, gateway_mac: MacAddress) -> Result<Self> {
-        let rssi = push_data
-            .get_signal_rssi()
-            .unwrap_or_else(|| push_data.get_channel_rssi());
+    pub fn from_push_data(
+        push_data: &push_data::RxPk,
+        gateway_mac: MacAddress,
+        settings: &MetadataSettings,
+    ) -> Result<Self> {
+        let metadata = Metadata::from_push_data(push_data, settings);
         let packet = LoraPacket {
             r#type: PacketType::Lorawan.into(),
-            signal_strength: rssi as f32,
-            snr: push_data.get_snr() as f32,
-            frequency: *push_data.get_frequency() as f32,
+            signal_strength: metadata.rssi_dbm as f32,
+            snr: metadata.snr(),
+            frequency: metadata.frequency_mhz(),
             timestamp: *push_data.get_timestamp(),
             datarate: push_data.get_datarate().to_string(),
             routing: mk_routing_information(push_data.get_data())?,
-            payload: push_data.get_data().to_vec(),
+            payload: vec![],
             rx2_window: None,
             oui: 0,
         };
         Ok(Self {
+            payload: Bytes::copy_from_slice(push_data.get_data()),
             gateway_mac,
+            trace_id: TraceId::random(),
+            radio: Radio::from_datarate(&packet.datarate),
+            antenna: Antenna::from_push_data(push_data),
+            metadata,
+            seq: 0,
+            router: None,
+            received: Some(Instant::now()),
             packet,
         })
     }
 
+    /// Resolves the channel index of the packet in the channel plan of the
+    /// given region. Returns false if the packet frequency and datarate do
+    /// not fit the region.
+    pub fn resolve_channel(&mut self, region: Region) -> bool {
+        let fits = region::candidates(self.packet.frequency).contains(&region);
+        if let Some(radio) = &mut self.radio {
+            radio.channel = region::channel_index(region, self.packet.frequency);
+        }
+        fits && self.radio.is_some()
+    }
+
     pub fn is_longfi(&self) -> bool {
         let mut decoded = [0xFE, 65];
-        longfi::Datagram::decode(&self.packet.payload, &mut decoded).is_ok()
+        longfi::Datagram::decode(&self.payload, &mut decoded).is_ok()
+    }
+
+    /// Whether the packet is likely to be answered with a downlink in one of
+    /// its receive windows, i.e. a join request or a confirmed uplink.
+    pub fn is_downlink_critical(&self) -> bool {
+        use lorawan::MType;
+        matches!(
+            self.payload.first().map(|mhdr| MType::from(mhdr >> 5)),
+            Some(MType::JoinRequest) | Some(MType::ConfirmedUp)
+        )
     }
 
     pub fn to_pull_resp(&self, use_rx2: bool) -> Result<Option<pull_resp::TxPk>> {
@@ -65,8 +354,8 @@ impl LinkPacket {
             // for normal lorawan packets we're not selecting different frequencies
             // like we are for PoC
             freq: frequency as f64,
-            data: self.packet.payload.clone(),
-            size: self.packet.payload.len() as u64,
+            data: self.payload.to_vec(),
+            size: self.payload.len() as u64,
             powe: 27,
             rfch: 0,
             tmst: match timestamp {
@@ -80,40 +369,89 @@ impl LinkPacket {
         }))
     }
 
+    /// Validates the downlink against the downlink plan of the given region
+    /// and checks that the payload fits the datarate of each window. An
+    /// invalid rx2 window is removed so that only the rx1 window is
+    /// attempted, while an invalid rx1 window rejects the downlink. Returns
+    /// the error for the rejected or removed window.
+    pub fn validate_downlink(
+        &mut self,
+        region: Region,
+        guard: &DownlinkGuardSettings,
+    ) -> Result<Option<DownlinkError>> {
+        let len = self.payload.len();
+        region::validate_downlink(region, self.packet.frequency, &self.packet.datarate)?;
+        region::check_downlink_size(region, &self.packet.datarate, len, guard)?;
+        if let Some(rx2) = &self.packet.rx2_window {
+            if let Err(err) = region::validate_downlink(region, rx2.frequency, &rx2.datarate)
+                .and_then(|_| region::check_downlink_size(region, &rx2.datarate, len, guard))
+            {
+                self.packet.rx2_window = None;
+                return Ok(Some(err));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn from_state_channel_message(
         message: BlockchainStateChannelMessageV1,
         gateway_mac: MacAddress,
+        trace_id: TraceId,
     ) -> Option<Self> {
         match message {
             BlockchainStateChannelMessageV1 {
                 msg:
                     Some(Msg::Response(BlockchainStateChannelResponseV1 {
-                        downlink: Some(downlink),
+                        downlink: Some(mut downlink),
                         ..
                     })),
             } => Some(Self {
+                payload: Bytes::from(std::mem::take(&mut downlink.payload)),
+                radio: Radio::from_datarate(&downlink.datarate),
+                antenna: Antenna::default(),
+                metadata: Metadata::from_packet(&downlink),
+                seq: 0,
+                router: None,
+                received: None,
                 packet: downlink,
                 gateway_mac,
+                trace_id,
             }),
             _ => None,
         }
     }
 
-    pub fn to_state_channel_message(
-        &self,
-        keypair: &Keypair,
+    /// Converts the packet into a signed state channel message in the given
+    /// envelope version. The message owns its payload, so this is the one
+    /// place the shared payload of the packet is copied.
+    pub async fn into_state_channel_message(
+        self,
+        signer: &Signer,
         region: Region,
+        version: EnvelopeVersion,
     ) -> Result<BlockchainStateChannelMessageV1> {
+        let priority = if self.is_downlink_critical() {
+            Priority::DownlinkCritical
+        } else {
+            Priority::Normal
+        };
+        let mut packet = self.packet;
+        packet.payload = self.payload.to_vec();
         let mut router_packet = BlockchainStateChannelPacketV1 {
-            packet: Some(self.packet.clone()),
+            packet: Some(packet),
             signature: vec![],
-            hotspot: keypair.public_key().to_bytes().to_vec(),
+            hotspot: signer.public_key().to_bytes().to_vec(),
             region: region.into(),
             hold_time: 0,
         };
+        if version == EnvelopeVersion::V1 {
+            // Fields at their default value are not encoded
+            router_packet.region = Default::default();
+            router_packet.hold_time = Default::default();
+        }
         let mut encoded = vec![];
         router_packet.encode(&mut encoded)?;
-        router_packet.signature = keypair.sign(&encoded)?;
+        router_packet.signature = signer.sign(encoded, priority).await?;
         let message = BlockchainStateChannelMessageV1 {
             msg: Some(Msg::Packet(router_packet)),
         };
@@ -141,3 +479,168 @@ pub fn mk_routing_information(payload: &[u8]) -> Result<Option<RoutingInformatio
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        for _ in 0..1_000 {
+            let hz = rand::random::<u64>() % 1_000_000_000;
+            assert_eq!(hz, mhz_to_hz(hz as f64 / 1_000_000.0));
+            let tenths = rand::random::<i32>() % 1_000;
+            assert_eq!(tenths, db_to_tenths(tenths as f64 / 10.0));
+            let step = 1 + rand::random::<u32>() % 1_000;
+            let value = rand::random::<i32>() as i64;
+            let rounded = round_to_step(value, step);
+            assert_eq!(0, rounded % step as i64);
+            assert!((rounded - value).abs() <= step as i64 / 2);
+        }
+        assert_eq!(-120, round_to_step(-115, 10u32));
+        let rssi_dbm = |rssi_step_db| {
+            let settings = MetadataSettings {
+                frequency_step_hz: 1,
+                rssi_step_db,
+                snr_step_tenths: 1,
+            };
+            let rxpk: push_data::RxPk = serde_json::from_str(
+                r#"{"tmst":1,"chan":0,"rfch":0,"freq":868.1,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-117,"lsnr":5.1,"size":1,"data":"AA=="}"#,
+            )
+            .expect("rxpk");
+            Metadata::from_push_data(&rxpk, &settings).rssi_dbm
+        };
+        assert_eq!(-117, rssi_dbm(0));
+        assert_eq!(-117, rssi_dbm(1));
+        assert_eq!(-115, rssi_dbm(5));
+        assert_eq!(Some(0), parse_utc_nanos("1970-01-01T00:00:00Z"));
+        assert_eq!(
+            Some(1_364_746_877_528_002_000),
+            parse_utc_nanos("2013-03-31T16:21:17.528002Z")
+        );
+        assert_eq!(
+            Some(1_709_251_199_000_000_000),
+            parse_utc_nanos("2024-02-29T23:59:59+00:00")
+        );
+        assert_eq!(None, parse_utc_nanos("2024-02-29 23:59:59"));
+        assert_eq!(315_964_782_000_000_000, gps_ms_to_utc_nanos(0));
+    }
+
+    #[test]
+    fn time() {
+        let settings = MetadataSettings {
+            frequency_step_hz: 1,
+            rssi_step_db: 1,
+            snr_step_tenths: 1,
+        };
+        let time_ns = |rxpk: &str| {
+            let rxpk: push_data::RxPk = serde_json::from_str(rxpk).expect("rxpk");
+            Metadata::from_push_data(&rxpk, &settings).time_ns
+        };
+        let v2 = r#""aesk":0,"brd":0,"codr":"4/5","data":"AA==","datr":"SF7BW125","freq":868.1,"jver":2,"modu":"LORA","rsig":[{"ant":0,"chan":0,"rssic":-80,"lsnr":5.5}],"size":1,"stat":1,"tmst":1000"#;
+        assert_eq!(
+            Some(1_364_746_877_528_002_000),
+            time_ns(&format!(
+                r#"{{{},"time":"2013-03-31T16:21:17.528002Z","tmms":0}}"#,
+                v2
+            ))
+        );
+        assert_eq!(
+            Some(315_964_782_000_000_000),
+            time_ns(&format!(r#"{{{},"tmms":0}}"#, v2))
+        );
+        assert_eq!(None, time_ns(&format!("{{{}}}", v2)));
+    }
+
+    #[test]
+    fn antenna() {
+        let antenna = |rxpk: &str| {
+            let rxpk: push_data::RxPk = serde_json::from_str(rxpk).expect("rxpk");
+            Antenna::from_push_data(&rxpk)
+        };
+        assert_eq!(
+            Antenna {
+                antenna: Some(1),
+                rf_chain: Some(1),
+                if_chain: Some(5),
+            },
+            antenna(
+                r#"{"tmst":1,"chan":5,"rfch":1,"freq":868.1,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-35,"lsnr":5.1,"size":1,"data":"AA=="}"#
+            )
+        );
+        assert_eq!(
+            Antenna {
+                antenna: Some(2),
+                rf_chain: None,
+                if_chain: Some(3),
+            },
+            antenna(
+                r#"{"aesk":0,"brd":0,"codr":"4/5","data":"AA==","datr":"SF7BW125","freq":868.1,"jver":2,"modu":"LORA","rsig":[{"ant":2,"chan":3,"rssic":-80,"lsnr":5.5}],"size":1,"stat":1,"tmst":1000}"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn envelope() {
+        use helium_crypto::{KeyTag, KeyType, Network};
+        use settings::SigningSettings;
+        use signer::Signatures;
+        use std::sync::Arc;
+
+        let keypair = Arc::new(Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut rand::rngs::OsRng,
+        ));
+        let signatures = Signatures::with_limits(
+            keypair,
+            &SigningSettings {
+                uplink_per_minute: 0,
+                peer_per_minute: 0,
+            },
+        );
+        let (signer, mut service) = signer::signer(signatures);
+        let (shutdown_trigger, shutdown) = triggered::trigger();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        tokio::spawn(async move { service.run(shutdown, &logger).await });
+
+        let rxpk: push_data::RxPk = serde_json::from_str(
+            r#"{"tmst":1,"chan":0,"rfch":0,"freq":868.1,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-35,"lsnr":5.1,"size":14,"data":"QAECAwQAAQABqgECAwQ="}"#,
+        )
+        .expect("rxpk");
+        let settings = MetadataSettings {
+            frequency_step_hz: 1,
+            rssi_step_db: 1,
+            snr_step_tenths: 1,
+        };
+        let packet =
+            LinkPacket::from_push_data(&rxpk, MacAddress::new(&[0; 8]), &settings).expect("packet");
+        let envelope = |message: &BlockchainStateChannelMessageV1| match &message.msg {
+            Some(Msg::Packet(envelope)) => envelope.clone(),
+            other => panic!("unexpected message: {:?}", other),
+        };
+
+        let v2 = packet
+            .clone()
+            .into_state_channel_message(&signer, Region::Eu868, EnvelopeVersion::V2)
+            .await
+            .expect("v2");
+        assert_eq!(Region::Eu868 as i32, envelope(&v2).region);
+        let verified = verify::verify_message(&v2, None).expect("verified");
+        assert_eq!(Region::Eu868, verified.region);
+
+        // Version 1 leaves the region and hold time out of the signed
+        // encoding, so the region to check against has to be given
+        let v1 = packet
+            .into_state_channel_message(&signer, Region::Eu868, EnvelopeVersion::V1)
+            .await
+            .expect("v1");
+        assert_eq!(0, envelope(&v1).region);
+        assert_eq!(0, envelope(&v1).hold_time);
+        let verified = verify::verify_message(&v1, Some(Region::Eu868)).expect("verified");
+        assert_eq!(signer.public_key(), verified.hotspot);
+        shutdown_trigger.trigger();
+    }
+}
@@ -0,0 +1,509 @@
+use crate::*;
+use gateway::sessions::Timing;
+use helium_proto::routing_information::Data as RoutingData;
+use link_packet::LinkPacket;
+use relay::Upstreams;
+use semtech_udp::{pull_resp, push_data, tx_ack, MacAddress, StringOrNum};
+use serde_json::json;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time,
+};
+
+/// Maximum number of frames for the network server queued before uplinks
+/// for it are dropped.
+pub const LNS_QUEUE_SIZE: usize = 64;
+/// Seconds between PULL_DATA keepalives to the network server for each
+/// packet forwarder.
+pub const PULL_INTERVAL_SECS: u64 = 10;
+/// Downlinks from different sources for the same forwarder are considered to
+/// collide when their concentrator timestamps are closer than this, the
+/// longest LoRaWAN downlink airtime.
+pub const CLAIM_GUARD_US: u64 = 2_000_000;
+/// Claimed transmit slots are forgotten after this long.
+pub const CLAIM_MAX_AGE_SECS: u64 = 10;
+
+const PROTOCOL_VERSION: u8 = 0x02;
+const PUSH_DATA: u8 = 0x00;
+const PULL_DATA: u8 = 0x02;
+const PULL_RESP: u8 = 0x03;
+const TX_ACK: u8 = 0x05;
+
+/// A DevAddr prefix, written as "<hex devaddr>/<bits>".
+#[derive(Debug, Clone, Copy)]
+pub struct DevAddrRange {
+    prefix: u32,
+    bits: u32,
+}
+
+impl DevAddrRange {
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || Error::custom(format!("invalid devaddr range: {}", s));
+        let (prefix, bits) = s.split_once('/').ok_or_else(invalid)?;
+        let prefix = u32::from_str_radix(prefix, 16).map_err(|_| invalid())?;
+        let bits: u32 = bits.parse().map_err(|_| invalid())?;
+        if bits > 32 {
+            return Err(invalid());
+        }
+        Ok(Self { prefix, bits })
+    }
+
+    pub fn contains(&self, dev_addr: u32) -> bool {
+        ((dev_addr ^ self.prefix) as u64) >> (32 - self.bits) == 0
+    }
+
+    /// Picks a random DevAddr in the range.
+    pub fn random(&self) -> u32 {
+        let host_mask = (u32::MAX as u64 >> self.bits) as u32;
+        (self.prefix & !host_mask) | (rand::random::<u32>() & host_mask)
+    }
+}
+
+/// Which uplinks are forwarded to the network server.
+#[derive(Debug, Default)]
+struct Filter {
+    dev_addrs: Vec<DevAddrRange>,
+    join_euis: Vec<u64>,
+}
+
+impl Filter {
+    fn new(settings: &settings::LnsSettings) -> Result<Self> {
+        let dev_addrs = settings
+            .dev_addrs
+            .iter()
+            .map(|range| DevAddrRange::parse(range))
+            .collect::<Result<_>>()?;
+        let join_euis = settings
+            .join_euis
+            .iter()
+            .map(|eui| {
+                u64::from_str_radix(eui, 16)
+                    .map_err(|_| Error::custom(format!("invalid join eui: {}", eui)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            dev_addrs,
+            join_euis,
+        })
+    }
+
+    /// Without any ranges or join euis all uplinks match.
+    fn matches(&self, packet: &LinkPacket) -> bool {
+        if self.dev_addrs.is_empty() && self.join_euis.is_empty() {
+            return true;
+        }
+        match packet.packet.routing.as_ref().and_then(|r| r.data.as_ref()) {
+            Some(RoutingData::Devaddr(dev_addr)) => {
+                self.dev_addrs.iter().any(|range| range.contains(*dev_addr))
+            }
+            Some(RoutingData::Eui(eui)) => self.join_euis.contains(&eui.appeui),
+            None => false,
+        }
+    }
+}
+
+/// A downlink requested by the network server.
+#[derive(Debug)]
+pub struct LnsDownlink {
+    pub gateway_mac: MacAddress,
+    /// The PULL_RESP token, echoed in the TX_ACK
+    pub token: u16,
+    pub txpk: pull_resp::TxPk,
+}
+
+#[derive(Debug)]
+enum Up {
+    Uplink(MacAddress, serde_json::Value),
+    Forwarder(MacAddress),
+    TxAck(MacAddress, u16, serde_json::Value),
+}
+
+/// Creates the handle the gateway forwards uplinks to a private network
+/// server with, and the service speaking GWMP to it.
+pub fn lns(settings: &Settings) -> Result<(Lns, LnsService)> {
+    let lns = &settings.lns;
+    if !lns.enabled {
+        return Ok((Lns::disabled(), LnsService { state: None }));
+    }
+    let upstream = lns
+        .upstream
+        .as_ref()
+        .ok_or_else(|| Error::custom("lns enabled without an upstream"))?;
+    let upstream = upstream
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::custom(format!("unresolved lns address: {}", upstream)))?;
+    let (up_sender, up_receiver) = mpsc::channel(LNS_QUEUE_SIZE);
+    let (down_sender, down_receiver) = mpsc::channel(LNS_QUEUE_SIZE);
+    Ok((
+        Lns {
+            sender: Some(up_sender),
+            downlinks: Some(down_receiver),
+            filter: Filter::new(lns)?,
+            exclusive: lns.exclusive,
+        },
+        LnsService {
+            state: Some((upstream, up_receiver, down_sender)),
+        },
+    ))
+}
+
+/// The gateway side of a private network server connection. Uplinks are
+/// forwarded next to, or with `exclusive` instead of, routing them over the
+/// Helium network. Forwarding never blocks the packet path; uplinks are
+/// dropped when the network server connection falls behind.
+#[derive(Debug)]
+pub struct Lns {
+    sender: Option<Sender<Up>>,
+    downlinks: Option<Receiver<LnsDownlink>>,
+    filter: Filter,
+    exclusive: bool,
+}
+
+impl Lns {
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            downlinks: None,
+            filter: Filter::default(),
+            exclusive: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Forwards an uplink if it matches the filter. Returns whether the
+    /// uplink was taken exclusively and is not to be routed over the Helium
+    /// network.
+    pub fn uplink(&self, rxpk: &push_data::RxPk, packet: &LinkPacket) -> bool {
+        let sender = match &self.sender {
+            Some(sender) if self.filter.matches(packet) => sender,
+            _ => return false,
+        };
+        if let Ok(rxpk) = serde_json::to_value(rxpk) {
+            let _ = sender.try_send(Up::Uplink(packet.gateway_mac, rxpk));
+        }
+        self.exclusive
+    }
+
+    /// Registers a forwarder with the network server so it can receive
+    /// downlinks before its first uplink.
+    pub fn pull_data(&self, mac: MacAddress) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Up::Forwarder(mac));
+        }
+    }
+
+    /// Reports the outcome of a network server downlink, None when it was
+    /// sent.
+    pub fn tx_ack(&self, mac: MacAddress, token: u16, error: Option<String>) {
+        let ack = json!({ "error": error.as_deref().unwrap_or("NONE") });
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Up::TxAck(mac, token, ack));
+        }
+    }
+
+    /// Reports a network server downlink that was sent with less than the
+    /// requested power, with the power used, like a packet forwarder does.
+    pub fn tx_ack_power(&self, mac: MacAddress, token: u16, power: u64) {
+        let ack = json!({ "warn": "TX_POWER", "value": power });
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Up::TxAck(mac, token, ack));
+        }
+    }
+
+    /// Reports a network server downlink the forwarder received too late,
+    /// with the timing that made it miss when it is reported.
+    pub fn tx_ack_too_late(&self, mac: MacAddress, token: u16, timing: Option<Timing>) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Up::TxAck(mac, token, too_late_ack(timing)));
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<LnsDownlink> {
+        self.downlinks.as_mut()?.recv().await
+    }
+}
+
+/// The source of a downlink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Helium,
+    Lns,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Helium => f.write_str("helium"),
+            Self::Lns => f.write_str("lns"),
+        }
+    }
+}
+
+/// Arbitrates the transmitter of each forwarder between the Helium router
+/// and the network server. The first source to claim a transmit time wins,
+/// and a downlink from the other source that would overlap it is refused.
+#[derive(Debug, Default)]
+pub struct Arbiter {
+    claims: HashMap<MacAddress, Vec<(Source, u64, Instant)>>,
+}
+
+impl Arbiter {
+    /// Claims the transmitter at the given concentrator timestamp, returning
+    /// the source holding a colliding claim if there is one.
+    pub fn claim(&mut self, mac: MacAddress, source: Source, tmst: u64) -> Option<Source> {
+        let claims = self.claims.entry(mac).or_default();
+        claims
+            .retain(|(_, _, claimed)| claimed.elapsed() < Duration::from_secs(CLAIM_MAX_AGE_SECS));
+        if let Some(holder) = self.holder(&mac, source, tmst) {
+            return Some(holder);
+        }
+        self.claims
+            .entry(mac)
+            .or_default()
+            .push((source, tmst, Instant::now()));
+        None
+    }
+
+    /// The other source holding a claim that collides with the given
+    /// concentrator timestamp, without claiming it.
+    pub fn holder(&self, mac: &MacAddress, source: Source, tmst: u64) -> Option<Source> {
+        let max_age = Duration::from_secs(CLAIM_MAX_AGE_SECS);
+        // Concentrator timestamps are a 32 bit microsecond counter
+        self.claims
+            .get(mac)?
+            .iter()
+            .find(|(holder, claimed, at)| {
+                let distance = tmst.wrapping_sub(*claimed) & 0xffff_ffff;
+                *holder != source
+                    && at.elapsed() < max_age
+                    && distance.min(0x1_0000_0000 - distance) < CLAIM_GUARD_US
+            })
+            .map(|(holder, _, _)| *holder)
+    }
+
+    pub fn expire(&mut self) {
+        let max_age = Duration::from_secs(CLAIM_MAX_AGE_SECS);
+        self.claims.retain(|_, claims| {
+            claims.retain(|(_, _, claimed)| claimed.elapsed() < max_age);
+            !claims.is_empty()
+        });
+    }
+}
+
+/// The concentrator timestamp a network server downlink is sent at, None
+/// for immediate downlinks.
+pub fn txpk_timestamp(txpk: &pull_resp::TxPk) -> Option<u64> {
+    match &txpk.tmst {
+        StringOrNum::N(tmst) => Some(*tmst),
+        StringOrNum::S(_) => None,
+    }
+}
+
+/// The GWMP name of a tx_ack error, like TOO_LATE for TooLate.
+pub fn ack_error_name(error: &tx_ack::Error) -> String {
+    let mut name = String::new();
+    for (i, c) in format!("{:?}", error).chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+
+/// The tx_ack of a downlink that was too late. The timing is added next to
+/// the TOO_LATE error, where network servers that don't know it ignore it.
+pub fn too_late_ack(timing: Option<Timing>) -> serde_json::Value {
+    let mut ack = json!({ "error": ack_error_name(&tx_ack::Error::TooLate) });
+    if let Some(timing) = timing {
+        ack["deadline_ms"] = timing.deadline_ms.into();
+        ack["elapsed_ms"] = timing.elapsed_ms.into();
+        ack["budget_ms"] = timing.budget_ms.into();
+    }
+    ack
+}
+
+/// Speaks GWMP to the network server on behalf of each packet forwarder,
+/// each with its own socket so the network server sees separate gateways.
+#[derive(Debug)]
+pub struct LnsService {
+    state: Option<(SocketAddr, Receiver<Up>, Sender<LnsDownlink>)>,
+}
+
+impl LnsService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "lns"));
+        let (upstream, mut ups, downlinks) = match self.state.take() {
+            Some(state) => state,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "upstream" => upstream.to_string());
+        let (mut forwarders, mut replies) = Upstreams::new(upstream);
+        let mut pull_timer = time::interval(Duration::from_secs(PULL_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                Some(up) = ups.recv() => {
+                    let (mac, frame) = match up {
+                        Up::Uplink(mac, rxpk) => {
+                            let payload = json!({ "rxpk": [rxpk] }).to_string();
+                            (mac, frame(PUSH_DATA, rand::random(), &mac, payload.as_bytes()))
+                        }
+                        Up::Forwarder(mac) if forwarders.contains(&mac) => continue,
+                        Up::Forwarder(mac) => (mac, frame(PULL_DATA, rand::random(), &mac, &[])),
+                        Up::TxAck(mac, token, ack) => {
+                            let payload = json!({ "txpk_ack": ack }).to_string();
+                            (mac, frame(TX_ACK, token, &mac, payload.as_bytes()))
+                        }
+                    };
+                    match forwarders.connect(&mac, &logger).await {
+                        Ok(true) => info!(logger, "connected {} to the network server", mac),
+                        Ok(false) => (),
+                        Err(err) => {
+                            warn!(logger, "failed to connect {} to the network server: {:?}", mac, err);
+                            continue;
+                        }
+                    }
+                    if let Err(err) = forwarders.send(&mac, &frame).await {
+                        warn!(logger, "failed to send to network server for {}: {:?}", mac, err);
+                    }
+                },
+                Some((mac, frame)) = replies.recv() => {
+                    if let Some(downlink) = pull_resp(&logger, mac, &frame) {
+                        // The gateway is gone when shutting down
+                        let _ = downlinks.send(downlink).await;
+                    }
+                },
+                _ = pull_timer.tick() => {
+                    for mac in forwarders.keys() {
+                        let frame = frame(PULL_DATA, rand::random(), &mac, &[]);
+                        if let Err(err) = forwarders.send(&mac, &frame).await {
+                            warn!(logger, "failed to send keepalive for {}: {:?}", mac, err);
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// The downlink of a PULL_RESP frame from the network server, which is the
+/// only frame the gateway acts on.
+fn pull_resp(logger: &Logger, mac: MacAddress, frame: &[u8]) -> Option<LnsDownlink> {
+    match frame {
+        [_, token_hi, token_lo, PULL_RESP, payload @ ..] => {
+            match serde_json::from_slice::<PullRespPayload>(payload) {
+                Ok(PullRespPayload { txpk }) => Some(LnsDownlink {
+                    gateway_mac: mac,
+                    token: u16::from_be_bytes([*token_hi, *token_lo]),
+                    txpk,
+                }),
+                Err(err) => {
+                    warn!(logger, "ignoring invalid pull_resp for {}: {:?}", mac, err);
+                    None
+                }
+            }
+        }
+        frame => {
+            debug!(
+                logger,
+                "network server frame for {}: {:?}",
+                mac,
+                frame.get(3)
+            );
+            None
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PullRespPayload {
+    txpk: pull_resp::TxPk,
+}
+
+/// Builds an upstream GWMP frame, which carries the gateway MAC after the
+/// frame type.
+fn frame(kind: u8, token: u16, mac: &MacAddress, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend_from_slice(&token.to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&mac_bytes(mac));
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn mac_bytes(mac: &MacAddress) -> [u8; 8] {
+    let hex: String = mac
+        .to_string()
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect();
+    u64::from_str_radix(&hex, 16).unwrap_or(0).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arbitrate() {
+        let mut arbiter = Arbiter::default();
+        let mac = MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(None, arbiter.claim(mac, Source::Helium, 1_000_000));
+        // The same source can use both receive windows
+        assert_eq!(None, arbiter.claim(mac, Source::Helium, 2_000_000));
+        assert_eq!(
+            Some(Source::Helium),
+            arbiter.claim(mac, Source::Lns, 1_000_000)
+        );
+        assert_eq!(None, arbiter.claim(mac, Source::Lns, 5_000_000));
+        // Timestamps wrap around at 32 bits
+        assert_eq!(
+            Some(Source::Helium),
+            arbiter.claim(mac, Source::Lns, 0x1_0000_0000 - 100)
+        );
+
+        let range = DevAddrRange::parse("26011000/20").expect("range");
+        assert!(range.contains(0x2601_1fff));
+        assert!(!range.contains(0x2601_2000));
+        assert!(DevAddrRange::parse("0/0")
+            .expect("range")
+            .contains(0xffff_ffff));
+        assert!(range.contains(range.random()));
+    }
+
+    #[test]
+    fn too_late() {
+        assert_eq!(json!({ "error": "TOO_LATE" }), too_late_ack(None));
+        let timing = Timing {
+            deadline_ms: 1000,
+            elapsed_ms: 1040,
+            budget_ms: -40,
+        };
+        assert_eq!(
+            json!({
+                "error": "TOO_LATE",
+                "deadline_ms": 1000,
+                "elapsed_ms": 1040,
+                "budget_ms": -40,
+            }),
+            too_late_ack(Some(timing))
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Entry {
+    since: Instant,
+    suppressed: u64,
+}
+
+/// Collapses repeated warnings into periodic summaries. The first warning of
+/// a kind is logged, further warnings of the same kind within the interval
+/// are only counted and reported by `summaries`. This keeps high rate error
+/// conditions, like a scanner sending junk to the packet forwarder port, from
+/// flooding the log.
+#[derive(Debug)]
+pub struct LogLimiter {
+    interval: Duration,
+    entries: HashMap<&'static str, Entry>,
+}
+
+impl LogLimiter {
+    /// Creates a limiter for the given interval. A zero interval disables
+    /// limiting.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Whether a warning of the given kind should be logged. Suppressed
+    /// warnings are counted.
+    pub fn allow(&mut self, kind: &'static str) -> bool {
+        if self.interval == Duration::from_secs(0) {
+            return true;
+        }
+        match self.entries.get_mut(kind) {
+            Some(entry) if entry.since.elapsed() < self.interval => {
+                entry.suppressed += 1;
+                false
+            }
+            // Restart the interval, keeping counts not yet summarized
+            Some(entry) => {
+                entry.since = Instant::now();
+                true
+            }
+            None => {
+                self.entries.insert(
+                    kind,
+                    Entry {
+                        since: Instant::now(),
+                        suppressed: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Returns the number of suppressed warnings by kind for the kinds whose
+    /// interval has passed, and forgets them.
+    pub fn summaries(&mut self) -> Vec<(&'static str, u64)> {
+        let interval = self.interval;
+        let mut summaries = Vec::new();
+        self.entries.retain(|kind, entry| {
+            if entry.since.elapsed() < interval {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                summaries.push((*kind, entry.suppressed));
+            }
+            false
+        });
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapse() {
+        let mut limiter = LogLimiter::new(Duration::from_millis(50));
+        assert!(limiter.allow("junk"));
+        assert!(!limiter.allow("junk"));
+        assert!(!limiter.allow("junk"));
+        assert!(limiter.allow("other"));
+        assert!(limiter.summaries().is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(vec![("junk", 2)], limiter.summaries());
+        assert!(limiter.allow("junk"));
+    }
+}
@@ -1,10 +1,12 @@
 use gateway_rs::{
     cmd,
     error::Result,
-    settings::{LogMethod, Settings},
+    exit::ExitReason,
+    settings::{LogMethod, RuntimeFlavor, Settings},
+    signals::{self, LogSwitch, SwitchedLevel},
 };
-use slog::{self, o, Drain, Logger};
-use std::{io, path::PathBuf};
+use slog::{self, error, info, o, Drain, Logger};
+use std::{io, path::PathBuf, process};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -19,6 +21,11 @@ pub struct Cli {
     #[structopt(long)]
     daemon: bool,
 
+    /// Settings profile to apply, one of the `[profile.<name>]` tables in the
+    /// settings. Overrides the GW_PROFILE environment variable.
+    #[structopt(long)]
+    profile: Option<String>,
+
     #[structopt(subcommand)]
     cmd: Cmd,
 }
@@ -29,6 +36,21 @@ pub enum Cmd {
     Update(cmd::update::Cmd),
     Server(cmd::server::Cmd),
     Add(Box<cmd::add::Cmd>),
+    AddressBook(cmd::address_book::Cmd),
+    Tunnel(cmd::tunnel::Cmd),
+    Doctor(cmd::doctor::Cmd),
+    Bench(cmd::bench::Cmd),
+    Migrate(cmd::migrate::Cmd),
+    Info(cmd::info::Cmd),
+    Downlinks(cmd::downlinks::Cmd),
+    Decisions(cmd::decisions::Cmd),
+    Audit(cmd::audit::Cmd),
+    Stats(cmd::stats::Cmd),
+    Dutycycle(cmd::dutycycle::Cmd),
+    Cw(cmd::cw::Cmd),
+    Modtest(cmd::modtest::Cmd),
+    Export(cmd::export::Cmd),
+    Import(cmd::import::Cmd),
 }
 
 /// An emptye timestamp function for when timestamp should not be included in
@@ -38,16 +60,20 @@ fn timestamp_none(_io: &mut dyn io::Write) -> io::Result<()> {
     Ok(())
 }
 
-fn mk_logger(settings: &Settings) -> Logger {
+fn mk_logger(settings: &Settings, switch: &LogSwitch) -> Logger {
     let async_drain = match settings.log.method {
         LogMethod::Syslog => {
             let drain = slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)
                 .unwrap()
                 .fuse();
-            slog_async::Async::new(drain)
-                .build()
-                .filter_level(settings.log.level)
-                .fuse()
+            let drain = slog_async::Async::new(drain).build();
+            SwitchedLevel::new(
+                drain,
+                settings.log.level,
+                settings.log.modules.clone(),
+                switch.clone(),
+            )
+            .fuse()
         }
         LogMethod::Stdio => {
             let decorator = slog_term::PlainDecorator::new(io::stdout());
@@ -60,16 +86,20 @@ fn mk_logger(settings: &Settings) -> Logger {
                 .use_custom_timestamp(timestamp)
                 .build()
                 .fuse();
-            slog_async::Async::new(drain)
-                .build()
-                .filter_level(settings.log.level)
-                .fuse()
+            let drain = slog_async::Async::new(drain).build();
+            SwitchedLevel::new(
+                drain,
+                settings.log.level,
+                settings.log.modules.clone(),
+                switch.clone(),
+            )
+            .fuse()
         }
     };
     slog::Logger::root(async_drain, o!())
 }
 
-pub fn main() -> Result {
+pub fn main() {
     let cli = Cli::from_args();
     if cli.daemon {
         daemonize::Daemonize::new()
@@ -78,36 +108,104 @@ pub fn main() -> Result {
             .expect("daemon start");
     }
 
-    let settings = Settings::new(&cli.config)?;
-    let logger = mk_logger(&settings);
+    let settings = match Settings::with_profile(&cli.config, cli.profile.as_deref()) {
+        Ok(settings) => settings,
+        Err(err) => {
+            // Without settings there is no configured logger, log to stderr
+            let decorator = slog_term::PlainSyncDecorator::new(io::stderr());
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            let logger = slog::Logger::root(drain, o!());
+            let reason = ExitReason::from_error(&err);
+            error!(logger, "exiting";
+                "reason" => reason.name(),
+                "code" => reason.code(),
+                "error" => format!("{:?}", err));
+            process::exit(reason.code());
+        }
+    };
+    let log_switch = LogSwitch::default();
+    let logger = mk_logger(&settings, &log_switch);
     let scope_guard = slog_scope::set_global_logger(logger);
     let run_logger = slog_scope::logger().new(o!());
+    let exit_logger = slog_scope::logger().new(o!());
     // Start the runtime after the daemon fork
-    let res = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(async {
+    let mut runtime = match settings.runtime.flavor {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if settings.runtime.workers > 0 {
+                builder.worker_threads(settings.runtime.workers);
+            }
+            builder
+        }
+    };
+    let (res, signal) = match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(async {
             let (shutdown_trigger, shutdown_listener) = triggered::trigger();
-            tokio::spawn(async move {
-                let _ = tokio::signal::ctrl_c().await;
+            let signal = tokio::spawn(async move {
+                let signal = signals::shutdown_signal().await;
                 shutdown_trigger.trigger();
+                signal
             });
-            run(cli, settings, &shutdown_listener, run_logger).await
-        });
+            let res = run(cli, settings, &shutdown_listener, log_switch, run_logger).await;
+            // Only a signal triggers the shutdown, and the signal task ends
+            // right after triggering it
+            let signal = if shutdown_listener.is_triggered() {
+                signal.await.ok()
+            } else {
+                None
+            };
+            (res, signal)
+        }),
+        Err(err) => (Err(err.into()), None),
+    };
+    // A final record with the exit reason for supervisors and log collectors
+    let reason = ExitReason::from_result(&res, signal);
+    match &res {
+        Ok(()) => info!(exit_logger, "exiting";
+            "reason" => reason.name(),
+            "signal" => signal,
+            "code" => reason.code()),
+        Err(err) => error!(exit_logger, "exiting";
+            "reason" => reason.name(),
+            "code" => reason.code(),
+            "error" => format!("{:?}", err)),
+    }
+    // Flush the log before exiting, which skips destructors
+    drop(exit_logger);
     drop(scope_guard);
-    res
+    process::exit(reason.code());
 }
 
 pub async fn run(
     cli: Cli,
     settings: Settings,
     shutdown_listener: &triggered::Listener,
+    log_switch: LogSwitch,
     logger: Logger,
 ) -> Result {
     match cli.cmd {
         Cmd::Key(cmd) => cmd.run(settings).await,
         Cmd::Update(cmd) => cmd.run(settings).await,
         Cmd::Add(cmd) => cmd.run(settings).await,
-        Cmd::Server(cmd) => cmd.run(shutdown_listener, settings, &logger).await,
+        Cmd::AddressBook(cmd) => cmd.run(settings).await,
+        Cmd::Doctor(cmd) => cmd.run(settings).await,
+        Cmd::Bench(cmd) => cmd.run(settings).await,
+        Cmd::Migrate(cmd) => cmd.run(settings).await,
+        Cmd::Info(cmd) => cmd.run(settings).await,
+        Cmd::Downlinks(cmd) => cmd.run(settings).await,
+        Cmd::Decisions(cmd) => cmd.run(settings).await,
+        Cmd::Audit(cmd) => cmd.run(settings).await,
+        Cmd::Stats(cmd) => cmd.run(settings).await,
+        Cmd::Dutycycle(cmd) => cmd.run(settings).await,
+        Cmd::Cw(cmd) => cmd.run(settings).await,
+        Cmd::Modtest(cmd) => cmd.run(settings).await,
+        Cmd::Export(cmd) => cmd.run(settings).await,
+        Cmd::Import(cmd) => cmd.run(settings).await,
+        Cmd::Tunnel(cmd) => cmd.run(shutdown_listener, settings, &logger).await,
+        Cmd::Server(cmd) => {
+            cmd.run(shutdown_listener, settings, log_switch, &logger)
+                .await
+        }
     }
 }
@@ -0,0 +1,49 @@
+use crate::*;
+use settings::MemorySettings;
+
+/// Approximate in-memory size of a packet waiting in one of the packet
+/// queues, including its payload.
+pub const PACKET_SIZE_ESTIMATE: usize = 512;
+
+/// A memory budget for the caches and queues of the gateway. The configured
+/// total is partitioned across the routing table and the packet queues so
+/// the service can stay within the RAM limits of small devices.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBudget {
+    total: usize,
+    routing_share: u8,
+    queue_share: u8,
+}
+
+impl MemoryBudget {
+    pub fn new(settings: &MemorySettings) -> Result<Self> {
+        if settings.routing_share as u16 + settings.queue_share as u16 > 100 {
+            return Err(Error::custom(
+                "memory budget shares add up to more than 100 percent",
+            ));
+        }
+        Ok(Self {
+            total: settings.budget * 1024,
+            routing_share: settings.routing_share,
+            queue_share: settings.queue_share,
+        })
+    }
+
+    /// The number of bytes available to the routing table.
+    pub fn routing_bytes(&self) -> usize {
+        self.share(self.routing_share)
+    }
+
+    /// The capacity to use for a packet queue with the given default
+    /// capacity. The queue budget is split across the given number of queues
+    /// and only ever lowers the capacity, since deeper queues would just add
+    /// latency to the packet path.
+    pub fn queue_capacity(&self, default: usize, queues: usize) -> usize {
+        let budget = self.share(self.queue_share) / queues.max(1) / PACKET_SIZE_ESTIMATE;
+        budget.min(default).max(1)
+    }
+
+    fn share(&self, percent: u8) -> usize {
+        self.total / 100 * percent as usize
+    }
+}
@@ -0,0 +1,146 @@
+//! Migrates settings files written for older settings layouts to the current
+//! layout.
+//!
+//! Only `settings.toml` is migrated; `default.toml` ships with the release and
+//! the generated overlays are always current. Migration happens in memory on
+//! every load until the migrated file is written back with the `migrate`
+//! command.
+
+use crate::*;
+use std::{fs, path::Path};
+use toml::{value::Table, Value};
+
+/// The current settings layout version, stored as `version` in migrated
+/// files. Files without a version are version 1.
+pub const SETTINGS_VERSION: i64 = 2;
+
+/// Release channels a single router setting applies to
+const ROUTER_CHANNELS: &[&str] = &["alpha", "beta", "release"];
+
+/// A settings file and the changes made to bring it to the current layout.
+#[derive(Debug)]
+pub struct Migration {
+    pub settings: Value,
+    /// A description of every change, empty when the file was current
+    pub changes: Vec<String>,
+}
+
+impl Migration {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Reads and migrates the settings file at the given path.
+    pub fn load(path: &Path) -> Result<Self> {
+        let settings = fs::read_to_string(path)?
+            .parse::<Value>()
+            .map_err(|err| Error::custom(format!("invalid {}: {}", path.display(), err)))?;
+        Self::migrate(settings)
+    }
+
+    pub fn migrate(mut settings: Value) -> Result<Self> {
+        let table = settings
+            .as_table_mut()
+            .ok_or_else(|| Error::custom("settings are not a table"))?;
+        let version = match table.get("version") {
+            Some(Value::Integer(version)) => *version,
+            Some(other) => {
+                return Err(Error::custom(format!(
+                    "invalid settings version: {}",
+                    other
+                )))
+            }
+            None => 1,
+        };
+        if version > SETTINGS_VERSION {
+            return Err(Error::custom(format!(
+                "settings version {} is newer than supported version {}",
+                version, SETTINGS_VERSION
+            )));
+        }
+        let mut changes = vec![];
+        if version < 2 {
+            migrate_v2(table, &mut changes);
+        }
+        if !changes.is_empty() {
+            table.insert("version".to_string(), Value::Integer(SETTINGS_VERSION));
+        }
+        Ok(Self { settings, changes })
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(&self.settings)
+            .map_err(|err| Error::custom(format!("unable to write settings: {}", err)))
+    }
+}
+
+fn migrate_v2(table: &mut Table, changes: &mut Vec<String>) {
+    // A single router used to apply to all release channels
+    let single_router = match table.get("router") {
+        Some(Value::Table(router)) => router.contains_key("uri"),
+        _ => false,
+    };
+    if single_router {
+        if let Some(router) = table.remove("router") {
+            let channels = ROUTER_CHANNELS
+                .iter()
+                .map(|channel| (channel.to_string(), router.clone()))
+                .collect();
+            table.insert("router".to_string(), Value::Table(channels));
+            changes.push(format!(
+                "moved the single router to \"router.<channel>\" for channels {}",
+                ROUTER_CHANNELS.join(", ")
+            ));
+        }
+    }
+    // A single gateway became the gateways list
+    if let Some(gateway) = table.remove("gateway") {
+        let mut gateways = match table.remove("gateways") {
+            Some(Value::Array(gateways)) => gateways,
+            _ => vec![],
+        };
+        gateways.insert(0, gateway);
+        table.insert("gateways".to_string(), Value::Array(gateways));
+        changes.push("moved \"gateway\" into the \"gateways\" list".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate() {
+        let old: Value = r#"
+            keypair = "/etc/helium_gateway/keypair.bin"
+            [router]
+            public_key = "router_key"
+            uri = "http://router:8080"
+            [[gateways]]
+            public_key = "gw2"
+            uri = "http://gw2:8080"
+            [gateway]
+            public_key = "gw1"
+            uri = "http://gw1:8080"
+        "#
+        .parse()
+        .expect("toml");
+        let migration = Migration::migrate(old).expect("migration");
+        assert_eq!(2, migration.changes.len());
+        let settings = &migration.settings;
+        assert_eq!(Some(SETTINGS_VERSION), settings["version"].as_integer());
+        assert_eq!(
+            Some("/etc/helium_gateway/keypair.bin"),
+            settings["keypair"].as_str()
+        );
+        assert_eq!(
+            Some("http://router:8080"),
+            settings["router"]["beta"]["uri"].as_str()
+        );
+        assert_eq!(Some("gw1"), settings["gateways"][0]["public_key"].as_str());
+        assert_eq!(Some("gw2"), settings["gateways"][1]["public_key"].as_str());
+
+        let current = Migration::migrate(migration.settings).expect("migration");
+        assert!(current.is_empty());
+    }
+}
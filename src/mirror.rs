@@ -0,0 +1,172 @@
+use crate::*;
+use link_packet::LinkPacket;
+use serde_json::json;
+use settings::PayloadPrivacy;
+use slog::{info, o, warn, Logger};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task,
+};
+
+/// Maximum number of mirrored packets queued before packets are dropped.
+pub const MIRROR_QUEUE_SIZE: usize = 128;
+
+/// Where mirrored packets are sent.
+#[derive(Debug, Clone)]
+enum Sink {
+    /// One json datagram per packet
+    Udp(SocketAddr),
+    /// One json line per packet appended to a file
+    File(PathBuf),
+}
+
+impl Sink {
+    fn parse(uri: &str) -> Result<Self> {
+        if let Some(addr) = uri.strip_prefix("udp://") {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| Error::custom(format!("unresolved mirror address: {}", addr)))?;
+            return Ok(Self::Udp(addr));
+        }
+        if let Some(path) = uri.strip_prefix("file://") {
+            return Ok(Self::File(PathBuf::from(path)));
+        }
+        Err(Error::custom(format!("unsupported mirror sink: {}", uri)))
+    }
+}
+
+/// Creates a mirror handle and the service that delivers mirrored packets to
+/// the configured sink.
+pub fn mirror(settings: &Settings) -> Result<(Mirror, MirrorService)> {
+    let mirror = &settings.mirror;
+    if !mirror.enabled {
+        return Ok((Mirror::disabled(), MirrorService { packets: None }));
+    }
+    let sink = Sink::parse(&mirror.uri)?;
+    let (sender, receiver) = mpsc::channel(MIRROR_QUEUE_SIZE);
+    Ok((
+        Mirror {
+            sender: Some(sender),
+            downlinks: mirror.downlinks,
+            privacy: settings.privacy.payload,
+        },
+        MirrorService {
+            packets: Some((sink, receiver)),
+        },
+    ))
+}
+
+/// A cheaply cloneable handle to mirror packets. Mirroring never blocks the
+/// packet path; packets are dropped when the sink falls behind.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    sender: Option<Sender<String>>,
+    downlinks: bool,
+    privacy: PayloadPrivacy,
+}
+
+impl Mirror {
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            downlinks: false,
+            privacy: PayloadPrivacy::Keep,
+        }
+    }
+
+    pub fn uplink(&self, packet: &LinkPacket) {
+        self.send("up", packet)
+    }
+
+    pub fn downlink(&self, packet: &LinkPacket) {
+        if self.downlinks {
+            self.send("down", packet)
+        }
+    }
+
+    fn send(&self, direction: &str, packet: &LinkPacket) {
+        if let Some(sender) = &self.sender {
+            let record = json!({
+                "direction": direction,
+                "time": sntp::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0),
+                "trace_id": packet.trace_id.to_string(),
+                "gateway_mac": packet.gateway_mac.to_string(),
+                "frequency": packet.packet.frequency,
+                "datarate": packet.packet.datarate,
+                "rssi": packet.packet.signal_strength,
+                "snr": packet.packet.snr,
+                "timestamp": packet.packet.timestamp,
+                "time_ns": packet.metadata.time_ns,
+                "antenna": packet.antenna.antenna,
+                "rf_chain": packet.antenna.rf_chain,
+                "if_chain": packet.antenna.if_chain,
+                "payload": base64::encode(privacy::payload(&packet.payload, self.privacy)),
+            });
+            let _ = sender.try_send(record.to_string());
+        }
+    }
+}
+
+/// Writes mirrored packets to the sink. Writes are blocking and happen on a
+/// blocking thread to keep them off the async runtime.
+#[derive(Debug)]
+pub struct MirrorService {
+    packets: Option<(Sink, Receiver<String>)>,
+}
+
+impl MirrorService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "mirror"));
+        let (sink, mut packets) = match self.packets.take() {
+            Some(packets) => packets,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "sink" => format!("{:?}", sink));
+        let writer_logger = logger.clone();
+        let writer = task::spawn_blocking(move || -> Result {
+            let udp = match &sink {
+                Sink::Udp(_) => Some(UdpSocket::bind("0.0.0.0:0")?),
+                Sink::File(_) => None,
+            };
+            while let Some(record) = packets.blocking_recv() {
+                let written = match (&sink, &udp) {
+                    (Sink::Udp(addr), Some(socket)) => {
+                        socket.send_to(record.as_bytes(), addr).map(|_| ())
+                    }
+                    (Sink::File(path), _) => OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut file| writeln!(file, "{}", record)),
+                    _ => Ok(()),
+                };
+                if let Err(err) = written {
+                    warn!(writer_logger, "failed to mirror packet: {:?}", err);
+                }
+            }
+            Ok(())
+        });
+        tokio::select! {
+            _ = shutdown.clone() => {
+                info!(logger, "shutting down");
+                Ok(())
+            },
+            result = writer => result
+                .map_err(|err| Error::custom(format!("mirror writer failed: {:?}", err)))?,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::*;
+use gateway::{allowlist::Allowlist, quarantine::frame_mac};
+use relay::{Upstreams, MAX_DATAGRAM};
+use semtech_udp::MacAddress;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+use supervisor::Liveness;
+use tokio::{net::UdpSocket, sync::watch, time};
+
+/// Forwarders that have not sent anything for this long are forgotten.
+pub const PEER_TIMEOUT_SECS: u64 = 120;
+
+const PUSH_DATA: u8 = 0x00;
+const PULL_DATA: u8 = 0x02;
+const PULL_RESP: u8 = 0x03;
+const TX_ACK: u8 = 0x05;
+
+/// Frame counts of a packet forwarder.
+#[derive(Debug, Default)]
+struct Counters {
+    push_data: u64,
+    pull_data: u64,
+    tx_ack: u64,
+    pull_resp: u64,
+    /// Frames to the network server that were not push data, pull data or
+    /// tx acks
+    other: u64,
+}
+
+/// Relays GWMP between packet forwarders and a legacy network server without
+/// interpreting it, in place of the gateway and router. Forwarders are still
+/// filtered by the allowlist, counted, and kept alive for the supervisor.
+///
+/// Every forwarder address gets its own upstream socket, so the network
+/// server sees each forwarder as a separate client as it would without the
+/// proxy.
+#[derive(Debug)]
+pub struct Passthrough {
+    listen_addr: SocketAddr,
+    upstream: SocketAddr,
+    allowlist: Allowlist,
+    liveness: Liveness,
+    snapshots: watch::Receiver<()>,
+    counters: HashMap<MacAddress, Counters>,
+    /// The MAC of the forwarder at each address
+    macs: HashMap<SocketAddr, MacAddress>,
+}
+
+impl Passthrough {
+    pub fn new(
+        liveness: Liveness,
+        snapshots: watch::Receiver<()>,
+        settings: &Settings,
+    ) -> Result<Self> {
+        let upstream = settings
+            .passthrough
+            .upstream
+            .as_ref()
+            .ok_or_else(|| Error::custom("passthrough enabled without an upstream"))?;
+        let upstream = upstream
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::custom(format!("unresolved upstream address: {}", upstream)))?;
+        Ok(Self {
+            listen_addr: settings.listen_addr,
+            upstream,
+            allowlist: Allowlist::new(&settings.allowlist)?,
+            liveness,
+            snapshots,
+            counters: HashMap::new(),
+            macs: HashMap::new(),
+        })
+    }
+
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "passthrough"));
+        info!(logger, "starting";
+            "listen_addr" => self.listen_addr.to_string(),
+            "upstream" => self.upstream.to_string());
+        let socket = UdpSocket::bind(self.listen_addr).await?;
+        let (mut peers, mut replies) = Upstreams::new(self.upstream);
+        let mut eviction_timer = time::interval(Duration::from_secs(PEER_TIMEOUT_SECS));
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                received = socket.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            warn!(logger, "receive failed: {:?}", err);
+                            continue;
+                        }
+                    };
+                    let frame = &buf[..len];
+                    let mac = match frame_mac(frame) {
+                        Some(mac) => mac,
+                        None => {
+                            debug!(logger, "dropping non GWMP datagram from {}", addr);
+                            continue;
+                        }
+                    };
+                    self.allowlist.update_addr(mac, &addr);
+                    if !self.allowlist.allows(&mac) {
+                        debug!(logger, "dropping frame from unauthorized forwarder {}, {}", mac, addr);
+                        continue;
+                    }
+                    self.count_up(mac, frame[3]);
+                    self.macs.insert(addr, mac);
+                    match peers.connect(&addr, &logger).await {
+                        Ok(true) => info!(logger, "new packet forwarder: {}, {}", mac, addr),
+                        Ok(false) => (),
+                        Err(err) => {
+                            warn!(logger, "failed to connect {} upstream: {:?}", mac, err);
+                            continue;
+                        }
+                    }
+                    if let Err(err) = peers.send(&addr, frame).await {
+                        warn!(logger, "failed to relay frame from {}: {:?}", mac, err);
+                    }
+                },
+                Some((addr, frame)) = replies.recv() => {
+                    if let Some(mac) = self.macs.get(&addr) {
+                        if frame.get(3) == Some(&PULL_RESP) {
+                            self.counters.entry(*mac).or_default().pull_resp += 1;
+                        }
+                    }
+                    if let Err(err) = socket.send_to(&frame, addr).await {
+                        warn!(logger, "failed to relay frame to {}: {:?}", addr, err);
+                    }
+                },
+                Ok(()) = self.snapshots.changed() => self.log_snapshot(&logger, peers.len()),
+                _ = eviction_timer.tick() => {
+                    for addr in peers.evict(Duration::from_secs(PEER_TIMEOUT_SECS)) {
+                        info!(logger, "forgetting idle packet forwarder {}", addr);
+                        self.macs.remove(&addr);
+                    }
+                    if let Err(err) = self.allowlist.refresh() {
+                        warn!(logger, "keeping current forwarder allowlist: {:?}", err);
+                    }
+                },
+            }
+        }
+    }
+
+    fn count_up(&mut self, mac: MacAddress, frame_type: u8) {
+        let counters = self.counters.entry(mac).or_default();
+        match frame_type {
+            PUSH_DATA => counters.push_data += 1,
+            PULL_DATA => {
+                counters.pull_data += 1;
+                self.liveness.pull_data();
+            }
+            TX_ACK => counters.tx_ack += 1,
+            _ => counters.other += 1,
+        }
+    }
+
+    fn log_snapshot(&self, logger: &Logger, peers: usize) {
+        info!(logger, "snapshot"; "forwarders" => peers);
+        for (mac, counters) in &self.counters {
+            info!(logger, "forwarder {}", mac;
+                "push_data" => counters.push_data,
+                "pull_data" => counters.pull_data,
+                "tx_ack" => counters.tx_ack,
+                "pull_resp" => counters.pull_resp,
+                "other" => counters.other);
+        }
+    }
+}
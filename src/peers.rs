@@ -0,0 +1,619 @@
+use crate::*;
+use helium_crypto::Verify;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use signer::{Purpose, Signatures};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::Notify, time};
+
+/// Maximum size of a peer message.
+pub const PEER_MESSAGE_SIZE: usize = 1400;
+/// Peers are forgotten when not heard from for this many announce intervals.
+pub const PEER_EXPIRY_INTERVALS: u32 = 3;
+/// Maximum number of uplinks reported in one message, to stay under the
+/// message size.
+pub const SIGHTINGS_PER_MESSAGE: usize = 16;
+/// How long the uplinks reported by peers are remembered.
+pub const SIGHTING_TTL: Duration = Duration::from_secs(10);
+
+/// A message between the gateways of a site, signed by the key of the
+/// sending gateway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Periodically broadcast to let the other gateways know this one is
+    /// there
+    Announce {
+        key: String,
+        version: String,
+        /// Unix seconds, which have to increase between announcements of a
+        /// peer to tell them from replays and be within the peer expiry of
+        /// the receiving gateway
+        timestamp: u64,
+        /// The address the announcement is sent from, which it has to arrive
+        /// from
+        addr: SocketAddr,
+    },
+    /// Sent right after receiving uplinks, so the gateways that heard the
+    /// same uplink can leave forwarding it to the one with the best signal
+    Seen {
+        key: String,
+        /// Unix milliseconds, which have to increase between reports of a
+        /// peer and be within the sighting ttl of the receiving gateway
+        timestamp: u64,
+        uplinks: Vec<Sighting>,
+    },
+    /// Sent to the other hosts of a cluster, with whether the sender holds
+    /// the lease of the cluster leader
+    Heartbeat {
+        key: String,
+        /// Unix milliseconds, which have to increase between heartbeats
+        timestamp: u64,
+        leader: bool,
+    },
+}
+
+/// An uplink heard by a gateway, identified by its dedup key hash, with the
+/// signal it was heard with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sighting {
+    pub hash: u64,
+    pub snr_tenths: i32,
+    pub rssi_dbm: i32,
+}
+
+impl Sighting {
+    /// Whether this sighting, by the gateway with the given key, has a
+    /// better signal than the other one. The SNR counts first, then the
+    /// RSSI, and ties go to the lowest key so exactly one gateway wins.
+    pub fn beats(&self, key: &str, other: &Sighting, other_key: &str) -> bool {
+        (self.snr_tenths, self.rssi_dbm, std::cmp::Reverse(key))
+            > (
+                other.snr_tenths,
+                other.rssi_dbm,
+                std::cmp::Reverse(other_key),
+            )
+    }
+}
+
+/// A signed message as sent over the wire.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    message: Message,
+    /// The signature (base64) over the json encoded message
+    signature: String,
+}
+
+impl Message {
+    /// The public key of the gateway that sent the message.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Announce { key, .. } | Self::Seen { key, .. } | Self::Heartbeat { key, .. } => {
+                key
+            }
+        }
+    }
+
+    /// Encodes a message signed by the gateway key for the given purpose.
+    pub fn encode(&self, signatures: &Signatures, purpose: Purpose) -> Result<Vec<u8>> {
+        let signature = signatures.sign(purpose, &serde_json::to_vec(self)?)?;
+        Ok(serde_json::to_vec(&Envelope {
+            message: self.clone(),
+            signature: base64::encode(signature),
+        })?)
+    }
+
+    /// Decodes a message, checking that it was signed by the key it names.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let envelope: Envelope = serde_json::from_slice(bytes)?;
+        let key = PublicKey::from_str(envelope.message.key())?;
+        let signature = base64::decode(&envelope.signature)?;
+        key.verify(&serde_json::to_vec(&envelope.message)?, &signature)
+            .map_err(|_| Error::custom("invalid peer message signature"))?;
+        Ok(envelope.message)
+    }
+}
+
+/// A gateway found on the LAN.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub key: String,
+    pub addr: SocketAddr,
+    pub version: String,
+    timestamp: u64,
+    /// The timestamp of the last uplink report
+    seen_timestamp: u64,
+    last_seen: Instant,
+}
+
+impl Peer {
+    fn to_json(&self) -> Value {
+        let name = self
+            .key
+            .parse::<angry_purple_tiger::AnimalName>()
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+        json!({
+            "key": self.key,
+            "name": name,
+            "addr": self.addr.to_string(),
+            "version": self.version,
+            "last_seen_secs": self.last_seen.elapsed().as_secs(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    peers: HashMap<String, Peer>,
+    /// The best sighting of each uplink reported by a peer, with the key of
+    /// the peer
+    sightings: HashMap<u64, (Sighting, String, Instant)>,
+    /// Sightings of this gateway to report to the peers
+    outbox: Vec<Sighting>,
+}
+
+/// A cheaply cloneable handle to the gateways found on the LAN and the
+/// uplinks they reported.
+#[derive(Debug, Clone, Default)]
+pub struct Peers(Arc<Mutex<State>>, Arc<Notify>);
+
+impl Peers {
+    /// The peers heard from recently, by key.
+    pub fn list(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self
+            .0
+            .lock()
+            .expect("peers")
+            .peers
+            .values()
+            .cloned()
+            .collect();
+        peers.sort_by(|a, b| a.key.cmp(&b.key));
+        peers
+    }
+
+    pub fn to_json(&self) -> Value {
+        let peers: Vec<Value> = self.list().iter().map(Peer::to_json).collect();
+        json!({ "peers": peers })
+    }
+
+    /// Queues an uplink heard by this gateway to be reported to the peers.
+    pub fn seen(&self, sighting: Sighting) {
+        self.0.lock().expect("peers").outbox.push(sighting);
+        self.1.notify_one();
+    }
+
+    /// The key of a peer that reported the uplink of the given sighting with
+    /// a better signal than this gateway, which has the given key.
+    pub fn better_peer(&self, sighting: &Sighting, own_key: &str) -> Option<String> {
+        let state = self.0.lock().expect("peers");
+        match state.sightings.get(&sighting.hash) {
+            Some((best, key, _)) if best.beats(key, sighting, own_key) => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Records the uplinks reported by a peer. Reports of gateways that did
+    /// not announce themselves and reports that are not newer than the last
+    /// one of the peer are ignored.
+    fn sighted(&self, key: &str, timestamp: u64, sightings: Vec<Sighting>) -> bool {
+        let mut state = self.0.lock().expect("peers");
+        match state.peers.get_mut(key) {
+            Some(peer) if timestamp > peer.seen_timestamp => peer.seen_timestamp = timestamp,
+            _ => return false,
+        }
+        for sighting in sightings {
+            let best = state
+                .sightings
+                .get(&sighting.hash)
+                .map_or(true, |(best, best_key, _)| {
+                    sighting.beats(key, best, best_key)
+                });
+            if best {
+                state
+                    .sightings
+                    .insert(sighting.hash, (sighting, key.to_string(), Instant::now()));
+            }
+        }
+        true
+    }
+
+    fn take_outbox(&self) -> Vec<Sighting> {
+        std::mem::take(&mut self.0.lock().expect("peers").outbox)
+    }
+
+    /// Records an announcement. Returns whether the peer is new, and false
+    /// for announcements that are not newer than the last one of the peer.
+    fn announced(&self, key: &str, version: &str, timestamp: u64, addr: SocketAddr) -> bool {
+        let peers = &mut self.0.lock().expect("peers").peers;
+        match peers.get_mut(key) {
+            Some(peer) if timestamp <= peer.timestamp => false,
+            Some(peer) => {
+                peer.addr = addr;
+                peer.version = version.to_string();
+                peer.timestamp = timestamp;
+                peer.last_seen = Instant::now();
+                false
+            }
+            None => {
+                peers.insert(
+                    key.to_string(),
+                    Peer {
+                        key: key.to_string(),
+                        addr,
+                        version: version.to_string(),
+                        timestamp,
+                        seen_timestamp: 0,
+                        last_seen: Instant::now(),
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    fn expire(&self, max_age: Duration) -> Vec<String> {
+        let mut state = self.0.lock().expect("peers");
+        state
+            .sightings
+            .retain(|_, (_, _, seen)| seen.elapsed() <= SIGHTING_TTL);
+        let peers = &mut state.peers;
+        let expired: Vec<String> = peers
+            .values()
+            .filter(|peer| peer.last_seen.elapsed() > max_age)
+            .map(|peer| peer.key.clone())
+            .collect();
+        for key in &expired {
+            peers.remove(key);
+        }
+        expired
+    }
+}
+
+/// Creates the handle to the peers found on the LAN and the service that
+/// finds them.
+pub fn peers(settings: &Settings, signatures: Signatures) -> Result<(Peers, PeerService)> {
+    let peers = Peers::default();
+    let keys = settings
+        .peers
+        .keys
+        .iter()
+        .map(|key| PublicKey::from_str(key).map(|key| key.to_string()))
+        .collect::<std::result::Result<Vec<String>, _>>()?;
+    if settings.peers.enabled && keys.is_empty() {
+        return Err(Error::custom("peer keys are required for peer discovery"));
+    }
+    let service = PeerService {
+        enabled: settings.peers.enabled,
+        port: settings.peers.port,
+        broadcast: settings.peers.broadcast,
+        interval: Duration::from_secs(settings.peers.interval.max(1)),
+        keys,
+        signatures,
+        peers: peers.clone(),
+    };
+    Ok((peers, service))
+}
+
+/// Finds the other gateways of a site by broadcasting signed announcements
+/// on the LAN and listening for theirs. Only gateways that run on different
+/// hosts can find each other, since each binds the peer port.
+#[derive(Debug)]
+pub struct PeerService {
+    enabled: bool,
+    port: u16,
+    broadcast: Ipv4Addr,
+    interval: Duration,
+    /// The keys of the peers to accept
+    keys: Vec<String>,
+    signatures: Signatures,
+    peers: Peers,
+}
+
+impl PeerService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "peers"));
+        if !self.enabled {
+            info!(logger, "disabling");
+            return Ok(());
+        }
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.port)).await?;
+        socket.set_broadcast(true)?;
+        info!(logger, "starting"; "port" => self.port);
+        let mut own_key = self.signatures.public_key().to_string();
+        let mut rotations = self.signatures.rotations();
+        let mut announce_timer = time::interval(self.interval);
+        let mut buf = vec![0u8; PEER_MESSAGE_SIZE];
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = announce_timer.tick() => {
+                    if let Err(err) = self.announce(&socket, &own_key).await {
+                        warn!(logger, "failed to announce gateway: {:?}", err);
+                    }
+                    for key in self.peers.expire(self.interval * PEER_EXPIRY_INTERVALS) {
+                        info!(logger, "lost peer {}", key);
+                    }
+                },
+                Ok(()) = rotations.changed() => {
+                    own_key = self.signatures.public_key().to_string();
+                    info!(logger, "announcing rotated key {}", own_key);
+                    if let Err(err) = self.announce(&socket, &own_key).await {
+                        warn!(logger, "failed to announce gateway: {:?}", err);
+                    }
+                },
+                _ = self.peers.1.notified() => {
+                    if let Err(err) = self.report(&socket, &own_key).await {
+                        warn!(logger, "failed to report uplinks: {:?}", err);
+                    }
+                },
+                received = socket.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            warn!(logger, "peer receive failed: {:?}", err);
+                            continue;
+                        }
+                    };
+                    match Message::decode(&buf[..len]) {
+                        Ok(message) if message.key() == own_key => (),
+                        Ok(message) => self.handle_message(&logger, message, addr),
+                        Err(err) => debug!(logger, "ignoring peer message from {}: {:?}", addr, err),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn announce(&self, socket: &UdpSocket, own_key: &str) -> Result {
+        let message = Message::Announce {
+            key: own_key.to_string(),
+            version: settings::version().to_string(),
+            timestamp: stats::unix_secs(),
+            addr: self.local_addr()?,
+        };
+        let encoded = message.encode(&self.signatures, Purpose::Peer)?;
+        socket
+            .send_to(&encoded, (self.broadcast, self.port))
+            .await?;
+        Ok(())
+    }
+
+    async fn report(&self, socket: &UdpSocket, own_key: &str) -> Result {
+        let outbox = self.peers.take_outbox();
+        for uplinks in outbox.chunks(SIGHTINGS_PER_MESSAGE) {
+            let message = Message::Seen {
+                key: own_key.to_string(),
+                timestamp: stats::unix_millis(),
+                uplinks: uplinks.to_vec(),
+            };
+            let encoded = message.encode(&self.signatures, Purpose::Peer)?;
+            socket
+                .send_to(&encoded, (self.broadcast, self.port))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The address announcements are sent from, with the address of the
+    /// interface the broadcast goes out on.
+    fn local_addr(&self) -> Result<SocketAddr> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.connect((self.broadcast, self.port))?;
+        Ok(SocketAddr::new(socket.local_addr()?.ip(), self.port))
+    }
+
+    fn handle_message(&self, logger: &Logger, message: Message, addr: SocketAddr) {
+        if !self.keys.iter().any(|key| key == message.key()) {
+            debug!(
+                logger,
+                "ignoring unknown peer {} at {}",
+                message.key(),
+                addr
+            );
+            return;
+        }
+        match message {
+            Message::Announce {
+                key,
+                version,
+                timestamp,
+                addr: signed_addr,
+            } => {
+                let max_age = self.interval.as_secs() * PEER_EXPIRY_INTERVALS as u64;
+                if !is_current(timestamp, stats::unix_secs(), max_age) {
+                    debug!(logger, "ignoring expired announcement of peer {}", key; "timestamp" => timestamp);
+                } else if signed_addr != addr {
+                    debug!(
+                        logger,
+                        "ignoring announcement of peer {} for {} from {}", key, signed_addr, addr
+                    );
+                } else if self.peers.announced(&key, &version, timestamp, addr) {
+                    info!(logger, "found peer {} at {}", key, addr; "version" => version);
+                }
+            }
+            Message::Seen {
+                key,
+                timestamp,
+                uplinks,
+            } => {
+                if !is_fresh(timestamp, stats::unix_millis()) {
+                    debug!(logger, "ignoring stale uplink report of peer {}", key; "timestamp" => timestamp);
+                } else if !self.peers.sighted(&key, timestamp, uplinks) {
+                    debug!(
+                        logger,
+                        "ignoring uplinks of unannounced peer {} or a replayed report", key
+                    );
+                }
+            }
+            // Heartbeats go to the cluster port, not the peer port
+            Message::Heartbeat { .. } => (),
+        }
+    }
+}
+
+/// Whether an announcement sent at the given unix seconds is recent enough
+/// to keep the peer, allowing for the clocks of the gateways to be off by as
+/// much in either direction. Older announcements are replays.
+fn is_current(timestamp: u64, now: u64, max_age: u64) -> bool {
+    timestamp.saturating_add(max_age) >= now && timestamp <= now.saturating_add(max_age)
+}
+
+/// Whether a report sent at the given unix milliseconds is recent enough to
+/// be about uplinks still held, allowing for the clocks of the gateways to
+/// be off by as much in either direction.
+fn is_fresh(timestamp: u64, now: u64) -> bool {
+    let ttl = SIGHTING_TTL.as_millis() as u64;
+    timestamp.saturating_add(ttl) >= now && timestamp <= now.saturating_add(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network};
+    use rand::rngs::OsRng;
+    use settings::SigningSettings;
+
+    #[test]
+    fn announce() {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let key = keypair.public_key().to_string();
+        let signatures = Signatures::with_limits(
+            Arc::new(keypair),
+            &SigningSettings {
+                uplink_per_minute: 0,
+                peer_per_minute: 0,
+            },
+        );
+        let addr: SocketAddr = "192.168.1.2:1690".parse().expect("addr");
+        let message = Message::Announce {
+            key: key.clone(),
+            version: "1.0.0".to_string(),
+            timestamp: 10,
+            addr,
+        };
+        let encoded = message.encode(&signatures, Purpose::Peer).expect("encode");
+        assert_eq!(key, Message::decode(&encoded).expect("decode").key());
+        let forged = String::from_utf8(encoded)
+            .expect("json")
+            .replace("1.0.0", "6.6.6");
+        assert!(Message::decode(forged.as_bytes()).is_err());
+        let forged = String::from_utf8(message.encode(&signatures, Purpose::Peer).unwrap())
+            .expect("json")
+            .replace("192.168.1.2", "192.168.1.6");
+        assert!(Message::decode(forged.as_bytes()).is_err());
+
+        let peers = Peers::default();
+        assert!(peers.announced(&key, "1.0.0", 10, addr));
+        // A replay of an older announcement
+        assert!(!peers.announced(&key, "0.9.0", 9, addr));
+        assert_eq!("1.0.0", peers.list()[0].version);
+    }
+
+    #[test]
+    fn announcements() {
+        let service = PeerService {
+            enabled: true,
+            port: 1690,
+            broadcast: Ipv4Addr::BROADCAST,
+            interval: Duration::from_secs(30),
+            keys: vec!["b".to_string()],
+            signatures: Signatures::with_limits(
+                Arc::new(Keypair::generate(
+                    KeyTag {
+                        network: Network::MainNet,
+                        key_type: KeyType::Ed25519,
+                    },
+                    &mut OsRng,
+                )),
+                &SigningSettings {
+                    uplink_per_minute: 0,
+                    peer_per_minute: 0,
+                },
+            ),
+            peers: Peers::default(),
+        };
+        let logger = Logger::root(slog::Discard, o!());
+        let addr: SocketAddr = "192.168.1.2:1690".parse().expect("addr");
+        let announce = |key: &str, timestamp, addr| Message::Announce {
+            key: key.to_string(),
+            version: "1.0.0".to_string(),
+            timestamp,
+            addr,
+        };
+        let now = stats::unix_secs();
+        service.handle_message(&logger, announce("a", now, addr), addr);
+        // Expired after three intervals, like the peer it announced
+        service.handle_message(&logger, announce("b", now - 91, addr), addr);
+        // Sent from another address than the signed one
+        let spoofed: SocketAddr = "192.168.1.6:1690".parse().expect("addr");
+        service.handle_message(&logger, announce("b", now, addr), spoofed);
+        assert!(service.peers.list().is_empty());
+        service.handle_message(&logger, announce("b", now, addr), addr);
+        assert_eq!(addr, service.peers.list()[0].addr);
+    }
+
+    #[test]
+    fn sightings() {
+        let peers = Peers::default();
+        let addr: SocketAddr = "192.168.1.2:1690".parse().expect("addr");
+        let own = Sighting {
+            hash: 1,
+            snr_tenths: 50,
+            rssi_dbm: -90,
+        };
+        let better = Sighting {
+            snr_tenths: 70,
+            ..own
+        };
+        // Only announced peers are believed
+        assert!(!peers.sighted("b", 1, vec![better]));
+        assert!(peers.announced("b", "1.0.0", 10, addr));
+        assert!(peers.sighted("b", 1, vec![better]));
+        assert_eq!(Some("b".to_string()), peers.better_peer(&own, "c"));
+        assert_eq!(None, peers.better_peer(&Sighting { hash: 2, ..own }, "c"));
+        // A replayed report
+        assert!(!peers.sighted("b", 1, vec![Sighting { hash: 2, ..better }]));
+        assert_eq!(None, peers.better_peer(&Sighting { hash: 2, ..own }, "c"));
+        // A tie goes to the lowest key
+        assert!(peers.sighted("b", 2, vec![Sighting { hash: 3, ..own }]));
+        assert_eq!(None, peers.better_peer(&Sighting { hash: 3, ..own }, "a"));
+        assert!(peers
+            .better_peer(&Sighting { hash: 3, ..own }, "c")
+            .is_some());
+    }
+
+    #[test]
+    fn fresh() {
+        let now = 1_000_000;
+        assert!(is_fresh(now, now));
+        assert!(is_fresh(now - 10_000, now));
+        assert!(is_fresh(now + 10_000, now));
+        assert!(!is_fresh(now - 10_001, now));
+        assert!(!is_fresh(now + 10_001, now));
+
+        let now = 1_000;
+        assert!(is_current(now - 90, now, 90));
+        assert!(is_current(now + 90, now, 90));
+        assert!(!is_current(now - 91, now, 90));
+        assert!(!is_current(now + 91, now, 90));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::*;
+use gateway::clients::Location;
+use lorawan::MType;
+use settings::{LocationPrivacy, PayloadPrivacy};
+use std::borrow::Cow;
+
+/// Average edge length in km of the H3 cells of each resolution.
+const H3_EDGE_KM: [f64; 16] = [
+    1107.712591,
+    418.6760055,
+    158.2446558,
+    59.81085794,
+    22.6063794,
+    8.544408276,
+    3.229482772,
+    1.220629759,
+    0.461354684,
+    0.174375668,
+    0.065907807,
+    0.024910561,
+    0.009415526,
+    0.003559893,
+    0.001348575,
+    0.000509713,
+];
+/// Kilometers per degree of latitude.
+const KM_PER_DEGREE: f64 = 111.32;
+/// Length of the MIC at the end of a LoRaWAN frame.
+const MIC_LEN: usize = 4;
+/// Length of the GWMP header of a semtech udp frame: version, token,
+/// identifier and gateway MAC.
+const GWMP_HEADER_LEN: usize = 12;
+
+/// Returns the LoRaWAN frame as it may be exported to logs, mirrors and the
+/// feed. The MAC layer headers (MHDR, FHDR and FPort) are kept, the frame
+/// payload is zeroed out when redacting or cut off along with the MIC when
+/// truncating. Join requests carry no application payload and are kept as
+/// they are, while join accepts and proprietary frames are all payload.
+pub fn payload(frame: &[u8], privacy: PayloadPrivacy) -> Cow<'_, [u8]> {
+    if privacy == PayloadPrivacy::Keep || frame.is_empty() {
+        return Cow::Borrowed(frame);
+    }
+    let header_len = match MType::from(frame[0] >> 5) {
+        MType::JoinRequest => return Cow::Borrowed(frame),
+        MType::UnconfirmedUp
+        | MType::UnconfirmedDown
+        | MType::ConfirmedUp
+        | MType::ConfirmedDown => {
+            // MHDR, DevAddr, FCtrl with the FOpts length, FCnt and FOpts,
+            // and the FPort if the frame has a payload
+            let fopts_len = frame.get(5).map_or(0, |fctrl| (fctrl & 0x0f) as usize);
+            let fhdr_end = 1 + 7 + fopts_len;
+            if frame.len() > fhdr_end + MIC_LEN {
+                fhdr_end + 1
+            } else {
+                fhdr_end
+            }
+        }
+        _ => 1,
+    };
+    let payload_end = frame.len().saturating_sub(MIC_LEN).max(header_len);
+    let header_len = header_len.min(frame.len());
+    match privacy {
+        PayloadPrivacy::Truncate => Cow::Borrowed(&frame[..header_len]),
+        _ => {
+            let mut redacted = frame.to_vec();
+            for byte in &mut redacted[header_len..payload_end.min(frame.len())] {
+                *byte = 0;
+            }
+            Cow::Owned(redacted)
+        }
+    }
+}
+
+/// Returns a semtech udp frame as it may be logged, which is only its GWMP
+/// header unless payloads are kept.
+pub fn udp_frame(frame: &[u8], privacy: PayloadPrivacy) -> &[u8] {
+    match privacy {
+        PayloadPrivacy::Keep => frame,
+        _ => &frame[..GWMP_HEADER_LEN.min(frame.len())],
+    }
+}
+
+/// Returns a location as it may be reported. Truncated locations are
+/// snapped to the center of a latitude and longitude grid with cells about
+/// the size of an H3 cell of the given resolution, rather than to an actual
+/// H3 cell, since the gateway does not compute H3 indexes.
+pub fn location(location: Location, privacy: LocationPrivacy, resolution: u8) -> Option<Location> {
+    match privacy {
+        LocationPrivacy::Exact => Some(location),
+        LocationPrivacy::Hidden => None,
+        LocationPrivacy::Truncated => {
+            let edge_km = H3_EDGE_KM[usize::from(resolution).min(H3_EDGE_KM.len() - 1)];
+            // A cell is about two edges across
+            let lat_step = 2.0 * edge_km / KM_PER_DEGREE;
+            let latitude = snap(location.latitude, lat_step).clamp(-90.0, 90.0);
+            let lon_step = (lat_step / latitude.to_radians().cos().max(0.01)).min(360.0);
+            let longitude = snap(location.longitude, lon_step).clamp(-180.0, 180.0);
+            Some(Location {
+                latitude,
+                longitude,
+                altitude: None,
+            })
+        }
+    }
+}
+
+fn snap(value: f64, step: f64) -> f64 {
+    ((value / step).floor() + 0.5) * step
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        // An unconfirmed uplink with one byte of FOpts, FPort 1, a three
+        // byte frame payload and the MIC
+        let frame = [0x40, 1, 2, 3, 4, 0x01, 7, 0, 0xaa, 1, 9, 9, 9, 5, 6, 7, 8];
+        assert_eq!(&frame[..], &*super::payload(&frame, PayloadPrivacy::Keep));
+        assert_eq!(
+            &frame[..10],
+            &*super::payload(&frame, PayloadPrivacy::Truncate)
+        );
+        assert_eq!(
+            &[0x40, 1, 2, 3, 4, 0x01, 7, 0, 0xaa, 1, 0, 0, 0, 5, 6, 7, 8][..],
+            &*super::payload(&frame, PayloadPrivacy::Redact)
+        );
+        // A join accept is all payload
+        let accept = [0x20, 1, 2, 3];
+        assert_eq!(
+            &[0x20][..],
+            &*super::payload(&accept, PayloadPrivacy::Truncate)
+        );
+        assert_eq!(12, udp_frame(&[0; 40], PayloadPrivacy::Redact).len());
+    }
+
+    #[test]
+    fn location() {
+        let exact = Location {
+            latitude: 52.370216,
+            longitude: 4.895168,
+            altitude: Some(12.0),
+        };
+        assert_eq!(
+            Some(exact),
+            super::location(exact, LocationPrivacy::Exact, 8)
+        );
+        assert_eq!(None, super::location(exact, LocationPrivacy::Hidden, 8));
+        let truncated = super::location(exact, LocationPrivacy::Truncated, 5).expect("location");
+        assert_eq!(None, truncated.altitude);
+        // Within a cell of about 17 km
+        assert!((truncated.latitude - exact.latitude).abs() < 0.1);
+        assert!((truncated.longitude - exact.longitude).abs() < 0.2);
+        let nearby = Location {
+            latitude: exact.latitude + 0.001,
+            ..exact
+        };
+        assert_eq!(
+            Some(truncated),
+            super::location(nearby, LocationPrivacy::Truncated, 5)
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use crate::*;
+use audit::AuditLog;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use signer::Signatures;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// The name of the settings overlay file written for a fleet manager push in
+/// the settings folder.
+pub const OVERLAY_FILE: &str = "push.toml";
+/// The name of the file in the settings folder that keeps a push on
+/// probation, with the overlay to roll back to.
+const STAGED_FILE: &str = "push.staged.json";
+/// Settings that stay local to the gateway and can not be pushed.
+const LOCAL_KEYS: &[&str] = &["keypair", "profile"];
+
+/// A setting a push changes, by its dotted key. A setting that is not set is
+/// null.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub key: String,
+    pub from: Value,
+    pub to: Value,
+}
+
+/// What a push would change, or why it would be refused.
+#[derive(Debug, Default, Serialize)]
+pub struct Preview {
+    pub valid: bool,
+    pub errors: Vec<String>,
+    pub changes: Vec<Change>,
+}
+
+/// A push on probation, kept across restarts of the gateway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Staged {
+    /// The overlay before the push, None when there was none
+    previous: Option<String>,
+    /// Unix seconds when the push was applied
+    applied: u64,
+    /// Seconds a packet forwarder has to stay connected
+    probation: u64,
+    /// Whether a packet forwarder was connected when the push was applied.
+    /// Without one only the starts of the gateway are checked.
+    forwarder: bool,
+    /// How often the gateway started with the push on probation
+    starts: u32,
+}
+
+/// Applies settings pushed by a fleet manager as an overlay that takes
+/// effect on restart. A push is on probation after the restart: it is kept
+/// once a packet forwarder is connected at the end of the probation, and
+/// rolled back when none is or when the gateway keeps failing to start.
+#[derive(Debug)]
+pub struct Push {
+    path: PathBuf,
+    audit: AuditLog,
+    profile: Option<String>,
+    signatures: Signatures,
+    probation: u64,
+    max_starts: u32,
+    /// The push on probation and when this start of the gateway picked it up
+    staged: Option<(Staged, Instant)>,
+}
+
+/// Counts a start of the gateway with a push on probation. This is the first
+/// step of a start, so a push that makes any later step fail is counted too.
+/// A push the gateway started with more than `max_starts` times is rolled
+/// back, returning a restart error to start over with the previous settings.
+pub fn count_start(settings: &Settings, signatures: &Signatures) -> Result {
+    let push = Push::new(settings, signatures.clone())?;
+    let mut staged = match &push.staged {
+        Some((staged, _)) => staged.clone(),
+        None => return Ok(()),
+    };
+    staged.starts += 1;
+    if staged.starts > push.max_starts {
+        push.rollback(&staged)?;
+        return Err(Error::Restart(format!(
+            "rolled back settings push after {} failed starts",
+            push.max_starts
+        )));
+    }
+    fs::write(push.path.join(STAGED_FILE), serde_json::to_vec(&staged)?)?;
+    Ok(())
+}
+
+impl Push {
+    /// Picks up the push on probation, if any. Its starts are counted by
+    /// `count_start`.
+    pub fn new(settings: &Settings, signatures: Signatures) -> Result<Self> {
+        let mut push = Self {
+            path: settings.path.clone(),
+            audit: AuditLog::new(settings),
+            profile: settings.profile.clone(),
+            signatures,
+            probation: settings.push.probation,
+            max_starts: settings.push.max_starts,
+            staged: None,
+        };
+        let staged_file = push.path.join(STAGED_FILE);
+        if staged_file.exists() {
+            let staged: Staged = serde_json::from_slice(&fs::read(&staged_file)?)?;
+            push.staged = Some((staged, Instant::now()));
+        }
+        Ok(push)
+    }
+
+    /// Checks a pushed overlay without applying it: it has to be valid toml,
+    /// leave the local settings alone and result in valid settings. Lists
+    /// the settings that would change, after environment overrides.
+    pub fn preview(&self, overlay: &str) -> Preview {
+        let mut preview = Preview::default();
+        match overlay.parse::<toml::Value>() {
+            Ok(value) => {
+                for key in LOCAL_KEYS {
+                    if value.get(key).is_some() {
+                        preview.errors.push(format!("{} can not be pushed", key));
+                    }
+                }
+            }
+            Err(err) => preview.errors.push(format!("invalid toml: {}", err)),
+        }
+        if preview.errors.is_empty() {
+            let profile = self.profile.as_deref();
+            match (
+                Settings::with_push(&self.path, profile, None),
+                Settings::with_push(&self.path, profile, Some(overlay)),
+            ) {
+                (Ok((_, current)), Ok((_, candidate))) => {
+                    preview.changes = diff(&current, &candidate)
+                }
+                (_, Err(err)) => preview.errors.push(describe(&err)),
+                (Err(err), _) => preview
+                    .errors
+                    .push(format!("current settings: {}", describe(&err))),
+            }
+        }
+        preview.valid = preview.errors.is_empty();
+        preview
+    }
+
+    /// Writes a pushed overlay and records the change in the audit log.
+    /// Returns the preview of the push and whether the gateway has to
+    /// restart, which puts the push on probation. An overlay that changes no
+    /// setting is written without probation, and one that can not be
+    /// recorded is rolled back.
+    pub fn apply(
+        &mut self,
+        overlay: &str,
+        forwarder: bool,
+        probation: Option<u64>,
+    ) -> Result<(Preview, bool)> {
+        // Also covers a push applied since this start, waiting for a restart
+        if self.path.join(STAGED_FILE).exists() {
+            return Err(Error::custom(
+                "the last settings push is still on probation",
+            ));
+        }
+        let preview = self.preview(overlay);
+        if !preview.valid {
+            return Err(Error::custom(preview.errors.join("; ")));
+        }
+        let path = self.path.join(OVERLAY_FILE);
+        let previous = fs::read_to_string(&path).ok();
+        if previous.as_deref() == Some(overlay) {
+            return Ok((preview, false));
+        }
+        let restart = !preview.changes.is_empty();
+        if restart {
+            // Staged before writing the overlay, so there is always a way back
+            let staged = Staged {
+                previous: previous.clone(),
+                applied: stats::unix_secs(),
+                probation: probation.unwrap_or(self.probation),
+                forwarder,
+                starts: 0,
+            };
+            fs::write(self.path.join(STAGED_FILE), serde_json::to_vec(&staged)?)?;
+        }
+        fs::write(&path, overlay)?;
+        if let Err(err) = self.record(previous.as_deref(), Some(overlay)) {
+            match &previous {
+                Some(previous) => fs::write(&path, previous)?,
+                None => fs::remove_file(&path)?,
+            }
+            if restart {
+                fs::remove_file(self.path.join(STAGED_FILE))?;
+            }
+            return Err(err);
+        }
+        Ok((preview, restart))
+    }
+
+    /// Ends the probation of a push once it is over. Returns whether the
+    /// push was kept, and a restart error when it was rolled back.
+    pub fn check(&mut self, forwarder: bool) -> Result<bool> {
+        let (staged, started) = match &self.staged {
+            Some(staged) => staged,
+            None => return Ok(false),
+        };
+        if started.elapsed() < Duration::from_secs(staged.probation) {
+            return Ok(false);
+        }
+        if forwarder || !staged.forwarder {
+            fs::remove_file(self.path.join(STAGED_FILE))?;
+            self.staged = None;
+            return Ok(true);
+        }
+        self.rollback(&staged.clone())?;
+        Err(Error::Restart(
+            "rolled back settings push, no packet forwarder connected after probation".to_string(),
+        ))
+    }
+
+    /// Restores the overlay from before a push on probation.
+    fn rollback(&self, staged: &Staged) -> Result {
+        let path = self.path.join(OVERLAY_FILE);
+        let pushed = fs::read_to_string(&path).ok();
+        match &staged.previous {
+            Some(previous) => fs::write(&path, previous)?,
+            None if path.exists() => fs::remove_file(&path)?,
+            None => (),
+        }
+        fs::remove_file(self.path.join(STAGED_FILE))?;
+        self.record(pushed.as_deref(), staged.previous.as_deref())
+    }
+
+    fn record(&self, before: Option<&str>, after: Option<&str>) -> Result {
+        self.audit.record(
+            &self.signatures,
+            "push",
+            &self.signatures.public_key().to_string(),
+            OVERLAY_FILE,
+            before,
+            after,
+        )
+    }
+
+    /// The push on probation, if any.
+    pub fn to_json(&self) -> Value {
+        match &self.staged {
+            Some((staged, started)) => json!({
+                "probation": true,
+                "applied": staged.applied,
+                "starts": staged.starts,
+                "max_starts": self.max_starts,
+                "remaining_secs": staged.probation.saturating_sub(started.elapsed().as_secs()),
+            }),
+            None => json!({ "probation": false }),
+        }
+    }
+}
+
+/// The settings that differ between two merged settings trees.
+pub fn diff(current: &Value, candidate: &Value) -> Vec<Change> {
+    let (mut from, mut to) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", current, &mut from);
+    flatten("", candidate, &mut to);
+    let keys: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    keys.into_iter()
+        .filter_map(|key| {
+            let change = Change {
+                key: key.clone(),
+                from: from.get(key).cloned().unwrap_or(Value::Null),
+                to: to.get(key).cloned().unwrap_or(Value::Null),
+            };
+            (change.from != change.to).then(|| change)
+        })
+        .collect()
+}
+
+fn flatten(prefix: &str, value: &Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, out);
+            }
+        }
+        value => {
+            out.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+/// A readable description of the error loading settings.
+pub fn describe(err: &Error) -> String {
+    match err {
+        Error::Config(err) => err.to_string(),
+        Error::Custom(msg) | Error::Key(msg) => msg.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let current = json!({
+            "region": "US915",
+            "api": { "enabled": true },
+            "labels": {},
+        });
+        let candidate = json!({
+            "region": "EU868",
+            "api": { "enabled": true },
+            "labels": { "site": "roof" },
+        });
+        assert_eq!(
+            vec![
+                Change {
+                    key: "labels".to_string(),
+                    from: json!({}),
+                    to: Value::Null,
+                },
+                Change {
+                    key: "labels.site".to_string(),
+                    from: Value::Null,
+                    to: json!("roof"),
+                },
+                Change {
+                    key: "region".to_string(),
+                    from: json!("US915"),
+                    to: json!("EU868"),
+                },
+            ],
+            diff(&current, &candidate)
+        );
+        assert!(diff(&current, &current).is_empty());
+    }
+}
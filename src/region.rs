@@ -0,0 +1,635 @@
+use crate::{
+    error::DownlinkError,
+    settings::{Concentrator, DownlinkGuardSettings},
+};
+use helium_proto::Region;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, time::Duration};
+
+/// The bytes a PHYPayload adds to its MACPayload, for the MHDR and MIC.
+const PHY_OVERHEAD: usize = 5;
+
+/// The uplink channel plan of a region. Frequencies are in kHz. A plan
+/// matches a frequency when the frequency is in the band and, if the plan has
+/// a channel grid, lies on one of its grids.
+struct Plan {
+    region: Region,
+    min: u32,
+    max: u32,
+    /// (origin, step) of the channel grids for the region. An empty list
+    /// means channels can be anywhere in the band.
+    grids: &'static [(u32, u32)],
+    /// (min, max) of the downlink band of the region
+    downlink: (u32, u32),
+    /// The maximum EIRP of a downlink in dBm, rounded down
+    max_eirp: u64,
+    /// Allowed downlink bandwidths in kHz
+    bandwidths: &'static [u32],
+    /// The frequency (kHz) and datarate of the default RX2 window
+    rx2: (u32, &'static str),
+    /// The maximum downlink MACPayload size in bytes for spreading factors 7
+    /// to 12, without a repeater and with dwell time limits off
+    max_macpayload: [usize; 6],
+    /// The uplink channels a concentrator listens on in the region. Empty for
+    /// regions whose default channels are not on whole kHz.
+    channels: &'static [u32],
+}
+
+impl Plan {
+    fn matches(&self, khz: u32) -> bool {
+        if khz < self.min || khz > self.max {
+            return false;
+        }
+        self.grids.is_empty()
+            || self
+                .grids
+                .iter()
+                .any(|(origin, step)| khz >= *origin && (khz - origin) % step == 0)
+    }
+
+    fn width(&self) -> u32 {
+        self.max - self.min
+    }
+
+    /// Returns the channel index of the frequency, counting the channels of
+    /// the grids in order.
+    fn channel_index(&self, khz: u32) -> Option<u32> {
+        if khz < self.min || khz > self.max {
+            return None;
+        }
+        let mut offset = 0;
+        for (origin, step) in self.grids {
+            if khz >= *origin && (khz - origin) % step == 0 {
+                return Some(offset + (khz - origin) / step);
+            }
+            offset += (self.max - origin) / step + 1;
+        }
+        None
+    }
+
+    fn validate_downlink(&self, khz: u32, datarate: &str) -> Result<(), DownlinkError> {
+        let (min, max) = self.downlink;
+        if khz < min || khz > max {
+            return Err(DownlinkError::Frequency(khz as f32 / 1000.0));
+        }
+        let (spreading_factor, bandwidth) = parse_lora_datarate(datarate)
+            .ok_or_else(|| DownlinkError::Datarate(datarate.to_string()))?;
+        if !(7..=12).contains(&spreading_factor) || !self.bandwidths.contains(&bandwidth) {
+            return Err(DownlinkError::Datarate(datarate.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a LoRa datarate of the form `SF<n>BW<khz>` into its spreading
+/// factor and bandwidth (in kHz).
+pub fn parse_lora_datarate(datarate: &str) -> Option<(u32, u32)> {
+    let rest = datarate.strip_prefix("SF")?;
+    let bw = rest.find("BW")?;
+    Some((rest[..bw].parse().ok()?, rest[bw + 2..].parse().ok()?))
+}
+
+/// The time on air of a LoRa downlink of the given PHYPayload length, sent
+/// like LoRaWAN downlinks are: an 8 symbol preamble, an explicit header,
+/// coding rate 4/5 and no payload CRC.
+pub fn airtime(datarate: &str, len: usize) -> Option<Duration> {
+    let (spreading_factor, bandwidth) = parse_lora_datarate(datarate)?;
+    let symbol_us = (1u64 << spreading_factor) as f64 * 1000.0 / bandwidth as f64;
+    // Low datarate optimization is on for symbols of 16 ms and longer
+    let low_datarate = if symbol_us >= 16_000.0 { 1.0 } else { 0.0 };
+    let spreading_factor = spreading_factor as f64;
+    let payload_bits = 8.0 * len as f64 - 4.0 * spreading_factor + 28.0;
+    let payload_symbols = 8.0
+        + ((payload_bits / (4.0 * (spreading_factor - 2.0 * low_datarate))).ceil() * 5.0).max(0.0);
+    let preamble_symbols = 8.0 + 4.25;
+    Some(Duration::from_micros(
+        ((preamble_symbols + payload_symbols) * symbol_us).round() as u64,
+    ))
+}
+
+/// Channel plans for the supported regions. The AS923 uplink plans cover the
+/// default and commonly used channels of each variant rather than the full
+/// 915-928 MHz band, which would otherwise make them indistinguishable. Their
+/// downlinks can use the whole band of the variant.
+const PLANS: &[Plan] = &[
+    Plan {
+        region: Region::Us915,
+        min: 902_300,
+        max: 914_900,
+        grids: &[(902_300, 200), (903_000, 1_600)],
+        downlink: (923_300, 927_500),
+        max_eirp: 30,
+        bandwidths: &[500],
+        rx2: (923_300, "SF12BW500"),
+        max_macpayload: [250, 250, 250, 250, 137, 61],
+        channels: &[
+            903_900, 904_100, 904_300, 904_500, 904_700, 904_900, 905_100, 905_300,
+        ],
+    },
+    Plan {
+        region: Region::Au915,
+        min: 915_200,
+        max: 927_800,
+        grids: &[(915_200, 200), (915_900, 1_600)],
+        downlink: (923_300, 927_500),
+        max_eirp: 30,
+        bandwidths: &[500],
+        rx2: (923_300, "SF12BW500"),
+        max_macpayload: [250, 250, 250, 250, 137, 61],
+        channels: &[
+            916_800, 917_000, 917_200, 917_400, 917_600, 917_800, 918_000, 918_200,
+        ],
+    },
+    Plan {
+        region: Region::As9231,
+        min: 922_000,
+        max: 923_400,
+        grids: &[(922_000, 200)],
+        downlink: (915_000, 928_000),
+        max_eirp: 16,
+        bandwidths: &[125, 250],
+        rx2: (923_200, "SF10BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[
+            922_000, 922_200, 922_400, 922_600, 922_800, 923_000, 923_200, 923_400,
+        ],
+    },
+    Plan {
+        region: Region::As9232,
+        min: 920_200,
+        max: 921_600,
+        grids: &[(920_200, 200)],
+        downlink: (915_000, 928_000),
+        max_eirp: 16,
+        bandwidths: &[125, 250],
+        rx2: (921_400, "SF10BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[
+            920_200, 920_400, 920_600, 920_800, 921_000, 921_200, 921_400, 921_600,
+        ],
+    },
+    Plan {
+        region: Region::As9233,
+        min: 915_400,
+        max: 916_800,
+        grids: &[(915_400, 200)],
+        downlink: (915_000, 928_000),
+        max_eirp: 16,
+        bandwidths: &[125, 250],
+        rx2: (916_600, "SF10BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[
+            915_400, 915_600, 915_800, 916_000, 916_200, 916_400, 916_600, 916_800,
+        ],
+    },
+    Plan {
+        region: Region::As9234,
+        min: 916_100,
+        max: 917_500,
+        grids: &[(916_100, 200)],
+        downlink: (917_000, 920_000),
+        max_eirp: 16,
+        bandwidths: &[125, 250],
+        rx2: (917_300, "SF10BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[
+            916_100, 916_300, 916_500, 916_700, 916_900, 917_100, 917_300, 917_500,
+        ],
+    },
+    Plan {
+        region: Region::Kr920,
+        min: 920_900,
+        max: 923_300,
+        grids: &[(920_900, 200)],
+        downlink: (920_900, 923_300),
+        max_eirp: 14,
+        bandwidths: &[125],
+        rx2: (921_900, "SF12BW125"),
+        max_macpayload: [230, 230, 123, 59, 59, 59],
+        channels: &[
+            921_900, 922_100, 922_300, 922_500, 922_700, 922_900, 923_100, 923_300,
+        ],
+    },
+    Plan {
+        region: Region::Eu868,
+        min: 863_000,
+        max: 870_000,
+        grids: &[],
+        downlink: (863_000, 870_000),
+        max_eirp: 16,
+        bandwidths: &[125, 250],
+        rx2: (869_525, "SF12BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[
+            867_100, 867_300, 867_500, 867_700, 867_900, 868_100, 868_300, 868_500,
+        ],
+    },
+    Plan {
+        region: Region::In865,
+        min: 865_000,
+        max: 867_000,
+        grids: &[],
+        downlink: (865_000, 867_000),
+        max_eirp: 30,
+        bandwidths: &[125],
+        rx2: (866_550, "SF10BW125"),
+        max_macpayload: [230, 230, 123, 59, 59, 59],
+        channels: &[],
+    },
+    Plan {
+        region: Region::Eu433,
+        min: 433_050,
+        max: 434_790,
+        grids: &[],
+        downlink: (433_050, 434_790),
+        max_eirp: 12,
+        bandwidths: &[125, 250],
+        rx2: (434_665, "SF12BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[433_175, 433_375, 433_575],
+    },
+    Plan {
+        region: Region::Cn470,
+        min: 470_300,
+        max: 489_300,
+        grids: &[(470_300, 200)],
+        downlink: (500_300, 509_700),
+        max_eirp: 19,
+        bandwidths: &[125],
+        rx2: (505_300, "SF12BW125"),
+        max_macpayload: [230, 230, 123, 59, 59, 59],
+        channels: &[
+            486_300, 486_500, 486_700, 486_900, 487_100, 487_300, 487_500, 487_700,
+        ],
+    },
+    Plan {
+        region: Region::Cn779,
+        min: 779_500,
+        max: 786_500,
+        grids: &[],
+        downlink: (779_500, 786_500),
+        max_eirp: 12,
+        bandwidths: &[125, 250],
+        rx2: (786_000, "SF12BW125"),
+        max_macpayload: [250, 250, 123, 59, 59, 59],
+        channels: &[779_500, 779_700, 779_900],
+    },
+];
+
+/// The fraction of observed uplinks a region has to explain before it is
+/// considered a confident inference.
+pub const CONFIDENCE_THRESHOLD: f32 = 0.9;
+
+fn plan_for(region: Region) -> Option<&'static Plan> {
+    PLANS.iter().find(|plan| plan.region == region)
+}
+
+/// Returns whether there is a channel plan for the given region.
+pub fn has_plan(region: Region) -> bool {
+    plan_for(region).is_some()
+}
+
+/// Returns the regions whose uplink channel plan includes the given
+/// frequency (in MHz).
+pub fn candidates(frequency: f32) -> Vec<Region> {
+    let khz = (frequency * 1000.0).round() as u32;
+    PLANS
+        .iter()
+        .filter(|plan| plan.matches(khz))
+        .map(|plan| plan.region)
+        .collect()
+}
+
+/// Returns the uplink channel index of the given frequency (in MHz) in the
+/// channel plan of the given region. Regions without a fixed channel grid have
+/// no channel indices.
+pub fn channel_index(region: Region, frequency: f32) -> Option<u32> {
+    plan_for(region)?.channel_index((frequency * 1000.0).round() as u32)
+}
+
+/// Checks a downlink frequency (in MHz) and datarate against the downlink
+/// channel plan of the given region.
+pub fn validate_downlink(
+    region: Region,
+    frequency: f32,
+    datarate: &str,
+) -> Result<(), DownlinkError> {
+    let plan = plan_for(region).ok_or(DownlinkError::Region(region))?;
+    plan.validate_downlink((frequency * 1000.0).round() as u32, datarate)
+}
+
+/// The maximum downlink transmit power in dBm of the given region, taking
+/// the transmit power as the EIRP. Antenna gain is left to the per forwarder
+/// power limits.
+pub fn max_power(region: Region) -> Option<u64> {
+    plan_for(region).map(|plan| plan.max_eirp)
+}
+
+/// Checks a downlink transmit power in dBm against the maximum EIRP of the
+/// given region.
+pub fn check_power(region: Region, power: u64) -> Result<(), DownlinkError> {
+    let max_power = max_power(region).ok_or(DownlinkError::Region(region))?;
+    if power > max_power {
+        return Err(DownlinkError::Power(power, max_power));
+    }
+    Ok(())
+}
+
+/// The frequency (MHz) and datarate of the default RX2 window of the given
+/// region.
+pub fn rx2_window(region: Region) -> Option<(f32, &'static str)> {
+    let (khz, datarate) = plan_for(region)?.rx2;
+    Some((khz as f32 / 1000.0, datarate))
+}
+
+/// The maximum downlink PHYPayload size in bytes for the datarate in the
+/// given region.
+pub fn max_payload(region: Region, datarate: &str) -> Option<usize> {
+    let plan = plan_for(region)?;
+    let (spreading_factor, _) = parse_lora_datarate(datarate)?;
+    let index = spreading_factor.checked_sub(7)? as usize;
+    Some(plan.max_macpayload.get(index)? + PHY_OVERHEAD)
+}
+
+/// Checks that a downlink PHYPayload of the given length fits the datarate
+/// in the given region and, when limited, the maximum airtime. The maximum
+/// sizes of the regional parameters can be lowered or raised per datarate in
+/// the settings.
+pub fn check_downlink_size(
+    region: Region,
+    datarate: &str,
+    len: usize,
+    settings: &DownlinkGuardSettings,
+) -> Result<(), DownlinkError> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    let max = settings
+        .max_payload
+        .get(datarate)
+        .copied()
+        .or_else(|| max_payload(region, datarate));
+    if let Some(max) = max {
+        if len > max {
+            return Err(DownlinkError::Payload(len, max, datarate.to_string()));
+        }
+    }
+    if settings.max_airtime_ms > 0 {
+        if let Some(airtime) = airtime(datarate, len) {
+            let airtime_ms = airtime.as_millis() as u64;
+            if airtime_ms > settings.max_airtime_ms {
+                return Err(DownlinkError::Airtime(
+                    airtime_ms,
+                    settings.max_airtime_ms,
+                    datarate.to_string(),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns a packet forwarder `SX1301_conf` section, or the `SX130x_conf`
+/// section of the SX1302 forwarder, with the uplink channels of the given
+/// region, for merging into a `global_conf.json`. The channels are split over
+/// the two radios of the concentrator, each radio centered on its half of the
+/// channels. Returns None for regions without default channels.
+pub fn concentrator_conf(region: Region, concentrator: Concentrator) -> Option<Value> {
+    let plan = plan_for(region)?;
+    if plan.channels.is_empty() {
+        return None;
+    }
+    let mut channels = plan.channels.to_vec();
+    channels.sort_unstable();
+    let (low, high) = channels.split_at((channels.len() + 1) / 2);
+    let center = |group: &[u32]| group.first().zip(group.last()).map(|(a, b)| (a + b) / 2);
+    // The SX1255 radio covers the sub 500 MHz bands, the SX1250 of the
+    // SX1302 covers all of them
+    let radio_type = match concentrator {
+        Concentrator::Sx1302 => "SX1250",
+        Concentrator::Sx1301 if plan.max < 600_000 => "SX1255",
+        Concentrator::Sx1301 => "SX1257",
+    };
+    let mut conf = Map::new();
+    for (radio, group) in [low, high].iter().copied().enumerate() {
+        let value = match center(group) {
+            Some(freq) => json!({
+                "enable": true,
+                "type": radio_type,
+                "freq": freq * 1000,
+                "tx_enable": radio == 0,
+                "tx_freq_min": plan.downlink.0 * 1000,
+                "tx_freq_max": plan.downlink.1 * 1000,
+            }),
+            None => json!({ "enable": false }),
+        };
+        conf.insert(format!("radio_{}", radio), value);
+    }
+    for (index, khz) in channels.iter().enumerate() {
+        let (radio, group) = if index < low.len() {
+            (0, low)
+        } else {
+            (1, high)
+        };
+        let offset = (*khz as i64 - center(group)? as i64) * 1000;
+        conf.insert(
+            format!("chan_multiSF_{}", index),
+            json!({ "enable": true, "radio": radio, "if": offset }),
+        );
+    }
+    conf.insert("chan_Lora_std".to_string(), json!({ "enable": false }));
+    conf.insert("chan_FSK".to_string(), json!({ "enable": false }));
+    let section = match concentrator {
+        Concentrator::Sx1301 => "SX1301_conf",
+        Concentrator::Sx1302 => "SX130x_conf",
+    };
+    Some(json!({ section: conf }))
+}
+
+/// The result of a region inference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Inference {
+    pub region: Region,
+    /// Fraction of the observed uplinks that fit the region's channel plan
+    pub confidence: f32,
+    /// Number of uplinks the inference is based on
+    pub samples: u32,
+}
+
+impl Inference {
+    pub fn is_confident(&self, min_samples: u32) -> bool {
+        self.samples >= min_samples && self.confidence >= CONFIDENCE_THRESHOLD
+    }
+}
+
+/// Infers the likely region of a gateway from the frequencies of the uplinks
+/// it receives.
+///
+/// Every uplink is counted toward each region whose channel plan includes
+/// its frequency. The inferred region is the one that explains the most
+/// uplinks, with ties going to the region with the narrowest band since it is
+/// the more specific explanation.
+#[derive(Debug, Default)]
+pub struct RegionInference {
+    samples: u32,
+    counts: HashMap<Region, u32>,
+}
+
+impl RegionInference {
+    /// Records an uplink frequency (in MHz) and returns the updated
+    /// inference, if any region matches the observed uplinks.
+    pub fn observe(&mut self, frequency: f32) -> Option<Inference> {
+        self.samples += 1;
+        for region in candidates(frequency) {
+            *self.counts.entry(region).or_insert(0) += 1;
+        }
+        self.inference()
+    }
+
+    pub fn inference(&self) -> Option<Inference> {
+        self.counts
+            .iter()
+            .filter_map(|(region, count)| plan_for(*region).map(|plan| (plan, *count)))
+            .max_by(|(a_plan, a_count), (b_plan, b_count)| {
+                a_count
+                    .cmp(b_count)
+                    .then_with(|| b_plan.width().cmp(&a_plan.width()))
+            })
+            .map(|(plan, count)| Inference {
+                region: plan.region,
+                confidence: count as f32 / self.samples as f32,
+                samples: self.samples,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer(frequencies: &[f32]) -> Option<Region> {
+        let mut inference = RegionInference::default();
+        frequencies
+            .iter()
+            .filter_map(|f| inference.observe(*f))
+            .last()
+            .map(|i| i.region)
+    }
+
+    #[test]
+    fn us915() {
+        assert_eq!(Some(Region::Us915), infer(&[903.9, 904.1, 904.3, 904.6]));
+    }
+
+    #[test]
+    fn au915_subband_2() {
+        assert_eq!(Some(Region::Au915), infer(&[916.8, 917.0, 917.6, 918.2]));
+    }
+
+    #[test]
+    fn eu868_and_in865() {
+        assert_eq!(Some(Region::Eu868), infer(&[867.1, 868.1, 868.5]));
+        assert_eq!(Some(Region::In865), infer(&[865.0625, 865.4025, 865.985]));
+    }
+
+    #[test]
+    fn as923_and_kr920() {
+        assert_eq!(Some(Region::As9231), infer(&[923.2, 923.4, 922.0, 922.6]));
+        assert_eq!(Some(Region::Kr920), infer(&[922.1, 922.3, 922.5, 923.1]));
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(Some(0), channel_index(Region::Us915, 902.3));
+        assert_eq!(Some(8), channel_index(Region::Us915, 903.9));
+        assert_eq!(Some(64), channel_index(Region::Us915, 903.0));
+        assert_eq!(Some(71), channel_index(Region::Us915, 914.2));
+        assert_eq!(None, channel_index(Region::Us915, 903.95));
+        assert_eq!(None, channel_index(Region::Eu868, 868.1));
+    }
+
+    #[test]
+    fn downlink() {
+        assert!(validate_downlink(Region::Us915, 923.3, "SF10BW500").is_ok());
+        assert!(validate_downlink(Region::Eu868, 869.525, "SF12BW125").is_ok());
+        assert_eq!(
+            Err(DownlinkError::Frequency(904.1)),
+            validate_downlink(Region::Us915, 904.1, "SF10BW500")
+        );
+        assert_eq!(
+            Err(DownlinkError::Datarate("SF10BW125".to_string())),
+            validate_downlink(Region::Us915, 923.3, "SF10BW125")
+        );
+        // AS923 downlinks can use the whole band of the variant
+        assert!(validate_downlink(Region::As9231, 925.0, "SF10BW125").is_ok());
+        assert!(validate_downlink(Region::As9234, 918.0, "SF10BW125").is_ok());
+        assert!(validate_downlink(Region::As9234, 916.1, "SF10BW125").is_err());
+        for plan in PLANS {
+            let (frequency, datarate) = rx2_window(plan.region).expect("rx2 window");
+            assert!(validate_downlink(plan.region, frequency, datarate).is_ok());
+        }
+    }
+
+    #[test]
+    fn power() {
+        assert!(check_power(Region::Us915, 27).is_ok());
+        assert_eq!(
+            Err(DownlinkError::Power(27, 16)),
+            check_power(Region::Eu868, 27)
+        );
+        assert!(check_power(Region::As9232, 16).is_ok());
+        assert_eq!(Some(14), max_power(Region::Kr920));
+    }
+
+    #[test]
+    fn downlink_size() {
+        let guard = DownlinkGuardSettings {
+            enabled: true,
+            max_airtime_ms: 0,
+            max_payload: HashMap::new(),
+        };
+        assert_eq!(Some(64), max_payload(Region::Eu868, "SF12BW125"));
+        assert_eq!(Some(66), max_payload(Region::Us915, "SF12BW500"));
+        assert!(check_downlink_size(Region::Eu868, "SF12BW125", 64, &guard).is_ok());
+        assert_eq!(
+            Err(DownlinkError::Payload(65, 64, "SF12BW125".to_string())),
+            check_downlink_size(Region::Eu868, "SF12BW125", 65, &guard)
+        );
+        assert_eq!(Some(Duration::from_micros(41_216)), airtime("SF7BW125", 13));
+        assert_eq!(
+            Some(Duration::from_micros(1_155_072)),
+            airtime("SF12BW125", 13)
+        );
+        let guard = DownlinkGuardSettings {
+            max_airtime_ms: 400,
+            max_payload: vec![("SF7BW125".to_string(), 20)].into_iter().collect(),
+            ..guard
+        };
+        assert!(check_downlink_size(Region::Eu868, "SF12BW125", 13, &guard).is_err());
+        assert!(check_downlink_size(Region::Eu868, "SF7BW125", 21, &guard).is_err());
+    }
+
+    #[test]
+    fn concentrator() {
+        let conf = concentrator_conf(Region::Us915, Concentrator::Sx1301).expect("us915 conf");
+        let conf = &conf["SX1301_conf"];
+        assert_eq!(904_200_000, conf["radio_0"]["freq"]);
+        assert_eq!(905_000_000, conf["radio_1"]["freq"]);
+        assert_eq!("SX1257", conf["radio_0"]["type"]);
+        assert_eq!(-300_000, conf["chan_multiSF_0"]["if"]);
+        assert_eq!(1, conf["chan_multiSF_7"]["radio"]);
+        assert_eq!(300_000, conf["chan_multiSF_7"]["if"]);
+        assert_eq!(None, concentrator_conf(Region::In865, Concentrator::Sx1301));
+
+        let conf = concentrator_conf(Region::Us915, Concentrator::Sx1302).expect("us915 conf");
+        assert!(conf.get("SX1301_conf").is_none());
+        let conf = &conf["SX130x_conf"];
+        assert_eq!("SX1250", conf["radio_0"]["type"]);
+        assert_eq!(904_200_000, conf["radio_0"]["freq"]);
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(None, infer(&[100.0]));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::*;
+use slog::{warn, Logger};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+
+/// Largest UDP datagram relayed.
+pub const MAX_DATAGRAM: usize = 65_507;
+/// Maximum number of datagrams from upstream queued for the owner of the
+/// relay.
+pub const REPLY_QUEUE_SIZE: usize = 32;
+
+/// The upstream socket of a client and the task receiving on it. The task
+/// ends when the client is dropped.
+#[derive(Debug)]
+struct Upstream {
+    socket: Arc<UdpSocket>,
+    task: JoinHandle<()>,
+    last_seen: Instant,
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Relays datagrams from clients to an upstream address, each client with
+/// its own upstream socket so the far end sees every client separately and
+/// replies reach the right one. Datagrams from upstream are queued with the
+/// key of their client for the owner of the relay to pass on.
+///
+/// A failed receive on an upstream socket is logged and the socket kept: a
+/// connected UDP socket reports an unreachable upstream from an earlier send
+/// on the next receive, which says nothing about the next datagram.
+#[derive(Debug)]
+pub struct Upstreams<K> {
+    addr: SocketAddr,
+    clients: HashMap<K, Upstream>,
+    replies: Sender<(K, Vec<u8>)>,
+}
+
+impl<K> Upstreams<K>
+where
+    K: Clone + Eq + Hash + Display + Send + 'static,
+{
+    pub fn new(addr: SocketAddr) -> (Self, Receiver<(K, Vec<u8>)>) {
+        let (replies, receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+        let upstreams = Self {
+            addr,
+            clients: HashMap::new(),
+            replies,
+        };
+        (upstreams, receiver)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.clients.contains_key(key)
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        self.clients.keys().cloned().collect()
+    }
+
+    /// The local address of the upstream socket of a client, which the far
+    /// end sees the client as.
+    pub fn local_addr(&self, key: &K) -> Option<SocketAddr> {
+        self.clients
+            .get(key)
+            .and_then(|upstream| upstream.socket.local_addr().ok())
+    }
+
+    /// Opens the upstream socket of a client unless it has one. Returns
+    /// whether the client is new.
+    pub async fn connect(&mut self, key: &K, logger: &Logger) -> Result<bool> {
+        if self.clients.contains_key(key) {
+            return Ok(false);
+        }
+        let bind_addr = if self.addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+        socket.connect(self.addr).await?;
+        let (reader, replies, client, logger) = (
+            socket.clone(),
+            self.replies.clone(),
+            key.clone(),
+            logger.clone(),
+        );
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                match reader.recv(&mut buf).await {
+                    Ok(len) => {
+                        if replies
+                            .send((client.clone(), buf[..len].to_vec()))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(err) => warn!(logger, "upstream receive failed for {}: {:?}", client, err),
+                }
+            }
+        });
+        self.clients.insert(
+            key.clone(),
+            Upstream {
+                socket,
+                task,
+                last_seen: Instant::now(),
+            },
+        );
+        Ok(true)
+    }
+
+    /// Sends a datagram upstream for a connected client.
+    pub async fn send(&mut self, key: &K, datagram: &[u8]) -> Result {
+        let upstream = self
+            .clients
+            .get_mut(key)
+            .ok_or_else(|| Error::custom(format!("no upstream for {}", key)))?;
+        upstream.last_seen = Instant::now();
+        upstream.socket.send(datagram).await?;
+        Ok(())
+    }
+
+    /// Forgets the clients that have not sent anything within the timeout,
+    /// returning their keys.
+    pub fn evict(&mut self, timeout: Duration) -> Vec<K> {
+        let idle: Vec<K> = self
+            .clients
+            .iter()
+            .filter(|(_, upstream)| upstream.last_seen.elapsed() >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &idle {
+            self.clients.remove(key);
+        }
+        idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn unreachable() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        // Nothing listens upstream for the first datagram
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .expect("bind")
+            .local_addr()
+            .expect("addr");
+        let (mut upstreams, mut replies) = Upstreams::new(addr);
+        let client = "client".to_string();
+        assert!(upstreams.connect(&client, &logger).await.expect("connect"));
+        assert!(!upstreams.connect(&client, &logger).await.expect("connect"));
+        upstreams.send(&client, b"lost").await.expect("send");
+        time::sleep(Duration::from_millis(50)).await;
+
+        // The refusal of the first datagram shows up on the next send or
+        // receive, and the client keeps its upstream either way
+        let server = UdpSocket::bind(addr).await.expect("bind");
+        if upstreams.send(&client, b"ping").await.is_err() {
+            upstreams.send(&client, b"ping").await.expect("send");
+        }
+        let mut buf = [0u8; 16];
+        let (len, from) = server.recv_from(&mut buf).await.expect("recv");
+        assert_eq!(b"ping", &buf[..len]);
+        server.send_to(b"pong", from).await.expect("send");
+        let reply = time::timeout(Duration::from_secs(1), replies.recv())
+            .await
+            .expect("reply");
+        assert_eq!(Some((client.clone(), b"pong".to_vec())), reply);
+
+        assert!(upstreams.evict(Duration::from_secs(60)).is_empty());
+        assert_eq!(vec![client], upstreams.evict(Duration::from_secs(0)));
+        assert!(upstreams.is_empty());
+    }
+}
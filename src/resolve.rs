@@ -0,0 +1,152 @@
+use crate::*;
+use settings::{BackhaulSettings, Nat64};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, time};
+
+/// The NAT64 well-known prefix (RFC 6052).
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+/// The name DNS64 resolvers synthesize an address for, revealing the NAT64
+/// prefix (RFC 7050).
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa";
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// Returns the NAT64 prefix to use. With `auto` the prefix is discovered
+/// through the DNS64 resolver, if there is one.
+pub async fn nat64_prefix(nat64: &Nat64) -> Option<Ipv6Addr> {
+    match nat64 {
+        Nat64::Off => None,
+        Nat64::Prefix(prefix) => Some(*prefix),
+        Nat64::Auto => tokio::net::lookup_host((IPV4ONLY_ARPA, 0))
+            .await
+            .ok()?
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V6(ip) if IPV4ONLY_ADDRS.contains(&embedded_ipv4(ip)) => {
+                    Some(synthesize(ip, Ipv4Addr::UNSPECIFIED))
+                }
+                _ => None,
+            }),
+    }
+}
+
+/// Embeds an IPv4 address in the last 32 bits of a /96 NAT64 prefix.
+pub fn synthesize(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&ip.octets());
+    Ipv6Addr::from(octets)
+}
+
+fn embedded_ipv4(ip: Ipv6Addr) -> Ipv4Addr {
+    let octets = ip.octets();
+    Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])
+}
+
+/// Rewrites an IPv4 literal host in the uri to its NAT64 address. Hostnames
+/// are left alone since a DNS64 resolver synthesizes their addresses.
+pub fn nat64_uri(uri: &http::Uri, prefix: Option<Ipv6Addr>) -> http::Uri {
+    let (prefix, ip) = match (prefix, uri.host().and_then(|host| host.parse().ok())) {
+        (Some(prefix), Some(ip)) => (prefix, ip),
+        _ => return uri.clone(),
+    };
+    let authority = match uri.port_u16() {
+        Some(port) => format!("[{}]:{}", synthesize(prefix, ip), port),
+        None => format!("[{}]", synthesize(prefix, ip)),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.authority = authority.parse().ok();
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some(http::uri::PathAndQuery::from_static("/"));
+    }
+    http::Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+/// Resolves a host to the addresses to try in order. IPv4 addresses get a
+/// NAT64 address next to them when a prefix is in use, and IPv6 addresses go
+/// first when preferred.
+pub async fn resolve(
+    host: &str,
+    port: u16,
+    backhaul: &BackhaulSettings,
+) -> Result<Vec<SocketAddr>> {
+    let mut addrs = vec![];
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        if let (SocketAddr::V4(v4), Some(prefix)) = (addr, backhaul.nat64_prefix) {
+            addrs.push(SocketAddr::new(synthesize(prefix, *v4.ip()).into(), port));
+        }
+        addrs.push(addr);
+    }
+    if backhaul.prefer_ipv6 {
+        // A stable sort keeps the resolver order within each family
+        addrs.sort_by_key(|addr| addr.is_ipv4());
+    }
+    addrs.dedup();
+    Ok(addrs)
+}
+
+/// The outcome of connecting to the first address of an address family.
+#[derive(Debug)]
+pub struct Attempt {
+    pub addr: SocketAddr,
+    pub result: Result<Duration>,
+}
+
+impl Attempt {
+    pub fn family(&self) -> &'static str {
+        if self.addr.is_ipv6() {
+            "ipv6"
+        } else {
+            "ipv4"
+        }
+    }
+}
+
+/// Connects to the first IPv6 and the first IPv4 address of the resolved
+/// addresses, in the order resolved, to show which families get through.
+pub async fn attempts(addrs: &[SocketAddr], timeout: Duration) -> Vec<Attempt> {
+    let mut attempts = vec![];
+    for ipv6 in &[true, false] {
+        if let Some(addr) = addrs.iter().find(|addr| addr.is_ipv6() == *ipv6) {
+            let started = Instant::now();
+            let result = match time::timeout(timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(_)) => Ok(started.elapsed()),
+                Ok(Err(err)) => Err(err.into()),
+                Err(_) => Err(Error::custom("connect timed out")),
+            };
+            attempts.push(Attempt {
+                addr: *addr,
+                result,
+            });
+        }
+    }
+    if addrs.first().map_or(false, |addr| addr.is_ipv4()) {
+        attempts.reverse();
+    }
+    attempts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nat64() {
+        let ip = Ipv4Addr::new(52, 8, 80, 146);
+        let synthesized = synthesize(WELL_KNOWN_PREFIX, ip);
+        assert_eq!("64:ff9b::3408:5092", synthesized.to_string());
+        assert_eq!(ip, embedded_ipv4(synthesized));
+
+        let uri: http::Uri = "http://52.8.80.146:8080".parse().expect("uri");
+        assert_eq!(
+            Some("[64:ff9b::3408:5092]:8080"),
+            nat64_uri(&uri, Some(WELL_KNOWN_PREFIX))
+                .authority()
+                .map(|authority| authority.as_str())
+        );
+        assert_eq!(uri, nat64_uri(&uri, None));
+        let uri: http::Uri = "http://router.example.com:8080".parse().expect("uri");
+        assert_eq!(uri, nat64_uri(&uri, Some(WELL_KNOWN_PREFIX)));
+    }
+}
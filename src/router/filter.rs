@@ -1,6 +1,6 @@
 use bytes::{Buf, BufMut, BytesMut};
 use helium_proto::Eui;
-use std::hash::Hasher;
+use std::{hash::Hasher, mem};
 use xorf::{Filter as XorFilter, Xor16};
 use xxhash_c::XXH64;
 
@@ -36,6 +36,11 @@ impl EuiFilter {
         let hash = hasher.finish();
         self.0.contains(&hash)
     }
+
+    /// Approximate memory used by the filter in bytes.
+    pub fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.0.fingerprints.len() * mem::size_of::<u16>()
+    }
 }
 
 impl DevAddrFilter {
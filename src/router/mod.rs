@@ -1,53 +1,147 @@
 use crate::*;
-use helium_proto::RoutingInformation;
+use address_book::Role;
+use decisions::{Decision, Decisions, Reason};
+use forwarder_config::ForwarderConfig;
+use futures::FutureExt;
+use helium_proto::{routing_information::Data as RoutingData, Message, RoutingInformation};
 use link_packet::LinkPacket;
+use lorawan::MType;
+use memory::MemoryBudget;
+use race::Race;
+use rand::{rngs::OsRng, seq::SliceRandom};
+use region::RegionInference;
+use sender::Senders;
 use service::{
+    breaker::{Breakers, CircuitBreaker},
     gateway::{Response as GatewayResponse, Service as GatewayService, Streaming},
-    router::Service as RouterService,
+    router::{EnvelopeVersion, RouteOptions, Service as RouterService},
 };
+use settings::{BackhaulSettings, DownlinkGuardSettings, PayloadPrivacy, RouterSelectionSettings};
+use signer::Signer;
 use slog::{debug, info, o, warn, Logger};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use stats::Stats;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use store::Store;
+use telemetry::Tracer;
 use tokio::{
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch,
+    },
     time,
 };
 
 pub mod filter;
+pub mod race;
 pub mod routing;
+pub mod sender;
 
 pub use helium_proto::Region;
 pub use routing::Routing;
 
+/// How often the request counters of router and gateway endpoints are logged.
+pub const ENDPOINT_REPORT_INTERVAL_SECS: u64 = 300;
+
+const STATE_TREE: &str = "router";
+const HEIGHT_KEY: &[u8] = b"height";
+/// Routing entries are saved as base64 encoded protobufs keyed by OUI
+const ROUTING_TREE: &str = "routing";
+
 pub struct Router {
     downlinks: Sender<LinkPacket>,
     uplinks: Receiver<LinkPacket>,
-    region: Region,
-    keypair: Arc<Keypair>,
-    gateways: Vec<KeyedUri>,
+    region: Option<Region>,
+    region_inference: RegionInference,
+    region_samples: u32,
+    region_lock: bool,
+    forwarder_config: ForwarderConfig,
+    senders: Senders,
+    signer: Signer,
+    /// Swaps of the gateway keypair, which the signer makes on its own
+    rotations: watch::Receiver<Arc<Keypair>>,
+    gateways: Vec<(KeyedUri, Arc<CircuitBreaker>)>,
     routing_height: u64,
     clients: HashMap<u32, Routing>,
     default_client: RouterService,
+    tracer: Tracer,
+    budget: MemoryBudget,
+    breakers: Breakers,
+    backhaul: BackhaulSettings,
+    /// The time router answers have, None when requests are left to time out
+    response_budget: Option<ResponseBudget>,
+    selection: RouterSelectionSettings,
+    privacy: PayloadPrivacy,
+    downlink_guard: Arc<DownlinkGuardSettings>,
+    /// The labels of the gateway sent with each request
+    labels: Option<Arc<str>>,
+    snapshots: watch::Receiver<()>,
+    store: Store,
+    stats: Stats,
+    decisions: Decisions,
 }
 
 impl Router {
     pub fn new(
         downlinks: Sender<LinkPacket>,
         uplinks: Receiver<LinkPacket>,
+        signer: Signer,
+        tracer: Tracer,
+        budget: MemoryBudget,
+        snapshots: watch::Receiver<()>,
+        store: Store,
+        stats: Stats,
+        decisions: Decisions,
         settings: &Settings,
     ) -> Result<Self> {
-        let gateways = settings.gateways.clone();
-        let router_settings = settings.default_router().clone();
-        let default_client =
-            RouterService::new(router_settings.uri, Some(router_settings.public_key))?;
+        let gateways = settings
+            .address_book
+            .with_role(Role::Gateway)
+            .into_iter()
+            .map(|gateway| {
+                let breaker = Arc::new(CircuitBreaker::new(&settings.circuit_breaker));
+                (gateway, breaker)
+            })
+            .collect();
+        let mut breakers = Breakers::new(&settings.circuit_breaker);
+        let router_settings = settings.default_router()?;
+        let default_client = RouterService::new(
+            router_settings.uri.clone(),
+            Some(router_settings.public_key),
+            breakers.get(&router_settings.uri),
+            &settings.backhaul,
+        )?;
         Ok(Self {
-            keypair: settings.keypair.clone(),
+            rotations: signer.rotations(),
+            signer,
             region: settings.region,
+            region_inference: RegionInference::default(),
+            region_samples: settings.region_inference.samples,
+            region_lock: settings.region_inference.lock,
+            forwarder_config: ForwarderConfig::new(&settings.forwarder_config),
+            senders: Senders::default(),
             uplinks,
             downlinks,
             gateways,
             routing_height: 0,
             clients: HashMap::new(),
             default_client,
+            tracer,
+            budget,
+            breakers,
+            backhaul: settings.backhaul.clone(),
+            response_budget: ResponseBudget::new(&settings.backhaul),
+            selection: settings.router_selection.clone(),
+            privacy: settings.privacy.payload,
+            downlink_guard: Arc::new(settings.downlink_guard.clone()),
+            labels: settings.labels_string().map(Arc::from),
+            snapshots,
+            store,
+            stats,
+            decisions,
         })
     }
 
@@ -60,9 +154,14 @@ impl Router {
                                 .as_ref()
                                 .map_or("none".to_string(), | v| v.to_string()),
             "uri" => self.default_client.uri.to_string());
+        if let Some(region) = self.region {
+            self.forwarder_config.apply(region, &logger);
+        }
+        self.restore_routing(&logger);
 
         loop {
-            let mut gateway = GatewayService::random_new(&self.gateways)?;
+            let (keyed_uri, breaker) = self.select_gateway()?;
+            let mut gateway = GatewayService::new(keyed_uri, &self.backhaul)?;
             info!(logger, "selected gateway";
                 "public_key" => gateway.verifier.to_string(),
                 "uri" => gateway.uri.to_string());
@@ -72,6 +171,9 @@ impl Router {
                         return Ok(())
                     },
                     routing_stream = gateway.routing(self.routing_height) => {
+                        if let Some(state) = breaker.record(routing_stream.is_ok()) {
+                            info!(logger, "gateway circuit {}: {}", state, gateway.uri);
+                        }
                         match routing_stream {
                             Ok(stream) => self.run_with_routing_stream(stream, shutdown.clone(), &logger).await?,
                             Err(err) => warn!(logger, "routing error: {:?}", err)
@@ -81,19 +183,33 @@ impl Router {
                             return Ok(())
                         } else {
                             // Wait a bit before trying another gateway service
-                            time::sleep(Duration::from_secs(5)).await;
+                            time::sleep(Duration::from_secs(self.backhaul.reconnect_delay)).await;
                         }
                     }
             }
         }
     }
 
+    /// Picks a random gateway whose circuit breaker allows a request, or any
+    /// gateway if all of them are failing.
+    fn select_gateway(&self) -> Result<(KeyedUri, Arc<CircuitBreaker>)> {
+        let mut gateways: Vec<&(KeyedUri, Arc<CircuitBreaker>)> = self.gateways.iter().collect();
+        gateways.shuffle(&mut OsRng);
+        gateways
+            .iter()
+            .find(|(_, breaker)| breaker.allow())
+            .or_else(|| gateways.first())
+            .map(|(keyed_uri, breaker)| (keyed_uri.clone(), breaker.clone()))
+            .ok_or_else(|| Error::custom("empty uri list"))
+    }
+
     async fn run_with_routing_stream(
         &mut self,
         mut routing_stream: Streaming,
         shutdown: triggered::Listener,
         logger: &Logger,
     ) -> Result {
+        let mut report_timer = time::interval(Duration::from_secs(ENDPOINT_REPORT_INTERVAL_SECS));
         loop {
             tokio::select! {
                 _ = shutdown.clone() => {
@@ -115,6 +231,10 @@ impl Router {
                     },
                     None => warn!(logger, "ignoring closed downlinks channel"),
                 },
+                _ = report_timer.tick() => self.report_endpoints(logger),
+                Ok(()) = self.snapshots.changed() => self.log_snapshot(logger),
+                Ok(()) = self.rotations.changed() => info!(logger, "signing uplinks with rotated key";
+                    "public_key" => self.signer.public_key().to_string()),
             }
         }
     }
@@ -138,39 +258,356 @@ impl Router {
             }
         };
         for routing in routings {
-            match routing::Routing::from_proto(logger, routing) {
+            match routing::Routing::from_proto(logger, routing, &mut self.breakers, &self.backhaul)
+            {
                 Ok(client) => {
                     self.clients.insert(routing.oui, client);
+                    self.save_routing(logger, routing);
                 }
                 Err(err) => warn!(logger, "failed to construct router client: {:?}", err),
             }
         }
+        self.enforce_routing_budget(logger);
+        self.breakers.prune();
         self.routing_height = update_height;
+        if let Err(err) = self
+            .store
+            .save(STATE_TREE, HEIGHT_KEY, &self.routing_height)
+        {
+            warn!(logger, "failed to save routing height: {:?}", err);
+        }
         info!(
             logger,
             "updated routing to height {:?}", self.routing_height
         )
     }
 
-    async fn handle_uplink(&mut self, logger: &Logger, uplink: LinkPacket) -> Result {
+    fn save_routing(&self, logger: &Logger, routing: &helium_proto::Routing) {
+        let mut encoded = Vec::with_capacity(routing.encoded_len());
+        let saved = routing
+            .encode(&mut encoded)
+            .map_err(Error::from)
+            .and_then(|_| {
+                self.store.save(
+                    ROUTING_TREE,
+                    &routing.oui.to_be_bytes(),
+                    &base64::encode(&encoded),
+                )
+            });
+        if let Err(err) = saved {
+            warn!(logger, "failed to save routing: {:?}", err; "oui" => routing.oui);
+        }
+    }
+
+    /// Restores the routing table saved with the last routing update so
+    /// uplinks are routed right away after a restart, and the routing stream
+    /// only has to catch up from the saved height. If any part of the saved
+    /// routing fails its integrity check, all of it is discarded and routing
+    /// is fetched from scratch.
+    fn restore_routing(&mut self, logger: &Logger) {
+        let height = match self.store.load::<u64>(STATE_TREE, HEIGHT_KEY) {
+            Ok(Some(saved)) => saved.value,
+            Ok(None) => return,
+            Err(err) => {
+                warn!(logger, "discarding saved routing: {:?}", err);
+                return;
+            }
+        };
+        let routings = self
+            .store
+            .load_all::<String>(ROUTING_TREE)
+            .and_then(|saved| {
+                saved
+                    .into_iter()
+                    .map(|saved| {
+                        let encoded = base64::decode(saved?.value)?;
+                        Ok(helium_proto::Routing::decode(encoded.as_slice())?)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+        let routings = match routings {
+            Ok(routings) => routings,
+            Err(err) => {
+                warn!(logger, "discarding saved routing: {:?}", err);
+                return;
+            }
+        };
+        for routing in &routings {
+            match routing::Routing::from_proto(logger, routing, &mut self.breakers, &self.backhaul)
+            {
+                Ok(client) => {
+                    self.clients.insert(routing.oui, client);
+                }
+                Err(err) => warn!(logger, "failed to construct router client: {:?}", err),
+            }
+        }
+        self.enforce_routing_budget(logger);
+        self.routing_height = height;
+        info!(logger, "restored routing at height {:?}", height;
+            "routings" => self.clients.len());
+    }
+
+    fn log_snapshot(&self, logger: &Logger) {
+        let inference = self.region_inference.inference();
+        info!(logger, "snapshot";
+            "region" => format!("{:?}", self.region),
+            "inferred_region" => inference.map(|i| format!("{:?}", i.region)),
+            "region_samples" => inference.map(|i| i.samples),
+            "routing_height" => self.routing_height,
+            "routings" => self.clients.len(),
+            "routing_bytes" => self.clients.values().map(Routing::size).sum::<usize>());
+        self.report_endpoints(logger);
+    }
+
+    fn report_endpoints(&self, logger: &Logger) {
+        let routers = self
+            .breakers
+            .iter()
+            .map(|(uri, breaker)| ("router", uri.clone(), breaker));
+        let gateways = self
+            .gateways
+            .iter()
+            .map(|(keyed_uri, breaker)| ("gateway", keyed_uri.uri.to_string(), breaker));
+        for (role, uri, breaker) in routers.chain(gateways) {
+            let metrics = breaker.metrics();
+            info!(logger, "endpoint {}", uri;
+                "role" => role,
+                "state" => breaker.state().to_string(),
+                "successes" => metrics.successes,
+                "failures" => metrics.failures,
+                "rejected" => metrics.rejected,
+                "opened" => metrics.opened);
+        }
+    }
+
+    /// Evicts the largest routing entries until the routing table fits in its
+    /// memory budget. Uplinks for evicted entries go to the default router.
+    fn enforce_routing_budget(&mut self, logger: &Logger) {
+        let budget = self.budget.routing_bytes();
+        let mut size: usize = self.clients.values().map(Routing::size).sum();
+        while size > budget {
+            let largest = self
+                .clients
+                .iter()
+                .max_by_key(|(_, routing)| routing.size())
+                .map(|(oui, _)| *oui);
+            match largest.and_then(|oui| self.clients.remove(&oui).map(|r| (oui, r))) {
+                Some((oui, routing)) => {
+                    warn!(logger, "evicting routing over memory budget";
+                        "oui" => oui,
+                        "size" => routing.size(),
+                        "budget" => budget);
+                    if let Err(err) = self.store.remove(ROUTING_TREE, &oui.to_be_bytes()) {
+                        warn!(logger, "failed to remove saved routing: {:?}", err; "oui" => oui);
+                    }
+                    size -= routing.size();
+                }
+                None => break,
+            }
+        }
+    }
+
+    async fn handle_uplink(&mut self, logger: &Logger, mut uplink: LinkPacket) -> Result {
+        let logger = logger.new(o!("trace_id" => uplink.trace_id.to_string()));
+        let mut span = self.tracer.span("uplink forward", uplink.trace_id);
+        let deadline = self
+            .response_budget
+            .map(|budget| time::Instant::from_std(budget.deadline(&uplink)));
         if uplink.packet.routing.is_none() {
             info!(logger, "ignoring, no routing data");
+            span.attribute("result", "no_routing");
+            self.decisions
+                .record(Decision::new(&uplink, Reason::NoRouting));
             return Ok(());
         };
         let gateway_mac = uplink.gateway_mac;
-        let message = uplink.to_state_channel_message(&self.keypair, self.region)?;
-        for mut client in self.router_clients_for_uplink(&uplink) {
+        let trace_id = uplink.trace_id;
+        let seq = uplink.seq;
+        let region = match self.uplink_region(&logger, &uplink) {
+            Some(region) => region,
+            None => {
+                warn!(
+                    logger,
+                    "ignoring, unable to infer region from frequency {}", uplink.packet.frequency
+                );
+                span.attribute("result", "no_region");
+                self.decisions
+                    .record(Decision::new(&uplink, Reason::NoRegion));
+                return Ok(());
+            }
+        };
+        if !uplink.resolve_channel(region) {
+            debug!(logger, "uplink does not fit the {:?} channel plan", region;
+                "frequency" => uplink.packet.frequency,
+                "datarate" => &uplink.packet.datarate);
+        }
+        if let Some(radio) = &uplink.radio {
+            span.attribute("spreading_factor", radio.spreading_factor);
+            span.attribute("bandwidth", radio.bandwidth);
+            if let Some(channel) = radio.channel {
+                span.attribute("channel", channel);
+            }
+        }
+        // Pin the envelope version of every router up front since a rejected
+        // request may renegotiate it while the uplink is dispatched
+        let (found, mut decision) = self.router_clients_for_uplink(&uplink);
+        let mut clients: Vec<(RouterService, EnvelopeVersion, Option<Race>)> = vec![];
+        for (client, race) in found {
+            if !client.breaker.allow() {
+                debug!(logger, "skipping router with open circuit: {}", client.uri);
+                decision.skipped.push(client.uri.to_string());
+                continue;
+            }
+            decision.routers.push(client.uri.to_string());
+            let version = client.envelope_version();
+            clients.push((client, version, race));
+        }
+        if clients.is_empty() && !decision.skipped.is_empty() {
+            decision.reason = Reason::CircuitOpen;
+        }
+        self.decisions.record(decision);
+        // Sign the uplink once for every envelope version in use
+        let mut versions: Vec<EnvelopeVersion> = vec![];
+        for (_, version, _) in &clients {
+            if !versions.contains(version) {
+                versions.push(*version);
+            }
+        }
+        let mut messages = Vec::with_capacity(versions.len());
+        let mut uplink = Some(uplink);
+        for (i, version) in versions.iter().enumerate() {
+            // Only clone the uplink when it is needed for another version
+            let packet = if i + 1 < versions.len() {
+                uplink.clone()
+            } else {
+                uplink.take()
+            }
+            .expect("uplink");
+            let message = packet
+                .into_state_channel_message(&self.signer, region, *version)
+                .await?;
+            messages.push((*version, Some(message)));
+        }
+        for (i, (client, version, race)) in clients.iter().enumerate() {
+            let (mut client, version, race) = (client.clone(), *version, race.clone());
             let downlinks = self.downlinks.clone();
-            let message = message.clone();
+            let stats = self.stats.clone();
+            let privacy = self.privacy;
+            let downlink_guard = self.downlink_guard.clone();
+            let labels = self.labels.clone();
+            // Only clone a message when it is needed for another router
+            let needed_again = clients[i + 1..]
+                .iter()
+                .any(|(_, other, _)| *other == version);
+            let message = messages
+                .iter_mut()
+                .find(|(message_version, _)| *message_version == version)
+                .and_then(|(_, message)| {
+                    if needed_again {
+                        message.clone()
+                    } else {
+                        message.take()
+                    }
+                })
+                .expect("state channel message");
             let logger = logger.clone();
+            let mut round_trip = self.tracer.span("router round-trip", trace_id);
+            round_trip.attribute("uri", &client.uri);
+            span.attribute("uri", &client.uri);
             info!(logger, "routing packet to: {}", client.uri);
-            tokio::spawn(async move {
-                match client.route(message).await {
+            let router_uri = client.uri.to_string();
+            let request = async move {
+                let mut round_trip = round_trip;
+                let started = Instant::now();
+                let uri = client.uri.clone();
+                let options = RouteOptions {
+                    seq: Some(seq),
+                    labels: labels.as_deref(),
+                    envelope: Some(version),
+                };
+                let request = async {
+                    match deadline {
+                        Some(deadline) => {
+                            time::timeout_at(deadline, client.route(message, options))
+                                .await
+                                .ok()
+                        }
+                        None => Some(client.route(message, options).await),
+                    }
+                };
+                let response = match &race {
+                    Some(race) => tokio::select! {
+                        response = request => response,
+                        _ = race.decided() => {
+                            debug!(logger, "cancelled router request, another router accepted first: {}", uri);
+                            round_trip.attribute("result", "lost_race");
+                            return;
+                        }
+                    },
+                    None => request.await,
+                };
+                let response = match response {
+                    Some(response) => response,
+                    None => {
+                        // Neither a success nor a failure of the router
+                        stats.router_cancelled();
+                        debug!(logger, "cancelled router request past the receive windows: {}", uri;
+                            "elapsed_ms" => started.elapsed().as_millis() as u64);
+                        round_trip.attribute("result", "deadline");
+                        return;
+                    }
+                };
+                let latency = started.elapsed();
+                stats.router_request(latency, response.is_ok());
+                if response.is_ok() {
+                    client.breaker.record_latency(latency);
+                }
+                if let Some(state) = client.breaker.record(response.is_ok()) {
+                    info!(logger, "router circuit {}: {}", state, client.uri);
+                }
+                match response {
                     Ok(response) => {
-                        debug!(logger, "response from router {:?}", response);
-                        if let Some(downlink) =
-                            LinkPacket::from_state_channel_message(response, gateway_mac)
-                        {
+                        if privacy == PayloadPrivacy::Keep {
+                            debug!(logger, "response from router {:?}", response);
+                        } else {
+                            debug!(logger, "response from router, payload not logged");
+                        }
+                        let mut downlink =
+                            LinkPacket::from_state_channel_message(response, gateway_mac, trace_id);
+                        if let Some(downlink) = &mut downlink {
+                            downlink.router = Some(uri.to_string());
+                            match downlink.validate_downlink(region, &downlink_guard) {
+                                Ok(None) => (),
+                                Ok(Some(err)) => {
+                                    warn!(logger, "dropping invalid rx2 window: {}", err);
+                                    round_trip.attribute("rx2_rejected", &err);
+                                }
+                                Err(err) => {
+                                    warn!(logger, "rejecting invalid downlink: {:?}", err);
+                                    round_trip.attribute("result", "invalid_downlink");
+                                    if let Error::Downlink(err) = &err {
+                                        round_trip.attribute("rejected", err);
+                                    }
+                                    // Counted as a failed downlink of the router,
+                                    // like one the forwarder failed to send
+                                    stats.routed_downlink(
+                                        gateway::sessions::downlink_dev_addr(&downlink.payload),
+                                        downlink.router.as_deref(),
+                                        false,
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                        if !race.map_or(true, |race| race.answer(downlink.is_some())) {
+                            debug!(
+                                logger,
+                                "dropping answer, another router accepted first: {}", uri
+                            );
+                            round_trip.attribute("result", "lost_race");
+                            return;
+                        }
+                        if let Some(downlink) = downlink {
                             match downlinks.send(downlink).await {
                                 Ok(()) => (),
                                 Err(_) => {
@@ -181,29 +618,159 @@ impl Router {
                     }
                     Err(err) => warn!(logger, "ignoring uplink error: {:?}", err),
                 }
-            });
+            };
+            // Started from the task of the router, in sequence order
+            if !self.senders.send(&router_uri, request.boxed()) {
+                self.stats.router_cancelled();
+                warn!(
+                    logger,
+                    "dropping uplink, too many requests queued for {}", router_uri
+                );
+            }
         }
         Ok(())
     }
 
-    fn router_clients_for_uplink(&self, uplink: &LinkPacket) -> Vec<RouterService> {
-        match &uplink.packet.routing {
+    /// Returns the region to use for the given uplink. This is the configured
+    /// region, or if the region is set to be inferred, the region inferred
+    /// from the uplinks seen so far, including the given one.
+    fn uplink_region(&mut self, logger: &Logger, uplink: &LinkPacket) -> Option<Region> {
+        if let Some(region) = self.region {
+            return Some(region);
+        }
+        let previous = self.region_inference.inference().map(|i| i.region);
+        let inference = self.region_inference.observe(uplink.packet.frequency)?;
+        if previous != Some(inference.region) {
+            info!(logger, "inferred region {:?}", inference.region;
+                "confidence" => inference.confidence,
+                "samples" => inference.samples);
+        }
+        if self.region_lock && inference.is_confident(self.region_samples) {
+            info!(logger, "locking inferred region {:?}, set \"region\" in settings to make this permanent", inference.region;
+                "confidence" => inference.confidence,
+                "samples" => inference.samples);
+            self.region = Some(inference.region);
+        }
+        if inference.is_confident(self.region_samples) {
+            self.forwarder_config.apply(inference.region, logger);
+        }
+        Some(inference.region)
+    }
+
+    /// Returns the routers to send the uplink to, with the decision that
+    /// picked them. The routers of a first accept OUI share a race.
+    fn router_clients_for_uplink(
+        &mut self,
+        uplink: &LinkPacket,
+    ) -> (Vec<(RouterService, Option<Race>)>, Decision) {
+        let routing_data = match &uplink.packet.routing {
             Some(RoutingInformation {
                 data: Some(routing_data),
-            }) => {
-                let found: Vec<RouterService> = self
-                    .clients
-                    .values()
-                    .filter(|&routing| routing.matches_routing_data(routing_data))
-                    .flat_map(|routing| routing.clients.clone())
-                    .collect();
-                if found.is_empty() {
-                    vec![self.default_client.clone()]
-                } else {
-                    found
-                }
+            }) => routing_data,
+            _ => return (vec![], Decision::new(uplink, Reason::NoRouting)),
+        };
+        let reason = match routing_data {
+            RoutingData::Eui(_) => Reason::EuiFilter,
+            RoutingData::Devaddr(_) => Reason::DevaddrPrefix,
+        };
+        let mut decision = Decision::new(uplink, reason);
+        let mut found: Vec<(RouterService, Option<Race>)> = vec![];
+        for (oui, routing) in self.clients.iter_mut() {
+            if routing.matches_routing_data(routing_data) {
+                decision.ouis.push(*oui);
+                let first_accept = self.selection.first_accept.contains(oui);
+                let (selected, race) = routing.select_race(first_accept, &self.selection);
+                found.extend(selected.into_iter().map(|client| (client, race.clone())));
             }
-            _ => vec![],
         }
+        decision.ouis.sort_unstable();
+        if found.is_empty() {
+            decision.reason = Reason::Default;
+            found.push((self.default_client.clone(), None));
+        }
+        (found, decision)
+    }
+}
+
+/// The time from an uplink to its last receive window, rx2 for data uplinks
+/// and the second join accept window for join requests, less the margin to
+/// get the downlink to the packet forwarder.
+#[derive(Debug, Clone, Copy)]
+struct ResponseBudget {
+    receive_delay2: Duration,
+    join_accept_delay2: Duration,
+    margin: Duration,
+}
+
+impl ResponseBudget {
+    fn new(backhaul: &BackhaulSettings) -> Option<Self> {
+        if !backhaul.response_deadline {
+            return None;
+        }
+        Some(Self {
+            receive_delay2: Duration::from_secs(backhaul.receive_delay2),
+            join_accept_delay2: Duration::from_secs(backhaul.join_accept_delay2),
+            margin: Duration::from_millis(backhaul.response_margin),
+        })
+    }
+
+    /// When an answer to the given uplink has to arrive to still be sent in
+    /// its last receive window, counted from when the gateway received the
+    /// uplink, since it may have waited in the uplink queue.
+    fn deadline(&self, uplink: &LinkPacket) -> Instant {
+        let window = match uplink.payload.first().map(|mhdr| MType::from(mhdr >> 5)) {
+            Some(MType::JoinRequest) => self.join_accept_delay2,
+            _ => self.receive_delay2,
+        };
+        uplink.received.unwrap_or_else(Instant::now)
+            + window.checked_sub(self.margin).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_proto::Packet;
+    use link_packet::{Antenna, TraceId};
+    use semtech_udp::MacAddress;
+
+    fn uplink(mhdr: u8, received: Instant) -> LinkPacket {
+        LinkPacket {
+            gateway_mac: MacAddress::new(&1u64.to_be_bytes()),
+            trace_id: TraceId::from(1),
+            packet: Packet::default(),
+            payload: vec![mhdr].into(),
+            radio: None,
+            antenna: Antenna::default(),
+            metadata: Default::default(),
+            seq: 0,
+            router: None,
+            received: Some(received),
+        }
+    }
+
+    #[test]
+    fn response_budget() {
+        let budget = ResponseBudget {
+            receive_delay2: Duration::from_secs(2),
+            join_accept_delay2: Duration::from_secs(6),
+            margin: Duration::from_millis(200),
+        };
+        let received = Instant::now() - Duration::from_secs(1);
+        // Counted from when the uplink was received, not when it is routed
+        assert_eq!(
+            received + Duration::from_millis(1_800),
+            budget.deadline(&uplink(0x40, received))
+        );
+        assert_eq!(
+            received + Duration::from_millis(5_800),
+            budget.deadline(&uplink(0x00, received))
+        );
+        // A margin longer than the window leaves no time
+        let budget = ResponseBudget {
+            margin: Duration::from_secs(3),
+            ..budget
+        };
+        assert_eq!(received, budget.deadline(&uplink(0x40, received)));
     }
 }
@@ -0,0 +1,92 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// The routers of an OUI with first accept routing race for an uplink: the
+/// first to answer it with a downlink wins, its downlink is sent and the
+/// requests to the other routers are cancelled. Clones share the race.
+#[derive(Clone)]
+pub struct Race {
+    won: Arc<AtomicBool>,
+    trigger: triggered::Trigger,
+    listener: triggered::Listener,
+}
+
+impl Default for Race {
+    fn default() -> Self {
+        let (trigger, listener) = triggered::trigger();
+        Self {
+            won: Arc::new(AtomicBool::new(false)),
+            trigger,
+            listener,
+        }
+    }
+}
+
+impl Race {
+    /// Claims the win for an accepted uplink, cancelling the others. Returns
+    /// false when another router already won.
+    pub fn claim(&self) -> bool {
+        if self.won.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.trigger.trigger();
+        true
+    }
+
+    /// Decides on the answer of a router, returning whether it is used. Only
+    /// an answer with a downlink claims the win, an answer without one
+    /// leaves the other routers racing since one of them may have it.
+    pub fn answer(&self, downlink: bool) -> bool {
+        !downlink || self.claim()
+    }
+
+    /// Resolves once a router won the race.
+    pub fn decided(&self) -> triggered::Listener {
+        self.listener.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim() {
+        let race = Race::default();
+        let other = race.clone();
+        assert!(other.claim());
+        assert!(!race.claim());
+        assert!(!Race::default().won.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn routers() {
+        use std::time::Duration;
+        use tokio::time;
+
+        // The first router answers without a downlink, the second with one
+        // and the third is slower and cancelled
+        let race = Race::default();
+        let routers = vec![(10, false), (20, true), (200, true)]
+            .into_iter()
+            .map(|(delay_ms, downlink)| {
+                let race = race.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = time::sleep(Duration::from_millis(delay_ms)) => {
+                            Some((downlink, race.answer(downlink)))
+                        },
+                        _ = race.decided() => None,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut answers = vec![];
+        for router in routers {
+            answers.push(router.await.expect("router"));
+        }
+        assert_eq!(vec![Some((false, true)), Some((true, true)), None], answers);
+    }
+}
@@ -1,13 +1,23 @@
 use crate::*;
 use helium_proto::routing_information::Data as RoutingData;
-use router::filter::{DevAddrFilter, EuiFilter};
-use service::router::Service as RouterService;
+use router::{
+    filter::{DevAddrFilter, EuiFilter},
+    race::Race,
+};
+use service::{
+    breaker::{BreakerState, Breakers},
+    router::Service as RouterService,
+};
+use settings::{BackhaulSettings, RouterSelectionSettings};
 use slog::{warn, Logger};
+use std::{mem, time::Duration};
 
 pub struct Routing {
     pub(crate) filters: Vec<EuiFilter>,
     pub(crate) subnets: Vec<DevAddrFilter>,
     pub(crate) clients: Vec<RouterService>,
+    /// The uri of the router picked by latency, if any
+    pub(crate) preferred: Option<http::Uri>,
 }
 
 impl Routing {
@@ -20,7 +30,79 @@ impl Routing {
         }
     }
 
-    pub fn from_proto(logger: &Logger, r: &helium_proto::Routing) -> Result<Self> {
+    /// Returns the routers of the entry to send an uplink to. With latency
+    /// selection this is the router with the lowest recent round trip time,
+    /// which is only replaced by one faster by more than the hysteresis so
+    /// bursts of slow responses do not flap between routers. Routers without
+    /// a recent round trip time, and those with a circuit that is not
+    /// closed, are included as well so they are measured or probed.
+    pub fn select_clients(&mut self, selection: &RouterSelectionSettings) -> Vec<RouterService> {
+        if !selection.lowest_latency || self.clients.len() < 2 {
+            return self.clients.clone();
+        }
+        let probe_interval = Duration::from_secs(selection.probe_interval);
+        let mut selected = vec![];
+        let mut measured = vec![];
+        for client in &self.clients {
+            match client.breaker.latency() {
+                Some(latency)
+                    if latency.at.elapsed() < probe_interval
+                        && client.breaker.state() == BreakerState::Closed =>
+                {
+                    measured.push((client, latency.average))
+                }
+                _ => selected.push(client.clone()),
+            }
+        }
+        let fastest = measured.iter().min_by_key(|(_, latency)| *latency).copied();
+        let preferred = measured
+            .iter()
+            .find(|(client, _)| Some(&client.uri) == self.preferred.as_ref())
+            .copied();
+        let margin = 1.0 - f64::from(selection.hysteresis.min(100)) / 100.0;
+        let chosen = match (fastest, preferred) {
+            (Some((_, fastest)), Some(preferred)) if fastest >= preferred.1.mul_f64(margin) => {
+                Some(preferred)
+            }
+            (fastest, _) => fastest,
+        };
+        if let Some((client, _)) = chosen {
+            self.preferred = Some(client.uri.clone());
+            selected.push(client.clone());
+        }
+        selected
+    }
+
+    /// Returns the routers of the entry to send an uplink to and, for a
+    /// first accept OUI with more than one router, the race they share. All
+    /// routers of a first accept OUI race, since latency selection would
+    /// leave only one.
+    pub fn select_race(
+        &mut self,
+        first_accept: bool,
+        selection: &RouterSelectionSettings,
+    ) -> (Vec<RouterService>, Option<Race>) {
+        if !first_accept {
+            return (self.select_clients(selection), None);
+        }
+        let race = (self.clients.len() > 1).then(Race::default);
+        (self.clients.clone(), race)
+    }
+
+    /// Approximate memory used by the routing entry in bytes.
+    pub fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.filters.iter().map(EuiFilter::size).sum::<usize>()
+            + self.subnets.len() * mem::size_of::<DevAddrFilter>()
+            + self.clients.len() * mem::size_of::<RouterService>()
+    }
+
+    pub fn from_proto(
+        logger: &Logger,
+        r: &helium_proto::Routing,
+        breakers: &mut Breakers,
+        backhaul: &BackhaulSettings,
+    ) -> Result<Self> {
         let filters = r.filters.iter().map(|f| EuiFilter::from_bin(f)).collect();
         let subnets = r
             .subnets
@@ -31,7 +113,10 @@ impl Routing {
         for address in r.addresses.iter().filter(|a| !a.uri.is_empty()) {
             let uri_str = String::from_utf8_lossy(&address.uri);
             match uri_str.parse() {
-                Ok(uri) => clients.push(RouterService::new(uri, None)?),
+                Ok(uri) => {
+                    let breaker = breakers.get(&uri);
+                    clients.push(RouterService::new(uri, None, breaker, backhaul)?)
+                }
                 Err(err) => warn!(
                     logger,
                     "ignoring invalid uri: \"{}\": {:?}", uri_str, err;
@@ -43,6 +128,135 @@ impl Routing {
             filters,
             subnets,
             clients,
+            preferred: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::{breaker::CircuitBreaker, router::EnvelopeVersion};
+    use settings::{BackhaulPreset, CircuitBreakerSettings, Nat64};
+    use std::sync::Arc;
+
+    fn routing(hosts: &[&str]) -> Routing {
+        let backhaul = BackhaulSettings {
+            preset: BackhaulPreset::Ethernet,
+            timeout: 5,
+            keepalive: 60,
+            reconnect_delay: 5,
+            interface: None,
+            prefer_ipv6: false,
+            nat64: Nat64::Off,
+            nat64_prefix: None,
+            envelope_version: EnvelopeVersion::V2,
+            response_deadline: true,
+            response_margin: 200,
+            receive_delay2: 2,
+            join_accept_delay2: 6,
+        };
+        let clients = hosts
+            .iter()
+            .map(|host| {
+                let breaker = Arc::new(CircuitBreaker::new(&CircuitBreakerSettings {
+                    failures: 2,
+                    open_secs: 60,
+                }));
+                let uri = format!("http://{}:8080", host).parse().expect("uri");
+                RouterService::new(uri, None, breaker, &backhaul).expect("router")
+            })
+            .collect();
+        Routing {
+            filters: vec![],
+            subnets: vec![],
+            clients,
+            preferred: None,
+        }
+    }
+
+    fn selection() -> RouterSelectionSettings {
+        RouterSelectionSettings {
+            lowest_latency: true,
+            hysteresis: 20,
+            probe_interval: 300,
+            first_accept: vec![],
+        }
+    }
+
+    fn hosts(clients: &[RouterService]) -> Vec<&str> {
+        clients
+            .iter()
+            .filter_map(|client| client.uri.host())
+            .collect()
+    }
+
+    fn measured(hosts: &[&str], latencies_ms: &[u64]) -> Routing {
+        let routing = routing(hosts);
+        for (client, latency_ms) in routing.clients.iter().zip(latencies_ms) {
+            client
+                .breaker
+                .record_latency(Duration::from_millis(*latency_ms));
+        }
+        routing
+    }
+
+    #[tokio::test]
+    async fn hysteresis() {
+        // Without a preferred router the fastest is picked
+        let mut routing = measured(&["router-1", "router-2"], &[100, 90]);
+        assert_eq!(
+            vec!["router-2"],
+            hosts(&routing.select_clients(&selection()))
+        );
+        assert_eq!(Some(&routing.clients[1].uri), routing.preferred.as_ref());
+
+        // A router faster by less than the hysteresis does not replace the
+        // preferred one
+        let mut routing = measured(&["router-1", "router-2"], &[100, 90]);
+        routing.preferred = Some(routing.clients[0].uri.clone());
+        assert_eq!(
+            vec!["router-1"],
+            hosts(&routing.select_clients(&selection()))
+        );
+        assert_eq!(
+            vec!["router-1"],
+            hosts(&routing.select_clients(&selection()))
+        );
+        assert_eq!(Some(&routing.clients[0].uri), routing.preferred.as_ref());
+
+        // One faster by more than the hysteresis does
+        let mut routing = measured(&["router-1", "router-2"], &[100, 70]);
+        routing.preferred = Some(routing.clients[0].uri.clone());
+        assert_eq!(
+            vec!["router-2"],
+            hosts(&routing.select_clients(&selection()))
+        );
+        assert_eq!(Some(&routing.clients[1].uri), routing.preferred.as_ref());
+        assert_eq!(
+            vec!["router-2"],
+            hosts(&routing.select_clients(&selection()))
+        );
+
+        // Unmeasured routers are sent to as well so they are measured
+        let mut routing = measured(&["router-1", "router-2", "router-3"], &[100, 70]);
+        assert_eq!(
+            vec!["router-3", "router-2"],
+            hosts(&routing.select_clients(&selection()))
+        );
+    }
+
+    #[tokio::test]
+    async fn first_accept() {
+        let mut routing = measured(&["router-1", "router-2", "router-3"], &[300, 100, 200]);
+        let (selected, race) = routing.select_race(false, &selection());
+        assert_eq!(vec!["router-2"], hosts(&selected));
+        assert!(race.is_none());
+        // Latency selection would leave nothing to race
+        let (selected, race) = routing.select_race(true, &selection());
+        assert_eq!(vec!["router-1", "router-2", "router-3"], hosts(&selected));
+        assert!(race.is_some());
+        let mut single = self::routing(&["router-1"]);
+        assert!(single.select_race(true, &selection()).1.is_none());
+    }
+}
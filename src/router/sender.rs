@@ -0,0 +1,165 @@
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
+
+/// Number of requests waiting to be started for a router before more are
+/// dropped.
+pub const ROUTER_QUEUE_SIZE: usize = 32;
+/// How long the task of a router waits for a request before it ends.
+pub const ROUTER_IDLE: Duration = Duration::from_secs(60);
+
+/// Starts the requests for each router in the order they were queued, from a
+/// task of its own. Each request is polled once when it is taken off the
+/// queue, which hands it to the router connection, and then runs alongside
+/// the other requests of the router until its answer is handled. Uplinks
+/// handed out in sequence order thus reach every router in that order,
+/// which separately spawned requests do not guarantee, while a slow answer
+/// does not hold back the requests after it.
+///
+/// The task of a router ends once it has been idle for `ROUTER_IDLE`, so
+/// routers that left the routing table do not keep one.
+#[derive(Debug, Default)]
+pub struct Senders {
+    routers: HashMap<String, mpsc::Sender<BoxFuture<'static, ()>>>,
+}
+
+impl Senders {
+    /// Queues a request for the router with the given uri, starting its task
+    /// when it has none. Returns false when the queue of the router is full
+    /// and the request was dropped.
+    pub fn send(&mut self, uri: &str, request: BoxFuture<'static, ()>) -> bool {
+        self.routers.retain(|_, sender| !sender.is_closed());
+        let sender = self
+            .routers
+            .entry(uri.to_string())
+            .or_insert_with(|| spawn_router());
+        match sender.try_send(request) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Closed(request)) => {
+                // The task of the router went idle since the check above
+                self.routers.remove(uri);
+                self.send(uri, request)
+            }
+        }
+    }
+}
+
+fn spawn_router() -> mpsc::Sender<BoxFuture<'static, ()>> {
+    let (sender, mut receiver) = mpsc::channel::<BoxFuture<'static, ()>>(ROUTER_QUEUE_SIZE);
+    tokio::spawn(async move {
+        let mut running = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                request = receiver.recv() => match request {
+                    Some(request) => start(&mut running, request).await,
+                    None => break,
+                },
+                Some(()) = running.next(), if !running.is_empty() => (),
+                _ = time::sleep(ROUTER_IDLE), if running.is_empty() => {
+                    receiver.close();
+                    // Requests queued before the close are still started
+                    while let Some(request) = receiver.recv().await {
+                        start(&mut running, request).await;
+                    }
+                    break;
+                }
+            }
+        }
+        while running.next().await.is_some() {}
+    });
+    sender
+}
+
+/// Polls the request once, which starts it before any request queued after
+/// it, and keeps it running when it is waiting for its answer.
+async fn start(
+    running: &mut FuturesUnordered<BoxFuture<'static, ()>>,
+    mut request: BoxFuture<'static, ()>,
+) {
+    if futures::poll!(&mut request).is_pending() {
+        running.push(request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn order() {
+        time::pause();
+        let mut senders = Senders::default();
+        let started = Arc::new(Mutex::new(vec![]));
+        let answered = Arc::new(Mutex::new(vec![]));
+        for seq in 0..20u64 {
+            let uri = if seq % 2 == 0 { "a" } else { "b" };
+            let (started, answered) = (started.clone(), answered.clone());
+            let request = async move {
+                started.lock().expect("started").push((uri, seq));
+                // Earlier requests take longer to answer
+                time::sleep(Duration::from_millis(100 - seq)).await;
+                answered.lock().expect("answered").push((uri, seq));
+            };
+            assert!(senders.send(uri, request.boxed()));
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        let started = started.lock().expect("started").clone();
+        let answered = answered.lock().expect("answered").clone();
+        assert_eq!(20, answered.len());
+        for uri in &["a", "b"] {
+            let seqs = |sent: &[(&str, u64)]| -> Vec<u64> {
+                sent.iter()
+                    .filter(|(sent_uri, _)| sent_uri == uri)
+                    .map(|(_, seq)| *seq)
+                    .collect()
+            };
+            // Requests start in order but are answered as they come
+            let started = seqs(&started);
+            assert_eq!(10, started.len());
+            assert!(started.windows(2).all(|pair| pair[0] < pair[1]));
+            let answered = seqs(&answered);
+            assert!(answered.windows(2).all(|pair| pair[0] > pair[1]));
+        }
+    }
+
+    #[tokio::test]
+    async fn idle() {
+        time::pause();
+        let mut senders = Senders::default();
+        let (release, released) = triggered::trigger();
+        assert!(senders.send("a", released.clone().boxed()));
+        assert!(senders.send("b", async {}.boxed()));
+        // A router with a request running keeps its task
+        time::sleep(ROUTER_IDLE * 2).await;
+        assert!(senders.send("c", async {}.boxed()));
+        assert_eq!(vec!["a", "c"], {
+            let mut uris: Vec<&str> = senders.routers.keys().map(String::as_str).collect();
+            uris.sort_unstable();
+            uris
+        });
+        release.trigger();
+        time::sleep(ROUTER_IDLE * 2).await;
+        assert!(senders.send("c", async {}.boxed()));
+        assert_eq!(1, senders.routers.len());
+    }
+
+    #[tokio::test]
+    async fn full() {
+        time::pause();
+        let mut senders = Senders::default();
+        // The task of the router does not run until this test yields
+        for _ in 0..ROUTER_QUEUE_SIZE {
+            assert!(senders.send("a", async {}.boxed()));
+        }
+        assert!(!senders.send("a", async {}.boxed()));
+        assert!(senders.send("b", async {}.boxed()));
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(senders.send("a", async {}.boxed()));
+    }
+}
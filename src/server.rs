@@ -1,25 +1,192 @@
 use crate::*;
+use bootstrap::Bootstrap;
+use decisions::Decisions;
+use fingerprint::Fingerprint;
 use gateway::Gateway;
+use keypair::RotationService;
+use memory::MemoryBudget;
+use passthrough::Passthrough;
 use router::Router;
-use slog::{info, Logger};
+use settings::Nat64;
+use signals::{LogSwitch, Signals};
+use signer::Signatures;
+use slog::{info, warn, Logger};
+use sntp::SntpService;
+use stats::{Stats, StatsService};
+use std::{future::Future, pin::Pin};
+use store::Store;
 use tokio::sync::mpsc;
+use tunnel::Relay;
 use updater::Updater;
 
-pub async fn run(shutdown: &triggered::Listener, settings: &Settings, logger: &Logger) -> Result {
-    let (uplink_sender, uplink_receiver) = mpsc::channel(20);
-    let (downlink_sender, downlink_receiver) = mpsc::channel(10);
-    let mut router = Router::new(downlink_sender, uplink_receiver, settings)?;
-    let mut gateway = Gateway::new(uplink_sender, downlink_receiver, settings).await?;
+pub async fn run(
+    shutdown: &triggered::Listener,
+    settings: &Settings,
+    log_switch: LogSwitch,
+    logger: &Logger,
+) -> Result {
+    let signatures = Signatures::new(settings).with_logger(logger);
+    push::count_start(settings, &signatures)?;
+    let budget = MemoryBudget::new(&settings.memory)?;
+    let store = Store::open(&settings.store)?;
+    let stats = Stats::default();
+    let decisions = Decisions::new(settings.api.decisions);
+    let mut stats_service = StatsService::new(stats.clone(), store.clone(), &settings.stats);
+    let (alerts, mut alert_service) =
+        alerts::alerts(stats.clone(), store.clone(), signatures.clone(), settings);
+    let (uplink_sender, uplink_receiver) = mpsc::channel(budget.queue_capacity(20, 2));
+    let (downlink_sender, downlink_receiver) = mpsc::channel(budget.queue_capacity(10, 2));
+    let (tracer, mut exporter) = telemetry::tracer(settings);
+    let (mirror, mut mirror_service) = mirror::mirror(settings)?;
+    let (feed, mut feed_service) = feed::feed(settings);
+    let (lns, mut lns_service) = lns::lns(settings)?;
+    let (join_server, mut join_service) = join_server::join_server(settings)?;
+    let (api, mut api_service) = api::api(settings);
+    let (peers, mut peer_service) = peers::peers(settings, signatures.clone())?;
+    let (cluster, mut cluster_service) = cluster::cluster(settings, signatures.clone())?;
+    let (liveness, mut supervisor) = supervisor::supervisor(settings);
+    let (signer, mut signing_service) = signer::signer(signatures.clone());
+    let (snapshot_trigger, snapshots) = signals::snapshots();
+    let signals = Signals::new(log_switch, snapshot_trigger);
+    // The gateway and router are the hot stages of the packet path and run in
+    // their own tasks so a multi threaded runtime can schedule them in
+    // parallel. In passthrough mode the proxy takes the listen address and
+    // replaces both.
+    let packet_path: Pin<Box<dyn Future<Output = Result>>> = if settings.passthrough.enabled {
+        let mut passthrough = Passthrough::new(liveness, snapshots, settings)?;
+        let (shutdown, logger) = (shutdown.clone(), logger.clone());
+        Box::pin(stage(
+            async move { passthrough.run(shutdown, &logger).await },
+        ))
+    } else {
+        let mut router = Router::new(
+            downlink_sender,
+            uplink_receiver,
+            signer,
+            tracer.clone(),
+            budget,
+            snapshots.clone(),
+            store.clone(),
+            stats.clone(),
+            decisions.clone(),
+            settings,
+        )?;
+        let mut gateway = Gateway::new(
+            gateway::transport::open(settings, logger).await?,
+            uplink_sender,
+            downlink_receiver,
+            tracer,
+            mirror,
+            feed,
+            lns,
+            join_server,
+            api,
+            alerts,
+            peers,
+            cluster,
+            signatures.clone(),
+            liveness,
+            snapshots,
+            store,
+            stats,
+            decisions,
+            settings,
+        )
+        .await?;
+        let gateway_stage = {
+            let (shutdown, logger) = (shutdown.clone(), logger.clone());
+            async move { gateway.run(shutdown, &logger).await }
+        };
+        let router_stage = {
+            let (shutdown, logger) = (shutdown.clone(), logger.clone());
+            async move { router.run(shutdown, &logger).await }
+        };
+        Box::pin(
+            async move { tokio::try_join!(stage(gateway_stage), stage(router_stage)).map(|_| ()) },
+        )
+    };
     let updater = Updater::new(settings)?;
+    let rotation = RotationService::new(settings, signatures.clone());
+    let bootstrap = Bootstrap::new(settings, signatures);
+    let mut tunnel = Relay::server(settings)?;
+    let sntp = SntpService::new(settings);
+    let fingerprint = Fingerprint::collect(settings);
     info!(logger,
         "starting server";
-        "version" => settings::version().to_string(),
+        "version" => fingerprint.version,
         "key" => settings.keypair.public_key().to_string(),
+        "key_type" => fingerprint.key_type,
+        "platform" => fingerprint.platform,
+        "arch" => fingerprint.arch,
+        "hardware" => fingerprint.hardware,
+        "os" => fingerprint.os,
+        "kernel" => fingerprint.kernel,
+        "profile" => settings.profile.as_deref().unwrap_or("none"),
+        "features" => settings.features.list().join(","),
+        "labels" => settings.labels_string().unwrap_or_default(),
     );
+    if settings.backhaul.nat64 == Nat64::Auto && settings.backhaul.nat64_prefix.is_none() {
+        warn!(
+            logger,
+            "no NAT64 prefix discovered, IPv4 addresses are used as is"
+        );
+    }
+    if !settings.migrations.is_empty() {
+        warn!(logger, "{} uses an older settings layout, run the migrate command to update it", settings::SETTINGS_FILE;
+            "changes" => settings.migrations.join("; "));
+    }
     tokio::try_join!(
-        gateway.run(shutdown.clone(), logger),
-        router.run(shutdown.clone(), logger),
-        updater.run(shutdown.clone(), logger)
+        packet_path,
+        updater.run(shutdown.clone(), logger),
+        exporter.run(shutdown.clone(), logger),
+        mirror_service.run(shutdown.clone(), logger),
+        feed_service.run(shutdown.clone(), logger),
+        lns_service.run(shutdown.clone(), logger),
+        join_service.run(shutdown.clone(), logger),
+        api_service.run(shutdown.clone(), logger),
+        peer_service.run(shutdown.clone(), logger),
+        cluster_service.run(shutdown.clone(), logger),
+        stats_service.run(shutdown.clone(), logger),
+        alert_service.run(shutdown.clone(), logger),
+        bootstrap.run(shutdown.clone(), logger),
+        signing_service.run(shutdown.clone(), logger),
+        rotation.run(shutdown.clone(), logger),
+        signals.run(shutdown.clone(), logger),
+        supervisor.run(shutdown.clone(), logger),
+        run_tunnel(tunnel.as_mut(), shutdown.clone(), logger),
+        run_sntp(sntp.as_ref(), shutdown.clone(), logger)
     )
     .map(|_| ())
 }
+
+async fn run_tunnel(
+    tunnel: Option<&mut Relay>,
+    shutdown: triggered::Listener,
+    logger: &Logger,
+) -> Result {
+    match tunnel {
+        Some(tunnel) => tunnel.run(shutdown, logger).await,
+        None => Ok(()),
+    }
+}
+
+async fn run_sntp(
+    sntp: Option<&SntpService>,
+    shutdown: triggered::Listener,
+    logger: &Logger,
+) -> Result {
+    match sntp {
+        Some(sntp) => sntp.run(shutdown, logger).await,
+        None => Ok(()),
+    }
+}
+
+/// Runs a stage of the service in its own task.
+async fn stage<F>(future: F) -> Result
+where
+    F: std::future::Future<Output = Result> + Send + 'static,
+{
+    tokio::spawn(future)
+        .await
+        .map_err(|err| Error::custom(format!("stage failed: {:?}", err)))?
+}
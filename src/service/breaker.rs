@@ -0,0 +1,270 @@
+use crate::*;
+use settings::CircuitBreakerSettings;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are let through
+    Closed,
+    /// Requests are rejected until the open period ends
+    Open,
+    /// A single probe request is let through to test the endpoint
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("closed"),
+            Self::Open => f.write_str("open"),
+            Self::HalfOpen => f.write_str("half_open"),
+        }
+    }
+}
+
+/// Request counters of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BreakerMetrics {
+    pub successes: u64,
+    pub failures: u64,
+    /// Requests rejected while the breaker was open
+    pub rejected: u64,
+    /// Number of times the breaker opened
+    pub opened: u64,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+/// Weight of a new round trip time in the moving average of an endpoint.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// The moving average of the round trip times of an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub average: Duration,
+    /// When the last round trip time was recorded
+    pub at: Instant,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    metrics: BreakerMetrics,
+    latency: Option<Latency>,
+}
+
+/// A circuit breaker for an upstream endpoint. The breaker opens after a
+/// number of consecutive failures and rejects requests for a while, after
+/// which a single probe request is allowed through. A successful probe closes
+/// the breaker again while a failed one re-opens it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failures: u32,
+    open_for: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            failures: settings.failures.max(1),
+            open_for: Duration::from_secs(settings.open_secs),
+            inner: Mutex::new(Inner {
+                state: State::Closed { failures: 0 },
+                metrics: BreakerMetrics::default(),
+                latency: None,
+            }),
+        }
+    }
+
+    /// Whether a request to the endpoint should be made. Every allowed
+    /// request is expected to be followed by a call to `record`.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().expect("breaker lock");
+        let allowed = match inner.state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                inner.state = State::HalfOpen { probing: true };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { probing: true } => false,
+            State::HalfOpen { probing: false } => {
+                inner.state = State::HalfOpen { probing: true };
+                true
+            }
+        };
+        if !allowed {
+            inner.metrics.rejected += 1;
+        }
+        allowed
+    }
+
+    /// Records the outcome of a request. Returns the new state of the
+    /// breaker if the outcome opened or closed it.
+    pub fn record(&self, success: bool) -> Option<BreakerState> {
+        let mut inner = self.inner.lock().expect("breaker lock");
+        if success {
+            inner.metrics.successes += 1;
+            let was_closed = matches!(inner.state, State::Closed { .. });
+            inner.state = State::Closed { failures: 0 };
+            return (!was_closed).then(|| BreakerState::Closed);
+        }
+        inner.metrics.failures += 1;
+        let open = match inner.state {
+            State::Closed { failures } if failures + 1 < self.failures => {
+                inner.state = State::Closed {
+                    failures: failures + 1,
+                };
+                false
+            }
+            State::Closed { .. } | State::HalfOpen { .. } => true,
+            // A late result for a request made before the breaker opened
+            State::Open { .. } => false,
+        };
+        if !open {
+            return None;
+        }
+        inner.state = State::Open {
+            until: Instant::now() + self.open_for,
+        };
+        inner.metrics.opened += 1;
+        Some(BreakerState::Open)
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.inner.lock().expect("breaker lock").state {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn metrics(&self) -> BreakerMetrics {
+        self.inner.lock().expect("breaker lock").metrics
+    }
+
+    /// Records the round trip time of a successful request.
+    pub fn record_latency(&self, latency: Duration) {
+        let mut inner = self.inner.lock().expect("breaker lock");
+        let average = match inner.latency {
+            Some(previous) => previous
+                .average
+                .mul_f64(1.0 - LATENCY_WEIGHT)
+                .saturating_add(latency.mul_f64(LATENCY_WEIGHT)),
+            None => latency,
+        };
+        inner.latency = Some(Latency {
+            average,
+            at: Instant::now(),
+        });
+    }
+
+    /// The recent round trip time of the endpoint, if measured.
+    pub fn latency(&self) -> Option<Latency> {
+        self.inner.lock().expect("breaker lock").latency
+    }
+}
+
+/// The circuit breakers for a set of endpoints, by uri. Breakers are kept
+/// across routing updates so an endpoint that keeps failing stays open even
+/// when its routing is replaced.
+#[derive(Debug)]
+pub struct Breakers {
+    settings: CircuitBreakerSettings,
+    breakers: HashMap<String, Arc<CircuitBreaker>>,
+}
+
+impl Breakers {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            breakers: HashMap::new(),
+        }
+    }
+
+    /// Returns the breaker for the given uri, creating it if needed.
+    pub fn get(&mut self, uri: &http::Uri) -> Arc<CircuitBreaker> {
+        let settings = &self.settings;
+        self.breakers
+            .entry(uri.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(settings)))
+            .clone()
+    }
+
+    /// Removes the breakers for endpoints that are no longer in use.
+    pub fn prune(&mut self) {
+        self.breakers
+            .retain(|_, breaker| Arc::strong_count(breaker) > 1);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<CircuitBreaker>)> {
+        self.breakers.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerSettings {
+            failures: 2,
+            open_secs: 0,
+        })
+    }
+
+    #[test]
+    fn opens_after_failures() {
+        let breaker = breaker();
+        assert!(breaker.allow());
+        assert_eq!(None, breaker.record(false));
+        assert_eq!(None, breaker.record(true));
+        assert_eq!(None, breaker.record(false));
+        assert_eq!(Some(BreakerState::Open), breaker.record(false));
+        assert_eq!(BreakerState::Open, breaker.state());
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let breaker = breaker();
+        breaker.record(false);
+        breaker.record(false);
+        // The open period has passed, so only a single probe is allowed
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(BreakerState::HalfOpen, breaker.state());
+        assert_eq!(Some(BreakerState::Open), breaker.record(false));
+        assert!(breaker.allow());
+        assert_eq!(Some(BreakerState::Closed), breaker.record(true));
+        assert_eq!(
+            BreakerMetrics {
+                successes: 1,
+                failures: 3,
+                rejected: 1,
+                opened: 2,
+            },
+            breaker.metrics()
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let breaker = breaker();
+        assert_eq!(None, breaker.latency());
+        breaker.record_latency(Duration::from_millis(100));
+        breaker.record_latency(Duration::from_millis(200));
+        let average = breaker.latency().expect("latency").average;
+        assert_eq!(130, (average.as_secs_f64() * 1000.0).round() as u64);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::*;
+use settings::BackhaulSettings;
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tonic::codegen::Service;
+
+/// Connects grpc channels through sockets bound to the backhaul interface.
+/// An interface given as an address binds the source address of the
+/// connection, and one given by name binds the socket to the device with
+/// SO_BINDTODEVICE, which needs Linux and, before kernel 5.7, CAP_NET_RAW.
+/// Hosts are resolved like the other requests of the gateway and their
+/// addresses tried in order.
+#[derive(Debug, Clone)]
+pub struct BoundConnector {
+    interface: String,
+    backhaul: Arc<BackhaulSettings>,
+}
+
+impl BoundConnector {
+    /// A connector for the interface of the backhaul settings, None when
+    /// none is set.
+    pub fn new(backhaul: &BackhaulSettings) -> Option<Self> {
+        backhaul.interface.as_ref().map(|interface| Self {
+            interface: interface.clone(),
+            backhaul: Arc::new(backhaul.clone()),
+        })
+    }
+}
+
+impl Service<http::Uri> for BoundConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: http::Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move { connector.connect(uri).await })
+    }
+}
+
+impl BoundConnector {
+    async fn connect(self, uri: http::Uri) -> io::Result<TcpStream> {
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            });
+        let addrs = resolve::resolve(&host, port, &self.backhaul)
+            .await
+            .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+        let mut last_err = io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no address for {}", host),
+        );
+        for addr in addrs {
+            let connector = self.clone();
+            let connected = tokio::task::spawn_blocking(move || connector.connect_addr(addr))
+                .await
+                .map_err(io::Error::other)?;
+            match connected {
+                Ok(stream) => return TcpStream::from_std(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Connects to one address with a bound socket, blocking up to the
+    /// backhaul timeout.
+    fn connect_addr(&self, addr: SocketAddr) -> io::Result<std::net::TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        match self.interface.parse::<IpAddr>() {
+            Ok(ip) => socket.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?,
+            Err(_) => bind_device(&socket, &self.interface)?,
+        }
+        socket.set_nodelay(true)?;
+        socket.set_tcp_keepalive(
+            &TcpKeepalive::new().with_time(Duration::from_secs(self.backhaul.keepalive.max(1))),
+        )?;
+        socket.connect_timeout(
+            &SockAddr::from(addr),
+            Duration::from_secs(self.backhaul.timeout),
+        )?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::other(format!(
+        "binding to interface {} needs Linux, use its address instead",
+        interface
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::router::EnvelopeVersion;
+    use settings::{BackhaulPreset, Nat64};
+
+    #[tokio::test]
+    async fn source_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener");
+        let port = listener.local_addr().expect("addr").port();
+        let backhaul = BackhaulSettings {
+            preset: BackhaulPreset::Ethernet,
+            timeout: 5,
+            keepalive: 60,
+            reconnect_delay: 5,
+            interface: Some("127.0.0.2".to_string()),
+            prefer_ipv6: false,
+            nat64: Nat64::Off,
+            nat64_prefix: None,
+            envelope_version: EnvelopeVersion::V2,
+            response_deadline: true,
+            response_margin: 200,
+            receive_delay2: 2,
+            join_accept_delay2: 6,
+        };
+        let mut connector = BoundConnector::new(&backhaul).expect("connector");
+        let uri: http::Uri = format!("http://127.0.0.1:{}", port).parse().unwrap();
+        let stream = connector.call(uri).await.expect("connect");
+        let (_accepted, peer) = listener.accept().await.expect("accept");
+        assert_eq!(stream.local_addr().expect("local"), peer);
+        assert_eq!(IpAddr::from([127, 0, 0, 2]), peer.ip());
+
+        let backhaul = BackhaulSettings {
+            interface: None,
+            ..backhaul
+        };
+        assert!(BoundConnector::new(&backhaul).is_none());
+    }
+}
@@ -1,11 +1,11 @@
 use crate::{service::*, *};
-use helium_crypto::Verify;
 use helium_proto::{
-    services::{self, Channel, Endpoint},
+    services::{self, Channel},
     *,
 };
 use rand::{rngs::OsRng, seq::SliceRandom};
-use std::{sync::Arc, time::Duration};
+use settings::BackhaulSettings;
+use std::sync::Arc;
 
 type ServiceClient = services::gateway::Client<Channel>;
 
@@ -20,17 +20,11 @@ pub struct Response(GatewayRespV1);
 impl Streaming {
     pub async fn message(&mut self) -> Result<Option<Response>> {
         match self.streaming.message().await {
-            Ok(Some(response)) => {
-                // Create a clone with an empty signature
-                let mut v = response.clone();
-                v.signature = vec![];
-                // Encode the clone
-                let mut buf = vec![];
-                v.encode(&mut buf)?;
-                // And verify against signature in the message
-                self.verifier.verify(&buf, &response.signature)?;
-                Ok(Some(Response(v)))
-            }
+            Ok(Some(response)) => Ok(Some(Response(signed::verify_message(
+                &self.verifier,
+                &response,
+                |response| &mut response.signature,
+            )?))),
             Ok(None) => Ok(None),
             Err(err) => Err(err.into()),
         }
@@ -57,33 +51,31 @@ impl Response {
 pub struct Service {
     pub uri: http::Uri,
     pub verifier: Arc<PublicKey>,
-    client: ServiceClient,
+    channel: LazyChannel,
 }
 
 impl Service {
-    pub fn new(keyed_uri: KeyedUri) -> Result<Self> {
-        let channel = Endpoint::from(keyed_uri.uri.clone())
-            .timeout(Duration::from_secs(CONNECT_TIMEOUT))
-            .connect_lazy()?;
+    pub fn new(keyed_uri: KeyedUri, backhaul: &BackhaulSettings) -> Result<Self> {
         Ok(Self {
+            channel: service::channel(&keyed_uri.uri, backhaul)?,
             uri: keyed_uri.uri,
-            client: ServiceClient::new(channel),
             verifier: Arc::new(keyed_uri.public_key),
         })
     }
 
     pub async fn routing(&mut self, height: u64) -> Result<Streaming> {
-        let stream = self.client.routing(GatewayRoutingReqV1 { height }).await?;
+        let mut client = ServiceClient::new(self.channel.get().await?);
+        let stream = client.routing(GatewayRoutingReqV1 { height }).await?;
         Ok(Streaming {
             streaming: stream.into_inner(),
             verifier: self.verifier.clone(),
         })
     }
 
-    pub fn random_new(uris: &[KeyedUri]) -> Result<Self> {
+    pub fn random_new(uris: &[KeyedUri], backhaul: &BackhaulSettings) -> Result<Self> {
         let uri = uris
             .choose(&mut OsRng)
             .ok_or_else(|| Error::custom("empty uri list"))?;
-        Self::new(uri.clone())
+        Self::new(uri.clone(), backhaul)
     }
 }
@@ -1,5 +1,64 @@
-pub const CONNECT_TIMEOUT: u64 = 10;
+use crate::*;
+use connector::BoundConnector;
+use helium_proto::services::{Channel, Endpoint};
+use settings::BackhaulSettings;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 pub mod api;
+pub mod breaker;
+pub mod connector;
 pub mod gateway;
 pub mod router;
+pub mod signed;
+
+/// A grpc channel that connects on first use, shared by its clones.
+///
+/// Channels without a backhaul interface are lazily connected channels of
+/// the grpc transport. Bound channels go through a `BoundConnector`, which
+/// the transport only takes for channels it connects up front, so they
+/// connect with the first request instead and reconnect on their own after.
+#[derive(Debug, Clone)]
+pub struct LazyChannel {
+    endpoint: Endpoint,
+    connector: Option<BoundConnector>,
+    channel: Arc<Mutex<Option<Channel>>>,
+}
+
+impl LazyChannel {
+    /// The channel, connecting it first if it is not yet.
+    pub async fn get(&self) -> Result<Channel> {
+        let connected = self.channel.lock().expect("channel").clone();
+        if let Some(channel) = connected {
+            return Ok(channel);
+        }
+        let connector = match &self.connector {
+            Some(connector) => connector.clone(),
+            None => return Ok(self.endpoint.connect_lazy()?),
+        };
+        let channel = self.endpoint.connect_with_connector(connector).await?;
+        *self.channel.lock().expect("channel") = Some(channel.clone());
+        Ok(channel)
+    }
+}
+
+/// Creates a lazily connected channel to the given uri, using the request
+/// timeout, TCP keepalive and interface of the backhaul settings. IPv4
+/// literal hosts are reached through NAT64 when a prefix is in use.
+pub fn channel(uri: &http::Uri, backhaul: &BackhaulSettings) -> Result<LazyChannel> {
+    let endpoint = Endpoint::from(resolve::nat64_uri(uri, backhaul.nat64_prefix))
+        .timeout(Duration::from_secs(backhaul.timeout))
+        .tcp_keepalive(Some(Duration::from_secs(backhaul.keepalive.max(1))));
+    let connector = BoundConnector::new(backhaul);
+    let channel = match connector {
+        Some(_) => None,
+        None => Some(endpoint.connect_lazy()?),
+    };
+    Ok(LazyChannel {
+        endpoint,
+        connector,
+        channel: Arc::new(Mutex::new(channel)),
+    })
+}
@@ -1,36 +1,211 @@
 use crate::*;
 use helium_proto::{
-    services::{self, Channel, Endpoint},
+    services::{self, Channel},
     BlockchainStateChannelMessageV1,
 };
-use service::CONNECT_TIMEOUT;
-use std::{sync::Arc, time::Duration};
+use service::{breaker::CircuitBreaker, LazyChannel};
+use settings::BackhaulSettings;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tonic::metadata::MetadataValue;
 
 type ServiceClient = services::router::Client<Channel>;
 
+/// How long a router stays on an older envelope version before the
+/// configured one is tried again.
+pub const ENVELOPE_RETRY: Duration = Duration::from_secs(3600);
+/// The request metadata key carrying the sequence number of an uplink, which
+/// increases in the order the gateway hands uplinks to the routers.
+pub const UPLINK_SEQ_KEY: &str = "x-uplink-seq";
+/// The request metadata key carrying the labels of the gateway, as comma
+/// separated name=value pairs.
+pub const GATEWAY_LABELS_KEY: &str = "x-gateway-labels";
+
+/// Versions of the state channel packet envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EnvelopeVersion {
+    /// The packet as signed before the region and hold time fields were
+    /// added. Routers that predate those fields drop them when re-encoding
+    /// the packet to verify its signature, so they are left out entirely.
+    V1 = 1,
+    /// The packet with the region and hold time
+    V2 = 2,
+}
+
+impl EnvelopeVersion {
+    pub const LATEST: Self = Self::V2;
+
+    pub fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+
+    /// The version before this one, if any.
+    pub fn previous(self) -> Option<Self> {
+        Self::from_u8((self as u8).saturating_sub(1))
+    }
+}
+
+impl fmt::Display for EnvelopeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", *self as u8)
+    }
+}
+
+/// The envelope version negotiated with a router. The router protocol has no
+/// way for a router to announce the versions it speaks, so the version is
+/// learned from its answers: a packet the router rejects as an invalid
+/// argument, which is how routers that predate a version answer a packet they
+/// can not verify, moves the router to the previous version. The configured
+/// version is tried again after the retry time so routers upgraded during a
+/// rollout move back to it.
+#[derive(Debug)]
+struct Negotiation {
+    configured: EnvelopeVersion,
+    retry: Duration,
+    /// The version in use and, when below the configured one, since when
+    state: Mutex<(EnvelopeVersion, Option<Instant>)>,
+}
+
+impl Negotiation {
+    fn new(configured: EnvelopeVersion, retry: Duration) -> Self {
+        Self {
+            configured,
+            retry,
+            state: Mutex::new((configured, None)),
+        }
+    }
+
+    fn version(&self) -> EnvelopeVersion {
+        let mut state = self.state.lock().expect("envelope lock");
+        if matches!(state.1, Some(since) if since.elapsed() >= self.retry) {
+            *state = (self.configured, None);
+        }
+        state.0
+    }
+
+    /// Records the answer of the router to a packet in the given version.
+    /// Answers to packets in a version no longer in use are ignored, so a
+    /// burst of rejected requests moves down a single version.
+    fn record(&self, version: EnvelopeVersion, rejected: bool) {
+        let mut state = self.state.lock().expect("envelope lock");
+        if !rejected || state.0 != version {
+            return;
+        }
+        if let Some(previous) = version.previous() {
+            *state = (previous, Some(Instant::now()));
+        }
+    }
+}
+
+/// Options of a single router request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RouteOptions<'a> {
+    /// The sequence number of the uplink, see `UPLINK_SEQ_KEY`
+    pub seq: Option<u64>,
+    /// The labels of the gateway, see `GATEWAY_LABELS_KEY`
+    pub labels: Option<&'a str>,
+    /// The envelope version the packet of the message is encoded in. None
+    /// for messages that are not uplink packets, which say nothing about the
+    /// version the router speaks.
+    pub envelope: Option<EnvelopeVersion>,
+}
+
+/// A client for a router service.
+///
+/// Packets are encoded in the envelope version negotiated with the router,
+/// starting with the configured one, see `Negotiation`.
 #[derive(Debug, Clone)]
 pub struct Service {
     pub uri: http::Uri,
     pub verifier: Option<Arc<PublicKey>>,
-    client: ServiceClient,
+    /// The circuit breaker for the router endpoint, shared by all clients
+    /// for the same uri
+    pub breaker: Arc<CircuitBreaker>,
+    channel: LazyChannel,
+    /// The negotiated envelope version, shared by the clones of the client
+    envelope: Arc<Negotiation>,
 }
 
 impl Service {
-    pub fn new(uri: http::Uri, verifier: Option<PublicKey>) -> Result<Self> {
-        let channel = Endpoint::from(uri.clone())
-            .timeout(Duration::from_secs(CONNECT_TIMEOUT))
-            .connect_lazy()?;
+    pub fn new(
+        uri: http::Uri,
+        verifier: Option<PublicKey>,
+        breaker: Arc<CircuitBreaker>,
+        backhaul: &BackhaulSettings,
+    ) -> Result<Self> {
         Ok(Self {
+            channel: service::channel(&uri, backhaul)?,
             uri,
-            client: ServiceClient::new(channel),
             verifier: verifier.map(Arc::new),
+            breaker,
+            envelope: Arc::new(Negotiation::new(backhaul.envelope_version, ENVELOPE_RETRY)),
         })
     }
 
+    /// The envelope version to encode packets for this router in.
+    pub fn envelope_version(&self) -> EnvelopeVersion {
+        self.envelope.version()
+    }
+
     pub async fn route(
         &mut self,
         msg: BlockchainStateChannelMessageV1,
+        options: RouteOptions<'_>,
     ) -> Result<BlockchainStateChannelMessageV1> {
-        Ok(self.client.route(msg).await?.into_inner())
+        let mut request = tonic::Request::new(msg);
+        if let Some(seq) = options.seq {
+            request
+                .metadata_mut()
+                .insert(UPLINK_SEQ_KEY, MetadataValue::from(seq));
+        }
+        // Labels that are not valid header values are left out
+        if let Some(labels) = options
+            .labels
+            .and_then(|labels| MetadataValue::from_str(labels).ok())
+        {
+            request.metadata_mut().insert(GATEWAY_LABELS_KEY, labels);
+        }
+        let mut client = ServiceClient::new(self.channel.get().await?);
+        let response = client.route(request).await;
+        if let Some(version) = options.envelope {
+            let rejected =
+                matches!(&response, Err(status) if status.code() == tonic::Code::InvalidArgument);
+            self.envelope.record(version, rejected);
+        }
+        Ok(response?.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let negotiation = Negotiation::new(EnvelopeVersion::V2, Duration::from_millis(50));
+        negotiation.record(EnvelopeVersion::V2, false);
+        assert_eq!(EnvelopeVersion::V2, negotiation.version());
+        negotiation.record(EnvelopeVersion::V2, true);
+        assert_eq!(EnvelopeVersion::V1, negotiation.version());
+        // Rejections of packets sent before the switch, and of the oldest
+        // version, do not move the router further down
+        negotiation.record(EnvelopeVersion::V2, true);
+        negotiation.record(EnvelopeVersion::V1, true);
+        assert_eq!(EnvelopeVersion::V1, negotiation.version());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(EnvelopeVersion::V2, negotiation.version());
+
+        let negotiation = Negotiation::new(EnvelopeVersion::V1, Duration::from_millis(0));
+        negotiation.record(EnvelopeVersion::V1, true);
+        assert_eq!(EnvelopeVersion::V1, negotiation.version());
+        assert_eq!(None, EnvelopeVersion::V1.previous());
+        assert_eq!(Some(EnvelopeVersion::V1), EnvelopeVersion::V2.previous());
     }
 }
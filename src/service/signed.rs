@@ -0,0 +1,216 @@
+//! Request signing and response verification for the config services the
+//! gateway fetches from, with their key pinned in the settings.
+//!
+//! The bootstrap server, which also provides the region, gets signed
+//! requests and returns responses signed over the request nonce. Routing
+//! updates from gateways are verified with `verify_message`, but arrive on a
+//! stream of protobuf messages that has no room for a nonce, so a replayed
+//! update is only told apart by its height. Update manifests come from GitHub
+//! releases, which have no service key to pin and can not sign a nonce, so
+//! they are not covered.
+
+use crate::*;
+use helium_crypto::Verify;
+use helium_proto::Message;
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use signer::{Purpose, Signatures};
+
+pub const KEY_HEADER: &str = "x-gateway-key";
+pub const TIMESTAMP_HEADER: &str = "x-gateway-timestamp";
+pub const NONCE_HEADER: &str = "x-gateway-nonce";
+pub const SIGNATURE_HEADER: &str = "x-gateway-signature";
+
+/// How old, in seconds, the timestamp of a signed response may be.
+pub const MAX_RESPONSE_AGE: u64 = 300;
+
+/// A request to a config service signed by the gateway key, so the service
+/// knows which gateway asks. The service is expected to sign the nonce and
+/// timestamp into its response, which makes a recorded response useless for
+/// a later request.
+#[derive(Debug, Clone)]
+pub struct SignedRequest {
+    pub key: String,
+    pub path: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    /// The signature (base64) over the key, path, timestamp and nonce
+    pub signature: String,
+}
+
+/// The replay protection fields of a signed response.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Freshness {
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+impl SignedRequest {
+    pub fn new(signatures: &Signatures, path: &str) -> Result<Self> {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let mut request = Self {
+            key: signatures.public_key().to_string(),
+            path: path.to_string(),
+            timestamp: stats::unix_secs(),
+            nonce: base64::encode(nonce),
+            signature: String::new(),
+        };
+        request.signature =
+            base64::encode(signatures.sign(Purpose::Request, &request.signed_bytes())?);
+        Ok(request)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{}",
+            self.key, self.path, self.timestamp, self.nonce
+        )
+        .into_bytes()
+    }
+
+    /// The curl arguments sending the request signature as headers.
+    pub fn curl_args(&self) -> Vec<String> {
+        vec![
+            (KEY_HEADER, self.key.clone()),
+            (TIMESTAMP_HEADER, self.timestamp.to_string()),
+            (NONCE_HEADER, self.nonce.clone()),
+            (SIGNATURE_HEADER, self.signature.clone()),
+        ]
+        .into_iter()
+        .flat_map(|(name, value)| vec!["-H".to_string(), format!("{}: {}", name, value)])
+        .collect()
+    }
+
+    /// Verifies a response to this request signed by the pinned key of the
+    /// service. A fresh response is signed over the nonce and timestamp
+    /// followed by the body, and has to carry the nonce of this request and
+    /// a recent timestamp. Services that predate signed requests sign only
+    /// the body, which is only accepted when `require_fresh` is off.
+    pub fn verify_response(
+        &self,
+        key: &PublicKey,
+        body: &[u8],
+        signature: &str,
+        freshness: &Freshness,
+        require_fresh: bool,
+    ) -> Result {
+        let signature = base64::decode(signature)?;
+        let signed = match (&freshness.nonce, freshness.timestamp) {
+            (Some(nonce), Some(timestamp)) => {
+                if *nonce != self.nonce {
+                    return Err(Error::custom("response nonce does not match the request"));
+                }
+                if stats::unix_secs().saturating_sub(timestamp) > MAX_RESPONSE_AGE {
+                    return Err(Error::custom("stale response"));
+                }
+                let mut signed = format!("{}\n{}\n", nonce, timestamp).into_bytes();
+                signed.extend_from_slice(body);
+                signed
+            }
+            _ if require_fresh => return Err(Error::custom("response without a nonce")),
+            _ => body.to_vec(),
+        };
+        key.verify(&signed, &signature)
+            .map_err(|_| Error::custom("invalid response signature"))?;
+        Ok(())
+    }
+}
+
+/// Verifies a service message signed by the pinned key of the service over
+/// the message encoded with an empty signature. Returns the message with the
+/// signature cleared.
+pub fn verify_message<M>(
+    key: &PublicKey,
+    message: &M,
+    signature: fn(&mut M) -> &mut Vec<u8>,
+) -> Result<M>
+where
+    M: Message + Clone,
+{
+    let mut unsigned = message.clone();
+    let signature = std::mem::take(signature(&mut unsigned));
+    let mut buf = vec![];
+    unsigned.encode(&mut buf)?;
+    key.verify(&buf, &signature)?;
+    Ok(unsigned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network, Sign};
+    use settings::SigningSettings;
+    use std::sync::Arc;
+
+    fn keypair() -> Keypair {
+        Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+    }
+
+    #[test]
+    fn response() {
+        let (gateway, service) = (keypair(), keypair());
+        let key = gateway.public_key().to_string();
+        let signatures = Signatures::with_limits(
+            Arc::new(gateway),
+            &SigningSettings {
+                uplink_per_minute: 0,
+                peer_per_minute: 0,
+            },
+        );
+        let request = SignedRequest::new(&signatures, "/v1/gateways").expect("request");
+        assert_eq!(key, request.key);
+
+        let body = b"{}";
+        let sign = |freshness: &Freshness| {
+            let mut signed = match (&freshness.nonce, freshness.timestamp) {
+                (Some(nonce), Some(timestamp)) => {
+                    format!("{}\n{}\n", nonce, timestamp).into_bytes()
+                }
+                _ => vec![],
+            };
+            signed.extend_from_slice(body);
+            base64::encode(service.sign(&signed).expect("sign"))
+        };
+        let verify = |freshness: &Freshness, require_fresh| {
+            request.verify_response(
+                service.public_key(),
+                body,
+                &sign(freshness),
+                freshness,
+                require_fresh,
+            )
+        };
+        let fresh = Freshness {
+            nonce: Some(request.nonce.clone()),
+            timestamp: Some(request.timestamp),
+        };
+        assert!(verify(&fresh, true).is_ok());
+        assert!(verify(&Freshness::default(), false).is_ok());
+        assert!(verify(&Freshness::default(), true).is_err());
+
+        let replayed = Freshness {
+            nonce: Some("other".to_string()),
+            ..fresh.clone()
+        };
+        assert!(verify(&replayed, false).is_err());
+        let stale = Freshness {
+            timestamp: Some(request.timestamp - 2 * MAX_RESPONSE_AGE),
+            ..fresh.clone()
+        };
+        assert!(verify(&stale, false).is_err());
+
+        // Signed by another key than the pinned one
+        assert!(request
+            .verify_response(&signatures.public_key(), body, &sign(&fresh), &fresh, false)
+            .is_err());
+    }
+}
@@ -1,51 +1,249 @@
 use crate::*;
-use config::{Config, Environment, File};
-use helium_crypto::{KeyTag, KeyType, Network};
+use address_book::AddressBook;
+use config::{Config, Environment, File, FileFormat};
 use helium_proto::Region;
 use http::uri::Uri;
-use rand::rngs::OsRng;
-use serde::{de, Deserialize, Deserializer};
-use std::{collections::HashMap, net::SocketAddr, path::Path, sync::Arc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use service::router::EnvelopeVersion;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The environment variable selecting a settings profile
+pub const PROFILE_ENV: &str = "GW_PROFILE";
+/// The local settings file, merged over default.toml
+pub const SETTINGS_FILE: &str = "settings.toml";
 
 pub fn version() -> semver::Version {
     semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("unable to parse version")
 }
 
-/// A URI that has an associated public key
-#[derive(Debug, Clone, Deserialize)]
+/// A URI that has an associated public key. The public key can be given with
+/// a key type prefix, as in "ed25519:<key>" or "ecc_compact:<key>", in which
+/// case the key must be of that type. Two keyed uris are equal when both the
+/// uri and the public key bytes are equal.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct KeyedUri {
-    #[serde(deserialize_with = "deserialize_uri")]
+    #[serde(
+        deserialize_with = "deserialize_uri",
+        serialize_with = "serialize_display"
+    )]
     pub uri: Uri,
-    #[serde(deserialize_with = "deserialize_pubkey")]
+    #[serde(
+        deserialize_with = "deserialize_pubkey",
+        serialize_with = "serialize_display"
+    )]
     pub public_key: PublicKey,
 }
 
+impl PartialEq for KeyedUri {
+    fn eq(&self, other: &Self) -> bool {
+        self.uri == other.uri && self.public_key.to_vec() == other.public_key.to_vec()
+    }
+}
+
+impl Eq for KeyedUri {}
+
+impl Hash for KeyedUri {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.uri.hash(state);
+        self.public_key.to_vec().hash(state);
+    }
+}
+
+/// The key types a public key can be tagged with, by the key type nibble of
+/// the binary key encoding.
+const KEY_TYPES: &[(&str, u8)] = &[("ecc_compact", 0), ("ed25519", 1)];
+
+/// Returns the name of the key type of the given public key.
+pub fn key_type_name(public_key: &PublicKey) -> Option<&'static str> {
+    let key_type = public_key.to_vec().first()? & 0x0f;
+    KEY_TYPES
+        .iter()
+        .find(|(_, t)| *t == key_type)
+        .map(|(name, _)| *name)
+}
+
+/// Parses a b58 encoded public key with an optional key type prefix.
+pub fn parse_pubkey(s: &str) -> Result<PublicKey> {
+    let (tag, key_string) = match s.split_once(':') {
+        Some((tag, key_string)) => (Some(tag.to_lowercase()), key_string),
+        None => (None, s),
+    };
+    let public_key: PublicKey = key_string.parse()?;
+    if let Some(tag) = tag {
+        if !KEY_TYPES.iter().any(|(name, _)| *name == tag) {
+            return Err(Error::custom(format!("unsupported key type: {}", tag)));
+        }
+        if key_type_name(&public_key) != Some(tag.as_str()) {
+            return Err(Error::custom(format!("public key is not an {} key", tag)));
+        }
+    }
+    Ok(public_key)
+}
+
 /// Settings are all the configuration parameters the service needs to operate.
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     /// The listen address to use for listening for the semtech UDP packet forwarder.
-    /// Default "127.0.0.1:1680"
+    /// Default "127.0.0.1:1680". The receive buffer of the socket is sized by
+    /// the system (net.core.rmem_default on Linux); packets dropped because
+    /// it is full are logged.
     #[serde(deserialize_with = "deserialize_listen_addr")]
     pub listen_addr: SocketAddr,
+    /// The number of seconds without a PULL_DATA keepalive after which a
+    /// packet forwarder is considered stale. Downlinks to stale forwarders are
+    /// refused and the forwarder is eventually evicted. Default 60
+    pub client_timeout: u64,
+    /// Whether downlinks for an unknown packet forwarder are sent to the most
+    /// recently active forwarder instead. This helps when a concentrator card
+    /// is swapped and reports a new MAC. Default false
+    pub client_fallback: bool,
+    /// Stable logical ids for packet forwarders, each mapping to the MACs the
+    /// forwarder has reported over time. The id is used in logs, traces and
+    /// alarms in place of the MAC. Upstream messages identify the gateway by
+    /// its key and do not include the MAC.
+    #[serde(default)]
+    pub gateway_ids: HashMap<String, Vec<String>>,
     /// The location of the keypair binary file for the gateway. Defaults to
     /// "/etc/helium_gateway/keypair.bin". If the keyfile is not found there a new
     /// one is generated and saved in that location.
     #[serde(deserialize_with = "deserialize_keypair")]
     pub keypair: Arc<Keypair>,
+    /// The path the keypair was loaded from
+    #[serde(skip)]
+    pub keypair_path: String,
     /// The lorawan region to use. This value should line up with the configured
-    /// region of the semtech packet forwarder. Defaults to "US91%"
+    /// region of the semtech packet forwarder. Defaults to "US91%". When set to
+    /// "auto" the region is inferred from the frequencies of received uplinks.
     #[serde(deserialize_with = "deserialize_region")]
-    pub region: Region,
+    pub region: Option<Region>,
+    /// Region inference settings used when the region is set to "auto"
+    pub region_inference: RegionInferenceSettings,
     /// Log settings
     pub log: LogSettings,
+    /// Async runtime settings
+    pub runtime: RuntimeSettings,
+    /// Memory budget settings
+    pub memory: MemorySettings,
     /// Update settings
     pub update: UpdateSettings,
+    /// Trace export settings
+    pub telemetry: TelemetrySettings,
+    /// Packet mirroring settings
+    pub mirror: MirrorSettings,
+    /// Settings for the local uplink feed
+    pub feed: FeedSettings,
+    /// Concentrator health alarm settings
+    pub health: HealthSettings,
+    /// Settings for buffering downlinks to unreachable packet forwarders
+    pub downlink_buffer: DownlinkBufferSettings,
+    /// Antenna names and downlink antenna selection
+    pub antennas: AntennaSettings,
+    /// Settings for tracking recent device transactions
+    pub sessions: SessionSettings,
+    /// Packet forwarders allowed to connect. All forwarders are allowed by
+    /// default
+    #[serde(default)]
+    pub allowlist: AllowlistSettings,
+    /// Settings for writing the channel configuration of a managed packet
+    /// forwarder
+    #[serde(default)]
+    pub forwarder_config: ForwarderConfigSettings,
+    /// Settings for supervising a local packet forwarder
+    pub supervisor: SupervisorSettings,
+    /// Settings for encrypted tunnels from remote packet forwarders
+    pub tunnel: TunnelSettings,
+    /// Settings for keeping application payloads out of exported data
+    pub privacy: PrivacySettings,
+    /// Settings for ignoring forwarders that send unparseable frames
+    pub quarantine: QuarantineSettings,
+    /// Settings for where gateway state is stored
+    pub store: StoreSettings,
+    /// Settings for ignoring duplicate uplinks
+    pub dedup: DedupSettings,
+    /// Precision of the uplink metadata
+    pub metadata: MetadataSettings,
+    /// Settings for queueing uplinks per packet forwarder
+    pub uplink_queue: UplinkQueueSettings,
+    /// Settings for downlink delivery
+    pub downlink: DownlinkSettings,
+    /// Settings for forwarding uplinks to a private network server as well
+    pub lns: LnsSettings,
+    /// Settings for the doctor loopback test
+    pub self_test: SelfTestSettings,
+    /// Safety interlocks of the certification test transmissions
+    pub certification: CertificationSettings,
+    /// Settings for the local api
+    pub api: ApiSettings,
+    /// Settings for keeping historical traffic stats
+    pub stats: StatsSettings,
+    /// Settings for alerting on health problems
+    pub alerts: AlertSettings,
+    /// Settings for resolving join requests with a join server
+    pub join_server: JoinServerSettings,
+    /// Settings for relaying packet forwarder traffic to a legacy network
+    /// server
+    pub passthrough: PassthroughSettings,
     /// The router to deliver packets to when no routers are found while
     /// processing a packet.
     pub router: HashMap<String, KeyedUri>,
     /// The validator(s) to query for chain related state. Defaults to a Helium
     /// validator.
     pub gateways: Vec<KeyedUri>,
+    /// Upstream connection settings
+    pub backhaul: BackhaulSettings,
+    /// Settings for the SNTP fallback on boards with an unset clock
+    pub sntp: SntpSettings,
+    /// Conditions to wait for before the server starts
+    pub startup: StartupSettings,
+    /// Circuit breaker settings for router and gateway endpoints
+    pub circuit_breaker: CircuitBreakerSettings,
+    /// Settings for picking between the routers of an OUI
+    pub router_selection: RouterSelectionSettings,
+    /// Settings for finding the other gateways of a site on the LAN
+    pub peers: PeerSettings,
+    /// Settings for redundant hosts sharing one concentrator network
+    pub cluster: ClusterSettings,
+    /// Limits on the signatures made with the gateway key
+    pub signing: SigningSettings,
+    /// Checks of downlink sizes against their datarate
+    pub downlink_guard: DownlinkGuardSettings,
+    /// Operator bootstrap settings
+    pub bootstrap: BootstrapSettings,
+    /// Settings pushed by a fleet manager over the local api
+    pub push: PushSettings,
+    /// Settings for the audit log of configuration changes
+    pub audit: AuditSettings,
+    /// Settings for registering the new key of a key rotation
+    #[serde(default)]
+    pub key_rotation: KeyRotationSettings,
+    /// Run-time feature flags for experimental behaviors (default: none)
+    #[serde(default)]
+    pub features: Features,
+    /// Free-form labels of the gateway, like its site, deployment or
+    /// customer, added to the uplink feed, router requests, traces, stats
+    /// and alerts (default: none)
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Named, trusted endpoints. The router, gateways and bootstrap server
+    /// settings are included in the address book as well.
+    #[serde(default)]
+    pub address_book: AddressBook,
+    /// The folder the settings were loaded from
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The settings profile applied, if any
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Changes made to migrate settings.toml from an older layout
+    #[serde(skip)]
+    pub migrations: Vec<String>,
 }
 
 /// The method to use for logging.
@@ -70,6 +268,49 @@ pub struct LogSettings {
 
     /// Whehter to show timestamps in the stdio output stream (default false)
     pub timestamp: bool,
+
+    /// Log levels for individual modules, like "gateway" or "router", that
+    /// override the global level (default: none)
+    #[serde(default, deserialize_with = "deserialize_module_log_levels")]
+    pub modules: HashMap<String, slog::Level>,
+
+    /// Interval in seconds over which repeated warnings of the same kind are
+    /// collapsed into a summary, 0 to disable (default 60)
+    pub summary_interval: u64,
+}
+
+/// Settings for the memory budget of caches and queues.
+#[derive(Debug, Deserialize)]
+pub struct MemorySettings {
+    /// The total budget in KiB (default: 4096)
+    pub budget: usize,
+    /// Percentage of the budget for the routing table (default: 75)
+    pub routing_share: u8,
+    /// Percentage of the budget for the uplink and downlink packet queues
+    /// (default: 25)
+    pub queue_share: u8,
+}
+
+/// The async runtime scheduler to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum RuntimeFlavor {
+    /// Run all tasks on the main thread. This is the lightest option and
+    /// well suited for single core gateways.
+    CurrentThread,
+    /// Run tasks on a pool of worker threads
+    MultiThread,
+}
+
+/// Settings for the async runtime.
+#[derive(Debug, Deserialize)]
+pub struct RuntimeSettings {
+    /// Which scheduler to use (current_thread or multi_thread, default
+    /// current_thread)
+    #[serde(deserialize_with = "deserialize_runtime_flavor")]
+    pub flavor: RuntimeFlavor,
+    /// The number of worker threads for the multi_thread scheduler. A value of
+    /// 0 uses one worker per CPU core (default 0)
+    pub workers: usize,
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -94,31 +335,1004 @@ pub struct UpdateSettings {
     pub command: String,
 }
 
+/// Settings for exporting packet traces to an OpenTelemetry collector.
+#[derive(Debug, Deserialize)]
+pub struct TelemetrySettings {
+    /// Whether trace export is enabled (default: false)
+    pub enabled: bool,
+    /// The OTLP/HTTP traces endpoint to export to (default:
+    /// http://127.0.0.1:4318/v1/traces)
+    #[serde(deserialize_with = "deserialize_uri")]
+    pub uri: Uri,
+    /// The fraction of uplink traces to export, between 0.0 and 1.0 (default:
+    /// 1.0)
+    pub sample_rate: f64,
+    /// How often buffered spans are exported in seconds (default: set by the
+    /// backhaul preset)
+    pub export_interval: u64,
+}
+
+/// Settings for mirroring packets to a secondary sink for analysis.
+#[derive(Debug, Deserialize)]
+pub struct MirrorSettings {
+    /// Whether mirroring is enabled (default: false)
+    pub enabled: bool,
+    /// The sink to mirror to, either "udp://<host>:<port>" for one json
+    /// datagram per packet or "file://<path>" for one json line per packet
+    /// (default: udp://127.0.0.1:1690)
+    pub uri: String,
+    /// Whether downlinks are mirrored as well as uplinks (default: false)
+    pub downlinks: bool,
+}
+
+/// Settings for streaming decoded uplinks to local applications.
+#[derive(Debug, Deserialize)]
+pub struct FeedSettings {
+    /// Whether the feed is enabled (default: false)
+    pub enabled: bool,
+    /// The unix domain socket consumers connect to (default:
+    /// /var/run/helium_gateway.sock)
+    pub path: PathBuf,
+}
+
+/// Settings for fetching operator provided configuration.
+#[derive(Debug, Deserialize)]
+pub struct BootstrapSettings {
+    /// The bootstrap server and the operator key its configuration is signed
+    /// with. Bootstrapping is disabled when not set.
+    pub server: Option<KeyedUri>,
+    /// How often to check for configuration updates (in minutes, default: 60)
+    pub interval: u32,
+    /// Whether to refuse configurations not signed over the nonce of the
+    /// request, which protects against replayed responses. Turn off only
+    /// for servers that predate signed requests (default: true)
+    pub require_fresh: bool,
+}
+
+/// Settings for the audit log of configuration changes.
+#[derive(Debug, Deserialize)]
+pub struct AuditSettings {
+    /// The file the head of the audit log chain is anchored in, outside the
+    /// settings folder holding the log (default:
+    /// /var/lib/helium_gateway/audit.head)
+    pub head: PathBuf,
+}
+
+/// Settings for applying settings pushed by a fleet manager.
+#[derive(Debug, Deserialize)]
+pub struct PushSettings {
+    /// How long pushed settings have to keep a packet forwarder connected
+    /// before they are kept, rolling them back otherwise (in seconds,
+    /// default: 300)
+    pub probation: u64,
+    /// How many times the gateway may start with pushed settings still on
+    /// probation before they are rolled back (default: 3)
+    pub max_starts: u32,
+}
+
+/// Settings for registering the new key of a key rotation upstream. The
+/// server continues the rotation every ten minutes while it runs.
+#[derive(Debug, Deserialize, Default)]
+pub struct KeyRotationSettings {
+    /// The url the new key is posted to as json, with the signatures of both
+    /// keys. With a url the keypair is only swapped once the new key is
+    /// registered (default: none)
+    pub register: Option<String>,
+}
+
+/// The type of backhaul the gateway uses to reach its upstream services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BackhaulPreset {
+    Ethernet,
+    /// Carrier grade NATs drop idle mappings quickly and links are lossy
+    Cellular,
+    /// High latency links where traffic is expensive
+    Satellite,
+}
+
+impl BackhaulPreset {
+    /// The settings defaults of the preset. Settings that are set explicitly
+    /// take precedence over these.
+    fn defaults(&self) -> &'static [(&'static str, i64)] {
+        match self {
+            Self::Ethernet => &[
+                ("backhaul.timeout", 10),
+                ("backhaul.keepalive", 30),
+                ("backhaul.reconnect_delay", 5),
+                ("circuit_breaker.failures", 5),
+                ("circuit_breaker.open_secs", 30),
+                ("telemetry.export_interval", 5),
+            ],
+            Self::Cellular => &[
+                ("backhaul.timeout", 20),
+                ("backhaul.keepalive", 20),
+                ("backhaul.reconnect_delay", 10),
+                ("circuit_breaker.failures", 8),
+                ("circuit_breaker.open_secs", 60),
+                ("telemetry.export_interval", 30),
+            ],
+            Self::Satellite => &[
+                ("backhaul.timeout", 30),
+                ("backhaul.keepalive", 60),
+                ("backhaul.reconnect_delay", 30),
+                ("circuit_breaker.failures", 10),
+                ("circuit_breaker.open_secs", 120),
+                ("telemetry.export_interval", 60),
+            ],
+        }
+    }
+}
+
+/// Settings for connections to upstream services. The defaults for these,
+/// the circuit breaker settings and the trace export interval are set by the
+/// backhaul preset.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackhaulSettings {
+    /// The backhaul type (ethernet, cellular or satellite, default: ethernet)
+    #[serde(deserialize_with = "deserialize_backhaul_preset")]
+    pub preset: BackhaulPreset,
+    /// Request timeout in seconds for router and gateway requests
+    pub timeout: u64,
+    /// TCP keepalive interval in seconds for upstream connections. Keeps NAT
+    /// mappings of idle connections alive.
+    pub keepalive: u64,
+    /// Seconds to wait before connecting to another gateway after a routing
+    /// stream ends
+    pub reconnect_delay: u64,
+    /// The network interface name or source address to bind router and
+    /// gateway connections, bootstrap and trace export requests to (default:
+    /// none). Bound grpc channels connect with their first request.
+    pub interface: Option<String>,
+    /// Whether to try IPv6 addresses before IPv4 ones where the gateway
+    /// resolves hosts itself (default: false). Router and gateway grpc
+    /// connections follow the system resolver order, which prefers IPv6 when
+    /// it is routable.
+    pub prefer_ipv6: bool,
+    /// The NAT64 prefix to reach IPv4 literal addresses through on IPv6-only
+    /// networks: off, auto to discover it through the DNS64 resolver, or a /96
+    /// prefix like "64:ff9b::" (default: off)
+    #[serde(deserialize_with = "deserialize_nat64")]
+    pub nat64: Nat64,
+    /// The NAT64 prefix in use, set on startup
+    #[serde(skip)]
+    pub nat64_prefix: Option<Ipv6Addr>,
+    /// The state channel envelope version sent to routers (default: 2).
+    /// Routers that reject a packet as invalid get the previous version for
+    /// an hour before this one is tried again.
+    #[serde(deserialize_with = "deserialize_envelope_version")]
+    pub envelope_version: EnvelopeVersion,
+    /// Whether to cancel a router request once its answer could no longer
+    /// make the last receive window of the uplink, instead of waiting for the
+    /// request timeout (default: true)
+    pub response_deadline: bool,
+    /// Milliseconds before the last receive window by which a router answer
+    /// has to arrive to still be sent (default: 200)
+    pub response_margin: u64,
+    /// Seconds from a data uplink to its rx2 window, as set by the network
+    /// server (default: 2)
+    pub receive_delay2: u64,
+    /// Seconds from a join request to its second join accept window
+    /// (default: 6)
+    pub join_accept_delay2: u64,
+}
+
+/// Settings for keeping application payloads out of logs, the mirror and the
+/// feed.
+#[derive(Debug, Deserialize)]
+pub struct PrivacySettings {
+    /// How payloads are exported: keep, redact to zero the frame payload or
+    /// truncate to cut it off. MAC layer headers are always kept (default:
+    /// keep)
+    #[serde(deserialize_with = "deserialize_payload_privacy")]
+    pub payload: PayloadPrivacy,
+    /// How packet forwarder locations are reported: exact, truncated to
+    /// about the size of an H3 cell of the location resolution, or hidden
+    /// (default: exact)
+    #[serde(deserialize_with = "deserialize_location_privacy")]
+    pub location: LocationPrivacy,
+    /// The H3 resolution, 0 to 15, truncated locations are reported at
+    /// (default: 8)
+    pub location_resolution: u8,
+}
+
+/// How precisely packet forwarder locations are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationPrivacy {
+    Exact,
+    /// Snapped to a grid of the size of an H3 cell, without the altitude
+    Truncated,
+    Hidden,
+}
+
+/// How the application payload of exported frames is treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadPrivacy {
+    Keep,
+    /// The payload bytes are zeroed, the frame keeps its length and MIC
+    Redact,
+    /// The frame is cut off after the MAC layer headers
+    Truncate,
+}
+
+/// How IPv4 literal addresses are reached on IPv6-only networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nat64 {
+    Off,
+    /// Discover the prefix from the DNS64 resolver (RFC 7050)
+    Auto,
+    Prefix(Ipv6Addr),
+}
+
+/// Settings for the circuit breakers of upstream endpoints.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerSettings {
+    /// Number of consecutive failures after which requests to an endpoint are
+    /// suspended (default: set by the backhaul preset)
+    pub failures: u32,
+    /// Seconds to suspend requests to a failing endpoint before probing it
+    /// again (default: set by the backhaul preset)
+    pub open_secs: u64,
+}
+
+/// Settings for learning the time over SNTP when the system clock was never
+/// set, as on boards without an RTC or an NTP daemon.
+#[derive(Debug, Deserialize)]
+pub struct SntpSettings {
+    /// Whether to query SNTP servers while the system clock is unset
+    /// (default: true)
+    pub enabled: bool,
+    /// The servers to query in order, as host or host:port (default:
+    /// ["pool.ntp.org"])
+    pub servers: Vec<String>,
+    /// Seconds between time queries (default: 3600)
+    pub interval: u64,
+}
+
+/// Settings for holding off the server until its dependencies are up, as
+/// on embedded hosts that boot slowly.
+#[derive(Debug, Deserialize)]
+pub struct StartupSettings {
+    /// Whether to wait for the system clock to be set or for the time over
+    /// SNTP (default: false)
+    pub wait_time: bool,
+    /// Whether to wait until a tcp connection to the router of the update
+    /// channel gets through (default: false)
+    pub wait_route: bool,
+    /// Whether to wait for a PULL_DATA from a packet forwarder, unless the
+    /// supervisor runs it (default: false)
+    pub wait_forwarder: bool,
+    /// Seconds to wait for each condition before starting anyway (default:
+    /// 120)
+    pub timeout: u64,
+}
+
+/// The feature flag that keys dedup on the frame instead of the payload.
+pub const FRAME_DEDUP_FEATURE: &str = "frame_dedup";
+/// The feature flag that shares the uplinks heard with the peers of the site.
+pub const COOPERATIVE_DEDUP_FEATURE: &str = "cooperative_dedup";
+
+/// Run-time feature flags gating experimental behaviors by name, so a
+/// behavior can be rolled out to some gateways without a separate build.
+/// Features that are not listed are off. The bootstrap server can set them
+/// per gateway, overriding the local settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Features(HashMap<String, bool>);
+
+impl Features {
+    /// Whether the named feature is turned on.
+    pub fn enabled(&self, name: &str) -> bool {
+        self.0.get(name).copied().unwrap_or(false)
+    }
+
+    /// The names of the features that are turned on, sorted.
+    pub fn list(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .0
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Turns on the experimental dedup behaviors whose flags are on, on top
+    /// of the settings that turn them on.
+    fn apply(&self, dedup: &mut DedupSettings, peers: &mut PeerSettings) {
+        if self.enabled(FRAME_DEDUP_FEATURE) {
+            dedup.key = DedupKey::Frame;
+        }
+        if self.enabled(COOPERATIVE_DEDUP_FEATURE) {
+            peers.dedup = true;
+        }
+    }
+}
+
+/// Settings for finding the other gateways of a site on the LAN.
+#[derive(Debug, Deserialize)]
+pub struct PeerSettings {
+    /// Whether to announce the gateway on the LAN and listen for other
+    /// gateways (default: false)
+    pub enabled: bool,
+    /// The udp port announcements are broadcast to and received on
+    /// (default: 1690)
+    pub port: u16,
+    /// The address announcements are broadcast to (default:
+    /// 255.255.255.255)
+    pub broadcast: Ipv4Addr,
+    /// Seconds between announcements. Peers are forgotten when not heard
+    /// from for three intervals (default: 30)
+    pub interval: u64,
+    /// The keys of the gateways accepted as peers, required when enabled
+    /// (default: [])
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Whether to share the uplinks heard with the peers so only the one
+    /// with the best signal forwards an uplink. Needs the same dedup key on
+    /// all gateways of the site and the keys of the peers (default: false)
+    pub dedup: bool,
+    /// Milliseconds each uplink is held to hear the reports of the peers
+    /// before it is forwarded (default: 200)
+    pub dedup_hold_ms: u64,
+}
+
+/// Settings for a cluster of hosts that serve the same packet forwarders,
+/// where only the elected leader forwards uplinks and dispatches downlinks.
+#[derive(Debug, Deserialize)]
+pub struct ClusterSettings {
+    /// Whether to take part in a cluster (default: false)
+    pub enabled: bool,
+    /// The address heartbeats are received on (default: "0.0.0.0:1692")
+    #[serde(deserialize_with = "deserialize_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// The heartbeat addresses, as ip:port, of the other hosts (default: [])
+    #[serde(default)]
+    pub peers: Vec<SocketAddr>,
+    /// The keys of the hosts accepted in the cluster, required when
+    /// clustering is enabled (default: [])
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Milliseconds the lease of the leader lasts without a heartbeat. A
+    /// failed leader is replaced after one lease (default: 3000)
+    pub lease_ms: u64,
+}
+
+/// Limits on the signatures made with the gateway key per purpose, to catch
+/// runaway signing loops. A limit of 0 disables it. The other purposes are
+/// counted without a limit.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SigningSettings {
+    /// Uplink envelopes signed per minute. A busy gateway signs more uplinks
+    /// than a quiet one, so there is no limit by default (default: 0)
+    pub uplink_per_minute: u32,
+    /// Peer messages signed per minute (default: 600)
+    pub peer_per_minute: u32,
+}
+
+/// Settings for rejecting downlinks that are too long for their datarate
+/// before they reach the packet forwarder, which would fail or truncate
+/// them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownlinkGuardSettings {
+    /// Whether to check downlink sizes (default: true)
+    pub enabled: bool,
+    /// The maximum airtime of a downlink in milliseconds, 0 for no limit
+    /// (default: 0)
+    pub max_airtime_ms: u64,
+    /// The maximum PHYPayload size in bytes by datarate, like SF9BW125,
+    /// replacing the one of the regional parameters (default: none)
+    #[serde(default)]
+    pub max_payload: HashMap<String, usize>,
+}
+
+/// Settings for picking between the routers of an OUI.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouterSelectionSettings {
+    /// Whether to send uplinks only to the router of an OUI with the lowest
+    /// recent round trip time instead of to all of them (default: false)
+    pub lowest_latency: bool,
+    /// How much faster in percent another router has to be before it
+    /// replaces the picked one (default: 20)
+    pub hysteresis: u8,
+    /// Seconds after which the round trip time of a router that is not
+    /// picked is measured again by sending it an uplink (default: 300)
+    pub probe_interval: u64,
+    /// OUIs whose routers race for each uplink: it is sent to all of them at
+    /// once, the first to answer with a downlink wins and the requests to
+    /// the others are cancelled (default: none)
+    #[serde(default)]
+    pub first_accept: Vec<u32>,
+}
+
+/// Settings for inferring the region from received uplinks.
+#[derive(Debug, Deserialize)]
+pub struct RegionInferenceSettings {
+    /// The minimum number of uplinks to observe before an inferred region is
+    /// considered confident (default: 20)
+    pub samples: u32,
+    /// Whether to stop re-evaluating once a confident region has been
+    /// inferred (default: true)
+    pub lock: bool,
+}
+
+/// Settings for holding downlinks for packet forwarders that are stale or not
+/// yet connected.
+#[derive(Debug, Deserialize)]
+pub struct DownlinkBufferSettings {
+    /// Maximum number of buffered downlinks, 0 disables buffering (default:
+    /// 0)
+    pub size: usize,
+    /// Milliseconds a downlink is kept before it is dropped (default: 2000)
+    pub max_age_ms: u64,
+}
+
+/// Settings for the antennas of multi-antenna concentrators.
+#[derive(Debug, Deserialize)]
+pub struct AntennaSettings {
+    /// Names for antenna indices, as reported in the uplinks of the packet
+    /// forwarder (default: none)
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    /// The radio chain to transmit downlinks on. Only radios with tx enabled
+    /// in the packet forwarder configuration can transmit (default: 0)
+    pub tx_rf_chain: u64,
+    /// The radio chain to transmit downlinks on by packet forwarder MAC or
+    /// gateway id, for forwarders whose tx radio is not the default one
+    /// (default: none)
+    #[serde(default)]
+    pub tx_rf_chains: HashMap<String, u64>,
+    /// Maximum downlink transmit power in dBm by packet forwarder MAC or
+    /// gateway id, for forwarders with high gain antennas. Downlinks asking
+    /// for more are sent at the maximum (default: none)
+    #[serde(default)]
+    pub max_power: HashMap<String, u64>,
+}
+
+/// Settings for the recent uplinks and downlinks kept per device.
+#[derive(Debug, Deserialize)]
+pub struct SessionSettings {
+    /// Maximum number of devices tracked, 0 disables tracking (default: 256)
+    pub size: usize,
+    /// Seconds a device is tracked after it was last heard (default: 600)
+    pub max_age_secs: u64,
+}
+
+/// Restrictions on the packet forwarders that are served. Empty lists do not
+/// restrict.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct AllowlistSettings {
+    /// Forwarder MACs that are allowed (default: all)
+    #[serde(default)]
+    pub macs: Vec<String>,
+    /// Networks in CIDR notation forwarders are allowed to connect from
+    /// (default: all)
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// A toml file with additional `macs` and `networks` entries. The file is
+    /// reloaded when it changes (default: none)
+    pub path: Option<PathBuf>,
+}
+
+/// Settings for keeping the channel configuration of a managed packet
+/// forwarder in line with the region.
+#[derive(Debug, Deserialize, Default)]
+pub struct ForwarderConfigSettings {
+    /// The file to write the channel configuration of the region to when the
+    /// region is set or inferred. Not written by default
+    pub path: Option<PathBuf>,
+    /// The concentrator of the packet forwarder, sx1301 or sx1302, which
+    /// picks the configuration section written (default: sx1301)
+    #[serde(default, deserialize_with = "deserialize_concentrator")]
+    pub concentrator: Concentrator,
+    /// Shell command run after the configuration file changed, usually to
+    /// restart the packet forwarder (default: none)
+    pub restart: Option<String>,
+}
+
+/// The concentrator chip a managed packet forwarder drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Concentrator {
+    /// SX1301 with SX1255 or SX1257 radios, configured by `SX1301_conf`
+    Sx1301,
+    /// SX1302 or SX1303 with SX1250 radios, configured by `SX130x_conf`
+    Sx1302,
+}
+
+impl Default for Concentrator {
+    fn default() -> Self {
+        Self::Sx1301
+    }
+}
+
+/// Settings for running and supervising a local packet forwarder.
+#[derive(Debug, Deserialize)]
+pub struct SupervisorSettings {
+    /// Shell command that runs the packet forwarder in the foreground. The
+    /// command runs in a process group of its own, which is killed as a
+    /// whole on a restart. The forwarder is not supervised if not set
+    /// (default: none)
+    pub command: Option<String>,
+    /// Seconds without a PULL_DATA after which the forwarder is restarted
+    /// (default: 60)
+    pub pull_timeout: u64,
+    /// Seconds to wait before restarting the forwarder (default: 5)
+    pub restart_delay: u64,
+}
+
+/// Settings for the encrypted tunnel for packet forwarders on untrusted
+/// networks.
+#[derive(Debug, Deserialize)]
+pub struct TunnelSettings {
+    /// Whether to accept tunneled packet forwarder traffic (default: false)
+    pub enabled: bool,
+    /// The address to listen on for tunneled traffic (default:
+    /// "0.0.0.0:1681")
+    #[serde(deserialize_with = "deserialize_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// The base64 encoded 32 byte pre-shared tunnel key. Generate one with
+    /// `tunnel generate-key`
+    pub key: Option<String>,
+}
+
+/// Settings for forwarding uplinks to a private LoRaWAN network server over
+/// GWMP next to routing them over the Helium network.
+#[derive(Debug, Deserialize)]
+pub struct LnsSettings {
+    /// Whether to forward uplinks to the network server (default: false)
+    pub enabled: bool,
+    /// The "<host>:<port>" of the network server
+    pub upstream: Option<String>,
+    /// DevAddr ranges, as "<hex devaddr>/<prefix bits>", of the uplinks to
+    /// forward (default: [])
+    pub dev_addrs: Vec<String>,
+    /// JoinEUIs, in hex, of the join requests to forward (default: [])
+    pub join_euis: Vec<String>,
+    /// Whether forwarded uplinks are no longer routed over the Helium
+    /// network (default: false)
+    pub exclusive: bool,
+}
+
+/// Settings for the loopback test of the doctor command.
+#[derive(Debug, Deserialize)]
+pub struct SelfTestSettings {
+    /// The frequency (MHz) to send the test frame on. Has to be one of the
+    /// concentrator's uplink channels for the frame to be heard
+    pub frequency: Option<f32>,
+    /// The datarate of the test frame (default: "SF7BW125")
+    pub datarate: String,
+    /// The transmit power in dBm of the test frame (default: 14)
+    pub power: u64,
+    /// Seconds to wait for the test frame to be heard (default: 5)
+    pub timeout: u64,
+}
+
+/// Safety interlocks of the cw and modtest commands, which transmit test
+/// signals for regulatory certification.
+#[derive(Debug, Deserialize)]
+pub struct CertificationSettings {
+    /// Whether test transmissions are allowed at all (default: false)
+    pub enabled: bool,
+    /// The maximum transmit power in dBm of a test transmission (default: 20)
+    pub max_power: u64,
+    /// The maximum duration in seconds of a test transmission (default: 60)
+    pub max_duration: u64,
+}
+
+/// Settings for the hourly and daily traffic aggregates kept in the store.
+#[derive(Debug, Deserialize)]
+pub struct StatsSettings {
+    /// Hours to keep hourly aggregates for (default: 168)
+    pub hourly_retention_hours: u64,
+    /// Days to keep daily aggregates for (default: 90)
+    pub daily_retention_days: u64,
+    /// Days to keep the daily transmit time per band for (default: 400)
+    pub dutycycle_retention_days: u64,
+}
+
+/// Settings for alerts on health problems, sent to a webhook and/or a local
+/// command and shown by the alerts api method. Each alert is sent when it
+/// fires and again when it resolves.
+#[derive(Debug, Deserialize)]
+pub struct AlertSettings {
+    /// Whether to check the alert rules (default: false)
+    pub enabled: bool,
+    /// The url to post alerts to as json (default: none)
+    pub webhook: Option<String>,
+    /// Shell command run for each alert with the json alert on stdin
+    /// (default: none)
+    pub command: Option<String>,
+    /// Seconds between health checks (default: 60)
+    pub check_interval: u64,
+    /// Minutes over which the router and downlink failures are counted
+    /// (default: 15)
+    pub window: u64,
+    /// Minutes without uplinks before alerting, 0 disables (default: 30)
+    pub no_uplinks: u64,
+    /// Whether to alert when all router requests in the window failed
+    /// (default: true)
+    pub router_down: bool,
+    /// Percentage of the store filesystem in use before alerting, 0
+    /// disables (default: 90)
+    pub disk_full: u64,
+    /// Percentage of downlinks in the window the packet forwarder failed to
+    /// send before alerting, 0 disables (default: 20)
+    pub ack_error_rate: u64,
+    /// Standard deviations the uplinks in the window may be off the usual
+    /// uplinks for the hour of the day before alerting, learned from the
+    /// hourly stats. 0 disables (default: 4.0)
+    pub anomaly_sensitivity: f64,
+}
+
+/// Settings for the local api, used by the downlinks command and local
+/// tools to inspect the running gateway.
+#[derive(Debug, Deserialize)]
+pub struct ApiSettings {
+    /// Whether to serve the local api (default: false)
+    pub enabled: bool,
+    /// The address to serve the api on (default: "127.0.0.1:4467")
+    #[serde(deserialize_with = "deserialize_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// A unix socket to serve the api on instead of the listen address
+    /// (default: none)
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    /// The file mode of the api socket (default: 0o660)
+    pub socket_mode: u32,
+    /// Number of recent uplink routing decisions kept for the decisions
+    /// method (default: 256, 0 to keep none)
+    pub decisions: usize,
+    /// The address to also serve the api on over http, for browser
+    /// dashboards and curl (default: none)
+    #[serde(default)]
+    pub http_listen_addr: Option<SocketAddr>,
+    /// Tokens that allow the methods reading gateway state. When no tokens
+    /// are set the api is open to anyone that can reach it (default: [])
+    #[serde(default)]
+    pub read_tokens: Vec<String>,
+    /// Tokens that allow all methods, including those changing the gateway
+    /// (default: [])
+    #[serde(default)]
+    pub control_tokens: Vec<String>,
+}
+
+impl ApiSettings {
+    /// The token local commands send, the first control token or else the
+    /// first read token.
+    pub fn client_token(&self) -> Option<&str> {
+        self.control_tokens
+            .first()
+            .or_else(|| self.read_tokens.first())
+            .map(String::as_str)
+    }
+}
+
+/// Settings for resolving join requests for local devices with a LoRaWAN
+/// Backend Interfaces join server instead of routing them.
+#[derive(Debug, Deserialize)]
+pub struct JoinServerSettings {
+    /// Whether to send join requests to the join server (default: false)
+    pub enabled: bool,
+    /// The JoinReq endpoint of the join server
+    pub uri: Option<String>,
+    /// The endpoint the session of an accepted join is posted to, with the
+    /// DevAddr given to the device and the session keys from the join
+    /// server. The join accept is only sent once the network server took the
+    /// session.
+    pub network_server: Option<String>,
+    /// JoinEUIs, in hex, of the join requests to resolve locally (default: [])
+    pub join_euis: Vec<String>,
+    /// The NetID, in hex, sent as the sender of join requests (default:
+    /// "000000")
+    pub net_id: String,
+    /// The DevAddr range, "<hex devaddr>/<prefix bits>", joining devices get
+    /// their address from (default: "00000000/7")
+    pub dev_addrs: String,
+    /// Seconds to wait for each of the join server and the network server.
+    /// Both have to answer within the five seconds to the first join accept
+    /// window (default: 2)
+    pub timeout: u64,
+    /// Client certificate and key files for join servers that require
+    /// mutual TLS
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Whether to use the first join accept window, at the frequency and
+    /// datarate of the join request. Only fits regions without separate
+    /// downlink channels (default: true)
+    pub rx1: bool,
+    /// Frequency (MHz) and datarate of the second join accept window
+    /// (default: the RX2 window of the region)
+    pub rx2_frequency: Option<f32>,
+    pub rx2_datarate: Option<String>,
+}
+
+/// Settings for relaying GWMP unchanged to a legacy UDP network server
+/// instead of routing it over the Helium network.
+#[derive(Debug, Deserialize)]
+pub struct PassthroughSettings {
+    /// Whether to pass packet forwarder traffic through (default: false)
+    pub enabled: bool,
+    /// The "<host>:<port>" of the network server to relay to
+    pub upstream: Option<String>,
+}
+
+/// Settings for temporarily ignoring packet forwarders that keep sending
+/// frames that can not be parsed.
+#[derive(Debug, Deserialize)]
+pub struct QuarantineSettings {
+    /// Number of unparseable frames within the window after which a forwarder
+    /// is ignored, 0 disables quarantining (default: 20)
+    pub threshold: u32,
+    /// Seconds over which unparseable frames are counted (default: 60)
+    pub window_secs: u64,
+    /// Seconds a forwarder is ignored for (default: 300)
+    pub duration_secs: u64,
+}
+
+/// What makes two uplinks the same uplink when dropping duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum DedupKey {
+    /// A hash of the whole PHYPayload
+    Payload,
+    /// The MHDR, DevAddr, frame counter and FPort of data frames, so a
+    /// retransmission with another MIC is a duplicate too. Other frames,
+    /// like join requests, are keyed by their payload.
+    Frame,
+}
+
+/// The storage backend for gateway state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum StoreBackend {
+    /// Keep state in memory only, nothing survives a restart
+    Memory,
+    /// An embedded sled database, needs the sled feature
+    Sled,
+    /// A sqlite database file, needs the sqlite feature
+    Sqlite,
+}
+
+/// Settings for the gateway state store.
+#[derive(Debug, Deserialize)]
+pub struct StoreSettings {
+    /// Which backend to use (memory, sled or sqlite, default memory)
+    #[serde(deserialize_with = "deserialize_store_backend")]
+    pub backend: StoreBackend,
+    /// The database path for the sled (a directory) and sqlite (a file)
+    /// backends (default: /var/lib/helium_gateway/state)
+    pub path: PathBuf,
+    /// Seconds between saves of the buffered downlinks and recent uplinks, at
+    /// least 1 (default: 30)
+    pub save_interval: u64,
+}
+
+/// What to do with downlinks that reach the packet forwarder too late for
+/// their receive window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TooLatePolicy {
+    /// Log a warning and add the timing to the downlink trace and the
+    /// network server's tx_ack
+    Report,
+    /// Only count them
+    Drop,
+}
+
+/// The time reference downlinks are scheduled by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ClockSource {
+    /// The concentrator's microsecond counter of the uplink
+    Tmst,
+    /// GPS time, derived from the concentrator counter and the GPS time of
+    /// the forwarder's recent uplinks
+    Tmms,
+    /// Send as soon as the forwarder receives the downlink
+    Immediate,
+}
+
+impl std::fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tmst => f.write_str("tmst"),
+            Self::Tmms => f.write_str("tmms"),
+            Self::Immediate => f.write_str("immediate"),
+        }
+    }
+}
+
+/// Settings for downlink delivery.
+#[derive(Debug, Deserialize)]
+pub struct DownlinkSettings {
+    /// How to handle downlinks that were too late (report or drop, default
+    /// report)
+    #[serde(deserialize_with = "deserialize_too_late_policy")]
+    pub too_late: TooLatePolicy,
+    /// The preferred scheduling reference (tmst, tmms or immediate, default
+    /// tmst). tmms falls back to tmst while the forwarder has no GPS lock.
+    #[serde(deserialize_with = "deserialize_clock_source")]
+    pub clock: ClockSource,
+}
+
+/// Settings for the per forwarder uplink queues.
+#[derive(Debug, Deserialize)]
+pub struct UplinkQueueSettings {
+    /// Maximum number of uplinks queued per packet forwarder while waiting to
+    /// be routed. The oldest uplink is dropped when a queue is full (default:
+    /// 16)
+    pub depth: usize,
+}
+
+/// Precision of the uplink metadata passed on to routers and other
+/// consumers. Each value is rounded to a multiple of its step, and a step of
+/// 0 or 1 keeps it as reported.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetadataSettings {
+    /// Frequency step in Hz (default: 1)
+    pub frequency_step_hz: u64,
+    /// Signal strength step in dB (default: 1)
+    pub rssi_step_db: u32,
+    /// Signal to noise ratio step in tenths of a dB (default: 1)
+    pub snr_step_tenths: u32,
+}
+
+/// Settings for ignoring uplinks that were already forwarded.
+#[derive(Debug, Deserialize)]
+pub struct DedupSettings {
+    /// Milliseconds an uplink payload is remembered, 0 disables dropping
+    /// duplicates (default: 2000)
+    pub window_ms: u64,
+    /// What identifies an uplink, payload or frame (default: payload)
+    #[serde(deserialize_with = "deserialize_dedup_key")]
+    pub key: DedupKey,
+    /// Seconds the uplinks seen before a restart are remembered after it, to
+    /// catch uplinks a packet forwarder buffered while the gateway was down
+    /// (default: 60)
+    pub restart_ttl_secs: u64,
+}
+
+/// Thresholds for alarms raised from the health information packet
+/// forwarders report in their `stat` frames.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthSettings {
+    /// Concentrator temperature in degrees Celsius above which an alarm is
+    /// raised (default: 85)
+    pub max_temperature: f64,
+    /// Whether to raise an alarm when a forwarder reports a lost PPS lock
+    /// (default: false)
+    pub pps_alarm: bool,
+}
+
 impl Settings {
     /// Load Settings from a given path. Settings are loaded from a default.toml
     /// file in the given path, followed by merging in an optional settings.toml
-    /// in the same folder.
+    /// in the same folder. The address_book.toml maintained by the
+    /// `address-book` command, an operator provided bootstrap.toml and the
+    /// push.toml of a fleet manager, if present, are merged in last.
     ///
     /// Environemnt overrides have the same name as the entries in the settings
     /// file in uppercase and prefixed with "GW_". For example "GW_KEY" will
     /// override the key file location.
+    ///
+    /// The profile named by the "GW_PROFILE" environment variable, if set, is
+    /// applied, see `with_profile`.
     pub fn new(path: &Path) -> Result<Self> {
+        Self::with_profile(path, None)
+    }
+
+    /// Load Settings like `new` and apply a named profile. Profiles are
+    /// tables under `profile` in the settings files, like `[profile.staging]`,
+    /// holding settings that override the others when the profile is
+    /// selected. Environment overrides still apply on top. Without a profile
+    /// given the "GW_PROFILE" environment variable selects one.
+    pub fn with_profile(path: &Path, profile: Option<&str>) -> Result<Self> {
+        Self::with_push(path, profile, None).map(|(settings, _)| settings)
+    }
+
+    /// Load Settings like `with_profile` with the given fleet manager overlay
+    /// in place of the push.toml in the folder, if any. The merged settings
+    /// are returned as a json tree as well, to compare them with the current
+    /// ones.
+    pub fn with_push(
+        path: &Path,
+        profile: Option<&str>,
+        push: Option<&str>,
+    ) -> Result<(Self, serde_json::Value)> {
+        let profile = profile
+            .map(|profile| profile.to_string())
+            .or_else(|| std::env::var(PROFILE_ENV).ok())
+            .filter(|profile| !profile.is_empty());
         let mut c = Config::new();
         let default_file = path.join("default.toml");
         // Load default config and merge in overrides
         c.merge(File::with_name(default_file.to_str().expect("file name")))?;
-        let settings_file = path.join("settings.toml");
+        let settings_file = path.join(SETTINGS_FILE);
+        let mut migrations = vec![];
         if settings_file.exists() {
-            c.merge(File::with_name(settings_file.to_str().expect("file name")))?;
+            // Merge settings in an older layout as migrated in memory
+            let migration = migration::Migration::load(&settings_file)?;
+            if migration.is_empty() {
+                c.merge(File::with_name(settings_file.to_str().expect("file name")))?;
+            } else {
+                c.merge(File::from_str(&migration.to_toml()?, FileFormat::Toml))?;
+                migrations = migration.changes;
+            }
+        }
+        let address_book_file = path.join(address_book::OVERLAY_FILE);
+        if address_book_file.exists() {
+            c.merge(File::with_name(
+                address_book_file.to_str().expect("file name"),
+            ))?;
+        }
+        let bootstrap_file = path.join(bootstrap::OVERLAY_FILE);
+        if bootstrap_file.exists() {
+            c.merge(File::with_name(bootstrap_file.to_str().expect("file name")))?;
+        }
+        let push_file = path.join(push::OVERLAY_FILE);
+        match push {
+            Some(push) => {
+                c.merge(File::from_str(push, FileFormat::Toml))?;
+            }
+            None if push_file.exists() => {
+                c.merge(File::with_name(push_file.to_str().expect("file name")))?;
+            }
+            None => (),
+        }
+        if let Some(profile) = &profile {
+            let overrides: toml::Value = c
+                .get(&format!("profile.{}", profile))
+                .map_err(|_| Error::custom(format!("unknown settings profile: {}", profile)))?;
+            let overrides = toml::to_string(&overrides).map_err(|err| {
+                Error::custom(format!("unable to apply profile {}: {}", profile, err))
+            })?;
+            c.merge(File::from_str(&overrides, FileFormat::Toml))?;
         }
         // Add in settings from the environment (with a prefix of APP)
         // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
         c.merge(Environment::with_prefix("gw"))?;
-        c.try_into().map_err(|e| e.into())
+        // Fill in the defaults of the backhaul preset
+        let preset = parse_backhaul_preset(&c.get_str("backhaul.preset")?)?;
+        for (key, value) in preset.defaults() {
+            c.set_default(key, *value)?;
+        }
+        let keypair_path = c.get_str("keypair")?;
+        // Loaded ahead of the other settings so a key that can not be loaded
+        // fails as a key error rather than a config error
+        keypair::load_or_generate(&keypair_path)?;
+        let tree: serde_json::Value = c.clone().try_into()?;
+        let mut settings: Settings = c.try_into()?;
+        settings.path = path.to_path_buf();
+        settings.profile = profile;
+        settings.keypair_path = keypair_path;
+        settings.migrations = migrations;
+        settings
+            .features
+            .apply(&mut settings.dedup, &mut settings.peers);
+        settings.address_book.add_settings(
+            &settings.router,
+            &settings.gateways,
+            settings.bootstrap.server.as_ref(),
+        );
+        Ok((settings, tree))
     }
 
-    pub fn default_router(&self) -> &KeyedUri {
-        &self.router[&self.update.channel.to_string()]
+    /// The router for the configured update channel.
+    pub fn default_router(&self) -> Result<KeyedUri> {
+        let channel = self.update.channel.to_string();
+        self.address_book
+            .get(&format!("router.{}", channel))
+            .map(|endpoint| endpoint.keyed_uri())
+            .ok_or_else(|| Error::custom(format!("no router for channel {}", channel)))
+    }
+
+    /// The labels as comma separated name=value pairs, None without labels.
+    pub fn labels_string(&self) -> Option<String> {
+        if self.labels.is_empty() {
+            return None;
+        }
+        let pairs: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        Some(pairs.join(","))
     }
 }
 
@@ -127,26 +1341,9 @@ where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(d)?;
-    match keypair::load_from_file(&s) {
-        Ok(k) => Ok(Arc::new(k)),
-        Err(Error::IO(io_error)) if io_error.kind() == std::io::ErrorKind::NotFound => {
-            let new_key = Keypair::generate(
-                KeyTag {
-                    network: Network::MainNet,
-                    key_type: KeyType::Ed25519,
-                },
-                &mut OsRng,
-            );
-            keypair::save_to_file(&new_key, &s).map_err(|e| {
-                de::Error::custom(format!("unable to save key file \"{}\": {:?}", s, e))
-            })?;
-            Ok(Arc::new(new_key))
-        }
-        Err(err) => Err(de::Error::custom(format!(
-            "unable to load key file \"{}\": {:?}",
-            s, err
-        ))),
-    }
+    keypair::load_or_generate(&s)
+        .map(Arc::new)
+        .map_err(|err| de::Error::custom(err.to_string()))
 }
 
 fn deserialize_listen_addr<'de, D>(d: D) -> std::result::Result<SocketAddr, D::Error>
@@ -158,11 +1355,12 @@ where
         .map_err(|e| de::Error::custom(format!("invalid listen address \"{}\": {}", s, e)))
 }
 
-fn deserialize_region<'de, D>(d: D) -> std::result::Result<Region, D::Error>
+fn deserialize_region<'de, D>(d: D) -> std::result::Result<Option<Region>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let region = match String::deserialize(d)?.as_str() {
+        "auto" => return Ok(None),
         "US915" => Region::Us915,
         "EU868" => Region::Eu868,
         "EU433" => Region::Eu433,
@@ -182,7 +1380,7 @@ where
             )))
         }
     };
-    Ok(region)
+    Ok(Some(region))
 }
 
 fn deserialize_log_level<'de, D>(d: D) -> std::result::Result<slog::Level, D::Error>
@@ -194,6 +1392,23 @@ where
         .map_err(|_| de::Error::custom(format!("invalid log level \"{}\"", s)))
 }
 
+fn deserialize_module_log_levels<'de, D>(
+    d: D,
+) -> std::result::Result<HashMap<String, slog::Level>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, String>::deserialize(d)?
+        .into_iter()
+        .map(|(module, level)| {
+            level
+                .parse()
+                .map(|level| (module, level))
+                .map_err(|_| de::Error::custom(format!("invalid log level \"{}\"", level)))
+        })
+        .collect()
+}
+
 fn deserialize_log_method<'de, D>(d: D) -> std::result::Result<LogMethod, D::Error>
 where
     D: Deserializer<'de>,
@@ -211,6 +1426,196 @@ where
     Ok(method)
 }
 
+fn deserialize_runtime_flavor<'de, D>(d: D) -> std::result::Result<RuntimeFlavor, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let flavor = match String::deserialize(d)?.to_lowercase().as_str() {
+        "current_thread" => RuntimeFlavor::CurrentThread,
+        "multi_thread" => RuntimeFlavor::MultiThread,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported runtime flavor: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(flavor)
+}
+
+fn deserialize_store_backend<'de, D>(d: D) -> std::result::Result<StoreBackend, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let backend = match String::deserialize(d)?.to_lowercase().as_str() {
+        "memory" => StoreBackend::Memory,
+        "sled" => StoreBackend::Sled,
+        "sqlite" => StoreBackend::Sqlite,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported store backend: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(backend)
+}
+
+fn deserialize_dedup_key<'de, D>(d: D) -> std::result::Result<DedupKey, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let key = match String::deserialize(d)?.to_lowercase().as_str() {
+        "payload" => DedupKey::Payload,
+        "frame" => DedupKey::Frame,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported dedup key: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(key)
+}
+
+fn deserialize_concentrator<'de, D>(d: D) -> std::result::Result<Concentrator, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let concentrator = match String::deserialize(d)?.to_lowercase().as_str() {
+        "sx1301" => Concentrator::Sx1301,
+        "sx1302" | "sx1303" => Concentrator::Sx1302,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported concentrator: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(concentrator)
+}
+
+fn deserialize_clock_source<'de, D>(d: D) -> std::result::Result<ClockSource, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let clock = match String::deserialize(d)?.to_lowercase().as_str() {
+        "tmst" => ClockSource::Tmst,
+        "tmms" => ClockSource::Tmms,
+        "immediate" => ClockSource::Immediate,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported clock source: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(clock)
+}
+
+fn deserialize_too_late_policy<'de, D>(d: D) -> std::result::Result<TooLatePolicy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let policy = match String::deserialize(d)?.to_lowercase().as_str() {
+        "report" => TooLatePolicy::Report,
+        "drop" => TooLatePolicy::Drop,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported too late policy: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(policy)
+}
+
+fn parse_backhaul_preset(s: &str) -> Result<BackhaulPreset> {
+    match s.to_lowercase().as_str() {
+        "ethernet" => Ok(BackhaulPreset::Ethernet),
+        "cellular" => Ok(BackhaulPreset::Cellular),
+        "satellite" => Ok(BackhaulPreset::Satellite),
+        unsupported => Err(Error::custom(format!(
+            "unsupported backhaul preset: \"{}\"",
+            unsupported
+        ))),
+    }
+}
+
+fn deserialize_backhaul_preset<'de, D>(d: D) -> std::result::Result<BackhaulPreset, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    parse_backhaul_preset(&s).map_err(|e| de::Error::custom(format!("{:?}", e)))
+}
+
+fn parse_payload_privacy(s: &str) -> Result<PayloadPrivacy> {
+    match s.to_lowercase().as_str() {
+        "keep" => Ok(PayloadPrivacy::Keep),
+        "redact" => Ok(PayloadPrivacy::Redact),
+        "truncate" => Ok(PayloadPrivacy::Truncate),
+        unsupported => Err(Error::custom(format!(
+            "unsupported payload privacy: \"{}\"",
+            unsupported
+        ))),
+    }
+}
+
+fn deserialize_location_privacy<'de, D>(d: D) -> std::result::Result<LocationPrivacy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let privacy = match String::deserialize(d)?.to_lowercase().as_str() {
+        "exact" => LocationPrivacy::Exact,
+        "truncated" => LocationPrivacy::Truncated,
+        "hidden" => LocationPrivacy::Hidden,
+        unsupported => {
+            return Err(de::Error::custom(format!(
+                "unsupported location privacy: \"{}\"",
+                unsupported
+            )))
+        }
+    };
+    Ok(privacy)
+}
+
+fn deserialize_payload_privacy<'de, D>(d: D) -> std::result::Result<PayloadPrivacy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    parse_payload_privacy(&s).map_err(|e| de::Error::custom(format!("{:?}", e)))
+}
+
+fn parse_nat64(s: &str) -> Result<Nat64> {
+    match s.to_lowercase().as_str() {
+        "off" => Ok(Nat64::Off),
+        "auto" => Ok(Nat64::Auto),
+        prefix => prefix
+            .parse()
+            .map(Nat64::Prefix)
+            .map_err(|_| Error::custom(format!("unsupported nat64 setting: \"{}\"", prefix))),
+    }
+}
+
+fn deserialize_nat64<'de, D>(d: D) -> std::result::Result<Nat64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    parse_nat64(&s).map_err(|e| de::Error::custom(format!("{:?}", e)))
+}
+
+fn deserialize_envelope_version<'de, D>(d: D) -> std::result::Result<EnvelopeVersion, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = u8::deserialize(d)?;
+    EnvelopeVersion::from_u8(version)
+        .ok_or_else(|| de::Error::custom(format!("unsupported envelope version: {}", version)))
+}
+
 fn deserialize_update_channel<'de, D>(d: D) -> std::result::Result<releases::Channel, D::Error>
 where
     D: Deserializer<'de>,
@@ -227,7 +1632,7 @@ where
     }
 }
 
-fn deserialize_uri<'de, D>(d: D) -> std::result::Result<Uri, D::Error>
+pub(crate) fn deserialize_uri<'de, D>(d: D) -> std::result::Result<Uri, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -238,16 +1643,125 @@ where
     }
 }
 
-fn deserialize_pubkey<'de, D>(d: D) -> std::result::Result<PublicKey, D::Error>
+pub(crate) fn deserialize_pubkey<'de, D>(d: D) -> std::result::Result<PublicKey, D::Error>
 where
     D: Deserializer<'de>,
 {
     let key_string = String::deserialize(d)?;
-    match key_string.parse() {
+    match parse_pubkey(&key_string) {
         Ok(key) => Ok(key),
         Err(err) => Err(de::Error::custom(format!(
-            "invalid public key: \"{}\"",
-            err
+            "invalid public key: \"{}\": {:?}",
+            key_string, err
         ))),
     }
 }
+
+fn serialize_display<T, S>(value: &T, s: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: fmt::Display,
+    S: Serializer,
+{
+    s.collect_str(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network};
+    use rand::rngs::OsRng;
+    use std::collections::HashSet;
+
+    fn keyed_uri(public_key: &str) -> serde_json::Result<KeyedUri> {
+        serde_json::from_value(serde_json::json!({
+            "uri": "http://127.0.0.1:8080",
+            "public_key": public_key,
+        }))
+    }
+
+    fn generate(key_type: KeyType) -> PublicKey {
+        let tag = KeyTag {
+            network: Network::MainNet,
+            key_type,
+        };
+        Keypair::generate(tag, &mut OsRng).public_key().clone()
+    }
+
+    #[test]
+    fn key_types() {
+        let ed25519 = generate(KeyType::Ed25519);
+        let ecc_compact = generate(KeyType::EccCompact);
+        assert_eq!(Some("ed25519"), key_type_name(&ed25519));
+        assert_eq!(Some("ecc_compact"), key_type_name(&ecc_compact));
+        assert!(keyed_uri(&format!("ed25519:{}", ed25519)).is_ok());
+        assert!(keyed_uri(&format!("ecc_compact:{}", ecc_compact)).is_ok());
+        assert!(keyed_uri(&format!("ecc_compact:{}", ed25519)).is_err());
+        assert!(keyed_uri(&format!("secp256k1:{}", ed25519)).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for key_type in [KeyType::Ed25519, KeyType::EccCompact] {
+            let public_key = generate(key_type).to_string();
+            let tagged = keyed_uri(&public_key).expect("keyed uri");
+            let json = serde_json::to_value(&tagged).expect("json");
+            assert_eq!(public_key, json["public_key"]);
+            assert_eq!(tagged, serde_json::from_value(json).expect("keyed uri"));
+        }
+    }
+
+    #[test]
+    fn equality() {
+        let public_key = generate(KeyType::Ed25519).to_string();
+        let plain = keyed_uri(&public_key).expect("keyed uri");
+        let tagged = keyed_uri(&format!("ed25519:{}", public_key)).expect("keyed uri");
+        let other = keyed_uri(&generate(KeyType::Ed25519).to_string()).expect("keyed uri");
+        assert_eq!(plain, tagged);
+        assert_ne!(plain, other);
+        let set: HashSet<KeyedUri> = vec![plain, tagged, other].into_iter().collect();
+        assert_eq!(2, set.len());
+    }
+
+    #[test]
+    fn features() {
+        let mut dedup = DedupSettings {
+            window_ms: 2_000,
+            key: DedupKey::Payload,
+            restart_ttl_secs: 60,
+        };
+        let mut peers = PeerSettings {
+            enabled: true,
+            port: 1690,
+            broadcast: Ipv4Addr::BROADCAST,
+            interval: 30,
+            keys: vec![],
+            dedup: false,
+            dedup_hold_ms: 200,
+        };
+        let features = Features(
+            vec![("other".to_string(), true)]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+        );
+        assert!(!features.enabled(FRAME_DEDUP_FEATURE));
+        features.apply(&mut dedup, &mut peers);
+        assert_eq!(DedupKey::Payload, dedup.key);
+        assert!(!peers.dedup);
+
+        let features = Features(
+            vec![
+                (FRAME_DEDUP_FEATURE.to_string(), true),
+                (COOPERATIVE_DEDUP_FEATURE.to_string(), true),
+            ]
+            .into_iter()
+            .collect::<HashMap<_, _>>(),
+        );
+        assert_eq!(
+            vec![COOPERATIVE_DEDUP_FEATURE, FRAME_DEDUP_FEATURE],
+            features.list()
+        );
+        features.apply(&mut dedup, &mut peers);
+        assert_eq!(DedupKey::Frame, dedup.key);
+        assert!(peers.dedup);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::*;
+use slog::{info, o, Drain, Key, Level, Logger, OwnedKVList, Record, Serializer, KV};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// A cheaply cloneable switch to turn debug logging on and off at runtime.
+#[derive(Debug, Clone, Default)]
+pub struct LogSwitch(Arc<AtomicBool>);
+
+impl LogSwitch {
+    /// Toggles debug logging, returning whether it is now enabled.
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A level filter that logs at debug level or lower while the switch is on.
+/// Records of a module with its own level, by the "module" key of the
+/// logger, are filtered at that level instead of the global one.
+#[derive(Debug)]
+pub struct SwitchedLevel<D> {
+    drain: D,
+    level: Level,
+    modules: HashMap<String, Level>,
+    switch: LogSwitch,
+}
+
+impl<D> SwitchedLevel<D> {
+    pub fn new(drain: D, level: Level, modules: HashMap<String, Level>, switch: LogSwitch) -> Self {
+        Self {
+            drain,
+            level,
+            modules,
+            switch,
+        }
+    }
+
+    fn module_level(&self, record: &Record, values: &OwnedKVList) -> Level {
+        if self.modules.is_empty() {
+            return self.level;
+        }
+        let mut module = ModuleKey(None);
+        let _ = values.serialize(record, &mut module);
+        module
+            .0
+            .and_then(|module| self.modules.get(&module).copied())
+            .unwrap_or(self.level)
+    }
+}
+
+/// Picks the "module" value out of a logger's key values.
+struct ModuleKey(Option<String>);
+
+impl Serializer for ModuleKey {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        if key == "module" {
+            self.0 = Some(value.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl<D: Drain> Drain for SwitchedLevel<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &Record,
+        values: &OwnedKVList,
+    ) -> std::result::Result<Self::Ok, Self::Err> {
+        let level = self.module_level(record, values);
+        let level = if self.switch.is_debug() && level.as_usize() < Level::Debug.as_usize() {
+            Level::Debug
+        } else {
+            level
+        };
+        if record.level().is_at_least(level) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Creates a trigger to request state snapshots and a receiver that modules
+/// can watch to log their state when requested.
+pub fn snapshots() -> (watch::Sender<()>, watch::Receiver<()>) {
+    watch::channel(())
+}
+
+/// Waits for a signal to shut down, `SIGINT` or `SIGTERM`, and returns its
+/// name.
+pub async fn shutdown_signal() -> &'static str {
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        },
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+            "SIGINT"
+        }
+    }
+}
+
+/// Handles runtime control signals:
+///
+/// * `SIGUSR1` - log a snapshot of the gateway and router state
+/// * `SIGUSR2` - toggle debug logging
+#[derive(Debug)]
+pub struct Signals {
+    switch: LogSwitch,
+    snapshots: watch::Sender<()>,
+}
+
+impl Signals {
+    pub fn new(switch: LogSwitch, snapshots: watch::Sender<()>) -> Self {
+        Self { switch, snapshots }
+    }
+
+    pub async fn run(&self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "signals"));
+        info!(logger, "starting");
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        let mut usr2 = signal(SignalKind::user_defined2())?;
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = usr1.recv() => {
+                    info!(logger, "state snapshot requested");
+                    // Receivers only go away on shutdown
+                    let _ = self.snapshots.send(());
+                },
+                _ = usr2.recv() => {
+                    let debug = self.switch.toggle();
+                    info!(logger, "debug logging {}", if debug { "enabled" } else { "disabled" });
+                },
+            }
+        }
+    }
+}
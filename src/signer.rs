@@ -0,0 +1,413 @@
+use crate::*;
+use helium_crypto::Sign;
+use serde_json::{json, Value};
+use settings::SigningSettings;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot, watch,
+    },
+    task,
+};
+
+/// Maximum number of queued signing requests per priority.
+pub const SIGNING_QUEUE_SIZE: usize = 32;
+/// Maximum number of requests signed in one batch.
+pub const SIGNING_BATCH_SIZE: usize = 8;
+/// The window signing rate limits apply to.
+pub const SIGNING_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// What the gateway key signs. Witness reports and state channel sessions are
+/// not signed by this gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Purpose {
+    /// Uplink envelopes sent to routers
+    Uplink,
+    /// Messages to the gateways of the site on the LAN
+    Peer,
+    /// Heartbeats to the other gateways of the cluster
+    Heartbeat,
+    /// Entries of the settings audit log
+    Audit,
+    /// Requests to the bootstrap service
+    Request,
+    /// Registrations of the next key of a rotation
+    Rotation,
+    /// Onboarding transactions and key checks of the commands
+    Command,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 7] = [
+        Purpose::Uplink,
+        Purpose::Peer,
+        Purpose::Heartbeat,
+        Purpose::Audit,
+        Purpose::Request,
+        Purpose::Rotation,
+        Purpose::Command,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Uplink => "uplink",
+            Self::Peer => "peer",
+            Self::Heartbeat => "heartbeat",
+            Self::Audit => "audit",
+            Self::Request => "request",
+            Self::Rotation => "rotation",
+            Self::Command => "command",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    signed: u64,
+    refused: u64,
+    window_start: Option<Instant>,
+    window_count: u32,
+    window_refused: u32,
+}
+
+/// A cheaply cloneable handle that counts the signatures made with the
+/// gateway key per purpose. A purpose that uses up its signatures for the
+/// current minute is refused more until the minute is over, so a runaway
+/// signing loop can not wear out the monotonic counters of a secure element.
+/// The first refusal of a purpose in a minute is logged.
+///
+/// The keypair is swapped for all handles at once when a key rotation
+/// completes, and the services that keep the public key follow the swaps.
+#[derive(Debug, Clone)]
+pub struct Signatures {
+    keypair: watch::Receiver<Arc<Keypair>>,
+    rotate: Arc<watch::Sender<Arc<Keypair>>>,
+    limits: Arc<HashMap<Purpose, u32>>,
+    usage: Arc<Mutex<HashMap<Purpose, Usage>>>,
+    logger: Option<Logger>,
+}
+
+impl Signatures {
+    pub fn new(settings: &Settings) -> Self {
+        Self::with_limits(settings.keypair.clone(), &settings.signing)
+    }
+
+    pub fn with_limits(keypair: Arc<Keypair>, signing: &SigningSettings) -> Self {
+        let SigningSettings {
+            uplink_per_minute,
+            peer_per_minute,
+        } = *signing;
+        let limits = vec![
+            (Purpose::Uplink, uplink_per_minute),
+            (Purpose::Peer, peer_per_minute),
+        ];
+        let (rotate, keypair) = watch::channel(keypair);
+        Self {
+            keypair,
+            rotate: Arc::new(rotate),
+            limits: Arc::new(limits.into_iter().collect()),
+            usage: Arc::default(),
+            logger: None,
+        }
+    }
+
+    /// Logs refused signatures to the given logger.
+    pub fn with_logger(mut self, logger: &Logger) -> Self {
+        self.logger = Some(logger.new(o!("module" => "signer")));
+        self
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.borrow().public_key().clone()
+    }
+
+    /// The current gateway keypair.
+    pub fn keypair(&self) -> Arc<Keypair> {
+        self.keypair.borrow().clone()
+    }
+
+    /// Swaps the gateway keypair for the next keypair of a completed
+    /// rotation.
+    pub fn rotate(&self, keypair: Arc<Keypair>) {
+        // Never fails, this handle holds a receiver
+        let _ = self.rotate.send(keypair);
+    }
+
+    /// Follows the swaps of the gateway keypair. The receiver sees the
+    /// swaps made after it was taken.
+    pub fn rotations(&self) -> watch::Receiver<Arc<Keypair>> {
+        self.keypair.clone()
+    }
+
+    /// Signs the given data with the gateway key for the given purpose,
+    /// counting the signature.
+    pub fn sign(&self, purpose: Purpose, data: &[u8]) -> Result<Vec<u8>> {
+        self.allow(purpose)?;
+        Ok(self.keypair().sign(data)?)
+    }
+
+    /// Counts a signature for the given purpose, or fails when the purpose
+    /// reached its limit for the current minute. For signatures made
+    /// elsewhere with the gateway key.
+    pub fn allow(&self, purpose: Purpose) -> Result {
+        let limit = self.limits.get(&purpose).copied().unwrap_or(0);
+        let mut usage = self.usage.lock().expect("signatures");
+        let usage = usage.entry(purpose).or_default();
+        let now = Instant::now();
+        match usage.window_start {
+            Some(start) if now.duration_since(start) < SIGNING_RATE_WINDOW => (),
+            _ => {
+                usage.window_start = Some(now);
+                usage.window_count = 0;
+                usage.window_refused = 0;
+            }
+        }
+        if limit > 0 && usage.window_count >= limit {
+            usage.refused += 1;
+            usage.window_refused += 1;
+            if let (Some(logger), 1) = (&self.logger, usage.window_refused) {
+                warn!(logger, "refusing signatures until the minute is over";
+                    "purpose" => purpose.name(),
+                    "limit_per_minute" => limit);
+            }
+            return Err(Error::custom(format!(
+                "{} signing limit of {} per minute reached",
+                purpose.name(),
+                limit
+            )));
+        }
+        usage.window_count += 1;
+        usage.signed += 1;
+        Ok(())
+    }
+
+    /// The signatures made and refused per purpose since the gateway
+    /// started, and in the current minute.
+    pub fn to_json(&self) -> Value {
+        let usage = self.usage.lock().expect("signatures");
+        let purposes: serde_json::Map<String, Value> = Purpose::ALL
+            .iter()
+            .map(|purpose| {
+                let (signed, refused, current_minute) =
+                    usage.get(purpose).map_or((0, 0, 0), |usage| {
+                        (usage.signed, usage.refused, usage.window_count)
+                    });
+                let value = json!({
+                    "signed": signed,
+                    "refused": refused,
+                    "current_minute": current_minute,
+                    "limit_per_minute": self.limits.get(purpose).copied().unwrap_or(0),
+                });
+                (purpose.name().to_string(), value)
+            })
+            .collect();
+        json!({
+            "backend": "file",
+            "key_type": settings::key_type_name(&self.public_key()),
+            "purposes": purposes,
+        })
+    }
+}
+
+/// The priority of a signing request. Downlink critical requests are for
+/// packets that are likely to be answered in a receive window and are always
+/// signed before any other queued requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    DownlinkCritical,
+}
+
+#[derive(Debug)]
+struct Request {
+    data: Vec<u8>,
+    response: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+/// Creates a signer for the keypair of the given signatures along with the
+/// service that performs the actual signing.
+pub fn signer(signatures: Signatures) -> (Signer, SigningService) {
+    let (critical_sender, critical_receiver) = mpsc::channel(SIGNING_QUEUE_SIZE);
+    let (normal_sender, normal_receiver) = mpsc::channel(SIGNING_QUEUE_SIZE);
+    (
+        Signer {
+            signatures: signatures.clone(),
+            critical: critical_sender,
+            normal: normal_sender,
+        },
+        SigningService {
+            signatures,
+            critical: critical_receiver,
+            normal: normal_receiver,
+        },
+    )
+}
+
+/// A cheaply cloneable handle to queue signing requests.
+#[derive(Debug, Clone)]
+pub struct Signer {
+    signatures: Signatures,
+    critical: Sender<Request>,
+    normal: Sender<Request>,
+}
+
+impl Signer {
+    pub fn public_key(&self) -> PublicKey {
+        self.signatures.public_key()
+    }
+
+    /// Follows the swaps of the gateway keypair.
+    pub fn rotations(&self) -> watch::Receiver<Arc<Keypair>> {
+        self.signatures.rotations()
+    }
+
+    /// Queues the given uplink envelope for signing and waits for the
+    /// signature.
+    pub async fn sign(&self, data: Vec<u8>, priority: Priority) -> Result<Vec<u8>> {
+        self.signatures.allow(Purpose::Uplink)?;
+        let (response, signature) = oneshot::channel();
+        let queue = match priority {
+            Priority::DownlinkCritical => &self.critical,
+            Priority::Normal => &self.normal,
+        };
+        queue
+            .send(Request { data, response })
+            .await
+            .map_err(|_| Error::custom("signing service closed"))?;
+        signature
+            .await
+            .map_err(|_| Error::custom("signing service closed"))?
+    }
+}
+
+/// Signs queued requests with the gateway keypair. Signing with a secure
+/// element can take tens of milliseconds, so signing happens off the async
+/// runtime and queued requests are signed in batches to limit the number of
+/// handoffs. Downlink critical requests are drained before normal ones.
+#[derive(Debug)]
+pub struct SigningService {
+    signatures: Signatures,
+    critical: Receiver<Request>,
+    normal: Receiver<Request>,
+}
+
+impl SigningService {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "signer"));
+        info!(logger, "starting");
+        loop {
+            let first = tokio::select! {
+                biased;
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                Some(request) = self.critical.recv() => request,
+                Some(request) = self.normal.recv() => request,
+            };
+            let batch = self.batch(first);
+            self.sign_batch(&logger, batch).await?;
+        }
+    }
+
+    /// Gathers a batch of requests starting with the given one, taking
+    /// critical requests before normal ones.
+    fn batch(&mut self, first: Request) -> Vec<Request> {
+        let mut batch = vec![first];
+        while batch.len() < SIGNING_BATCH_SIZE {
+            match self.critical.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+        while batch.len() < SIGNING_BATCH_SIZE {
+            match self.normal.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+        batch
+    }
+
+    async fn sign_batch(&self, logger: &Logger, batch: Vec<Request>) -> Result {
+        // A batch is signed with the keypair current when it was taken
+        let keypair = self.signatures.keypair();
+        let start = Instant::now();
+        let count = batch.len();
+        let signed = task::spawn_blocking(move || {
+            batch
+                .into_iter()
+                .map(|request| {
+                    let signature = keypair.sign(&request.data).map_err(Error::from);
+                    (request.response, signature)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|err| Error::custom(format!("signing task failed: {:?}", err)))?;
+        debug!(logger, "signed batch";
+            "count" => count,
+            "elapsed_ms" => start.elapsed().as_millis() as u64);
+        for (response, signature) in signed {
+            // The requester may have given up waiting
+            let _ = response.send(signature);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network, Verify};
+    use rand::rngs::OsRng;
+
+    fn signatures(uplink_per_minute: u32) -> Signatures {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        Signatures::with_limits(
+            Arc::new(keypair),
+            &SigningSettings {
+                uplink_per_minute,
+                peer_per_minute: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn limits() {
+        let signatures = signatures(2);
+        assert!(signatures.allow(Purpose::Uplink).is_ok());
+        assert!(signatures.clone().allow(Purpose::Uplink).is_ok());
+        assert!(signatures.allow(Purpose::Uplink).is_err());
+        for _ in 0..10 {
+            assert!(signatures.allow(Purpose::Audit).is_ok());
+        }
+        let json = signatures.to_json();
+        assert_eq!(2, json["purposes"]["uplink"]["signed"]);
+        assert_eq!(1, json["purposes"]["uplink"]["refused"]);
+        assert_eq!(2, json["purposes"]["uplink"]["limit_per_minute"]);
+        assert_eq!(10, json["purposes"]["audit"]["signed"]);
+        assert_eq!(0, json["purposes"]["audit"]["limit_per_minute"]);
+        assert_eq!(0, json["purposes"]["peer"]["signed"]);
+    }
+
+    #[test]
+    fn sign() {
+        let signatures = signatures(0);
+        let signature = signatures.sign(Purpose::Request, b"data").expect("sign");
+        assert!(signatures.public_key().verify(b"data", &signature).is_ok());
+        assert_eq!(1, signatures.to_json()["purposes"]["request"]["signed"]);
+    }
+}
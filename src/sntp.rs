@@ -0,0 +1,286 @@
+use crate::*;
+use settings::SntpSettings;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicI64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::{self, UdpSocket},
+    time,
+};
+
+/// Unix time of 2021-01-01. A system clock before this was never set, as on
+/// boards without an RTC before NTP has synced.
+pub const MIN_SANE_TIME: u64 = 1_609_459_200;
+/// Seconds between a failed time query and the next attempt.
+pub const SNTP_RETRY_SECS: u64 = 60;
+const NTP_PORT: u16 = 123;
+/// Seconds from the NTP epoch (1900) to the unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const PACKET_LEN: usize = 48;
+
+/// The offset in milliseconds from the system clock to the time learned over
+/// SNTP, 0 while the system clock is trusted.
+static OFFSET_MS: AtomicI64 = AtomicI64::new(0);
+
+/// The current time: the system time, corrected by the SNTP offset while
+/// the system clock was found to be unset.
+pub fn now() -> SystemTime {
+    let system = SystemTime::now();
+    let offset = OFFSET_MS.load(Ordering::Relaxed);
+    if offset >= 0 {
+        system + Duration::from_millis(offset as u64)
+    } else {
+        system - Duration::from_millis(offset.unsigned_abs())
+    }
+}
+
+/// Whether the given time is plausibly a set clock.
+pub fn is_sane(time: SystemTime) -> bool {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(false, |since| since.as_secs() >= MIN_SANE_TIME)
+}
+
+/// Asks an SNTP server for the time. Returns the offset of the server clock
+/// from the system clock in milliseconds, corrected for half the round trip.
+pub async fn query(server: &str, timeout: Duration) -> Result<i64> {
+    let (host, port) = host_port(server)?;
+    let addr = net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| Error::custom(format!("no address for {}", server)))?;
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    let mut request = [0u8; PACKET_LEN];
+    // No leap indicator, version 4, client mode
+    request[0] = 0x23;
+    let sent = SystemTime::now();
+    request[40..48].copy_from_slice(&to_ntp(sent).to_be_bytes());
+    socket.send(&request).await?;
+    let mut response = [0u8; PACKET_LEN];
+    let received = time::timeout(timeout, socket.recv(&mut response))
+        .await
+        .map_err(|_| Error::custom(format!("no answer from {}", server)))??;
+    let received_at = SystemTime::now();
+    parse_offset(&response[..received], sent, received_at)
+        .ok_or_else(|| Error::custom(format!("invalid answer from {}", server)))
+}
+
+/// Splits a server into its host and port, as host, host:port, an IPv6
+/// address or a bracketed IPv6 address with an optional port.
+fn host_port(server: &str) -> Result<(&str, u16)> {
+    let invalid = || Error::custom(format!("invalid sntp server: {}", server));
+    let (host, port) = match server.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+            None => return Err(invalid()),
+        },
+        // More than one colon is an IPv6 address without a port
+        None if server.matches(':').count() > 1 => (server, None),
+        None => match server.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (server, None),
+        },
+    };
+    match port {
+        Some(port) => Ok((host, port.parse().map_err(|_| invalid())?)),
+        None => Ok((host, NTP_PORT)),
+    }
+}
+
+/// Computes the clock offset from an SNTP response (RFC 4330). The response
+/// has to echo the transmit time of the request as its originate time, so
+/// a stray or spoofed datagram is not taken for the answer.
+fn parse_offset(response: &[u8], sent: SystemTime, received: SystemTime) -> Option<i64> {
+    if response.len() < PACKET_LEN {
+        return None;
+    }
+    let mode = response[0] & 0x07;
+    let stratum = response[1];
+    // A server answers in server mode and a stratum of 0 is a kiss of death
+    if mode != 4 || stratum == 0 {
+        return None;
+    }
+    let timestamp = |at: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&response[at..at + 8]);
+        u64::from_be_bytes(bytes)
+    };
+    if timestamp(24) != to_ntp(sent) {
+        return None;
+    }
+    let (server_received, server_sent) = (timestamp(32), timestamp(40));
+    if server_sent == 0 {
+        return None;
+    }
+    let millis = |ntp: u64| from_ntp(ntp) as i64;
+    let (t1, t2, t3, t4) = (
+        millis(to_ntp(sent)),
+        millis(server_received),
+        millis(server_sent),
+        millis(to_ntp(received)),
+    );
+    Some(((t2 - t1) + (t3 - t4)) / 2)
+}
+
+/// Converts a time to a 64 bit NTP timestamp, seconds since 1900 and a 32 bit
+/// fraction.
+fn to_ntp(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = (u64::from(since.subsec_nanos()) << 32) / 1_000_000_000;
+    (secs << 32) | fraction
+}
+
+/// Converts a 64 bit NTP timestamp to unix milliseconds.
+fn from_ntp(ntp: u64) -> u64 {
+    let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let millis = ((ntp & 0xffff_ffff) * 1000 + (1 << 31)) >> 32;
+    secs * 1000 + millis
+}
+
+/// Keeps the time offset up to date while the system clock is unset, for
+/// boards without an RTC or an NTP daemon. The system clock itself is left
+/// alone since setting it needs privileges the gateway does not have.
+#[derive(Debug)]
+pub struct SntpService {
+    servers: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl SntpService {
+    pub fn new(settings: &Settings) -> Option<Self> {
+        let sntp: &SntpSettings = &settings.sntp;
+        if !sntp.enabled || sntp.servers.is_empty() {
+            return None;
+        }
+        Some(Self {
+            servers: sntp.servers.clone(),
+            interval: Duration::from_secs(sntp.interval.max(SNTP_RETRY_SECS)),
+            timeout: Duration::from_secs(settings.backhaul.timeout),
+        })
+    }
+
+    pub async fn run(&self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "sntp"));
+        info!(logger, "starting");
+        loop {
+            let wait = self.sync(&logger).await;
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = time::sleep(wait) => (),
+            }
+        }
+    }
+
+    /// Updates the offset if the system clock is unset, and returns how long
+    /// to wait for the next check.
+    pub async fn sync(&self, logger: &Logger) -> Duration {
+        if is_sane(SystemTime::now()) {
+            if OFFSET_MS.swap(0, Ordering::Relaxed) != 0 {
+                info!(logger, "system clock is set, dropping the sntp time offset");
+            }
+            return self.interval;
+        }
+        for server in &self.servers {
+            match query(server, self.timeout).await {
+                Ok(offset) => {
+                    let previous = OFFSET_MS.swap(offset, Ordering::Relaxed);
+                    if previous == 0 {
+                        warn!(logger, "system clock is not set, using the time from {}", server;
+                            "offset_ms" => offset);
+                    } else {
+                        debug!(logger, "time offset updated from {}", server;
+                            "offset_ms" => offset,
+                            "step_ms" => offset - previous);
+                    }
+                    return self.interval;
+                }
+                Err(err) => debug!(logger, "time query failed: {:?}", err; "server" => server),
+            }
+        }
+        warn!(
+            logger,
+            "system clock is not set and no sntp server answered"
+        );
+        Duration::from_secs(SNTP_RETRY_SECS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset() {
+        let sent = UNIX_EPOCH + Duration::from_secs(10);
+        let received = sent + Duration::from_millis(100);
+        // The server clock is a day ahead and answered halfway the round trip
+        let server = sent + Duration::from_secs(86_400) + Duration::from_millis(50);
+        let mut response = [0u8; PACKET_LEN];
+        response[0] = 0x24;
+        response[1] = 2;
+        response[24..32].copy_from_slice(&to_ntp(sent).to_be_bytes());
+        response[32..40].copy_from_slice(&to_ntp(server).to_be_bytes());
+        response[40..48].copy_from_slice(&to_ntp(server).to_be_bytes());
+        assert_eq!(Some(86_400_000), parse_offset(&response, sent, received));
+        // An answer to another request
+        let other = sent + Duration::from_millis(1);
+        assert_eq!(None, parse_offset(&response, other, received));
+        // Kiss of death
+        response[1] = 0;
+        assert_eq!(None, parse_offset(&response, sent, received));
+        assert!(!is_sane(sent));
+    }
+
+    #[test]
+    fn servers() {
+        assert_eq!(("pool.ntp.org", 123), host_port("pool.ntp.org").unwrap());
+        assert_eq!(("10.0.0.1", 1123), host_port("10.0.0.1:1123").unwrap());
+        assert_eq!(("2001:db8::1", 123), host_port("2001:db8::1").unwrap());
+        assert_eq!(("2001:db8::1", 123), host_port("[2001:db8::1]").unwrap());
+        assert_eq!(
+            ("2001:db8::1", 1123),
+            host_port("[2001:db8::1]:1123").unwrap()
+        );
+        assert!(host_port("[2001:db8::1").is_err());
+        assert!(host_port("pool.ntp.org:ntp").is_err());
+    }
+
+    #[tokio::test]
+    async fn ipv6() {
+        let server = match UdpSocket::bind("[::1]:0").await {
+            Ok(server) => server,
+            // No IPv6 loopback on this host
+            Err(_) => return,
+        };
+        let addr = server.local_addr().expect("addr");
+        tokio::spawn(async move {
+            let mut request = [0u8; PACKET_LEN];
+            let (_, from) = server.recv_from(&mut request).await.expect("recv");
+            let mut response = [0u8; PACKET_LEN];
+            response[0] = 0x24;
+            response[1] = 2;
+            response[24..32].copy_from_slice(&request[40..48]);
+            let now = to_ntp(SystemTime::now()).to_be_bytes();
+            response[32..40].copy_from_slice(&now);
+            response[40..48].copy_from_slice(&now);
+            server.send_to(&response, from).await.expect("send");
+        });
+        let offset = query(&format!("[::1]:{}", addr.port()), Duration::from_secs(1))
+            .await
+            .expect("query");
+        assert!(offset.abs() < 1_000);
+    }
+}
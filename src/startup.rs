@@ -0,0 +1,161 @@
+use crate::*;
+use slog::{info, o, warn, Logger};
+use sntp::SntpService;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time};
+
+/// Seconds between checks of a startup condition that is not met yet.
+pub const STARTUP_POLL_SECS: u64 = 5;
+/// The semtech udp protocol identifiers of PULL_DATA and its acknowledgement.
+const PULL_DATA: u8 = 0x02;
+const PULL_ACK: u8 = 0x04;
+
+/// Waits for the conditions of the startup settings before the server
+/// starts, each for up to the timeout: the clock to be set, a route to the
+/// router and a PULL_DATA from a packet forwarder. A condition that times
+/// out is logged and the server starts anyway, so a missing dependency only
+/// delays it. Returns false when shut down while waiting.
+pub async fn wait(
+    settings: &Settings,
+    shutdown: &triggered::Listener,
+    logger: &Logger,
+) -> Result<bool> {
+    let logger = logger.new(o!("module" => "startup"));
+    let startup = &settings.startup;
+    let timeout = Duration::from_secs(startup.timeout);
+    if startup.wait_time
+        && !wait_for(
+            &logger,
+            shutdown,
+            "clock",
+            timeout,
+            clock(settings, &logger),
+        )
+        .await?
+    {
+        return Ok(false);
+    }
+    if startup.wait_route {
+        match settings.default_router() {
+            Ok(router) => {
+                if !wait_for(
+                    &logger,
+                    shutdown,
+                    "router route",
+                    timeout,
+                    route(settings, &router.uri),
+                )
+                .await?
+                {
+                    return Ok(false);
+                }
+            }
+            Err(err) => warn!(logger, "not waiting for a router route: {:?}", err),
+        }
+    }
+    if startup.wait_forwarder {
+        if settings.supervisor.command.is_some() {
+            // The supervisor starts the forwarder along with the server
+            info!(logger, "not waiting for the supervised packet forwarder");
+        } else if !wait_for(
+            &logger,
+            shutdown,
+            "packet forwarder",
+            timeout,
+            pull_data(settings.listen_addr),
+        )
+        .await?
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Waits for a condition up to the timeout. Returns false when shut down.
+async fn wait_for<F>(
+    logger: &Logger,
+    shutdown: &triggered::Listener,
+    name: &str,
+    timeout: Duration,
+    ready: F,
+) -> Result<bool>
+where
+    F: Future<Output = Result>,
+{
+    info!(logger, "waiting for {}", name; "timeout_secs" => timeout.as_secs());
+    let started = Instant::now();
+    tokio::select! {
+        _ = shutdown.clone() => Ok(false),
+        ready = time::timeout(timeout, ready) => {
+            match ready {
+                Ok(ready) => {
+                    ready?;
+                    info!(logger, "{} ready", name; "wait_secs" => started.elapsed().as_secs());
+                }
+                Err(_) => warn!(logger, "starting without {}, not ready after {}s", name, timeout.as_secs()),
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Ready once the system clock is set or SNTP gave the time.
+async fn clock(settings: &Settings, logger: &Logger) -> Result {
+    let sntp = SntpService::new(settings);
+    while !sntp::is_sane(sntp::now()) {
+        if let Some(sntp) = &sntp {
+            sntp.sync(logger).await;
+            if sntp::is_sane(sntp::now()) {
+                break;
+            }
+        }
+        time::sleep(Duration::from_secs(STARTUP_POLL_SECS)).await;
+    }
+    Ok(())
+}
+
+/// Ready once a tcp connection to the router gets through.
+async fn route(settings: &Settings, uri: &http::Uri) -> Result {
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']');
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("http") {
+            80
+        } else {
+            443
+        });
+    let timeout = Duration::from_secs(settings.backhaul.timeout);
+    loop {
+        if let Ok(addrs) = resolve::resolve(host, port, &settings.backhaul).await {
+            let attempts = resolve::attempts(&addrs, timeout).await;
+            if attempts.iter().any(|attempt| attempt.result.is_ok()) {
+                return Ok(());
+            }
+        }
+        time::sleep(Duration::from_secs(STARTUP_POLL_SECS)).await;
+    }
+}
+
+/// Ready once a packet forwarder sends a PULL_DATA to the listen address,
+/// which is acknowledged so the forwarder does not count it as lost. The
+/// socket is closed again for the server to bind.
+async fn pull_data(listen_addr: std::net::SocketAddr) -> Result {
+    let socket = UdpSocket::bind(listen_addr).await?;
+    let mut buf = [0u8; 64];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        if len >= 12 && buf[3] == PULL_DATA {
+            socket
+                .send_to(&[buf[0], buf[1], buf[2], PULL_ACK], addr)
+                .await?;
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use settings::StatsSettings;
+use slog::{info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+use store::Store;
+use tokio::time;
+
+const HOURLY_TREE: &str = "stats_hourly";
+const DAILY_TREE: &str = "stats_daily";
+const OUTCOMES_HOURLY_TREE: &str = "downlink_outcomes_hourly";
+const OUTCOMES_DAILY_TREE: &str = "downlink_outcomes_daily";
+const HOUR_SECS: u64 = 3600;
+const DAY_SECS: u64 = 86_400;
+/// Queries reaching back further than this are answered with daily
+/// aggregates.
+const MAX_HOURLY_QUERY_SECS: u64 = 2 * DAY_SECS;
+/// Seconds between merging the current counters into the stored aggregates.
+pub const STATS_FLUSH_INTERVAL_SECS: u64 = 60;
+/// Devices with downlink outcomes shown when the query gives no limit.
+pub const DEFAULT_DEVICE_LIMIT: usize = 20;
+
+/// Traffic counters over a period starting at `start` (unix seconds).
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Aggregate {
+    pub start: u64,
+    pub uplinks: u64,
+    pub downlinks: u64,
+    pub downlink_failures: u64,
+    pub router_requests: u64,
+    pub router_failures: u64,
+    /// Sum and maximum of the router round trip times in milliseconds
+    pub router_latency_ms: u64,
+    pub router_latency_max_ms: u64,
+    /// Router requests cancelled since their answer would have missed the
+    /// receive windows of the uplink
+    #[serde(default)]
+    pub router_cancelled: u64,
+    /// Uplinks of a packet forwarder that could not be read or converted
+    #[serde(default)]
+    pub invalid_rxpks: u64,
+}
+
+impl Aggregate {
+    fn merge(&mut self, other: &Aggregate) {
+        self.uplinks += other.uplinks;
+        self.downlinks += other.downlinks;
+        self.downlink_failures += other.downlink_failures;
+        self.router_requests += other.router_requests;
+        self.router_failures += other.router_failures;
+        self.router_latency_ms += other.router_latency_ms;
+        self.router_latency_max_ms = self.router_latency_max_ms.max(other.router_latency_max_ms);
+        self.router_cancelled += other.router_cancelled;
+        self.invalid_rxpks += other.invalid_rxpks;
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(map) = value.as_object_mut() {
+            let average = self
+                .router_latency_ms
+                .checked_div(self.router_requests)
+                .unwrap_or(0);
+            map.insert("router_latency_avg_ms".to_string(), average.into());
+            map.insert(
+                "downlink_success_rate".to_string(),
+                success_rate(self.downlinks, self.downlink_failures).into(),
+            );
+        }
+        value
+    }
+}
+
+/// What downlink outcomes are counted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// A device by its DevAddr (hex)
+    Device,
+    /// A router by its uri
+    Router,
+}
+
+/// The downlinks of a router sent to a device or for a router over a period
+/// starting at `start` (unix seconds), and how many of them failed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Outcomes {
+    pub start: u64,
+    pub target: Target,
+    pub key: String,
+    pub downlinks: u64,
+    pub failures: u64,
+}
+
+impl Outcomes {
+    fn store_key(&self) -> Vec<u8> {
+        let mut key = self.start.to_be_bytes().to_vec();
+        key.extend_from_slice(format!("{:?}/{}", self.target, self.key).as_bytes());
+        key
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// Counts since the last flush to the store
+    current: Aggregate,
+    /// Counts since the gateway started
+    total: Aggregate,
+    /// Downlinks and failures by device and by router since the last flush
+    outcomes: HashMap<(Target, String), (u64, u64)>,
+}
+
+/// A cheaply cloneable handle to count traffic for the historical stats.
+#[derive(Debug, Clone, Default)]
+pub struct Stats(Arc<Mutex<Counters>>);
+
+impl Stats {
+    pub fn uplink(&self) {
+        self.update(|current| current.uplinks += 1)
+    }
+
+    pub fn downlink(&self, delivered: bool) {
+        self.update(|current| {
+            current.downlinks += 1;
+            if !delivered {
+                current.downlink_failures += 1;
+            }
+        })
+    }
+
+    /// Counts a downlink of a router, by device as well when its DevAddr is
+    /// known and by the router that sent it.
+    pub fn routed_downlink(&self, dev_addr: Option<u32>, router: Option<&str>, delivered: bool) {
+        self.downlink(delivered);
+        let targets = dev_addr
+            .map(|dev_addr| (Target::Device, format!("{:08x}", dev_addr)))
+            .into_iter()
+            .chain(router.map(|router| (Target::Router, router.to_string())));
+        if let Ok(mut counters) = self.0.lock() {
+            for target in targets {
+                let (downlinks, failures) = counters.outcomes.entry(target).or_default();
+                *downlinks += 1;
+                if !delivered {
+                    *failures += 1;
+                }
+            }
+        }
+    }
+
+    pub fn router_request(&self, latency: Duration, ok: bool) {
+        let latency_ms = latency.as_millis() as u64;
+        self.update(|current| {
+            current.router_requests += 1;
+            if !ok {
+                current.router_failures += 1;
+            }
+            current.router_latency_ms += latency_ms;
+            current.router_latency_max_ms = current.router_latency_max_ms.max(latency_ms);
+        })
+    }
+
+    pub fn router_cancelled(&self) {
+        self.update(|current| current.router_cancelled += 1)
+    }
+
+    pub fn invalid_rxpks(&self, count: usize) {
+        self.update(|current| current.invalid_rxpks += count as u64)
+    }
+
+    fn update<F: Fn(&mut Aggregate)>(&self, f: F) {
+        if let Ok(mut counters) = self.0.lock() {
+            f(&mut counters.current);
+            f(&mut counters.total);
+        }
+    }
+
+    /// Takes the counters since the last call.
+    fn take(&self) -> Aggregate {
+        self.0
+            .lock()
+            .map(|mut counters| std::mem::take(&mut counters.current))
+            .unwrap_or_default()
+    }
+
+    /// Returns the downlink outcomes not stored yet, for the period starting
+    /// at the given time.
+    fn pending_outcomes(&self, start: u64) -> Vec<Outcomes> {
+        self.0
+            .lock()
+            .map(|counters| to_outcomes(counters.outcomes.clone(), start))
+            .unwrap_or_default()
+    }
+
+    /// Clears downlink outcomes once they are stored, keeping any counted
+    /// since they were read.
+    fn stored_outcome(&self, outcome: &Outcomes) {
+        if let Ok(mut counters) = self.0.lock() {
+            let key = (outcome.target, outcome.key.clone());
+            if let Some((downlinks, failures)) = counters.outcomes.get_mut(&key) {
+                *downlinks = downlinks.saturating_sub(outcome.downlinks);
+                *failures = failures.saturating_sub(outcome.failures);
+                if *downlinks == 0 {
+                    counters.outcomes.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Returns the counters since the gateway started.
+    pub fn totals(&self) -> Aggregate {
+        self.0
+            .lock()
+            .map(|counters| counters.total.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the hourly, or for longer periods daily, aggregates since the
+    /// given time ago, including the counters not stored yet. Downlink
+    /// outcomes are shown for up to `device_limit` devices, those with the
+    /// most failures.
+    pub fn query(
+        &self,
+        store: &Store,
+        since: Duration,
+        device_limit: usize,
+    ) -> Result<serde_json::Value> {
+        let now = unix_secs();
+        let (tree, period) = if since.as_secs() <= MAX_HOURLY_QUERY_SECS {
+            (HOURLY_TREE, HOUR_SECS)
+        } else {
+            (DAILY_TREE, DAY_SECS)
+        };
+        let from = now.saturating_sub(since.as_secs()) / period * period;
+        let mut aggregates: Vec<Aggregate> = store
+            .values::<Aggregate>(tree)?
+            .into_iter()
+            .filter(|aggregate| aggregate.start >= from)
+            .collect();
+        aggregates.sort_by_key(|aggregate| aggregate.start);
+        let pending = self
+            .0
+            .lock()
+            .map(|counters| counters.current.clone())
+            .unwrap_or_default();
+        let start = now / period * period;
+        match aggregates.last_mut() {
+            Some(last) if last.start == start => last.merge(&pending),
+            _ => aggregates.push(Aggregate { start, ..pending }),
+        }
+        let mut total = Aggregate {
+            start: from,
+            ..Default::default()
+        };
+        for aggregate in &aggregates {
+            total.merge(aggregate);
+        }
+        let outcomes_tree = if period == HOUR_SECS {
+            OUTCOMES_HOURLY_TREE
+        } else {
+            OUTCOMES_DAILY_TREE
+        };
+        let pending = self
+            .0
+            .lock()
+            .map(|counters| to_outcomes(counters.outcomes.clone(), start))
+            .unwrap_or_default();
+        let outcomes: Vec<Outcomes> = store
+            .values::<Outcomes>(outcomes_tree)?
+            .into_iter()
+            .filter(|outcomes| outcomes.start >= from)
+            .chain(pending)
+            .collect();
+        Ok(serde_json::json!({
+            "period": if period == HOUR_SECS { "hourly" } else { "daily" },
+            "total": total.to_json(),
+            "aggregates": aggregates.iter().map(Aggregate::to_json).collect::<Vec<_>>(),
+            "downlink_outcomes": {
+                "devices": outcomes_json(&outcomes, Target::Device, device_limit),
+                "device_count": outcomes_keys(&outcomes, Target::Device),
+                "routers": outcomes_json(&outcomes, Target::Router, usize::MAX),
+            },
+        }))
+    }
+}
+
+fn to_outcomes(counts: HashMap<(Target, String), (u64, u64)>, start: u64) -> Vec<Outcomes> {
+    counts
+        .into_iter()
+        .map(|((target, key), (downlinks, failures))| Outcomes {
+            start,
+            target,
+            key,
+            downlinks,
+            failures,
+        })
+        .collect()
+}
+
+/// The number of devices or routers with downlink outcomes.
+fn outcomes_keys(outcomes: &[Outcomes], target: Target) -> usize {
+    outcomes
+        .iter()
+        .filter(|outcome| outcome.target == target)
+        .map(|outcome| outcome.key.as_str())
+        .collect::<std::collections::HashSet<_>>()
+        .len()
+}
+
+/// The downlinks of up to `limit` devices or routers over all periods with
+/// their success rate, the most failures first.
+fn outcomes_json(outcomes: &[Outcomes], target: Target, limit: usize) -> Vec<serde_json::Value> {
+    let mut totals: HashMap<&str, (u64, u64)> = HashMap::new();
+    for outcome in outcomes.iter().filter(|outcome| outcome.target == target) {
+        let (downlinks, failures) = totals.entry(outcome.key.as_str()).or_default();
+        *downlinks += outcome.downlinks;
+        *failures += outcome.failures;
+    }
+    let mut totals: Vec<(&str, (u64, u64))> = totals.into_iter().collect();
+    totals.sort_by(|(a, (_, a_failures)), (b, (_, b_failures))| {
+        b_failures.cmp(a_failures).then(a.cmp(b))
+    });
+    totals
+        .into_iter()
+        .take(limit)
+        .map(|(key, (downlinks, failures))| {
+            serde_json::json!({
+                "key": key,
+                "downlinks": downlinks,
+                "failures": failures,
+                "success_rate": success_rate(downlinks, failures),
+            })
+        })
+        .collect()
+}
+
+/// The share of downlinks that were sent, None without downlinks.
+fn success_rate(downlinks: u64, failures: u64) -> Option<f64> {
+    if downlinks == 0 {
+        return None;
+    }
+    Some(downlinks.saturating_sub(failures) as f64 / downlinks as f64)
+}
+
+/// Returns the stored hourly aggregates.
+pub fn hourly(store: &Store) -> Result<Vec<Aggregate>> {
+    store.values(HOURLY_TREE)
+}
+
+/// Merges the traffic counters into hourly and daily aggregates in the
+/// store, and drops aggregates past their retention.
+#[derive(Debug)]
+pub struct StatsService {
+    stats: Stats,
+    store: Store,
+    hourly_retention: Duration,
+    daily_retention: Duration,
+}
+
+impl StatsService {
+    pub fn new(stats: Stats, store: Store, settings: &StatsSettings) -> Self {
+        Self {
+            stats,
+            store,
+            hourly_retention: Duration::from_secs(settings.hourly_retention_hours * HOUR_SECS),
+            daily_retention: Duration::from_secs(settings.daily_retention_days * DAY_SECS),
+        }
+    }
+
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "stats"));
+        info!(logger, "starting");
+        let mut flush_timer = time::interval(Duration::from_secs(STATS_FLUSH_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    self.flush(&logger);
+                    return Ok(())
+                },
+                _ = flush_timer.tick() => self.flush(&logger),
+            }
+        }
+    }
+
+    fn flush(&self, logger: &Logger) {
+        let current = self.stats.take();
+        let now = unix_secs();
+        let outcomes = self.stats.pending_outcomes(now);
+        let flushed = self
+            .merge(HOURLY_TREE, HOUR_SECS, now, &current)
+            .and_then(|_| self.merge(DAILY_TREE, DAY_SECS, now, &current))
+            .and_then(|_| self.merge_outcomes(&outcomes))
+            .and_then(|_| self.expire(HOURLY_TREE, now, self.hourly_retention))
+            .and_then(|_| self.expire(DAILY_TREE, now, self.daily_retention))
+            .and_then(|_| self.expire_outcomes(OUTCOMES_HOURLY_TREE, now, self.hourly_retention))
+            .and_then(|_| self.expire_outcomes(OUTCOMES_DAILY_TREE, now, self.daily_retention));
+        if let Err(err) = flushed {
+            warn!(logger, "failed to store stats: {:?}", err);
+        }
+    }
+
+    fn merge(&self, tree: &str, period: u64, now: u64, current: &Aggregate) -> Result {
+        let start = now / period * period;
+        let key = start.to_be_bytes();
+        let mut aggregate = self
+            .store
+            .get::<Aggregate>(tree, &key)?
+            .unwrap_or(Aggregate {
+                start,
+                ..Default::default()
+            });
+        aggregate.merge(current);
+        self.store.put(tree, &key, &aggregate)
+    }
+
+    /// Adds the downlink outcomes, taken at the time of their `start`, to
+    /// those stored for the hour and the day. Each is cleared from the
+    /// counters once stored, so outcomes that fail to store are kept for the
+    /// next flush.
+    fn merge_outcomes(&self, outcomes: &[Outcomes]) -> Result {
+        for outcome in outcomes {
+            for (tree, period) in &[
+                (OUTCOMES_HOURLY_TREE, HOUR_SECS),
+                (OUTCOMES_DAILY_TREE, DAY_SECS),
+            ] {
+                let mut stored = Outcomes {
+                    start: outcome.start / period * period,
+                    ..outcome.clone()
+                };
+                let key = stored.store_key();
+                if let Some(previous) = self.store.get::<Outcomes>(tree, &key)? {
+                    stored.downlinks += previous.downlinks;
+                    stored.failures += previous.failures;
+                }
+                self.store.put(tree, &key, &stored)?;
+            }
+            self.stats.stored_outcome(outcome);
+        }
+        Ok(())
+    }
+
+    fn expire_outcomes(&self, tree: &str, now: u64, retention: Duration) -> Result {
+        let oldest = now.saturating_sub(retention.as_secs());
+        for outcome in self.store.values::<Outcomes>(tree)? {
+            if outcome.start < oldest {
+                self.store.remove(tree, &outcome.store_key())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn expire(&self, tree: &str, now: u64, retention: Duration) -> Result {
+        let oldest = now.saturating_sub(retention.as_secs());
+        for aggregate in self.store.values::<Aggregate>(tree)? {
+            if aggregate.start < oldest {
+                self.store.remove(tree, &aggregate.start.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a duration like "30m", "24h" or "7d".
+pub fn parse_since(s: &str) -> Result<Duration> {
+    let invalid = || Error::custom(format!("invalid duration: {}", s));
+    let unit_at = s
+        .char_indices()
+        .last()
+        .map(|(i, _)| i)
+        .ok_or_else(invalid)?;
+    let (value, unit) = s.split_at(unit_at);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * HOUR_SECS,
+        "d" => value * DAY_SECS,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+pub fn unix_secs() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn unix_millis() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(stats: &Stats, store: &Store) -> StatsService {
+        StatsService::new(
+            stats.clone(),
+            store.clone(),
+            &StatsSettings {
+                hourly_retention_hours: 24,
+                daily_retention_days: 30,
+                dutycycle_retention_days: 30,
+            },
+        )
+    }
+
+    #[test]
+    fn query() {
+        let stats = Stats::default();
+        let store = Store::memory();
+        let service = service(&stats, &store);
+        stats.uplink();
+        stats.router_request(Duration::from_millis(300), true);
+        stats.router_request(Duration::from_millis(100), false);
+        service.flush(&Logger::root(slog::Discard, o!()));
+        stats.uplink();
+        stats.downlink(false);
+        stats.router_cancelled();
+        stats.invalid_rxpks(2);
+
+        let result = stats
+            .query(&store, Duration::from_secs(HOUR_SECS), DEFAULT_DEVICE_LIMIT)
+            .expect("query");
+        assert_eq!("hourly", result["period"]);
+        assert_eq!(2, result["total"]["uplinks"]);
+        assert_eq!(1, result["total"]["downlink_failures"]);
+        assert_eq!(1, result["total"]["router_cancelled"]);
+        assert_eq!(2, result["total"]["invalid_rxpks"]);
+        assert_eq!(200, result["total"]["router_latency_avg_ms"]);
+        assert_eq!(300, result["total"]["router_latency_max_ms"]);
+        assert_eq!(
+            Duration::from_secs(7 * DAY_SECS),
+            parse_since("7d").expect("since")
+        );
+        assert!(parse_since("7w").is_err());
+    }
+
+    #[test]
+    fn outcomes() {
+        let stats = Stats::default();
+        let store = Store::memory();
+        let service = service(&stats, &store);
+        let logger = Logger::root(slog::Discard, o!());
+        let router = Some("http://router:8080");
+        stats.routed_downlink(Some(0x0123_abcd), router, true);
+        // A stored entry that does not read fails the flush of the device
+        let corrupt = Outcomes {
+            start: unix_secs() / HOUR_SECS * HOUR_SECS,
+            target: Target::Device,
+            key: "0123abcd".to_string(),
+            downlinks: 0,
+            failures: 0,
+        }
+        .store_key();
+        store
+            .put(OUTCOMES_HOURLY_TREE, &corrupt, "corrupt")
+            .expect("put");
+        service.flush(&logger);
+        store
+            .remove(OUTCOMES_HOURLY_TREE, &corrupt)
+            .expect("remove");
+        stats.routed_downlink(None, router, false);
+        service.flush(&logger);
+        service.flush(&logger);
+
+        // The outcomes that failed to store are kept, and stored once
+        let result = stats
+            .query(&store, Duration::from_secs(HOUR_SECS), DEFAULT_DEVICE_LIMIT)
+            .expect("query");
+        assert_eq!(1.0 / 2.0, result["total"]["downlink_success_rate"]);
+        let outcomes = &result["downlink_outcomes"];
+        assert_eq!("0123abcd", outcomes["devices"][0]["key"]);
+        assert_eq!(1, outcomes["devices"][0]["downlinks"]);
+        assert_eq!(1.0, outcomes["devices"][0]["success_rate"]);
+        assert_eq!(2, outcomes["routers"][0]["downlinks"]);
+        assert_eq!(0.5, outcomes["routers"][0]["success_rate"]);
+
+        // Only the devices with the most failures are shown
+        for (dev_addr, failures) in &[(1u32, 1), (2, 3), (3, 2)] {
+            for _ in 0..*failures {
+                stats.routed_downlink(Some(*dev_addr), router, false);
+            }
+        }
+        let result = stats
+            .query(&store, Duration::from_secs(HOUR_SECS), 2)
+            .expect("query");
+        let outcomes = &result["downlink_outcomes"];
+        assert_eq!(4, outcomes["device_count"]);
+        let devices = outcomes["devices"].as_array().expect("devices");
+        assert_eq!(2, devices.len());
+        assert_eq!("00000002", devices[0]["key"]);
+        assert_eq!("00000003", devices[1]["key"]);
+    }
+}
@@ -0,0 +1,42 @@
+use super::Backend;
+use crate::*;
+use std::{collections::BTreeMap, sync::Mutex};
+
+type Key = (String, Vec<u8>);
+
+/// Keeps everything in memory. Nothing survives a restart, which makes this
+/// the choice for gateways without writable flash to spare.
+#[derive(Debug, Default)]
+pub struct Memory(Mutex<BTreeMap<Key, Vec<u8>>>);
+
+impl Backend for Memory {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entries = self.0.lock().expect("store lock");
+        Ok(entries.get(&(tree.to_string(), key.to_vec())).cloned())
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result {
+        let mut entries = self.0.lock().expect("store lock");
+        entries.insert((tree.to_string(), key.to_vec()), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result {
+        let mut entries = self.0.lock().expect("store lock");
+        entries.remove(&(tree.to_string(), key.to_vec()));
+        Ok(())
+    }
+
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self.0.lock().expect("store lock");
+        Ok(entries
+            .iter()
+            .filter(|((entry_tree, _), _)| entry_tree == tree)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&self) -> Result {
+        Ok(())
+    }
+}
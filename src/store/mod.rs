@@ -0,0 +1,217 @@
+use crate::*;
+use error::StoreError;
+use serde::{de::DeserializeOwned, Serialize};
+use settings::{StoreBackend, StoreSettings};
+use std::{
+    convert::TryInto,
+    hash::Hasher,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use xxhash_c::XXH64;
+
+mod memory;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// The version of the stored data layout. Bump this and add a migration step
+/// to `migrate` when the layout changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const META_TREE: &str = "meta";
+const VERSION_KEY: &[u8] = b"version";
+/// Saved values are prefixed with a checksum and the save time
+const SAVED_HEADER_LEN: usize = 16;
+
+/// A key value storage backend. Keys live in named trees so modules do not
+/// step on each other's keys.
+pub trait Backend: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result;
+    fn remove(&self, tree: &str, key: &[u8]) -> Result;
+    /// Returns all entries of a tree in key order.
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Makes sure all writes so far are on disk.
+    fn flush(&self) -> Result;
+}
+
+/// A value loaded with `Store::load`.
+#[derive(Debug)]
+pub struct Saved<T> {
+    pub value: T,
+    /// Wall clock time since the value was saved
+    pub age: Duration,
+}
+
+/// A cheaply cloneable handle to the configured storage backend.
+#[derive(Clone)]
+pub struct Store(Arc<dyn Backend>);
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Store").finish()
+    }
+}
+
+impl Store {
+    /// Opens the backend selected in the settings and migrates its data to
+    /// the current schema version. Backends not compiled in are an error.
+    pub fn open(settings: &StoreSettings) -> Result<Self> {
+        let backend: Arc<dyn Backend> = match settings.backend {
+            StoreBackend::Memory => Arc::new(memory::Memory::default()),
+            #[cfg(feature = "sled")]
+            StoreBackend::Sled => Arc::new(sled::Sled::open(&settings.path)?),
+            #[cfg(feature = "sqlite")]
+            StoreBackend::Sqlite => Arc::new(sqlite::Sqlite::open(&settings.path)?),
+            #[allow(unreachable_patterns)]
+            other => return Err(StoreError::Unsupported(format!("{:?}", other)).into()),
+        };
+        let store = Self(backend);
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// A store that keeps everything in memory.
+    pub fn memory() -> Self {
+        Self(Arc::new(memory::Memory::default()))
+    }
+
+    fn migrate(&self) -> Result {
+        let version = self
+            .get::<u32>(META_TREE, VERSION_KEY)?
+            .unwrap_or(SCHEMA_VERSION);
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::Version(version).into());
+        }
+        // Migration steps from older versions go here, in order
+        self.put(META_TREE, VERSION_KEY, &SCHEMA_VERSION)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, tree: &str, key: &[u8]) -> Result<Option<T>> {
+        match self.0.get(tree, key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put<T: Serialize + ?Sized>(&self, tree: &str, key: &[u8], value: &T) -> Result {
+        self.0.put(tree, key, &serde_json::to_vec(value)?)
+    }
+
+    pub fn remove(&self, tree: &str, key: &[u8]) -> Result {
+        self.0.remove(tree, key)
+    }
+
+    /// Returns all values of a tree in key order.
+    pub fn values<T: DeserializeOwned>(&self, tree: &str) -> Result<Vec<T>> {
+        self.0
+            .scan(tree)?
+            .into_iter()
+            .map(|(_, value)| Ok(serde_json::from_slice(&value)?))
+            .collect()
+    }
+
+    /// Saves state that has to survive a crash. The value is stored with a
+    /// checksum and the time it was saved, and is flushed to disk right away.
+    pub fn save<T: Serialize + ?Sized>(&self, tree: &str, key: &[u8], value: &T) -> Result {
+        let data = serde_json::to_vec(value)?;
+        let mut saved = Vec::with_capacity(SAVED_HEADER_LEN + data.len());
+        saved.extend_from_slice(&checksum(&data).to_be_bytes());
+        saved.extend_from_slice(&now_millis().to_be_bytes());
+        saved.extend_from_slice(&data);
+        self.0.put(tree, key, &saved)?;
+        self.0.flush()
+    }
+
+    /// Loads state stored with `save`. Values that fail the checksum, for
+    /// example after a torn write, are an error.
+    pub fn load<T: DeserializeOwned>(&self, tree: &str, key: &[u8]) -> Result<Option<Saved<T>>> {
+        match self.0.get(tree, key)? {
+            Some(saved) => Ok(Some(open_saved(tree, &saved)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads all values of a tree stored with `save`, in key order. Values
+    /// that fail to load are returned as errors so the others can still be
+    /// used.
+    pub fn load_all<T: DeserializeOwned>(&self, tree: &str) -> Result<Vec<Result<Saved<T>>>> {
+        Ok(self
+            .0
+            .scan(tree)?
+            .into_iter()
+            .map(|(_, saved)| open_saved(tree, &saved))
+            .collect())
+    }
+
+    pub fn flush(&self) -> Result {
+        self.0.flush()
+    }
+}
+
+fn open_saved<T: DeserializeOwned>(tree: &str, saved: &[u8]) -> Result<Saved<T>> {
+    if saved.len() < SAVED_HEADER_LEN {
+        return Err(StoreError::Corrupt(tree.to_string()).into());
+    }
+    let (header, data) = saved.split_at(SAVED_HEADER_LEN);
+    let expected = u64::from_be_bytes(header[..8].try_into().unwrap());
+    if checksum(data) != expected {
+        return Err(StoreError::Corrupt(tree.to_string()).into());
+    }
+    let saved_at = u64::from_be_bytes(header[8..].try_into().unwrap());
+    Ok(Saved {
+        value: serde_json::from_slice(data)?,
+        age: Duration::from_millis(now_millis().saturating_sub(saved_at)),
+    })
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = XXH64::new(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn now_millis() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory() {
+        let store = Store::memory();
+        store.put("test", b"b", &2u32).expect("put");
+        store.put("test", b"a", &1u32).expect("put");
+        store.put("other", b"a", &3u32).expect("put");
+        assert_eq!(Some(1), store.get::<u32>("test", b"a").expect("get"));
+        assert_eq!(vec![1, 2], store.values::<u32>("test").expect("values"));
+        store.remove("test", b"a").expect("remove");
+        assert_eq!(None, store.get::<u32>("test", b"a").expect("get"));
+    }
+
+    #[test]
+    fn saved() {
+        let store = Store::memory();
+        store.save("test", b"a", &[1u32, 2]).expect("save");
+        let saved = store.load::<Vec<u32>>("test", b"a").expect("load");
+        assert_eq!(Some(vec![1, 2]), saved.map(|saved| saved.value));
+
+        let mut corrupt = store.0.get("test", b"a").expect("get").expect("value");
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        store.0.put("test", b"b", &corrupt).expect("put");
+        assert!(store.load::<Vec<u32>>("test", b"b").is_err());
+        let all = store.load_all::<Vec<u32>>("test").expect("load all");
+        assert_eq!(
+            vec![true, false],
+            all.iter().map(Result::is_ok).collect::<Vec<_>>()
+        );
+    }
+}
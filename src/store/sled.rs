@@ -0,0 +1,50 @@
+use super::Backend;
+use crate::*;
+use error::StoreError;
+use std::path::Path;
+
+/// An embedded log structured store. Fast, but uses more flash and memory
+/// than sqlite.
+#[derive(Debug)]
+pub struct Sled(sled::Db);
+
+impl Sled {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self(sled::open(path).map_err(StoreError::from)?))
+    }
+}
+
+impl Backend for Sled {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.0.open_tree(tree).map_err(StoreError::from)?;
+        let value = tree.get(key).map_err(StoreError::from)?;
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result {
+        let tree = self.0.open_tree(tree).map_err(StoreError::from)?;
+        tree.insert(key, value).map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result {
+        let tree = self.0.open_tree(tree).map_err(StoreError::from)?;
+        tree.remove(key).map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree = self.0.open_tree(tree).map_err(StoreError::from)?;
+        tree.iter()
+            .map(|entry| {
+                let (key, value) = entry.map_err(StoreError::from)?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> Result {
+        self.0.flush().map_err(StoreError::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use super::Backend;
+use crate::*;
+use error::StoreError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{path::Path, sync::Mutex};
+
+/// A single file sqlite database. The lighter option on flash constrained
+/// gateways.
+#[derive(Debug)]
+pub struct Sqlite(Mutex<Connection>);
+
+impl Sqlite {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path).map_err(StoreError::from)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                    tree TEXT NOT NULL,
+                    key BLOB NOT NULL,
+                    value BLOB NOT NULL,
+                    PRIMARY KEY (tree, key)
+                )",
+            )
+            .map_err(StoreError::from)?;
+        Ok(Self(Mutex::new(connection)))
+    }
+}
+
+impl Backend for Sqlite {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let connection = self.0.lock().expect("store lock");
+        let value = connection
+            .query_row(
+                "SELECT value FROM entries WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(StoreError::from)?;
+        Ok(value)
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result {
+        let connection = self.0.lock().expect("store lock");
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO entries (tree, key, value) VALUES (?1, ?2, ?3)",
+                params![tree, key, value],
+            )
+            .map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result {
+        let connection = self.0.lock().expect("store lock");
+        connection
+            .execute(
+                "DELETE FROM entries WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+            )
+            .map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    fn scan(&self, tree: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let connection = self.0.lock().expect("store lock");
+        let mut statement = connection
+            .prepare("SELECT key, value FROM entries WHERE tree = ?1 ORDER BY key")
+            .map_err(StoreError::from)?;
+        let rows = statement
+            .query_map(params![tree], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(StoreError::from)?;
+        let entries = rows
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(StoreError::from)?;
+        Ok(entries)
+    }
+
+    fn flush(&self) -> Result {
+        // Every statement is committed as it runs
+        Ok(())
+    }
+}
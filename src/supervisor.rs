@@ -0,0 +1,118 @@
+use crate::*;
+use slog::{info, o, warn, Logger};
+use std::{
+    os::unix::process::CommandExt,
+    time::{Duration, Instant},
+};
+use tokio::{process, sync::watch, time};
+
+/// How often the supervised packet forwarder is checked for PULL_DATA.
+pub const LIVENESS_CHECK_INTERVAL_SECS: u64 = 5;
+
+/// Creates the liveness handle the gateway uses to report PULL_DATA and the
+/// supervisor that watches it.
+pub fn supervisor(settings: &Settings) -> (Liveness, Supervisor) {
+    let (sender, receiver) = watch::channel(());
+    let supervisor = &settings.supervisor;
+    (
+        Liveness(sender),
+        Supervisor {
+            command: supervisor.command.clone(),
+            pull_timeout: Duration::from_secs(supervisor.pull_timeout),
+            restart_delay: Duration::from_secs(supervisor.restart_delay),
+            pulls: receiver,
+        },
+    )
+}
+
+/// Reports packet forwarder keepalives to the supervisor.
+#[derive(Debug)]
+pub struct Liveness(watch::Sender<()>);
+
+impl Liveness {
+    pub fn pull_data(&self) {
+        // There is no receiver when the supervisor is disabled
+        let _ = self.0.send(());
+    }
+}
+
+/// Runs a local packet forwarder and restarts it when it exits or stops
+/// sending PULL_DATA keepalives, so a wedged concentrator recovers without
+/// intervention.
+#[derive(Debug)]
+pub struct Supervisor {
+    command: Option<String>,
+    pull_timeout: Duration,
+    restart_delay: Duration,
+    pulls: watch::Receiver<()>,
+}
+
+impl Supervisor {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "supervisor"));
+        let command = match self.command.clone() {
+            Some(command) => command,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "command" => &command);
+        let mut check_timer = time::interval(Duration::from_secs(LIVENESS_CHECK_INTERVAL_SECS));
+        loop {
+            let mut child = spawn(&command)?;
+            info!(logger, "started packet forwarder"; "pid" => child.id());
+            // The forwarder gets a full timeout to come up
+            let mut last_pull = Instant::now();
+            let reason = loop {
+                tokio::select! {
+                    _ = shutdown.clone() => {
+                        info!(logger, "shutting down");
+                        stop(&mut child).await;
+                        return Ok(())
+                    },
+                    status = child.wait() => break format!("exited with {:?}", status),
+                    Ok(()) = self.pulls.changed() => last_pull = Instant::now(),
+                    _ = check_timer.tick() => if last_pull.elapsed() > self.pull_timeout {
+                        stop(&mut child).await;
+                        break format!("sent no PULL_DATA for {}s", last_pull.elapsed().as_secs());
+                    },
+                }
+            };
+            warn!(
+                logger,
+                "packet forwarder {}, restarting in {}s",
+                reason,
+                self.restart_delay.as_secs()
+            );
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = time::sleep(self.restart_delay) => (),
+            }
+        }
+    }
+}
+
+/// Starts the forwarder command in a process group of its own, so that the
+/// forwarder can be stopped along with the shell that runs it.
+fn spawn(command: &str) -> Result<process::Child> {
+    let mut shell = std::process::Command::new("sh");
+    shell.arg("-c").arg(command).process_group(0);
+    Ok(process::Command::from(shell).kill_on_drop(true).spawn()?)
+}
+
+/// Kills the process group of the forwarder and reaps the shell.
+async fn stop(child: &mut process::Child) {
+    if let Some(pid) = child.id() {
+        let _ = process::Command::new("kill")
+            .arg("-KILL")
+            .arg("--")
+            .arg(format!("-{}", pid))
+            .status()
+            .await;
+    }
+    let _ = child.kill().await;
+}
@@ -0,0 +1,249 @@
+use crate::*;
+use link_packet::TraceId;
+use serde_json::{json, Value};
+use slog::{debug, info, o, warn, Logger};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time,
+};
+
+/// Maximum number of finished spans buffered before spans are dropped.
+pub const SPAN_QUEUE_SIZE: usize = 256;
+/// Maximum number of spans exported in a single request.
+pub const EXPORT_BATCH_SIZE: usize = 64;
+
+/// Creates a tracer and the exporter that delivers its finished spans to the
+/// configured OTLP endpoint. The tracer can be cloned and handed to the
+/// various modules, while the exporter is expected to be run alongside them.
+pub fn tracer(settings: &Settings) -> (Tracer, Exporter) {
+    let telemetry = &settings.telemetry;
+    let labels: Vec<Value> = settings
+        .labels
+        .iter()
+        .map(|(name, value)| {
+            json!({"key": format!("gateway.label.{}", name), "value": {"stringValue": value}})
+        })
+        .collect();
+    if !telemetry.enabled {
+        return (
+            Tracer::disabled(),
+            Exporter {
+                uri: telemetry.uri.clone(),
+                interval: Duration::from_secs(telemetry.export_interval.max(1)),
+                interface: settings.backhaul.interface.clone(),
+                spans: None,
+                pending: vec![],
+                labels,
+            },
+        );
+    }
+    let (sender, receiver) = mpsc::channel(SPAN_QUEUE_SIZE);
+    (
+        Tracer {
+            sender: Some(sender),
+            sample_rate: telemetry.sample_rate.max(0.0).min(1.0),
+        },
+        Exporter {
+            uri: telemetry.uri.clone(),
+            interval: Duration::from_secs(telemetry.export_interval.max(1)),
+            interface: settings.backhaul.interface.clone(),
+            spans: Some(receiver),
+            pending: vec![],
+            labels,
+        },
+    )
+}
+
+/// A cheaply cloneable handle used to start spans.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    sender: Option<Sender<SpanData>>,
+    sample_rate: f64,
+}
+
+impl Tracer {
+    /// A tracer that never records any spans.
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            sample_rate: 0.0,
+        }
+    }
+
+    /// Starts a span with the given name for the given trace. The span ends
+    /// when it is dropped. Spans for traces that are not sampled are not
+    /// recorded.
+    pub fn span(&self, name: &'static str, trace_id: TraceId) -> Span {
+        match &self.sender {
+            Some(sender) if self.is_sampled(trace_id) => Span {
+                sender: Some(sender.clone()),
+                data: Some(SpanData {
+                    trace_id,
+                    span_id: rand::random(),
+                    name,
+                    start: unix_nanos(),
+                    end: 0,
+                    attributes: vec![],
+                }),
+            },
+            _ => Span {
+                sender: None,
+                data: None,
+            },
+        }
+    }
+
+    /// Sampling is decided on the trace id, which means that all spans for a
+    /// given trace are either recorded or not.
+    fn is_sampled(&self, trace_id: TraceId) -> bool {
+        (trace_id.as_u64() as f64) < self.sample_rate * u64::MAX as f64
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    trace_id: TraceId,
+    span_id: u64,
+    name: &'static str,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl SpanData {
+    fn to_json(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+            .collect();
+        json!({
+            "traceId": format!("{:0>32}", self.trace_id.to_string()),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": self.end.to_string(),
+            "attributes": attributes,
+        })
+    }
+}
+
+/// An active span. The span is finished and queued for export when dropped.
+#[derive(Debug)]
+pub struct Span {
+    sender: Option<Sender<SpanData>>,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    /// Adds an attribute to the span. Attributes are ignored for spans that
+    /// are not recorded.
+    pub fn attribute<V: ToString>(&mut self, key: &'static str, value: V) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.to_string()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(sender), Some(mut data)) = (self.sender.take(), self.data.take()) {
+            data.end = unix_nanos();
+            // Spans are dropped rather than blocking the packet path when
+            // the exporter falls behind
+            let _ = sender.try_send(data);
+        }
+    }
+}
+
+/// Exports finished spans using the OTLP/HTTP JSON encoding.
+#[derive(Debug)]
+pub struct Exporter {
+    uri: http::Uri,
+    interval: Duration,
+    interface: Option<String>,
+    spans: Option<Receiver<SpanData>>,
+    pending: Vec<SpanData>,
+    /// The labels of the gateway, as resource attributes
+    labels: Vec<Value>,
+}
+
+impl Exporter {
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "telemetry"));
+        let mut spans = match self.spans.take() {
+            Some(spans) => spans,
+            None => {
+                info!(logger, "disabling");
+                return Ok(());
+            }
+        };
+        info!(logger, "starting"; "uri" => self.uri.to_string());
+        let mut interval = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    self.export(&logger).await;
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                span = spans.recv() => match span {
+                    Some(span) => {
+                        self.pending.push(span);
+                        if self.pending.len() >= EXPORT_BATCH_SIZE {
+                            self.export(&logger).await;
+                        }
+                    }
+                    None => {
+                        warn!(logger, "span channel closed");
+                        return Ok(())
+                    }
+                },
+                _ = interval.tick() => self.export(&logger).await,
+            }
+        }
+    }
+
+    async fn export(&mut self, logger: &Logger) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let spans: Vec<Value> = self.pending.drain(..).map(|s| s.to_json()).collect();
+        let count = spans.len();
+        let mut attributes = vec![
+            json!({"key": "service.name", "value": {"stringValue": env!("CARGO_PKG_NAME")}}),
+            json!({"key": "service.version", "value": {"stringValue": env!("CARGO_PKG_VERSION")}}),
+        ];
+        attributes.extend(self.labels.iter().cloned());
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": attributes,
+                },
+                "scopeSpans": [{
+                    "scope": {"name": module_path!()},
+                    "spans": spans,
+                }]
+            }]
+        });
+        let mut args = curl::interface_args(&self.interface);
+        args.extend_from_slice(&[
+            "-H".to_string(),
+            "Content-Type: application/json".to_string(),
+        ]);
+        match curl::post(self.uri.to_string(), &args, body.to_string(), |_| Ok(())).await {
+            Ok(()) => debug!(logger, "exported {} spans", count),
+            Err(err) => warn!(logger, "failed to export {} spans: {:?}", count, err),
+        }
+    }
+}
+
+fn unix_nanos() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,301 @@
+use crate::*;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use gateway::{allowlist::Allowlist, quarantine::frame_mac};
+use relay::{Upstreams, MAX_DATAGRAM};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryInto,
+    net::SocketAddr,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, time};
+
+/// Tunneled frames older (or further in the future) than this are rejected.
+/// Both ends of the tunnel need reasonably synced clocks.
+pub const MAX_FRAME_AGE_SECS: u64 = 30;
+/// Peers that have not sent anything for this long are forgotten.
+pub const PEER_TIMEOUT_SECS: u64 = 120;
+
+const NONCE_LEN: usize = 24;
+const TIMESTAMP_LEN: usize = 8;
+
+/// Seals and opens tunneled GWMP frames with a pre-shared key.
+///
+/// A sealed frame is a random 24 byte nonce followed by the
+/// XChaCha20Poly1305 encrypted frame, prefixed with a big endian millisecond
+/// timestamp, and the authentication tag. The nonces of opened frames are
+/// kept for as long as their timestamp could pass, so a replayed frame is
+/// rejected.
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    nonces: HashSet<[u8; NONCE_LEN]>,
+    /// The nonces in the order they were opened
+    opened: VecDeque<(Instant, [u8; NONCE_LEN])>,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").finish()
+    }
+}
+
+impl Cipher {
+    /// Creates a cipher from a base64 encoded 32 byte key.
+    pub fn new(key: &str) -> Result<Self> {
+        let key = base64::decode(key)?;
+        if key.len() != 32 {
+            return Err(Error::custom("tunnel key must be 32 bytes"));
+        }
+        Ok(Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            nonces: HashSet::new(),
+            opened: VecDeque::new(),
+        })
+    }
+
+    /// Generates a new base64 encoded key.
+    pub fn generate_key() -> String {
+        base64::encode(rand::random::<[u8; 32]>())
+    }
+
+    pub fn seal(&self, frame: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut plaintext = Vec::with_capacity(TIMESTAMP_LEN + frame.len());
+        plaintext.extend_from_slice(&now_millis().to_be_bytes());
+        plaintext.extend_from_slice(frame);
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| Error::custom("tunnel encrypt failed"))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::custom("short tunnel frame"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let mut plaintext = self
+            .aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::custom("invalid tunnel frame"))?;
+        if plaintext.len() < TIMESTAMP_LEN {
+            return Err(Error::custom("short tunnel frame"));
+        }
+        let timestamp = u64::from_be_bytes(plaintext[..TIMESTAMP_LEN].try_into().unwrap());
+        let now = now_millis();
+        let age = if now > timestamp {
+            now - timestamp
+        } else {
+            timestamp - now
+        };
+        if age > MAX_FRAME_AGE_SECS * 1000 {
+            return Err(Error::custom("expired tunnel frame"));
+        }
+        // A frame from the future passes for twice the maximum age
+        let keep = Duration::from_secs(2 * MAX_FRAME_AGE_SECS);
+        while let Some((opened, nonce)) = self.opened.front() {
+            if opened.elapsed() < keep {
+                break;
+            }
+            self.nonces.remove(nonce);
+            self.opened.pop_front();
+        }
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+        if !self.nonces.insert(nonce) {
+            return Err(Error::custom("replayed tunnel frame"));
+        }
+        self.opened.push_back((Instant::now(), nonce));
+        Ok(plaintext.split_off(TIMESTAMP_LEN))
+    }
+}
+
+fn now_millis() -> u64 {
+    sntp::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// What the relay does to datagrams received on its listen socket. Datagrams
+/// coming back from upstream get the reverse treatment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Encrypt plain GWMP from local forwarders (tunnel client)
+    Seal,
+    /// Decrypt tunneled GWMP from remote peers (tunnel server)
+    Open,
+}
+
+/// Relays GWMP datagrams between a listen socket and an upstream address,
+/// encrypting in one direction and decrypting in the other.
+///
+/// Every peer gets its own upstream socket, so the far end sees each peer as
+/// a separate client and replies reach the right peer. On the gateway the
+/// relay opens tunneled frames and passes them to the packet forwarder listen
+/// address; next to a remote packet forwarder a second gateway-rs instance
+/// runs the relay the other way around with `tunnel connect`.
+///
+/// The plain frames from forwarders are checked against the forwarder
+/// allowlist with the address of the peer, since the gateway only sees the
+/// relay as their source.
+#[derive(Debug)]
+pub struct Relay {
+    cipher: Cipher,
+    listen_addr: SocketAddr,
+    upstream: SocketAddr,
+    inbound: Direction,
+    allowlist: Allowlist,
+}
+
+impl Relay {
+    pub fn new(
+        key: &str,
+        listen_addr: SocketAddr,
+        upstream: SocketAddr,
+        inbound: Direction,
+        allowlist: Allowlist,
+    ) -> Result<Self> {
+        Ok(Self {
+            cipher: Cipher::new(key)?,
+            listen_addr,
+            upstream,
+            inbound,
+            allowlist,
+        })
+    }
+
+    /// Creates the gateway side of the tunnel if enabled in the settings.
+    pub fn server(settings: &Settings) -> Result<Option<Self>> {
+        let tunnel = &settings.tunnel;
+        if !tunnel.enabled {
+            return Ok(None);
+        }
+        let key = tunnel
+            .key
+            .as_ref()
+            .ok_or_else(|| Error::custom("tunnel enabled without a key"))?;
+        Self::new(
+            key,
+            tunnel.listen_addr,
+            settings.listen_addr,
+            Direction::Open,
+            Allowlist::new(&settings.allowlist)?,
+        )
+        .map(Some)
+    }
+
+    pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "tunnel"));
+        info!(logger, "starting";
+            "listen_addr" => self.listen_addr.to_string(),
+            "upstream" => self.upstream.to_string());
+        let socket = UdpSocket::bind(self.listen_addr).await?;
+        let (mut peers, mut replies) = Upstreams::new(self.upstream);
+        let mut eviction_timer = time::interval(Duration::from_secs(PEER_TIMEOUT_SECS));
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                received = socket.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            warn!(logger, "tunnel receive failed: {:?}", err);
+                            continue;
+                        }
+                    };
+                    let datagram = match self.inbound(addr, &buf[..len]) {
+                        Ok(datagram) => datagram,
+                        Err(err) => {
+                            debug!(logger, "dropping datagram from {}: {:?}", addr, err);
+                            continue;
+                        }
+                    };
+                    match peers.connect(&addr, &logger).await {
+                        Ok(true) => info!(logger, "new tunnel peer {}", addr),
+                        Ok(false) => (),
+                        Err(err) => {
+                            warn!(logger, "failed to connect tunnel peer {}: {:?}", addr, err);
+                            continue;
+                        }
+                    }
+                    if let Err(err) = peers.send(&addr, &datagram).await {
+                        warn!(logger, "failed to relay datagram from {}: {:?}", addr, err);
+                    }
+                },
+                Some((addr, datagram)) = replies.recv() => {
+                    let outbound = match self.inbound {
+                        Direction::Seal => self.cipher.open(&datagram),
+                        Direction::Open => self.cipher.seal(&datagram),
+                    };
+                    match outbound {
+                        Ok(datagram) => {
+                            if let Err(err) = socket.send_to(&datagram, addr).await {
+                                warn!(logger, "failed to relay datagram to {}: {:?}", addr, err);
+                            }
+                        }
+                        Err(err) => debug!(logger, "dropping upstream datagram for {}: {:?}", addr, err),
+                    }
+                },
+                _ = eviction_timer.tick() => {
+                    for addr in peers.evict(Duration::from_secs(PEER_TIMEOUT_SECS)) {
+                        info!(logger, "forgetting idle tunnel peer {}", addr);
+                    }
+                    if let Err(err) = self.allowlist.refresh() {
+                        warn!(logger, "keeping current forwarder allowlist: {:?}", err);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Turns a datagram from a peer into the one relayed upstream, checking
+    /// the forwarder of the plain frame against the allowlist.
+    fn inbound(&mut self, addr: SocketAddr, datagram: &[u8]) -> Result<Vec<u8>> {
+        let frame = match self.inbound {
+            Direction::Seal => datagram.to_vec(),
+            Direction::Open => self.cipher.open(datagram)?,
+        };
+        let mac = frame_mac(&frame).ok_or_else(|| Error::custom("not a GWMP frame"))?;
+        self.allowlist.update_addr(mac, &addr);
+        if !self.allowlist.allows(&mac) {
+            return Err(Error::custom(format!("unauthorized forwarder {}", mac)));
+        }
+        match self.inbound {
+            Direction::Seal => self.cipher.seal(&frame),
+            Direction::Open => Ok(frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open() {
+        let mut cipher = Cipher::new(&Cipher::generate_key()).expect("cipher");
+        let frame = b"\x02\x00\x01\x02gwmp";
+        let sealed = cipher.seal(frame).expect("seal");
+        assert_eq!(frame.to_vec(), cipher.open(&sealed).expect("open"));
+        assert!(cipher.open(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(cipher.open(&tampered).is_err());
+
+        let mut other = Cipher::new(&Cipher::generate_key()).expect("cipher");
+        assert!(other.open(&sealed).is_err());
+    }
+}
@@ -25,7 +25,7 @@ impl Updater {
             enabled: settings.update.enabled,
             channel: settings.update.channel.clone(),
             platform: settings.update.platform.clone(),
-            interval: time::Duration::from_secs(settings.update.interval as u64 * 60),
+            interval: time::Duration::from_secs(settings.update.interval.max(1) as u64 * 60),
             uri: settings.update.uri.clone(),
             install_command: settings.update.command.clone(),
         })
@@ -61,7 +61,9 @@ impl Updater {
                             let download_path = self.download_path(&asset.name);
                             asset.download(&download_path).await?;
                             info!(logger, "installing {asset}", asset=asset.name.clone());
-                            return self.install(&download_path, &logger).await;
+                            self.install(&download_path, &logger).await?;
+                            // Exit for the supervisor to start the new version
+                            return Err(Error::Restart(format!("installed {}", asset.name)));
                         },
                         Ok(None) => info!(logger,"no update found"),
                         Err(err) => warn!(logger,"failed to fetch releases: {:?}", err),
@@ -0,0 +1,188 @@
+//! Checks for the signed uplink envelopes the gateway sends to routers, for
+//! routers, downstream services and tests that consume them.
+
+use crate::*;
+use error::VerifyError;
+use helium_crypto::Verify;
+use helium_proto::{
+    blockchain_state_channel_message_v1::Msg, BlockchainStateChannelMessageV1,
+    BlockchainStateChannelPacketV1, Message, Packet, Region,
+};
+use std::time::Duration;
+
+/// A verified uplink envelope.
+#[derive(Debug, Clone)]
+pub struct Verified {
+    /// The key of the gateway that signed the packet
+    pub hotspot: PublicKey,
+    /// The region the packet was checked against
+    pub region: Region,
+    pub packet: Packet,
+}
+
+/// Verifies a state channel message sent by a gateway: it has to carry an
+/// uplink packet signed by the hotspot key it names, on a frequency of the
+/// region. The region is the one in the envelope unless given. Version 1
+/// envelopes leave the region out, which encodes the same as US915, so an
+/// envelope region of 0 counts as none and the expected region has to be
+/// passed for those and for US915 packets.
+pub fn verify_message(
+    message: &BlockchainStateChannelMessageV1,
+    region: Option<Region>,
+) -> Result<Verified> {
+    let envelope = match &message.msg {
+        Some(Msg::Packet(envelope)) => envelope,
+        _ => return Err(VerifyError::NotAPacket.into()),
+    };
+    let hotspot = verify_signature(envelope)?;
+    let region = match (region, envelope.region) {
+        (Some(region), _) => region,
+        (None, 0) => return Err(VerifyError::NoRegion.into()),
+        (None, region) => Region::from_i32(region).ok_or(VerifyError::UnknownRegion(region))?,
+    };
+    let packet = envelope.packet.clone().ok_or(VerifyError::NotAPacket)?;
+    verify_region(&packet, region)?;
+    Ok(Verified {
+        hotspot,
+        region,
+        packet,
+    })
+}
+
+/// Checks the signature of a packet envelope, which is over the encoded
+/// envelope without the signature, and returns the key that signed it.
+pub fn verify_signature(envelope: &BlockchainStateChannelPacketV1) -> Result<PublicKey> {
+    let hotspot = PublicKey::from_bytes(&envelope.hotspot).map_err(|_| VerifyError::Key)?;
+    let unsigned = BlockchainStateChannelPacketV1 {
+        signature: vec![],
+        ..envelope.clone()
+    };
+    let mut encoded = vec![];
+    unsigned.encode(&mut encoded)?;
+    hotspot
+        .verify(&encoded, &envelope.signature)
+        .map_err(|_| VerifyError::Signature)?;
+    Ok(hotspot)
+}
+
+/// Checks that the packet was received on a frequency of the uplink channel
+/// plan of the region. Regions without a known plan are not checked.
+pub fn verify_region(packet: &Packet, region: Region) -> Result {
+    if region::has_plan(region) && !region::candidates(packet.frequency).contains(&region) {
+        return Err(VerifyError::Region(packet.frequency, region).into());
+    }
+    Ok(())
+}
+
+/// Checks that the packet was received at most `max_age` before the given
+/// concentrator time. Packets carry the 32 bit microsecond counter of the
+/// concentrator rather than a wall clock time, so freshness can only be
+/// judged against a recent timestamp of the same concentrator. The counter
+/// wraps about every 71 minutes.
+pub fn verify_freshness(packet: &Packet, concentrator_now: u64, max_age: Duration) -> Result {
+    let age_us = concentrator_now.wrapping_sub(packet.timestamp) & 0xffff_ffff;
+    let age = Duration::from_micros(age_us);
+    if age > max_age {
+        return Err(VerifyError::Stale(age.as_millis() as u64).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network, Sign};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn message() {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let mut envelope = BlockchainStateChannelPacketV1 {
+            packet: Some(Packet {
+                frequency: 868.1,
+                timestamp: 0xffff_ff00,
+                payload: vec![0x40, 1, 2, 3, 4],
+                ..Default::default()
+            }),
+            signature: vec![],
+            hotspot: keypair.public_key().to_bytes().to_vec(),
+            region: Region::Eu868.into(),
+            hold_time: 0,
+        };
+        let mut encoded = vec![];
+        envelope.encode(&mut encoded).expect("encode");
+        envelope.signature = keypair.sign(&encoded).expect("sign");
+        let message = |envelope: &BlockchainStateChannelPacketV1| BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Packet(envelope.clone())),
+        };
+
+        let verified = verify_message(&message(&envelope), None).expect("verified");
+        assert_eq!(keypair.public_key(), &verified.hotspot);
+        assert!(verify_message(&message(&envelope), Some(Region::Us915)).is_err());
+        assert!(verify_message(&message(&envelope), Some(Region::Eu868)).is_ok());
+        // The counter wrapped 356 us after the packet
+        assert!(verify_freshness(&verified.packet, 100, Duration::from_millis(1)).is_ok());
+        assert!(verify_freshness(&verified.packet, 2_000, Duration::from_millis(1)).is_err());
+
+        envelope.packet.as_mut().expect("packet").payload[1] = 9;
+        assert!(verify_message(&message(&envelope), None).is_err());
+    }
+
+    #[test]
+    fn region() {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let signed = |region: i32| {
+            let mut envelope = BlockchainStateChannelPacketV1 {
+                packet: Some(Packet {
+                    frequency: 868.1,
+                    payload: vec![0x40, 1, 2, 3, 4],
+                    ..Default::default()
+                }),
+                signature: vec![],
+                hotspot: keypair.public_key().to_bytes().to_vec(),
+                region,
+                hold_time: 0,
+            };
+            let mut encoded = vec![];
+            envelope.encode(&mut encoded).expect("encode");
+            envelope.signature = keypair.sign(&encoded).expect("sign");
+            BlockchainStateChannelMessageV1 {
+                msg: Some(Msg::Packet(envelope)),
+            }
+        };
+        let verify_error = |message: &BlockchainStateChannelMessageV1, region: Option<Region>| {
+            match verify_message(message, region) {
+                Err(Error::Verify(err)) => Some(err),
+                _ => None,
+            }
+        };
+
+        // A version 1 envelope has no region to check against
+        assert!(matches!(
+            verify_error(&signed(0), None),
+            Some(VerifyError::NoRegion)
+        ));
+        let verified = verify_message(&signed(0), Some(Region::Eu868)).expect("verified");
+        assert_eq!(Region::Eu868, verified.region);
+        assert!(matches!(
+            verify_error(&signed(1_000), None),
+            Some(VerifyError::UnknownRegion(1_000))
+        ));
+        assert!(matches!(
+            verify_error(&signed(Region::Eu868.into()), Some(Region::Us915)),
+            Some(VerifyError::Region(_, Region::Us915))
+        ));
+    }
+}